@@ -0,0 +1,13 @@
+// Generates the gRPC control API (`src/grpc.rs`) from proto/provider.proto.
+// `PROTOC` picks the protoc to use, a vendored one is used without it.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::var_os("PROTOC").is_none() {
+        // The build script is single-threaded, nothing reads the environment concurrently
+        unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?) };
+    }
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/provider.proto"], &["proto"])?;
+    println!("cargo:rerun-if-changed=proto/provider.proto");
+    Ok(())
+}
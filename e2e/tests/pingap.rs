@@ -0,0 +1,157 @@
+//! End-to-end tests with testcontainers: a real Pingap container, the
+//! provider binary against its Admin API and labeled app containers on the
+//! same Docker daemon. They need the daemon (the local socket or
+//! `DOCKER_HOST`) and a built provider, so they are ignored by default:
+//!
+//! ```sh
+//! cargo build                                 # in the repository root
+//! cd e2e && cargo test -- --ignored
+//! ```
+//!
+//! `PROVIDER_BIN` points at another provider binary, `PINGAP_IMAGE` at
+//! another Pingap image (default `vicanso/pingap:latest`).
+
+use std::process::Stdio;
+use std::time::Duration;
+use serde_json::Value;
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
+use tokio::process::{Child, Command};
+
+const ADMIN_PORT: u16 = 3018;
+const WAIT: Duration = Duration::from_secs(60);
+
+/// A Pingap container with its Admin API on a host port.
+struct Pingap {
+    _container: ContainerAsync<GenericImage>,
+    admin_url: String,
+    client: reqwest::Client,
+}
+
+impl Pingap {
+    async fn start() -> Self {
+        let image = std::env::var("PINGAP_IMAGE").unwrap_or_else(|_| "vicanso/pingap:latest".to_string());
+        let (name, tag) = image.rsplit_once(':').unwrap_or((&image, "latest"));
+        let container = GenericImage::new(name, tag)
+            .with_exposed_port(ADMIN_PORT.tcp())
+            .with_wait_for(WaitFor::Nothing)
+            .with_cmd(["-c", "/opt/pingap/conf", &format!("--admin=0.0.0.0:{}", ADMIN_PORT)])
+            .start().await
+            .expect("Failed to start the Pingap container");
+        let port = container.get_host_port_ipv4(ADMIN_PORT).await.unwrap();
+        let pingap = Self {
+            _container: container,
+            admin_url: format!("http://127.0.0.1:{}", port),
+            client: reqwest::Client::new(),
+        };
+        pingap.wait_for("the Admin API", || async {
+            pingap.client.get(pingap.url("config")).send().await.is_ok_and(|resp| resp.status().is_success())
+        }).await;
+        pingap
+    }
+
+    /// `path` under the Admin API, with the provider's `PINGAP_ADMIN_PATH_PREFIX` if set.
+    fn url(&self, path: &str) -> String {
+        let prefix = std::env::var("PINGAP_ADMIN_PATH_PREFIX").unwrap_or_default();
+        [self.admin_url.as_str(), prefix.trim_matches('/'), path].iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// The resource Pingap has under `section/name`, None while it has none.
+    async fn resource(&self, section: &str, name: &str) -> Option<Value> {
+        let resp = self.client.get(self.url(&format!("{}/{}", section, name))).send().await.ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        resp.json().await.ok()
+    }
+
+    /// Polls until `check` passes.
+    async fn wait_for<F, Fut>(&self, what: &str, check: F)
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        let deadline = tokio::time::Instant::now() + WAIT;
+        while !check().await {
+            if tokio::time::Instant::now() > deadline {
+                panic!("Timed out waiting for {}", what);
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+}
+
+fn start_provider(pingap: &Pingap) -> Child {
+    let binary = std::env::var("PROVIDER_BIN")
+        .unwrap_or_else(|_| concat!(env!("CARGO_MANIFEST_DIR"), "/../target/debug/pingap-docker-provider").to_string());
+    Command::new(&binary)
+        .env("PINGAP_ADMIN_URL", &pingap.admin_url)
+        .env("LOG_LEVEL", "debug")
+        .env("COMPOSE_STOP_GROUP_SECS", "0")
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap_or_else(|e| panic!("Failed to start the provider at {} (run `cargo build` first): {}", binary, e))
+}
+
+/// A labeled app container routing `<service>.local`.
+async fn start_app(service: &str) -> ContainerAsync<GenericImage> {
+    GenericImage::new("busybox", "latest")
+        .with_wait_for(WaitFor::Nothing)
+        .with_cmd(["sleep", "300"])
+        .with_label("pingap.enable", "true")
+        .with_label("pingap.service.name", service)
+        .with_label("pingap.service.port", "80")
+        .with_label("pingap.http.host", format!("{}.local", service))
+        .start().await
+        .expect("Failed to start the app container")
+}
+
+/// Service names unique per test and run, other labeled containers on the daemon don't interfere.
+fn service_name(test: &str) -> String {
+    format!("e2e-{}-{}", std::process::id(), test)
+}
+
+#[tokio::test]
+#[ignore = "needs a Docker daemon and a built provider"]
+async fn app_container_is_routed_through_real_pingap() {
+    let pingap = Pingap::start().await;
+    let _provider = start_provider(&pingap);
+    let service = service_name("route");
+
+    // Give the provider time to subscribe to events before the app starts
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let app = start_app(&service).await;
+    let addr = format!("{}:80", app.get_bridge_ip_address().await.unwrap());
+
+    pingap.wait_for("the upstream", || async {
+        pingap.resource("upstreams", &service).await
+            .is_some_and(|upstream| upstream["addrs"] == serde_json::json!([addr.clone()]))
+    }).await;
+    let location = pingap.resource("locations", &service).await.expect("location written with the upstream");
+    assert_eq!(location["upstream"], service.as_str());
+    assert_eq!(location["host"], format!("{}.local", service));
+
+    app.rm().await.unwrap();
+    pingap.wait_for("the removal", || async { pingap.resource("upstreams", &service).await.is_none() }).await;
+    assert!(pingap.resource("locations", &service).await.is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs a Docker daemon and a built provider"]
+async fn running_app_is_picked_up_by_initial_sync() {
+    let pingap = Pingap::start().await;
+    let service = service_name("initial");
+    let app = start_app(&service).await;
+    let _provider = start_provider(&pingap);
+
+    pingap.wait_for("the upstream", || async { pingap.resource("upstreams", &service).await.is_some() }).await;
+    let upstream = pingap.resource("upstreams", &service).await.unwrap();
+    assert_eq!(upstream["remark"], "managed-by: pingap-docker-provider");
+    app.rm().await.unwrap();
+}
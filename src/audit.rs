@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::signal;
+use tracing::{debug, error, info, warn};
+use crate::docker::DockerClient;
+use crate::hooks::{ConfigHook, HookOutcome};
+use crate::models::{ContainerInfo, PingapServiceConfig};
+use crate::pingap::{self, PingapClient};
+use crate::plugins;
+use crate::status::Status;
+
+/// A difference between what Docker labels ask for and what Pingap serves.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    /// Desired by a running container but absent from Pingap
+    Missing { service: String },
+    /// Present in Pingap with different settings
+    Different { service: String, fields: Vec<String> },
+    /// Provider-managed resource in Pingap without a backing container
+    Orphaned { service: String },
+}
+
+impl Drift {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Drift::Missing { .. } => "missing",
+            Drift::Different { .. } => "different",
+            Drift::Orphaned { .. } => "orphaned",
+        }
+    }
+}
+
+/// Fields of `expected` that are absent or different in `actual`, prefixed with `section`.
+fn differing_fields(section: &str, expected: &Value, actual: &Value) -> Vec<String> {
+    expected.as_object()
+        .map(|fields| fields.iter()
+            .filter(|(key, value)| actual.get(key.as_str()) != Some(value))
+            .map(|(key, _)| format!("{}.{}", section, key))
+            .collect())
+        .unwrap_or_default()
+}
+
+/// Compares desired service configs against Pingap's full config document.
+pub fn detect_drift(desired: &[PingapServiceConfig], actual: &Value) -> Result<Vec<Drift>> {
+    let mut drift = Vec::new();
+    let upstreams = &actual["upstreams"];
+    let locations = &actual["locations"];
+
+    for config in desired {
+        let upstream = upstreams.get(config.upstream_name());
+        let location = locations.get(config.location_name());
+
+        match (upstream, location) {
+            (None, None) => drift.push(Drift::Missing { service: config.name.clone() }),
+            _ => {
+                let mut fields = Vec::new();
+                match upstream {
+                    Some(u) => fields.extend(differing_fields("upstream", &pingap::upstream_payload(config), u)),
+                    None => fields.push("upstream".to_string()),
+                }
+                let mut expected_location = pingap::location_payload(config)?;
+                if config.deployment.is_some() {
+                    // Which slot is active is up to `cutover`, not the labels
+                    expected_location.as_object_mut().map(|fields| fields.remove("upstream"));
+                }
+                match location {
+                    Some(l) => fields.extend(differing_fields("location", &expected_location, l)),
+                    None => fields.push("location".to_string()),
+                }
+                if !fields.is_empty() {
+                    drift.push(Drift::Different { service: config.name.clone(), fields });
+                }
+            }
+        }
+    }
+
+    let desired_names = desired.iter()
+        .flat_map(|c| [c.name.as_str(), c.upstream_name(), c.location_name()])
+        .collect::<HashSet<_>>();
+    let mut orphaned = HashSet::new();
+    for section in [upstreams, locations] {
+        if let Some(entries) = section.as_object() {
+            for (name, resource) in entries {
+                if pingap::is_managed(resource) && !desired_names.contains(name.as_str()) {
+                    orphaned.insert(name.clone());
+                }
+            }
+        }
+    }
+    let mut orphaned = orphaned.into_iter().collect::<Vec<_>>();
+    orphaned.sort();
+    drift.extend(orphaned.into_iter().map(|service| Drift::Orphaned { service }));
+
+    Ok(drift)
+}
+
+/// The service configs the running containers ask for.
+#[derive(Debug, Default)]
+pub struct DesiredConfigs {
+    pub configs: Vec<PingapServiceConfig>,
+    /// Services the config hook refused or failed on, as their labels
+    /// describe them. Pingap keeps what it has of them, so a hook error or
+    /// timeout never reads as the service being gone.
+    pub held: Vec<PingapServiceConfig>,
+}
+
+impl DesiredConfigs {
+    /// Whether `resource`, an upstream, location or generated plugin, belongs to a held service.
+    pub fn holds(&self, resource: &str) -> bool {
+        self.held.iter().any(|config| {
+            [config.name.as_str(), config.upstream_name(), config.location_name()].contains(&resource)
+                || plugins::PLUGIN_MIDDLEWARES.iter().any(|middleware| plugins::plugin_name(config.location_name(), middleware) == resource)
+        })
+    }
+
+    /// [`detect_drift`] of the configs, where the held services' resources are not orphaned.
+    pub fn drift(&self, actual: &Value) -> Result<Vec<Drift>> {
+        let mut drift = detect_drift(&self.configs, actual)?;
+        drift.retain(|drift| !matches!(drift, Drift::Orphaned { service } if self.holds(service)));
+        Ok(drift)
+    }
+}
+
+/// The service configs the running containers ask for, skipping invalid
+/// labels and holding back the services `hook` refuses or fails on.
+pub fn desired_configs(containers: &[ContainerInfo], hook: Option<&ConfigHook>) -> DesiredConfigs {
+    let mut desired = DesiredConfigs::default();
+    let configs = containers.iter()
+        .flat_map(|c| match c.parse_pingap_configs() {
+            Ok(configs) => configs.into_iter().map(move |config| (c, config)).collect(),
+            Err(e) => {
+                debug!("Ignoring container {} with invalid labels: {:?}", c.name, e);
+                Vec::new()
+            }
+        });
+    for (c, config) in configs {
+        match hook.map(|hook| hook.transform(&config, c)) {
+            None => desired.configs.push(config),
+            Some(Ok(HookOutcome::Apply(transformed))) => desired.configs.push(*transformed),
+            Some(Ok(HookOutcome::Veto(_))) => {
+                debug!("Keeping service {} as Pingap has it, the config hook refused it", config.name);
+                desired.held.push(config);
+            },
+            Some(Err(e)) => {
+                warn!("Keeping service {} as Pingap has it: {:#}", config.name, e);
+                desired.held.push(config);
+            },
+        }
+    }
+    desired
+}
+
+async fn audit_once(docker: &DockerClient, pingap: &PingapClient, hook: Option<&ConfigHook>) -> Result<Vec<Drift>> {
+    let containers = docker.get_running_containers().await?;
+    let desired = desired_configs(&containers, hook);
+
+    let actual = pingap.cached_full_config().await?;
+    desired.drift(&actual)
+}
+
+fn publish(status: &Status, drift: &[Drift]) {
+    status.update(|s| {
+        s.drift = drift.to_vec();
+        s.ready = true;
+    });
+
+    status.metrics.reset_gauge("pingap_provider_drift");
+    for kind in ["missing", "different", "orphaned"] {
+        let count = drift.iter().filter(|d| d.kind() == kind).count();
+        status.metrics.set_gauge("pingap_provider_drift", &[("kind", kind)], count as f64);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    status.metrics.set_gauge("pingap_provider_audit_last_run_timestamp_seconds", &[], now.as_secs() as f64);
+}
+
+/// Read-only mode: watches Docker and Pingap and reports drift, never writes.
+pub async fn run(docker: &DockerClient, pingap: &PingapClient, hook: Option<&ConfigHook>, status: Arc<Status>, interval: Duration) -> Result<()> {
+    info!("Running in audit mode, Pingap will not be modified");
+
+    let mut events = docker.subscribe_to_events(None).await;
+    let mut ticker = tokio::time::interval(interval);
+    let mut last_drift: Option<Vec<Drift>> = None;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            event = events.next() => {
+                match event {
+                    Some(Ok(_)) => {},
+                    Some(Err(e)) => {
+                        error!("Docker event stream error: {:?}", e);
+                        continue;
+                    },
+                    None => {
+                        warn!("Docker event stream ended.");
+                        break;
+                    }
+                }
+            },
+            _ = signal::ctrl_c() => {
+                info!("Received shutdown signal");
+                break;
+            }
+        }
+
+        match audit_once(docker, pingap, hook).await {
+            Ok(drift) => {
+                // Only log when the picture changes, the status API always has the full list
+                if last_drift.as_ref() != Some(&drift) {
+                    if drift.is_empty() {
+                        info!("Audit: Pingap matches Docker labels, no drift");
+                    }
+                    for d in &drift {
+                        warn!("Audit drift: {:?}", d);
+                    }
+                }
+                publish(&status, &drift);
+                last_drift = Some(drift);
+            },
+            Err(e) => {
+                status.metrics.inc("pingap_provider_audit_errors_total", &[]);
+                error!("Audit pass failed: {:?}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Deployment, PingapServiceConfigBuilder, Slot};
+
+    fn service(name: &str, addr: &str) -> PingapServiceConfig {
+        PingapServiceConfigBuilder::new(name, vec![addr.to_string()], format!("Host(`{}.local`)", name)).build()
+    }
+
+    fn applied(configs: &[PingapServiceConfig]) -> Value {
+        let mut full = serde_json::json!({});
+        for config in configs {
+            full["upstreams"][&config.name] = pingap::upstream_payload(config);
+            full["locations"][&config.name] = pingap::location_payload(config).unwrap();
+        }
+        full
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let desired = vec![service("web", "10.0.0.1:80")];
+        let drift = detect_drift(&desired, &applied(&desired)).unwrap();
+        assert!(drift.is_empty());
+    }
+
+    #[test]
+    fn test_missing_service() {
+        let desired = vec![service("web", "10.0.0.1:80")];
+        let drift = detect_drift(&desired, &serde_json::json!({})).unwrap();
+        assert_eq!(drift, vec![Drift::Missing { service: "web".to_string() }]);
+    }
+
+    #[test]
+    fn test_different_upstream() {
+        let desired = vec![service("web", "10.0.0.1:80")];
+        let actual = applied(&[service("web", "10.0.0.2:80")]);
+
+        let drift = detect_drift(&desired, &actual).unwrap();
+        assert_eq!(drift, vec![Drift::Different {
+            service: "web".to_string(),
+            fields: vec!["upstream.addrs".to_string()],
+        }]);
+    }
+
+    #[test]
+    fn test_partially_missing_service() {
+        let desired = vec![service("web", "10.0.0.1:80")];
+        let mut actual = applied(&desired);
+        actual["locations"].as_object_mut().unwrap().remove("web");
+
+        let drift = detect_drift(&desired, &actual).unwrap();
+        assert_eq!(drift, vec![Drift::Different {
+            service: "web".to_string(),
+            fields: vec!["location".to_string()],
+        }]);
+    }
+
+    #[test]
+    fn test_orphaned_only_for_managed_resources() {
+        let mut actual = applied(&[service("gone", "10.0.0.3:80")]);
+        actual["upstreams"]["manual"] = serde_json::json!({ "addrs": ["10.0.0.9:80"] });
+
+        let drift = detect_drift(&[], &actual).unwrap();
+        assert_eq!(drift, vec![Drift::Orphaned { service: "gone".to_string() }]);
+    }
+
+    #[test]
+    fn test_services_the_hook_fails_on_are_kept() {
+        let container = ContainerInfo {
+            id: "c1".to_string(),
+            name: "/web".to_string(),
+            image: "web:1".to_string(),
+            labels: [("pingap.enable", "true"), ("pingap.http.host", "web.local")].into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ip_address: Some("10.0.0.1".to_string()),
+            ports: vec![80],
+            networks: Default::default(),
+            ipv6_networks: Default::default(),
+        };
+        let hook = ConfigHook::from_source("hook.lua", "function transform(config, container) error('boom') end").unwrap();
+        let desired = desired_configs(&[container], Some(&hook));
+        assert!(desired.configs.is_empty());
+        assert_eq!(desired.held.len(), 1);
+
+        // Its live route is no orphan, another service's is
+        let mut actual = applied(&desired.held);
+        let gone = applied(&[service("gone", "10.0.0.3:80")]);
+        actual["upstreams"]["gone"] = gone["upstreams"]["gone"].clone();
+        assert_eq!(desired.drift(&actual).unwrap(), [Drift::Orphaned { service: "gone".to_string() }]);
+    }
+
+    #[test]
+    fn test_publish_updates_status_and_metrics() {
+        let status = Status::new("audit");
+        publish(&status, &[Drift::Missing { service: "web".to_string() }]);
+
+        assert_eq!(status.snapshot().drift.len(), 1);
+        let metrics = status.metrics.render();
+        assert!(metrics.contains("pingap_provider_drift{kind=\"missing\"} 1"));
+        assert!(metrics.contains("pingap_provider_drift{kind=\"orphaned\"} 0"));
+    }
+
+    #[test]
+    fn test_blue_green_slots_not_drift() {
+        let slot = |name: &str, slot: Slot, addr: &str| {
+            let mut config = service(name, addr);
+            config.location.rule = "Host(`shop.local`)".to_string();
+            config.deployment = Some(Deployment { service: "shop".to_string(), slot });
+            config
+        };
+        let desired = vec![slot("shop-blue", Slot::Blue, "10.0.0.1:80"), slot("shop-green", Slot::Green, "10.0.0.2:80")];
+        let mut actual = applied(&desired);
+        // The location is shared, pointing at the green slot after a cutover
+        let mut location = actual["locations"]["shop-green"].take();
+        location["upstream"] = serde_json::json!("shop-green");
+        actual["locations"] = serde_json::json!({ "shop": location });
+
+        assert!(detect_drift(&desired, &actual).unwrap().is_empty());
+    }
+}
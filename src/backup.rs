@@ -0,0 +1,163 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Value};
+use tracing::{info, warn};
+use crate::pingap::PingapClient;
+
+/// Snapshots of Pingap's config taken before the provider deletes or
+/// overwrites a resource (`BACKUP_DIR`), one `pingap-<unix ms>.json` file
+/// each. A snapshot holds the complete sections (`upstreams`, `locations`,
+/// `plugins`) of the resources about to change; the newest `keep` are kept.
+pub struct Backups {
+    dir: PathBuf,
+    keep: usize,
+}
+
+impl Backups {
+    /// A `keep` of zero never removes old snapshots.
+    pub fn open(dir: impl Into<PathBuf>, keep: usize) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context(format!("Failed to create backup directory {}", dir.display()))?;
+        Ok(Self { dir, keep })
+    }
+
+    /// Snapshots the sections of `resources` (section, name) out of `full`.
+    /// A failure is logged, never passed on: a missing backup shouldn't stop
+    /// the provider from following the containers.
+    pub fn save(&self, full: &Value, resources: &[(&str, &str)]) {
+        match self.write(full, resources) {
+            Ok(path) => info!("Backed up Pingap config to {} before changing {} resources", path.display(), resources.len()),
+            Err(e) => warn!("Failed to back up Pingap config to {}: {:?}", self.dir.display(), e),
+        }
+    }
+
+    fn write(&self, full: &Value, resources: &[(&str, &str)]) -> Result<PathBuf> {
+        let mut sections = resources.iter().map(|(section, _)| *section).collect::<Vec<_>>();
+        sections.sort();
+        sections.dedup();
+        let config = sections.into_iter()
+            .map(|section| (section.to_string(), full.get(section).cloned().unwrap_or_else(|| Value::Object(Map::new()))))
+            .collect::<Map<_, _>>();
+        let created_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let snapshot = serde_json::json!({
+            "created_ms": created_ms,
+            "resources": resources.iter().map(|(section, name)| format!("{}/{}", section, name)).collect::<Vec<_>>(),
+            "config": config,
+        });
+
+        // Names sort by time, a second snapshot within the same millisecond takes the next one
+        let path = (created_ms..)
+            .map(|ms| self.dir.join(format!("pingap-{}.json", ms)))
+            .find(|path| !path.exists())
+            .unwrap_or_default();
+        fs::write(&path, serde_json::to_vec_pretty(&snapshot)?)?;
+        if self.keep > 0 {
+            let existing = snapshots(&self.dir)?;
+            for old in &existing[..existing.len().saturating_sub(self.keep)] {
+                fs::remove_file(old).context(format!("Failed to remove old backup {}", old.display()))?;
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// The snapshots in `dir`, oldest first.
+fn snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir).context(format!("Failed to list backups in {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("pingap-") && name.ends_with(".json")))
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// The config sections saved in a snapshot file.
+fn load(path: &Path) -> Result<Map<String, Value>> {
+    let content = fs::read_to_string(path).context(format!("Failed to read backup {}", path.display()))?;
+    let snapshot: Value = serde_json::from_str(&content).context(format!("Failed to decode backup {}", path.display()))?;
+    match snapshot.get("config") {
+        Some(Value::Object(sections)) => Ok(sections.clone()),
+        _ => Err(anyhow!("Backup {} has no config sections", path.display())),
+    }
+}
+
+/// The snapshot, `--dry-run` and `--prune` of the arguments.
+fn parse_args(args: &[String]) -> Result<(String, bool, bool)> {
+    let usage = || anyhow!("Usage: pingap-docker-provider restore <backup file>|latest [--dry-run] [--prune]");
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let prune = args.iter().any(|arg| arg == "--prune");
+    match args.iter().filter(|arg| *arg != "--dry-run" && *arg != "--prune").collect::<Vec<_>>().as_slice() {
+        [snapshot] if !snapshot.starts_with("--") => Ok((snapshot.to_string(), dry_run, prune)),
+        _ => Err(usage()),
+    }
+}
+
+/// `pingap-docker-provider restore <backup file>|latest [--dry-run] [--prune]`:
+/// puts the resources saved in a snapshot back into Pingap and prints the
+/// ones that differed. Resources the snapshot doesn't have are only removed
+/// with `--prune`. `latest` is the newest snapshot in `backup_dir`.
+pub async fn run(pingap: &PingapClient, backup_dir: Option<&str>, args: &[String]) -> Result<()> {
+    let (snapshot, dry_run, prune) = parse_args(args)?;
+    let path = if snapshot == "latest" {
+        let dir = backup_dir.ok_or_else(|| anyhow!("restore latest needs BACKUP_DIR"))?;
+        snapshots(Path::new(dir))?.pop().ok_or_else(|| anyhow!("No backups in {}", dir))?
+    } else {
+        PathBuf::from(snapshot)
+    };
+
+    let sections = load(&path)?;
+    let changed = pingap.restore(&sections, dry_run, prune).await?;
+    for resource in &changed {
+        println!("{}", resource);
+    }
+    if changed.is_empty() {
+        info!("Pingap already matches {}", path.display());
+    } else if dry_run {
+        info!("Dry run: restoring {} would change {} resources", path.display(), changed.len());
+    } else {
+        info!("Restored {} resources from {}", changed.len(), path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_snapshots_keep_affected_sections() {
+        let dir = std::env::temp_dir().join(format!("pingap-backups-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let backups = Backups::open(&dir, 2).unwrap();
+        let full = serde_json::json!({
+            "upstreams": { "web": { "addrs": ["10.0.0.1:80"] } },
+            "locations": { "web": { "upstream": "web" } },
+            "servers": { "main": { "addr": "0.0.0.0:80" } },
+        });
+        for _ in 0..3 {
+            backups.write(&full, &[("locations", "web"), ("upstreams", "web")]).unwrap();
+        }
+
+        let paths = snapshots(&dir).unwrap();
+        assert_eq!(paths.len(), 2);
+        let sections = load(paths.last().unwrap()).unwrap();
+        assert_eq!(sections.keys().collect::<Vec<_>>(), ["locations", "upstreams"]);
+        assert_eq!(sections["upstreams"]["web"]["addrs"][0], "10.0.0.1:80");
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&args(&["latest"])).unwrap(), ("latest".to_string(), false, false));
+        assert_eq!(parse_args(&args(&["--dry-run", "/backups/pingap-1.json"])).unwrap(), ("/backups/pingap-1.json".to_string(), true, false));
+        assert_eq!(parse_args(&args(&["latest", "--prune"])).unwrap(), ("latest".to_string(), false, true));
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["a.json", "b.json"])).is_err());
+    }
+}
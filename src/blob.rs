@@ -0,0 +1,305 @@
+//! `pingap.config`: a service's labels as one JSON or YAML document, for
+//! setups that outgrow flat labels. Nested keys are read relative to
+//! `pingap.`, so `{"http": {"host": "app.local"}}` stands for
+//! `pingap.http.host=app.local`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use crate::models::{self, LabelType, LABEL_CONFIG, LABEL_ENABLE, LABEL_PROFILE};
+
+/// The labels a `pingap.config` document stands for. Every key must be a
+/// label the provider understands and every value must fit its type; errors
+/// tell the line and column of the offending value.
+pub fn expand(document: &str) -> Result<Vec<(String, String)>> {
+    let mut labels = Vec::new();
+    parse(document, Node { path: None, labels: &mut labels })?;
+    Ok(labels)
+}
+
+/// Several documents by name, as `{"<name>": {<document>}, ...}`.
+pub fn expand_named(document: &str) -> Result<BTreeMap<String, Vec<(String, String)>>> {
+    let mut documents = BTreeMap::new();
+    parse(document, Named(&mut documents))?;
+    Ok(documents)
+}
+
+/// Runs `seed` over `document`: JSON when it starts with `{` or `[`, YAML
+/// otherwise, so JSON keeps its own error messages.
+fn parse<S: for<'de> DeserializeSeed<'de, Value = ()>>(document: &str, seed: S) -> Result<()> {
+    if document.trim_start().starts_with(['{', '[']) {
+        let mut deserializer = serde_json::Deserializer::from_str(document);
+        seed.deserialize(&mut deserializer)
+            .and_then(|()| deserializer.end())
+            .map_err(|e| anyhow!("{}", e))
+    } else {
+        seed.deserialize(serde_yaml::Deserializer::from_str(document))
+            .map_err(|e| anyhow!("{}", e))
+    }
+}
+
+/// The labels with `pingap.config` expanded into them, or `None` without
+/// one. Labels set on their own win over the document's.
+pub fn merged(labels: &HashMap<String, String>) -> Result<Option<HashMap<String, String>>> {
+    let Some(document) = labels.get(LABEL_CONFIG) else {
+        return Ok(None);
+    };
+    let mut merged = expand(document)?.into_iter().collect::<HashMap<_, _>>();
+    merged.extend(labels.iter().filter(|(key, _)| key.as_str() != LABEL_CONFIG).map(|(key, value)| (key.clone(), value.clone())));
+    Ok(Some(merged))
+}
+
+/// A value of the document, at `path` (the label without `pingap.`).
+struct Node<'a> {
+    path: Option<String>,
+    labels: &'a mut Vec<(String, String)>,
+}
+
+impl Node<'_> {
+    /// The label the value at `path` sets and its type.
+    fn label<E: de::Error>(&self) -> Result<(String, LabelType), E> {
+        let Some(path) = &self.path else {
+            return Err(E::custom("expected an object of labels"));
+        };
+        let label = format!("pingap.{}", path);
+        if label == LABEL_ENABLE || label == LABEL_CONFIG || label == LABEL_PROFILE {
+            return Err(E::custom(format!("{} must be set as a label of its own", label)));
+        }
+        match models::label_type(&label) {
+            Some(kind) => Ok((label, kind)),
+            None => Err(E::custom(format!("unknown label {}", label))),
+        }
+    }
+
+    fn scalar<E: de::Error>(self, value: String, fits: impl Fn(LabelType) -> bool, what: &str) -> Result<(), E> {
+        let (label, kind) = self.label()?;
+        if !fits(kind) {
+            return Err(E::custom(format!("{} takes {}, not {}", label, describe(kind), what)));
+        }
+        self.labels.push((label, value));
+        Ok(())
+    }
+}
+
+/// What a label of this type is written as in the document.
+fn describe(kind: LabelType) -> &'static str {
+    match kind {
+        LabelType::Bool => "a boolean",
+        LabelType::Integer | LabelType::Ratio => "a number",
+        LabelType::List | LabelType::ListOf(_) => "a list",
+        LabelType::Text | LabelType::Duration | LabelType::OneOf(_) => "a string",
+    }
+}
+
+fn is_list(kind: LabelType) -> bool {
+    matches!(kind, LabelType::List | LabelType::ListOf(_))
+}
+
+impl<'de> DeserializeSeed<'de> for Node<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Node<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object of labels")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(Key(key)) = map.next_key()? {
+            let path = match &self.path {
+                Some(path) => format!("{}.{}", path, key),
+                None => key,
+            };
+            map.next_value_seed(Node { path: Some(path), labels: &mut *self.labels })?;
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let (label, kind) = self.label()?;
+        if !is_list(kind) {
+            return Err(de::Error::custom(format!("{} takes {}, not a list", label, describe(kind))));
+        }
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element::<serde_json::Value>()? {
+            match item {
+                serde_json::Value::String(item) if !item.contains(',') => items.push(item),
+                serde_json::Value::Number(item) => items.push(item.to_string()),
+                _ => return Err(de::Error::custom(format!("{} takes a list of strings without commas", label))),
+            }
+        }
+        self.labels.push((label, items.join(",")));
+        Ok(())
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<(), E> {
+        self.scalar(value.to_string(), |_| true, "a string")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<(), E> {
+        self.scalar(value.to_string(), |kind| kind == LabelType::Bool, "a boolean")
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<(), E> {
+        self.scalar(value.to_string(), |kind| matches!(kind, LabelType::Integer | LabelType::Ratio | LabelType::Text), "a number")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<(), E> {
+        self.scalar(value.to_string(), |kind| matches!(kind, LabelType::Integer | LabelType::Ratio | LabelType::Text), "a number")
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<(), E> {
+        self.scalar(value.to_string(), |kind| kind == LabelType::Ratio, "a fraction")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        let (label, _) = self.label()?;
+        Err(E::custom(format!("{} can't be null; leave it out instead", label)))
+    }
+}
+
+/// A key of the document. YAML reads keys like `503` of `errors` as
+/// numbers, they name labels all the same.
+struct Key(String);
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+struct KeyVisitor;
+
+impl Visitor<'_> for KeyVisitor {
+    type Value = Key;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a label name")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Key, E> {
+        Ok(Key(value.to_string()))
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Key, E> {
+        Ok(Key(value.to_string()))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Key, E> {
+        Ok(Key(value.to_string()))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Key, E> {
+        Ok(Key(value.to_string()))
+    }
+}
+
+/// Documents by name.
+struct Named<'a>(&'a mut BTreeMap<String, Vec<(String, String)>>);
+
+impl<'de> DeserializeSeed<'de> for Named<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Named<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object of named label documents")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(Key(name)) = map.next_key()? {
+            let mut labels = Vec::new();
+            map.next_value_seed(Node { path: None, labels: &mut labels })?;
+            self.0.insert(name, labels);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(labels: &[(String, String)], name: &str) -> Option<String> {
+        labels.iter().find(|(label, _)| label == name).map(|(_, value)| value.clone())
+    }
+
+    #[test]
+    fn test_expand_nested_keys() {
+        let labels = expand(r#"{
+            "http": { "host": "app.local", "paths": ["/api", "/v2"] },
+            "service.port": 8080,
+            "middleware": { "compress": true },
+            "errors": { "503": "<h1>Down</h1>" },
+            "services": { "admin": { "port": 9000 } }
+        }"#).unwrap();
+        assert_eq!(label(&labels, "pingap.http.host").as_deref(), Some("app.local"));
+        assert_eq!(label(&labels, "pingap.http.paths").as_deref(), Some("/api,/v2"));
+        assert_eq!(label(&labels, "pingap.service.port").as_deref(), Some("8080"));
+        assert_eq!(label(&labels, "pingap.middleware.compress").as_deref(), Some("true"));
+        assert_eq!(label(&labels, "pingap.errors.503").as_deref(), Some("<h1>Down</h1>"));
+        assert_eq!(label(&labels, "pingap.services.admin.port").as_deref(), Some("9000"));
+    }
+
+    #[test]
+    fn test_errors_tell_position() {
+        let error = expand("{\n  \"http\": {\n    \"hots\": \"app.local\"\n  }\n}").unwrap_err().to_string();
+        assert!(error.contains("unknown label pingap.http.hots"), "{}", error);
+        assert!(error.contains("line 3"), "{}", error);
+
+        let error = expand(r#"{"service": {"port": true}}"#).unwrap_err().to_string();
+        assert!(error.contains("pingap.service.port takes a number, not a boolean at line 1"), "{}", error);
+        assert!(expand(r#"{"http": {"host": ["a", "b"]}}"#).is_err());
+        assert!(expand(r#"{"enable": true}"#).is_err());
+        assert!(expand(r#"["http.host"]"#).is_err());
+        assert!(expand(r#"{"http": {"host": "a"}"#).is_err());
+    }
+
+    #[test]
+    fn test_expand_yaml() {
+        let labels = expand("http:\n  host: app.local\n  paths: [/api, /v2]\nservice.port: 8080\nmiddleware:\n  compress: true\nerrors:\n  503: <h1>Down</h1>\n").unwrap();
+        assert_eq!(label(&labels, "pingap.http.host").as_deref(), Some("app.local"));
+        assert_eq!(label(&labels, "pingap.http.paths").as_deref(), Some("/api,/v2"));
+        assert_eq!(label(&labels, "pingap.service.port").as_deref(), Some("8080"));
+        assert_eq!(label(&labels, "pingap.middleware.compress").as_deref(), Some("true"));
+        assert_eq!(label(&labels, "pingap.errors.503").as_deref(), Some("<h1>Down</h1>"));
+
+        let error = expand("http:\n  host: app.local\n  hots: app.local\n").unwrap_err().to_string();
+        assert!(error.contains("unknown label pingap.http.hots"), "{}", error);
+        assert!(error.contains("line 3"), "{}", error);
+        let error = expand("service:\n  port: true\n").unwrap_err().to_string();
+        assert!(error.contains("pingap.service.port takes a number, not a boolean"), "{}", error);
+        assert!(expand("http:\n  host: ~\n").is_err());
+        assert!(expand("- http.host\n").is_err());
+
+        let profiles = expand_named("spa:\n  middleware:\n    compress: true\n").unwrap();
+        assert_eq!(label(&profiles["spa"], "pingap.middleware.compress").as_deref(), Some("true"));
+    }
+
+    #[test]
+    fn test_flat_labels_win() {
+        let labels = HashMap::from([
+            (LABEL_CONFIG.to_string(), r#"{"http": {"host": "blob.local", "paths": ["/api"]}}"#.to_string()),
+            ("pingap.http.host".to_string(), "flat.local".to_string()),
+        ]);
+        let expanded = merged(&labels).unwrap().unwrap();
+        assert_eq!(expanded["pingap.http.host"], "flat.local");
+        assert_eq!(expanded["pingap.http.paths"], "/api");
+        assert!(!expanded.contains_key(LABEL_CONFIG));
+        assert!(merged(&HashMap::new()).unwrap().is_none());
+    }
+}
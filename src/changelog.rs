@@ -0,0 +1,203 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::warn;
+use crate::redact;
+
+tokio::task_local! {
+    /// The container(s) whose Docker event caused the Pingap writes of the current task.
+    pub static ACTOR: String;
+}
+
+/// One create/update/delete sent to Pingap's Admin API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub timestamp_ms: u64,
+    /// `create`, `update` or `delete`; `write` when the previous state is unknown
+    pub action: &'static str,
+    /// e.g. `upstreams/web`
+    pub resource: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    /// Field -> `{"before": ..., "after": ...}` for every field that changed, credentials masked
+    pub diff: Value,
+    /// `success` or `error`
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Change {
+    /// `before` is None when the previous state is unknown, Some(None) when
+    /// the resource didn't exist; `after` is None for a delete.
+    pub fn new(resource: String, before: Option<Option<&Value>>, after: Option<&Value>, error: Option<&anyhow::Error>) -> Self {
+        let action = match (before, after) {
+            (_, None) => "delete",
+            (None, Some(_)) => "write",
+            (Some(None), Some(_)) => "create",
+            (Some(Some(_)), Some(_)) => "update",
+        };
+        Self {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            action,
+            resource,
+            actor: ACTOR.try_with(|actor| actor.clone()).ok().filter(|actor| !actor.is_empty()),
+            diff: redact::value(&diff(before.flatten(), after)),
+            outcome: if error.is_none() { "success" } else { "error" },
+            error: error.map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+/// The top-level fields that differ between two versions of a resource.
+pub fn diff(before: Option<&Value>, after: Option<&Value>) -> Value {
+    let empty = Map::new();
+    let before = before.and_then(Value::as_object).unwrap_or(&empty);
+    let after = after.and_then(Value::as_object).unwrap_or(&empty);
+
+    let mut keys = before.keys().chain(after.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    let changed = keys.into_iter()
+        .filter(|key| before.get(*key) != after.get(*key))
+        .map(|key| (key.clone(), serde_json::json!({
+            "before": before.get(key).cloned().unwrap_or(Value::Null),
+            "after": after.get(key).cloned().unwrap_or(Value::Null),
+        })))
+        .collect::<Map<_, _>>();
+    Value::Object(changed)
+}
+
+/// Append-only JSONL file of every change written to Pingap, so it can be
+/// traced which container caused which proxy change. Past `max_bytes` the
+/// file is rotated to `<path>.1` ... `<path>.<keep>`.
+pub struct ChangeLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: Mutex<File>,
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+        .context(format!("Failed to open change log {}", path.display()))
+}
+
+impl ChangeLog {
+    /// A `max_bytes` of zero disables rotation.
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, keep: u32) -> Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        Ok(Self { path, max_bytes, keep, file: Mutex::new(file) })
+    }
+
+    /// Appends a change. A failure is logged, never passed on: the Pingap
+    /// write it describes already happened.
+    pub fn record(&self, change: &Change) {
+        if let Err(e) = self.append(change) {
+            warn!("Failed to write change log entry for {}: {:?}", change.resource, e);
+        }
+    }
+
+    fn append(&self, change: &Change) -> Result<()> {
+        let mut line = serde_json::to_string(change)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        let size = file.metadata()?.len();
+        if self.max_bytes > 0 && size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+            *file = open_append(&self.path)?;
+        }
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path).context("Failed to truncate change log");
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1)).context("Failed to rotate change log")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pingap-changelog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("changes.jsonl")
+    }
+
+    #[test]
+    fn test_diff_only_changed_fields() {
+        let before = serde_json::json!({ "addrs": ["10.0.0.1:80"], "remark": "x" });
+        let after = serde_json::json!({ "addrs": ["10.0.0.2:80"], "remark": "x", "weight": 1 });
+        assert_eq!(diff(Some(&before), Some(&after)), serde_json::json!({
+            "addrs": { "before": ["10.0.0.1:80"], "after": ["10.0.0.2:80"] },
+            "weight": { "before": null, "after": 1 },
+        }));
+        assert_eq!(diff(Some(&before), None)["remark"]["after"], Value::Null);
+    }
+
+    #[test]
+    fn test_change_actions() {
+        let payload = serde_json::json!({ "addrs": [] });
+        assert_eq!(Change::new("upstreams/web".into(), Some(None), Some(&payload), None).action, "create");
+        assert_eq!(Change::new("upstreams/web".into(), Some(Some(&payload)), Some(&payload), None).action, "update");
+        assert_eq!(Change::new("upstreams/web".into(), None, Some(&payload), None).action, "write");
+
+        let failed = Change::new("upstreams/web".into(), None, None, Some(&anyhow::anyhow!("boom")));
+        assert_eq!((failed.action, failed.outcome), ("delete", "error"));
+        assert_eq!(failed.error.as_deref(), Some("boom"));
+        assert_eq!(failed.actor, None);
+    }
+
+    #[tokio::test]
+    async fn test_actor_from_task() {
+        let change = ACTOR.scope("c1".to_string(), async {
+            Change::new("locations/web".into(), None, None, None)
+        }).await;
+        assert_eq!(change.actor.as_deref(), Some("c1"));
+    }
+
+    #[test]
+    fn test_append_and_rotate() {
+        let path = temp_path("rotate");
+        let log = ChangeLog::open(&path, 300, 2).unwrap();
+        let change = Change::new("upstreams/web".into(), Some(None), Some(&serde_json::json!({ "addrs": ["10.0.0.1:80"] })), None);
+        for _ in 0..6 {
+            log.record(&change);
+        }
+
+        let current = fs::read_to_string(&path).unwrap();
+        let entry: Value = serde_json::from_str(current.lines().next().unwrap()).unwrap();
+        assert_eq!(entry["resource"], "upstreams/web");
+        assert!(log.rotated(1).exists());
+        assert!(log.rotated(2).exists());
+        assert!(!log.rotated(3).exists());
+        for path in [path.clone(), log.rotated(1), log.rotated(2)] {
+            assert!(fs::metadata(path).unwrap().len() <= 300);
+        }
+    }
+}
@@ -1,28 +1,1254 @@
+use std::collections::HashMap;
 use std::env;
-use anyhow::{Result, Context};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{Result, Context, anyhow};
+use backoff::ExponentialBackoff;
+use crate::models::{
+    self, LABEL_ENABLE, LABEL_HEADERS_FORWARDED, LABEL_HEADERS_REQUEST_ID, LABEL_HTTP_HOST,
+    LABEL_MIDDLEWARE_BASIC_AUTH, LABEL_SERVICE_ADDRESS, LABEL_SERVICE_NAME,
+};
+use crate::lifecycle::LifecycleHooks;
+use crate::pingap::DEFAULT_MAINTENANCE_PLUGIN;
+use crate::secrets::Secrets;
+use crate::signing::RequestSigner;
 
-#[derive(Debug, Clone)]
+/// Preset for the provider's queue sizes, cache sizes and reconcile frequency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResourceProfile {
+    #[default]
+    Default,
+    /// Small queues and caches and a slower reconcile loop, for devices like a Raspberry Pi
+    Small,
+}
+
+impl FromStr for ResourceProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "default" => Ok(ResourceProfile::Default),
+            "small" => Ok(ResourceProfile::Small),
+            other => Err(anyhow!("unknown resource profile '{}', expected 'default' or 'small'", other)),
+        }
+    }
+}
+
+/// What the provider does with the Docker state it observes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Apply label changes to Pingap (default)
+    #[default]
+    Sync,
+    /// Only report drift between Docker and Pingap, never write
+    Audit,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Sync => "sync",
+            Mode::Audit => "audit",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sync" => Ok(Mode::Sync),
+            "audit" => Ok(Mode::Audit),
+            other => Err(anyhow!("unknown mode '{}', expected 'sync' or 'audit'", other)),
+        }
+    }
+}
+
+/// What happens to a service whose route overlaps the route of another
+/// service: same host and path at the same priority.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Apply it anyway and report the conflict (default)
+    #[default]
+    Warn,
+    /// Refuse it, the service that held the route first keeps it
+    Block,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(ConflictPolicy::Warn),
+            "block" => Ok(ConflictPolicy::Block),
+            other => Err(anyhow!("unknown conflict policy '{}', expected 'warn' or 'block'", other)),
+        }
+    }
+}
+
+/// How strictly the hostnames of routing rules are checked, beyond what
+/// every hostname has to be.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostnamePolicy {
+    /// Accept underscores, which some internal DNS names use (default)
+    #[default]
+    Lenient,
+    /// Refuse services with hostnames RFC 1123 doesn't allow, i.e. with underscores
+    Strict,
+}
+
+impl FromStr for HostnamePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "lenient" => Ok(HostnamePolicy::Lenient),
+            "strict" => Ok(HostnamePolicy::Strict),
+            other => Err(anyhow!("unknown hostname policy '{}', expected 'lenient' or 'strict'", other)),
+        }
+    }
+}
+
+/// What sync mode does when a resource it manages is edited or deleted in
+/// Pingap by someone else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DriftPolicy {
+    /// Leave it, the next change of the service overwrites it
+    Off,
+    /// Report it in the logs, `/status` and metrics (default)
+    #[default]
+    Observe,
+    /// Report it and write the provider's config back
+    Enforce,
+}
+
+impl FromStr for DriftPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(DriftPolicy::Off),
+            "observe" => Ok(DriftPolicy::Observe),
+            "enforce" => Ok(DriftPolicy::Enforce),
+            other => Err(anyhow!("unknown drift policy '{}', expected 'off', 'observe' or 'enforce'", other)),
+        }
+    }
+}
+
+/// How a service is named when its container has no `pingap.service.name`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServiceNameStrategy {
+    /// The container name, e.g. `shop-web-1`
+    #[default]
+    Container,
+    /// The compose service, e.g. `web`, so the replicas of a scaled service
+    /// share one name; other containers keep their container name
+    Compose,
+    /// None: containers without `pingap.service.name` are skipped
+    Label,
+    /// A label template, e.g. `{{ compose_project }}-{{ compose_service }}`
+    Template(String),
+}
+
+impl ServiceNameStrategy {
+    /// The `pingap.service.name` a container gets unless it sets its own.
+    fn default_name(&self) -> Option<&str> {
+        match self {
+            ServiceNameStrategy::Container | ServiceNameStrategy::Label => None,
+            ServiceNameStrategy::Compose => Some("{{ compose_service }}"),
+            ServiceNameStrategy::Template(template) => Some(template),
+        }
+    }
+}
+
+impl FromStr for ServiceNameStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(template) = s.strip_prefix("template:") {
+            models::check_template(template)
+                .map_err(|e| anyhow!("invalid service name template '{}': {}", template, e))?;
+            return Ok(ServiceNameStrategy::Template(template.to_string()));
+        }
+        match s.to_lowercase().as_str() {
+            "container" => Ok(ServiceNameStrategy::Container),
+            "compose" => Ok(ServiceNameStrategy::Compose),
+            "label" => Ok(ServiceNameStrategy::Label),
+            other => Err(anyhow!("unknown service name strategy '{}', expected 'container', 'compose', 'label' or 'template:<template>'", other)),
+        }
+    }
+}
+
+/// Daily time ranges (UTC) during which the provider holds back its Pingap
+/// writes, e.g. `08:00-10:00,22:30-01:00`. A range ending before it starts spans midnight.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FreezeWindows(Vec<(u32, u32)>);
+
+impl FreezeWindows {
+    /// Whether `time` falls into one of the windows.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let minute = (secs / 60 % (24 * 60)) as u32;
+        self.0.iter().any(|&(start, end)| {
+            if start < end {
+                start <= minute && minute < end
+            } else {
+                minute >= start || minute < end
+            }
+        })
+    }
+}
+
+/// Minutes since midnight of a `HH:MM` time.
+fn parse_time_of_day(s: &str) -> Result<u32> {
+    let (hours, minutes) = s.trim().split_once(':')
+        .ok_or_else(|| anyhow!("expected HH:MM, got '{}'", s.trim()))?;
+    match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+        (Ok(h), Ok(m)) if h < 24 && m < 60 && minutes.len() == 2 => Ok(h * 60 + m),
+        _ => Err(anyhow!("expected HH:MM, got '{}'", s.trim())),
+    }
+}
+
+impl FromStr for FreezeWindows {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut windows = Vec::new();
+        for window in s.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+            let (start, end) = window.split_once('-')
+                .ok_or_else(|| anyhow!("expected a HH:MM-HH:MM range, got '{}'", window))?;
+            let (start, end) = (parse_time_of_day(start)?, parse_time_of_day(end)?);
+            if start == end {
+                return Err(anyhow!("window '{}' is empty", window));
+            }
+            windows.push((start, end));
+        }
+        Ok(FreezeWindows(windows))
+    }
+}
+
+/// Host of an auto-discovered container unless `AUTO_DISCOVER_HOST` says otherwise.
+const DEFAULT_AUTO_DISCOVER_HOST: &str = "{{ container_name }}.localhost";
+
+#[derive(Debug, Clone, Default)]
 pub struct Config {
     pub pingap_admin_url: String,
+    /// Path the Admin API is served under, e.g. behind a reverse proxy
+    pub pingap_admin_path_prefix: Option<String>,
     pub docker_host: Option<String>,
     pub log_level: String,
+    pub retry: RetryPolicy,
+    pub connection_pool: ConnectionPool,
+    pub resources: ResourceLimits,
+    /// Push initial sync through Pingap's full-config endpoint in one request
+    pub batch_apply: bool,
+    /// How many services the initial sync applies at once without `batch_apply`
+    pub initial_sync_concurrency: usize,
+    /// Take over existing unmanaged Pingap resources during initial sync
+    pub adopt_existing: bool,
+    pub mode: Mode,
+    /// Address for the `/status` and `/metrics` endpoints, disabled when unset
+    pub status_addr: Option<String>,
+    /// Bearer token the status API's `/state` and `/maintenance` require; unset serves them to loopback clients only
+    pub status_api_token: Option<String>,
+    /// Address the gRPC control API listens on (sync mode), disabled when unset
+    pub grpc_addr: Option<String>,
+    /// Route to the status API the provider registers in Pingap for itself
+    pub self_expose: Option<SelfExpose>,
+    /// How often audit mode re-compares Docker and Pingap without events
+    pub audit_interval: Duration,
+    /// Start/stop transitions within `flap_window` that mark a container as flapping (0 disables)
+    pub flap_threshold: usize,
+    pub flap_window: Duration,
+    /// How long a flapping container's events are held back from Pingap
+    pub flap_hold_down: Duration,
+    /// How long identical errors are summed up instead of logged one by one (0 logs each)
+    pub error_log_window: Duration,
+    /// Limits for a single Pingap Admin API request; retries apply on top
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    /// How long the local mirror of Pingap's config is trusted before a full re-read (zero disables)
+    pub pingap_mirror_ttl: Duration,
+    /// How often Pingap is checked for a restart or lost config, which triggers a re-apply (zero disables)
+    pub pingap_poll_interval: Duration,
+    /// What the Pingap check does about managed resources changed by someone else
+    pub drift_policy: DriftPolicy,
+    /// How long a container inspection is reused while handling bursts of events (zero disables)
+    pub inspect_cache_ttl: Duration,
+    /// How often the Docker daemon is pinged to notice a lost connection
+    pub docker_ping_interval: Duration,
+    /// Only use the Docker containers and events endpoints (socket proxy friendly)
+    pub docker_minimal_permissions: bool,
+    /// Docker event actions subscribed to on top of the ones the provider handles
+    pub docker_event_actions: Vec<String>,
+    /// `label` filters of the event subscription and container listings (`key` or `key=value`), applied by the daemon
+    pub docker_label_filters: Vec<String>,
+    /// Skip containers with any invalid or unknown `pingap.*` label instead of applying the rest
+    pub strict_labels: bool,
+    /// Quiet period after which the collected stops of a compose project are removed together (zero disables)
+    pub compose_stop_group_window: Duration,
+    /// How long a removed service stays in Pingap as a tombstone before it is deleted (zero deletes right away)
+    pub tombstone_ttl: Duration,
+    /// How often upstream addresses are probed with a TCP connect (zero disables)
+    pub address_probe_interval: Duration,
+    /// Consecutive failed probes after which an address is left out of its upstream
+    pub address_evict_after: u32,
+    /// How often Docker stats of replicated services are read to weight their addresses by load (zero disables)
+    pub load_weight_interval: Duration,
+    /// Weight of an idle replica's addresses
+    pub load_weight_max: u32,
+    /// CPU cores in use at which a replica counts as fully loaded
+    pub load_weight_cpu_cores: f64,
+    /// Count memory use against the limit as load too
+    pub load_weight_memory: bool,
+    /// How long a service waits for its `pingap.depends_on` services before its route is activated anyway (zero waits forever)
+    pub dependency_timeout: Duration,
+    /// Pingap plugin that answers the requests of services in maintenance
+    pub maintenance_plugin: String,
+    /// Body of the 503 response the provider sets the maintenance plugin up with; unset leaves the plugin to the user
+    pub maintenance_response: Option<String>,
+    /// JSONL file every create/update/delete sent to Pingap is appended to; unset disables the change log
+    pub change_log_path: Option<String>,
+    /// Size after which the change log is rotated (zero disables rotation)
+    pub change_log_max_bytes: u64,
+    /// Rotated change log files kept next to the current one
+    pub change_log_keep: u32,
+    /// SQLite database the Docker events and the writes they led to are journaled in; unset disables the journal
+    pub journal_path: Option<String>,
+    /// How long journal entries are kept (zero keeps them all)
+    pub journal_retention: Duration,
+    /// Directory for snapshots of Pingap's config taken before resources are deleted or overwritten; unset disables backups
+    pub backup_dir: Option<String>,
+    /// Snapshots kept in `backup_dir` (zero keeps all)
+    pub backup_keep: usize,
+    /// Forward proxy for Pingap Admin API calls; unset leaves proxying to the system settings (`HTTP_PROXY`...)
+    pub pingap_http_proxy: Option<String>,
+    /// "Name: value" headers sent with every Admin API request
+    pub pingap_admin_headers: Vec<String>,
+    /// Shared secret the Admin API requests are HMAC-signed with
+    pub pingap_admin_hmac_secret: Option<String>,
+    /// Header carrying the signature
+    pub pingap_admin_hmac_header: String,
+    /// Comma separated hosts reached without `pingap_http_proxy`
+    pub no_proxy: Option<String>,
+    /// Where Pingap serves traffic, for `verify`
+    pub pingap_proxy_url: Option<String>,
+    /// Default of `pingap.headers.request_id` for every container
+    pub default_request_id: bool,
+    /// Default of `pingap.headers.forwarded` for every container
+    pub default_forwarded_headers: bool,
+    /// Times of day when changes are queued instead of written to Pingap
+    pub freeze_windows: FreezeWindows,
+    /// Still remove the services of stopped containers during a freeze window
+    pub freeze_allow_deletes: bool,
+    /// How services of containers without `pingap.service.name` are named
+    pub service_name_strategy: ServiceNameStrategy,
+    /// Whether a service whose route overlaps another service's is still applied
+    pub conflict_policy: ConflictPolicy,
+    /// Whether hostnames with underscores are refused
+    pub hostname_policy: HostnamePolicy,
+    /// Addresses of the proxy the hosts of routing rules should resolve to (empty skips the check)
+    pub hostname_expected_ips: Vec<IpAddr>,
+    /// Route containers without any `pingap.*` label by `auto_discover_host`
+    pub auto_discover: bool,
+    /// Host label template of auto-discovered containers
+    pub auto_discover_host: String,
+    /// Auto-discover containers without `pingap.enable`; otherwise only those with `pingap.enable=true`
+    pub expose_by_default: bool,
+    /// The Pingap container, never auto-discovered and re-applied to when it starts
+    pub pingap_container: PingapContainer,
+    /// Pingap's config file the provider writes instead of using the Admin API
+    pub pingap_config_file: Option<PingapConfigFile>,
+    /// File keeping the time of the last handled Docker event, so events missed while down are replayed; unset disables replay
+    pub event_cursor_path: Option<String>,
+    /// Lua script that may change or refuse every service config before it is applied
+    pub config_hook_script: Option<String>,
+    /// Commands run before and after applying a service, after deleting one and when the policy refuses one
+    pub lifecycle_hooks: LifecycleHooks,
+    /// JSON rules every route is checked against before it is applied
+    pub policy_file: Option<String>,
+    /// Named bundles of labels containers pick with `pingap.profile`
+    pub profiles_file: Option<String>,
+    /// Records pointing the routed hosts at the proxy (unset leaves DNS alone)
+    pub dns: Option<DnsConfig>,
+    /// Where `{{ secret "..." }}` references are resolved from
+    pub secrets: SecretsConfig,
+    /// Teams whose `<prefix>.pingap.*` labels are routed into their own Pingap
+    pub tenants: Vec<Tenant>,
+}
+
+/// How Pingap is made to pick up a rewritten config file (`PINGAP_RELOAD`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reload {
+    /// Send `signal` to the Pingap container, e.g. `SIGQUIT` for Pingap's graceful upgrade
+    Signal { container: String, signal: String },
+    /// Call the restart endpoint of the Admin API
+    Restart,
+    /// Leave it to Pingap, e.g. one started with `--autorestart`
+    None,
+}
+
+/// Pingap's TOML config file, written instead of going through the Admin
+/// API (`PINGAP_CONFIG_FILE`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingapConfigFile {
+    pub path: String,
+    pub reload: Reload,
+    /// Quiet period after the last write before Pingap is reloaded, so one reload covers a batch
+    pub reload_debounce: Duration,
+}
+
+impl PingapConfigFile {
+    fn from_env(path: String, pingap_container: &PingapContainer) -> Result<Self> {
+        let reload = match env::var("PINGAP_RELOAD").unwrap_or_else(|_| "restart".to_string()).trim().to_lowercase().as_str() {
+            "signal" => Reload::Signal {
+                container: pingap_container.name.clone()
+                    .ok_or_else(|| anyhow!("PINGAP_RELOAD=signal needs PINGAP_CONTAINER_NAME, the container the signal goes to"))?,
+                signal: env::var("PINGAP_RELOAD_SIGNAL").unwrap_or_else(|_| "SIGQUIT".to_string()),
+            },
+            "restart" => Reload::Restart,
+            "none" => Reload::None,
+            other => return Err(anyhow!("Invalid PINGAP_RELOAD '{}': expected signal, restart or none", other)),
+        };
+        let reload_debounce = Duration::from_millis(env_or("PINGAP_RELOAD_DEBOUNCE_MS", 1000)?);
+        Ok(Self { path, reload, reload_debounce })
+    }
+}
+
+/// Service name of the provider's own route, see [`SelfExpose`].
+pub const SELF_SERVICE_NAME: &str = "pingap-docker-provider";
+
+/// How the Pingap container itself is told apart (`PINGAP_CONTAINER_NAME`,
+/// `PINGAP_CONTAINER_LABEL`): `AUTO_DISCOVER` never routes Pingap through
+/// itself, and its start has every service written again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PingapContainer {
+    /// Container name, without the leading `/`
+    pub name: Option<String>,
+    /// A label key the container carries, with the value it must have if given
+    pub label: Option<(String, Option<String>)>,
+}
+
+impl PingapContainer {
+    fn from_env() -> Result<Self> {
+        let name = env::var("PINGAP_CONTAINER_NAME").ok()
+            .map(|name| name.trim().trim_start_matches('/').to_string())
+            .filter(|name| !name.is_empty());
+        let label = match env::var("PINGAP_CONTAINER_LABEL") {
+            Ok(label) => Some(Self::parse_label(&label)?),
+            Err(_) => None,
+        };
+        Ok(Self { name, label })
+    }
+
+    /// `key` or `key=value`.
+    fn parse_label(label: &str) -> Result<(String, Option<String>)> {
+        let (key, value) = match label.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim().to_string())),
+            None => (label.trim(), None),
+        };
+        if key.is_empty() {
+            return Err(anyhow!("Invalid PINGAP_CONTAINER_LABEL '{}': expected key or key=value", label));
+        }
+        Ok((key.to_string(), value))
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.name.is_some() || self.label.is_some()
+    }
+
+    /// Whether the container named `name` with `labels` is Pingap's.
+    pub fn matches(&self, name: &str, labels: &HashMap<String, String>) -> bool {
+        let named = self.name.as_deref().is_some_and(|own| own == name.trim_start_matches('/'));
+        let labeled = self.label.as_ref().is_some_and(|(key, value)| match value {
+            Some(value) => labels.get(key) == Some(value),
+            None => labels.contains_key(key),
+        });
+        named || labeled
+    }
+}
+
+/// The provider's status API published through Pingap
+/// (`PROVIDER_SELF_EXPOSE_HOST`), behind basic auth. It is routed like a
+/// container labeled with the host, address and credentials.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfExpose {
+    pub host: String,
+    /// Where Pingap reaches the status API, e.g. "provider:9090"
+    pub address: String,
+    /// Comma-separated `user:password` entries
+    pub basic_auth: String,
+}
+
+impl SelfExpose {
+    fn from_env(host: String, status_addr: Option<&str>) -> Result<Self> {
+        let port = status_addr.and_then(|addr| addr.rsplit_once(':')).map(|(_, port)| port)
+            .ok_or_else(|| anyhow!("PROVIDER_SELF_EXPOSE_HOST needs STATUS_ADDR, the status API it routes to"))?;
+        let address = match env::var("PROVIDER_SELF_EXPOSE_ADDRESS") {
+            Ok(address) => address,
+            // Docker resolves a container's hostname (its short ID) on user-defined networks
+            Err(_) => format!("{}:{}", env::var("HOSTNAME")
+                .map_err(|_| anyhow!("PROVIDER_SELF_EXPOSE_HOST needs PROVIDER_SELF_EXPOSE_ADDRESS when HOSTNAME is unset"))?, port),
+        };
+        // The status API switches services into maintenance, it is never exposed without credentials
+        let basic_auth = env::var("PROVIDER_SELF_EXPOSE_AUTH")
+            .map_err(|_| anyhow!("PROVIDER_SELF_EXPOSE_HOST needs PROVIDER_SELF_EXPOSE_AUTH (user:password)"))?;
+        let expose = Self { host, address, basic_auth };
+        // Secrets are checked once they are resolved
+        if !expose.basic_auth.contains("{{") {
+            expose.check_basic_auth()?;
+        }
+        Ok(expose)
+    }
+
+    fn check_basic_auth(&self) -> Result<()> {
+        let valid = |entry: &str| entry.trim().split_once(':').is_some_and(|(user, password)| !user.is_empty() && !password.is_empty());
+        if !self.basic_auth.split(',').all(valid) {
+            return Err(anyhow!("PROVIDER_SELF_EXPOSE_AUTH must be comma-separated user:password entries"));
+        }
+        Ok(())
+    }
+
+    /// Labels of the container standing in for the provider.
+    pub fn labels(&self) -> HashMap<String, String> {
+        HashMap::from([
+            (LABEL_ENABLE.to_string(), "true".to_string()),
+            (LABEL_SERVICE_NAME.to_string(), SELF_SERVICE_NAME.to_string()),
+            (LABEL_HTTP_HOST.to_string(), self.host.clone()),
+            (LABEL_SERVICE_ADDRESS.to_string(), self.address.clone()),
+            (LABEL_MIDDLEWARE_BASIC_AUTH.to_string(), self.basic_auth.clone()),
+        ])
+    }
+}
+
+/// A team sharing the Docker host (`TENANTS`): its containers are labeled
+/// `<label_prefix>.pingap.*` and routed into its own Pingap, by a provider
+/// of its own running next to the default one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tenant {
+    pub name: String,
+    /// Labels are `<label_prefix>.pingap.*`, the tenant name unless `TENANT_<NAME>_LABEL_PREFIX` is set
+    pub label_prefix: String,
+    pub admin_url: String,
+    pub admin_path_prefix: Option<String>,
+    /// Own Admin API headers and signing secret; unset uses `PINGAP_ADMIN_HEADERS` and `PINGAP_ADMIN_HMAC_SECRET`
+    pub admin_headers: Option<Vec<String>>,
+    pub admin_hmac_secret: Option<String>,
+    pub admin_hmac_header: Option<String>,
+    /// Where the tenant's status API listens, disabled when unset
+    pub status_addr: Option<String>,
+    /// Compose projects whose containers may use the tenant's labels
+    pub compose_projects: Vec<String>,
+    /// Where the DNS records of the tenant's hosts point, its Pingap; no records are kept for it when unset
+    pub dns_target: Option<String>,
+}
+
+impl Tenant {
+    fn from_env(name: &str) -> Result<Self> {
+        let key = |setting: &str| format!("TENANT_{}_{}", name.to_uppercase().replace(['-', '.'], "_"), setting);
+        let label_prefix = env::var(key("LABEL_PREFIX")).unwrap_or_else(|_| name.to_string());
+        if label_prefix.is_empty() || label_prefix == "pingap" || label_prefix.ends_with('.')
+            || label_prefix.contains(|c: char| c.is_whitespace() || c == '=') {
+            return Err(anyhow!("Invalid label prefix '{}' of tenant {}", label_prefix, name));
+        }
+        let admin_url = env::var(key("ADMIN_URL"))
+            .map_err(|_| anyhow!("Tenant {} needs {}, the Admin API of its Pingap", name, key("ADMIN_URL")))?;
+        let compose_projects = env_list(&key("COMPOSE_PROJECTS"));
+        if compose_projects.is_empty() {
+            return Err(anyhow!("Tenant {} needs {}, the compose projects whose containers may use its labels", name, key("COMPOSE_PROJECTS")));
+        }
+        let admin_headers = env::var(key("ADMIN_HEADERS")).is_ok().then(|| env_list(&key("ADMIN_HEADERS")));
+        let admin_hmac_secret = env::var(key("ADMIN_HMAC_SECRET")).ok();
+        let admin_hmac_header = env::var(key("ADMIN_HMAC_HEADER")).ok();
+        if let Some(secret) = &admin_hmac_secret {
+            RequestSigner::new(admin_hmac_header.as_deref().unwrap_or("X-Signature"), secret)
+                .context(format!("Invalid {}/{}", key("ADMIN_HMAC_SECRET"), key("ADMIN_HMAC_HEADER")))?;
+        }
+        Ok(Self {
+            name: name.to_string(),
+            label_prefix,
+            admin_url,
+            admin_path_prefix: env::var(key("ADMIN_PATH_PREFIX")).ok(),
+            admin_headers,
+            admin_hmac_secret,
+            admin_hmac_header,
+            status_addr: env::var(key("STATUS_ADDR")).ok(),
+            compose_projects,
+            dns_target: env::var(key("DNS_TARGET")).ok().filter(|target| !target.is_empty()),
+        })
+    }
+
+    /// Where the tenant's Pingap is reached, to keep two tenants from writing into the same one.
+    fn admin_base(&self) -> String {
+        admin_base(&self.admin_url, self.admin_path_prefix.as_deref())
+    }
+}
+
+fn admin_base(url: &str, path_prefix: Option<&str>) -> String {
+    format!("{}/{}", url.trim_end_matches('/'), path_prefix.unwrap_or_default().trim_matches('/'))
+        .trim_end_matches('/')
+        .to_lowercase()
+}
+
+/// DNS records for the routed hosts (`DNS_PROVIDER`), kept by [`crate::dns::DnsRecords`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsConfig {
+    /// Only hosts in this zone get records
+    pub zone: String,
+    /// Public address or hostname of the proxy: A/AAAA records for an address, CNAMEs for a hostname
+    pub target: String,
+    pub ttl: u32,
+    /// Written into the TXT ownership records, so providers sharing a zone only touch their own records
+    pub owner_id: String,
+    pub backend: DnsBackend,
+}
+
+/// Where the records are written, with its credentials.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnsBackend {
+    Cloudflare { api_token: String, zone_id: String },
+    Route53 { access_key_id: String, secret_access_key: String, session_token: Option<String>, hosted_zone_id: String },
+    /// Dynamic updates (RFC 2136), signed with an HMAC-SHA256 TSIG key when one is set
+    Rfc2136 { server: String, tsig_key: Option<(String, String)> },
+}
+
+impl DnsConfig {
+    fn from_env(provider: &str) -> Result<Self> {
+        let required = |key: &str| env::var(key).map_err(|_| anyhow!("DNS_PROVIDER={} needs {}", provider, key));
+        let backend = match provider.trim().to_lowercase().as_str() {
+            "cloudflare" => DnsBackend::Cloudflare {
+                api_token: required("CLOUDFLARE_API_TOKEN")?,
+                zone_id: required("CLOUDFLARE_ZONE_ID")?,
+            },
+            "route53" => DnsBackend::Route53 {
+                access_key_id: required("AWS_ACCESS_KEY_ID")?,
+                secret_access_key: required("AWS_SECRET_ACCESS_KEY")?,
+                session_token: env::var("AWS_SESSION_TOKEN").ok(),
+                hosted_zone_id: required("ROUTE53_HOSTED_ZONE_ID")?,
+            },
+            "rfc2136" => {
+                let tsig_key = match env::var("RFC2136_TSIG_KEY") {
+                    Ok(name) => Some((name, required("RFC2136_TSIG_SECRET")?)),
+                    Err(_) => None,
+                };
+                DnsBackend::Rfc2136 { server: required("RFC2136_SERVER")?, tsig_key }
+            },
+            other => return Err(anyhow!("unknown DNS_PROVIDER '{}', expected 'cloudflare', 'route53' or 'rfc2136'", other)),
+        };
+        Ok(Self {
+            zone: required("DNS_ZONE")?,
+            target: required("DNS_TARGET")?,
+            ttl: env_or("DNS_TTL", 300)?,
+            owner_id: env::var("DNS_OWNER_ID").unwrap_or_else(|_| "pingap-docker-provider".to_string()),
+            backend,
+        })
+    }
+}
+
+/// Where `{{ secret "..." }}` references are resolved from, see [`crate::secrets::Secrets`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecretsConfig {
+    /// `vault:` secrets, unset without `VAULT_ADDR`
+    pub vault: Option<VaultConfig>,
+    /// Program decrypting `sops:` files
+    pub sops_binary: String,
+    /// How long a Vault secret or a decrypted SOPS file is used before it is fetched again
+    pub cache_ttl: Duration,
+    /// How often the Vault token is renewed and expired secrets are fetched again (zero disables)
+    pub renew_interval: Duration,
+    /// `vault:` and `sops:` references (or ones they lead up to) that labels may use; none by default
+    pub label_allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VaultConfig {
+    pub addr: String,
+    pub token: String,
+    /// Vault Enterprise namespace
+    pub namespace: Option<String>,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            vault: None,
+            sops_binary: "sops".to_string(),
+            cache_ttl: Duration::from_secs(300),
+            renew_interval: Duration::from_secs(600),
+            label_allow: Vec::new(),
+        }
+    }
+}
+
+impl SecretsConfig {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+        let vault = match env::var("VAULT_ADDR") {
+            Ok(addr) => {
+                let token = match (env::var("VAULT_TOKEN"), env::var("VAULT_TOKEN_FILE")) {
+                    (Ok(token), _) => token,
+                    (Err(_), Ok(path)) => std::fs::read_to_string(&path)
+                        .context(format!("Failed to read VAULT_TOKEN_FILE {}", path))?
+                        .trim().to_string(),
+                    _ => return Err(anyhow!("VAULT_ADDR needs VAULT_TOKEN or VAULT_TOKEN_FILE")),
+                };
+                Some(VaultConfig { addr, token, namespace: env::var("VAULT_NAMESPACE").ok() })
+            },
+            Err(_) => None,
+        };
+        let label_allow = env_list("LABEL_SECRETS_ALLOW");
+        if let Some(allowed) = label_allow.iter().find(|allowed| !allowed.starts_with("vault:") && !allowed.starts_with("sops:")) {
+            return Err(anyhow!("LABEL_SECRETS_ALLOW can only list vault: and sops: secrets, not {}", allowed));
+        }
+        Ok(Self {
+            vault,
+            sops_binary: env::var("SOPS_BINARY").unwrap_or(defaults.sops_binary),
+            cache_ttl: Duration::from_secs(env_or("SECRETS_CACHE_TTL_SECS", defaults.cache_ttl.as_secs())?),
+            renew_interval: Duration::from_secs(env_or("SECRETS_RENEW_INTERVAL_SECS", defaults.renew_interval.as_secs())?),
+            label_allow,
+        })
+    }
+}
+
+/// Backoff and circuit breaker settings shared by all Pingap Admin API calls.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_interval: Duration,
+    pub multiplier: f64,
+    pub max_interval: Duration,
+    /// Randomization factor applied to every interval (0.0 disables jitter)
+    pub jitter: f64,
+    pub apply_max_elapsed: Duration,
+    pub delete_max_elapsed: Duration,
+    /// Consecutive 5xx responses that open the circuit (0 disables the breaker)
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_interval: Duration::from_millis(500),
+            multiplier: 1.5,
+            max_interval: Duration::from_secs(60),
+            jitter: 0.5,
+            apply_max_elapsed: Duration::from_secs(60),
+            delete_max_elapsed: Duration::from_secs(30),
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+        Ok(Self {
+            initial_interval: Duration::from_millis(
+                env_or("RETRY_INITIAL_INTERVAL_MS", defaults.initial_interval.as_millis() as u64)?),
+            multiplier: env_or("RETRY_MULTIPLIER", defaults.multiplier)?,
+            max_interval: Duration::from_millis(
+                env_or("RETRY_MAX_INTERVAL_MS", defaults.max_interval.as_millis() as u64)?),
+            jitter: env_or("RETRY_JITTER", defaults.jitter)?,
+            apply_max_elapsed: Duration::from_secs(
+                env_or("RETRY_APPLY_MAX_ELAPSED_SECS", defaults.apply_max_elapsed.as_secs())?),
+            delete_max_elapsed: Duration::from_secs(
+                env_or("RETRY_DELETE_MAX_ELAPSED_SECS", defaults.delete_max_elapsed.as_secs())?),
+            circuit_breaker_threshold: env_or("CIRCUIT_BREAKER_THRESHOLD", defaults.circuit_breaker_threshold)?,
+            circuit_breaker_cooldown: Duration::from_secs(
+                env_or("CIRCUIT_BREAKER_COOLDOWN_SECS", defaults.circuit_breaker_cooldown.as_secs())?),
+        })
+    }
+
+    pub fn backoff(&self, max_elapsed: Duration) -> ExponentialBackoff {
+        ExponentialBackoff {
+            current_interval: self.initial_interval,
+            initial_interval: self.initial_interval,
+            randomization_factor: self.jitter,
+            multiplier: self.multiplier,
+            max_interval: self.max_interval,
+            max_elapsed_time: Some(max_elapsed),
+            ..Default::default()
+        }
+    }
+}
+
+/// Connection reuse settings of the Pingap Admin API client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionPool {
+    /// Idle connections kept open per host (0 opens a new connection for every request)
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept (zero keeps it until the server closes it)
+    pub idle_timeout: Duration,
+    /// Interval of TCP keepalive probes on open connections (zero disables them)
+    pub tcp_keepalive: Duration,
+    /// Talk HTTP/2 to the Admin API right away (prior knowledge) instead of HTTP/1.1
+    pub http2: bool,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: usize::MAX,
+            idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Duration::ZERO,
+            http2: false,
+        }
+    }
+}
+
+impl ConnectionPool {
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+        Ok(Self {
+            max_idle_per_host: env_or("PINGAP_POOL_MAX_IDLE_PER_HOST", defaults.max_idle_per_host)?,
+            idle_timeout: Duration::from_secs(
+                env_or("PINGAP_POOL_IDLE_TIMEOUT_SECS", defaults.idle_timeout.as_secs())?),
+            tcp_keepalive: Duration::from_secs(
+                env_or("PINGAP_TCP_KEEPALIVE_SECS", defaults.tcp_keepalive.as_secs())?),
+            http2: env_or("PINGAP_HTTP2", defaults.http2)?,
+        })
+    }
+}
+
+/// Sizes of the provider's internal queues and caches, and how often it
+/// reconciles. `RESOURCE_PROFILE` picks the defaults, each can be overridden.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceLimits {
+    pub profile: ResourceProfile,
+    /// Finished background writes and probe rounds waiting for the sync loop
+    pub channel_capacity: usize,
+    pub inspect_cache_max_entries: usize,
+    pub image_label_cache_max_entries: usize,
+    /// Largest Pingap config document read before giving up
+    pub pingap_config_max_bytes: usize,
+    /// How often hold-downs, compose stop groups, dependencies and maintenance requests are checked
+    pub reconcile_interval: Duration,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self::for_profile(ResourceProfile::Default)
+    }
+}
+
+impl ResourceLimits {
+    pub fn for_profile(profile: ResourceProfile) -> Self {
+        match profile {
+            ResourceProfile::Default => Self {
+                profile,
+                channel_capacity: 1024,
+                inspect_cache_max_entries: 1024,
+                image_label_cache_max_entries: 512,
+                pingap_config_max_bytes: 32 * 1024 * 1024,
+                reconcile_interval: Duration::from_secs(1),
+            },
+            ResourceProfile::Small => Self {
+                profile,
+                channel_capacity: 64,
+                inspect_cache_max_entries: 64,
+                image_label_cache_max_entries: 32,
+                pingap_config_max_bytes: 8 * 1024 * 1024,
+                reconcile_interval: Duration::from_secs(2),
+            },
+        }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let profile = env_or("RESOURCE_PROFILE", ResourceProfile::Default)?;
+        let defaults = Self::for_profile(profile);
+        let limits = Self {
+            profile,
+            channel_capacity: env_or("CHANNEL_CAPACITY", defaults.channel_capacity)?,
+            inspect_cache_max_entries: env_or("INSPECT_CACHE_MAX_ENTRIES", defaults.inspect_cache_max_entries)?,
+            image_label_cache_max_entries: env_or("IMAGE_LABEL_CACHE_MAX_ENTRIES", defaults.image_label_cache_max_entries)?,
+            pingap_config_max_bytes: env_or("PINGAP_CONFIG_MAX_BYTES", defaults.pingap_config_max_bytes)?,
+            reconcile_interval: Duration::from_millis(
+                env_or("RECONCILE_INTERVAL_MS", defaults.reconcile_interval.as_millis() as u64)?),
+        };
+        if limits.channel_capacity == 0 {
+            return Err(anyhow!("CHANNEL_CAPACITY must be greater than 0"));
+        }
+        if limits.reconcile_interval.is_zero() {
+            return Err(anyhow!("RECONCILE_INTERVAL_MS must be greater than 0"));
+        }
+        Ok(limits)
+    }
+}
+
+/// Reads an optional environment variable, falling back to `default` when unset.
+fn env_or<T>(key: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env::var(key) {
+        Ok(v) => v.trim().parse::<T>()
+            .map_err(|e| anyhow!("Invalid value '{}' for {}: {}", v, key, e)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Comma-separated values of `key`, without blanks; unset means none.
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key).unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
         let pingap_admin_url = env::var("PINGAP_ADMIN_URL")
             .context("PINGAP_ADMIN_URL must be set")?;
+        let pingap_admin_path_prefix = env::var("PINGAP_ADMIN_PATH_PREFIX").ok();
         
         let docker_host = env::var("DOCKER_HOST").ok();
         
         let log_level = env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
 
+        let retry = RetryPolicy::from_env()?;
+
+        let connection_pool = ConnectionPool::from_env()?;
+
+        let resources = ResourceLimits::from_env()?;
+
+        let batch_apply = env_or("PINGAP_BATCH_APPLY", false)?;
+        let initial_sync_concurrency = env_or("INITIAL_SYNC_CONCURRENCY", 4)?;
+        if initial_sync_concurrency == 0 {
+            return Err(anyhow!("INITIAL_SYNC_CONCURRENCY must be greater than 0"));
+        }
+
+        // Also enabled by the --adopt-existing command line flag
+        let adopt_existing = env_or("ADOPT_EXISTING", false)?
+            || env::args().skip(1).any(|arg| arg == "--adopt-existing");
+
+        let mode = env_or("MODE", Mode::Sync)?;
+
+        let status_addr = env::var("STATUS_ADDR").ok();
+        let status_api_token = env::var("STATUS_API_TOKEN").ok().filter(|token| !token.is_empty());
+        let grpc_addr = env::var("GRPC_ADDR").ok();
+        let self_expose = env::var("PROVIDER_SELF_EXPOSE_HOST").ok()
+            .map(|host| SelfExpose::from_env(host, status_addr.as_deref()))
+            .transpose()?;
+
+        let audit_interval = Duration::from_secs(env_or("AUDIT_INTERVAL_SECS", 60)?);
+
+        let flap_threshold = env_or("FLAP_THRESHOLD", 6)?;
+        let flap_window = Duration::from_secs(env_or("FLAP_WINDOW_SECS", 60)?);
+        let flap_hold_down = Duration::from_secs(env_or("FLAP_HOLD_DOWN_SECS", 30)?);
+        let error_log_window = Duration::from_secs(env_or("ERROR_LOG_WINDOW_SECS", 60)?);
+
+        let connect_timeout = Duration::from_secs(env_or("PINGAP_CONNECT_TIMEOUT_SECS", 5)?);
+        let request_timeout = Duration::from_secs(env_or("PINGAP_REQUEST_TIMEOUT_SECS", 10)?);
+        let pingap_mirror_ttl = Duration::from_secs(env_or("PINGAP_MIRROR_TTL_SECS", 300)?);
+        let pingap_poll_interval = Duration::from_secs(env_or("PINGAP_POLL_INTERVAL_SECS", 30)?);
+        let drift_policy = env_or("DRIFT_POLICY", DriftPolicy::default())?;
+
+        let inspect_cache_ttl = Duration::from_secs(env_or("INSPECT_CACHE_TTL_SECS", 5)?);
+
+        let docker_ping_interval = Duration::from_secs(env_or("DOCKER_PING_INTERVAL_SECS", 10)?);
+        if docker_ping_interval.is_zero() {
+            return Err(anyhow!("DOCKER_PING_INTERVAL_SECS must be greater than 0"));
+        }
+
+        let docker_minimal_permissions = env_or("DOCKER_MINIMAL_PERMISSIONS", false)?;
+        let docker_event_actions = env_list("DOCKER_EVENT_ACTIONS");
+        let docker_label_filters = env_list("DOCKER_LABEL_FILTERS");
+        if let Some(label) = docker_label_filters.iter().find(|label| label.starts_with('=')) {
+            return Err(anyhow!("Invalid DOCKER_LABEL_FILTERS entry '{}': expected key or key=value", label));
+        }
+
+        let strict_labels = env_or("STRICT_LABELS", false)?;
+
+        let compose_stop_group_window = Duration::from_secs(env_or("COMPOSE_STOP_GROUP_SECS", 2)?);
+        let tombstone_ttl = Duration::from_secs(env_or("TOMBSTONE_TTL_SECS", 0)?);
+
+        let address_probe_interval = Duration::from_secs(env_or("ADDRESS_PROBE_INTERVAL_SECS", 0)?);
+        let address_evict_after = env_or("ADDRESS_EVICT_AFTER", 3)?;
+        let load_weight_interval = Duration::from_secs(env_or("LOAD_WEIGHT_INTERVAL_SECS", 0)?);
+        let load_weight_max = env_or("LOAD_WEIGHT_MAX", 10)?;
+        let load_weight_cpu_cores: f64 = env_or("LOAD_WEIGHT_CPU_CORES", 1.0)?;
+        if load_weight_cpu_cores.is_nan() || load_weight_cpu_cores <= 0.0 {
+            return Err(anyhow!("LOAD_WEIGHT_CPU_CORES must be above 0"));
+        }
+        let load_weight_memory = env_or("LOAD_WEIGHT_MEMORY", false)?;
+
+        let dependency_timeout = Duration::from_secs(env_or("DEPENDENCY_TIMEOUT_SECS", 60)?);
+
+        let maintenance_plugin = env::var("MAINTENANCE_PLUGIN")
+            .unwrap_or_else(|_| DEFAULT_MAINTENANCE_PLUGIN.to_string());
+        let maintenance_response = env::var("MAINTENANCE_RESPONSE").ok();
+
+        let change_log_path = env::var("CHANGE_LOG_PATH").ok();
+        let change_log_max_bytes = env_or("CHANGE_LOG_MAX_BYTES", 10 * 1024 * 1024)?;
+        let change_log_keep = env_or("CHANGE_LOG_KEEP", 5)?;
+        let journal_path = env::var("JOURNAL_PATH").ok();
+        let journal_retention = Duration::from_secs(env_or("JOURNAL_RETENTION_DAYS", 30)? * 24 * 60 * 60);
+        let backup_dir = env::var("BACKUP_DIR").ok();
+        let backup_keep = env_or("BACKUP_KEEP", 100)?;
+
+        let pingap_http_proxy = env::var("PINGAP_HTTP_PROXY").ok();
+        let pingap_admin_headers = env_list("PINGAP_ADMIN_HEADERS");
+        let pingap_admin_hmac_secret = env::var("PINGAP_ADMIN_HMAC_SECRET").ok();
+        let pingap_admin_hmac_header = env::var("PINGAP_ADMIN_HMAC_HEADER")
+            .unwrap_or_else(|_| "X-Signature".to_string());
+        if let Some(secret) = &pingap_admin_hmac_secret {
+            RequestSigner::new(&pingap_admin_hmac_header, secret).context("Invalid PINGAP_ADMIN_HMAC_SECRET/PINGAP_ADMIN_HMAC_HEADER")?;
+        }
+        let no_proxy = env::var("NO_PROXY").or_else(|_| env::var("no_proxy")).ok();
+        let pingap_proxy_url = env::var("PINGAP_PROXY_URL").ok();
+
+        let default_request_id = env_or("DEFAULT_REQUEST_ID", false)?;
+        let default_forwarded_headers = env_or("DEFAULT_FORWARDED_HEADERS", false)?;
+
+        let freeze_windows = env_or("FREEZE_WINDOWS", FreezeWindows::default())?;
+        let freeze_allow_deletes = env_or("FREEZE_ALLOW_DELETES", false)?;
+
+        let event_cursor_path = env::var("EVENT_CURSOR_PATH").ok();
+        let config_hook_script = env::var("CONFIG_HOOK_SCRIPT").ok();
+        let lifecycle_hooks = LifecycleHooks {
+            pre_apply: env::var("HOOK_PRE_APPLY").ok(),
+            post_apply: env::var("HOOK_POST_APPLY").ok(),
+            post_delete: env::var("HOOK_POST_DELETE").ok(),
+            policy_violation: env::var("HOOK_POLICY_VIOLATION").ok(),
+            timeout: Duration::from_secs(env_or("HOOK_TIMEOUT_SECS", 30)?),
+        };
+        let policy_file = env::var("POLICY_FILE").ok();
+        let profiles_file = env::var("PROFILES_FILE").ok();
+
+        let service_name_strategy = env_or("SERVICE_NAME_STRATEGY", ServiceNameStrategy::default())?;
+        let conflict_policy = env_or("CONFLICT_POLICY", ConflictPolicy::default())?;
+        let secrets = SecretsConfig::from_env()?;
+        let dns = env::var("DNS_PROVIDER").ok()
+            .map(|provider| DnsConfig::from_env(&provider))
+            .transpose()?;
+
+        let hostname_policy = env_or("HOSTNAME_POLICY", HostnamePolicy::default())?;
+        let hostname_expected_ips = env::var("HOSTNAME_EXPECTED_IPS").unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| ip.parse::<IpAddr>().map_err(|e| anyhow!("Invalid HOSTNAME_EXPECTED_IPS entry '{}': {}", ip, e)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let auto_discover = env_or("AUTO_DISCOVER", false)?;
+        let auto_discover_host = env::var("AUTO_DISCOVER_HOST")
+            .unwrap_or_else(|_| DEFAULT_AUTO_DISCOVER_HOST.to_string());
+        models::check_template(&auto_discover_host)
+            .map_err(|e| anyhow!("Invalid AUTO_DISCOVER_HOST '{}': {}", auto_discover_host, e))?;
+        let expose_by_default = env_or("EXPOSE_BY_DEFAULT", true)?;
+        let pingap_container = PingapContainer::from_env()?;
+        let pingap_config_file = env::var("PINGAP_CONFIG_FILE").ok()
+            .filter(|path| !path.trim().is_empty())
+            .map(|path| PingapConfigFile::from_env(path, &pingap_container))
+            .transpose()?;
+
+        let tenants = env_list("TENANTS").iter()
+            .map(|name| Tenant::from_env(name))
+            .collect::<Result<Vec<_>>>()?;
+        let mut admin_bases = HashMap::from([(admin_base(&pingap_admin_url, pingap_admin_path_prefix.as_deref()), "the default tenant")]);
+        let mut label_prefixes = HashMap::new();
+        for tenant in &tenants {
+            if let Some(other) = admin_bases.insert(tenant.admin_base(), &tenant.name) {
+                return Err(anyhow!("Tenant {} shares its Pingap Admin API with {}, each tenant needs a Pingap of its own", tenant.name, other));
+            }
+            if let Some(other) = label_prefixes.insert(&tenant.label_prefix, &tenant.name) {
+                return Err(anyhow!("Tenants {} and {} both use the label prefix '{}'", other, tenant.name, tenant.label_prefix));
+            }
+        }
+
         Ok(Self {
             pingap_admin_url,
+            pingap_admin_path_prefix,
             docker_host,
             log_level,
+            retry,
+            connection_pool,
+            resources,
+            batch_apply,
+            initial_sync_concurrency,
+            adopt_existing,
+            mode,
+            status_addr,
+            status_api_token,
+            grpc_addr,
+            self_expose,
+            audit_interval,
+            flap_threshold,
+            flap_window,
+            flap_hold_down,
+            error_log_window,
+            connect_timeout,
+            request_timeout,
+            pingap_mirror_ttl,
+            pingap_poll_interval,
+            drift_policy,
+            inspect_cache_ttl,
+            docker_ping_interval,
+            docker_minimal_permissions,
+            docker_event_actions,
+            docker_label_filters,
+            strict_labels,
+            compose_stop_group_window,
+            tombstone_ttl,
+            address_probe_interval,
+            address_evict_after,
+            load_weight_interval,
+            load_weight_max,
+            load_weight_cpu_cores,
+            load_weight_memory,
+            dependency_timeout,
+            maintenance_plugin,
+            maintenance_response,
+            change_log_path,
+            change_log_max_bytes,
+            change_log_keep,
+            journal_path,
+            journal_retention,
+            backup_dir,
+            backup_keep,
+            pingap_http_proxy,
+            pingap_admin_headers,
+            pingap_admin_hmac_secret,
+            pingap_admin_hmac_header,
+            no_proxy,
+            pingap_proxy_url,
+            default_request_id,
+            default_forwarded_headers,
+            freeze_windows,
+            freeze_allow_deletes,
+            event_cursor_path,
+            config_hook_script,
+            lifecycle_hooks,
+            policy_file,
+            profiles_file,
+            dns,
+            secrets,
+            service_name_strategy,
+            conflict_policy,
+            hostname_policy,
+            hostname_expected_ips,
+            auto_discover,
+            auto_discover_host,
+            expose_by_default,
+            pingap_container,
+            pingap_config_file,
+            tenants,
         })
     }
+
+    /// The config of a tenant's provider: its own Admin API, credentials and
+    /// status API, the rest shared. What only makes sense once per host
+    /// stays with the default tenant: auto-discovery, the self-exposed route,
+    /// the event cursor, the change log and backups.
+    pub fn for_tenant(&self, tenant: &Tenant) -> Config {
+        let prefix = format!("{}.", tenant.label_prefix);
+        Config {
+            pingap_admin_url: tenant.admin_url.clone(),
+            pingap_admin_path_prefix: tenant.admin_path_prefix.clone(),
+            pingap_admin_headers: tenant.admin_headers.clone().unwrap_or_else(|| self.pingap_admin_headers.clone()),
+            pingap_admin_hmac_secret: tenant.admin_hmac_secret.clone().or_else(|| self.pingap_admin_hmac_secret.clone()),
+            pingap_admin_hmac_header: tenant.admin_hmac_header.clone().unwrap_or_else(|| self.pingap_admin_hmac_header.clone()),
+            status_addr: tenant.status_addr.clone(),
+            grpc_addr: None,
+            // Filters on pingap.* labels apply to the tenant's labels
+            docker_label_filters: self.docker_label_filters.iter()
+                .map(|filter| match filter.starts_with("pingap.") {
+                    true => format!("{}{}", prefix, filter),
+                    false => filter.clone(),
+                })
+                .collect(),
+            // Records point at the tenant's own Pingap and have an owner of their own, or there are none
+            dns: tenant.dns_target.as_ref()
+                .and_then(|target| self.dns.clone().map(|dns| DnsConfig {
+                    target: target.clone(),
+                    owner_id: format!("{}-{}", dns.owner_id, tenant.name),
+                    ..dns
+                })),
+            self_expose: None,
+            auto_discover: false,
+            event_cursor_path: None,
+            change_log_path: None,
+            backup_dir: None,
+            pingap_config_file: None,
+            tenants: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Label prefixes of the tenants, e.g. `team-a.pingap.`.
+    pub fn tenant_label_prefixes(&self) -> Vec<String> {
+        self.tenants.iter().map(|tenant| format!("{}.pingap.", tenant.label_prefix)).collect()
+    }
+
+    /// Labels every container gets unless its image or itself sets them.
+    pub fn default_labels(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        if self.default_request_id {
+            labels.insert(LABEL_HEADERS_REQUEST_ID.to_string(), "true".to_string());
+        }
+        if self.default_forwarded_headers {
+            labels.insert(LABEL_HEADERS_FORWARDED.to_string(), "true".to_string());
+        }
+        if let Some(name) = self.service_name_strategy.default_name() {
+            labels.insert(LABEL_SERVICE_NAME.to_string(), name.to_string());
+        }
+        labels
+    }
+
+    /// Resolves the `{{ secret "..." }}` references in the credentials the
+    /// provider uses itself: the Admin API headers and signing secrets, the
+    /// forward proxy, the status API token, the self-exposed route's basic auth and the DNS
+    /// provider's credentials.
+    pub async fn resolve_secrets(&mut self, secrets: &Secrets) -> Result<()> {
+        for header in &mut self.pingap_admin_headers {
+            resolve_secret(secrets, "PINGAP_ADMIN_HEADERS", header).await?;
+        }
+        if let Some(secret) = &mut self.pingap_admin_hmac_secret {
+            resolve_secret(secrets, "PINGAP_ADMIN_HMAC_SECRET", secret).await?;
+        }
+        for tenant in &mut self.tenants {
+            for header in tenant.admin_headers.iter_mut().flatten() {
+                resolve_secret(secrets, &format!("the admin headers of tenant {}", tenant.name), header).await?;
+            }
+            if let Some(secret) = &mut tenant.admin_hmac_secret {
+                resolve_secret(secrets, &format!("the HMAC secret of tenant {}", tenant.name), secret).await?;
+            }
+        }
+        if let Some(proxy) = &mut self.pingap_http_proxy {
+            resolve_secret(secrets, "PINGAP_HTTP_PROXY", proxy).await?;
+        }
+        if let Some(token) = &mut self.status_api_token {
+            resolve_secret(secrets, "STATUS_API_TOKEN", token).await?;
+        }
+        if let Some(expose) = &mut self.self_expose {
+            resolve_secret(secrets, "PROVIDER_SELF_EXPOSE_AUTH", &mut expose.basic_auth).await?;
+            expose.check_basic_auth()?;
+        }
+        match self.dns.as_mut().map(|dns| &mut dns.backend) {
+            Some(DnsBackend::Cloudflare { api_token, .. }) => resolve_secret(secrets, "CLOUDFLARE_API_TOKEN", api_token).await?,
+            Some(DnsBackend::Route53 { secret_access_key, session_token, .. }) => {
+                resolve_secret(secrets, "AWS_SECRET_ACCESS_KEY", secret_access_key).await?;
+                if let Some(token) = session_token {
+                    resolve_secret(secrets, "AWS_SESSION_TOKEN", token).await?;
+                }
+            },
+            Some(DnsBackend::Rfc2136 { tsig_key: Some((_, secret)), .. }) => resolve_secret(secrets, "RFC2136_TSIG_SECRET", secret).await?,
+            Some(DnsBackend::Rfc2136 { tsig_key: None, .. }) | None => {},
+        }
+        Ok(())
+    }
+}
+
+async fn resolve_secret(secrets: &Secrets, key: &str, value: &mut String) -> Result<()> {
+    *value = secrets.render(value).await.context(format!("Failed to resolve the secret of {}", key))?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -36,6 +1262,7 @@ mod tests {
             pingap_admin_url: "http://localhost:6188".to_string(),
             docker_host: Some("unix:///var/run/docker.sock".to_string()),
             log_level: "debug".to_string(),
+            ..Default::default()
         };
         
         assert_eq!(config.pingap_admin_url, "http://localhost:6188");
@@ -49,6 +1276,7 @@ mod tests {
             pingap_admin_url: "http://pingap:6188".to_string(),
             docker_host: None,
             log_level: "info".to_string(),
+            ..Default::default()
         };
         
         let config2 = config1.clone();
@@ -62,6 +1290,7 @@ mod tests {
             pingap_admin_url: "http://pingap:6188".to_string(),
             docker_host: None,
             log_level: "info".to_string(),
+            ..Default::default()
         };
         
         assert_eq!(config.docker_host, None);
@@ -74,6 +1303,7 @@ mod tests {
             pingap_admin_url: "http://custom:9999".to_string(),
             docker_host: Some("tcp://remote:2375".to_string()),
             log_level: "trace".to_string(),
+            ..Default::default()
         };
         
         assert_eq!(config.pingap_admin_url, "http://custom:9999");
@@ -144,6 +1374,7 @@ mod tests {
             pingap_admin_url: "http://test:6188".to_string(),
             docker_host: None,
             log_level: "info".to_string(),
+            ..Default::default()
         };
         
         let debug_str = format!("{:?}", config);
@@ -210,4 +1441,267 @@ mod tests {
             env::remove_var("PINGAP_ADMIN_URL");
         }
     }
+
+    #[test]
+    fn test_retry_policy_defaults() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.apply_max_elapsed, Duration::from_secs(60));
+        assert_eq!(policy.delete_max_elapsed, Duration::from_secs(30));
+        assert_eq!(policy.circuit_breaker_threshold, 5);
+    }
+
+    #[test]
+    fn test_connection_pool_from_env() {
+        unsafe {
+            env::set_var("PINGAP_POOL_MAX_IDLE_PER_HOST", "2");
+            env::set_var("PINGAP_TCP_KEEPALIVE_SECS", "15");
+        }
+        let pool = ConnectionPool::from_env().unwrap();
+        assert_eq!(pool.max_idle_per_host, 2);
+        assert_eq!(pool.idle_timeout, Duration::from_secs(90));
+        assert_eq!(pool.tcp_keepalive, Duration::from_secs(15));
+        assert!(!pool.http2);
+        unsafe {
+            env::remove_var("PINGAP_POOL_MAX_IDLE_PER_HOST");
+            env::remove_var("PINGAP_TCP_KEEPALIVE_SECS");
+        }
+    }
+
+    #[test]
+    fn test_resource_profiles() {
+        assert_eq!("SMALL".parse::<ResourceProfile>().unwrap(), ResourceProfile::Small);
+        assert!("tiny".parse::<ResourceProfile>().is_err());
+
+        let small = ResourceLimits::for_profile(ResourceProfile::Small);
+        let default = ResourceLimits::default();
+        assert!(small.channel_capacity < default.channel_capacity);
+        assert!(small.inspect_cache_max_entries < default.inspect_cache_max_entries);
+        assert!(small.reconcile_interval > default.reconcile_interval);
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            initial_interval: Duration::from_millis(100),
+            multiplier: 2.0,
+            jitter: 0.0,
+            ..Default::default()
+        };
+        
+        let backoff = policy.backoff(Duration::from_secs(5));
+        assert_eq!(backoff.initial_interval, Duration::from_millis(100));
+        assert_eq!(backoff.current_interval, Duration::from_millis(100));
+        assert_eq!(backoff.multiplier, 2.0);
+        assert_eq!(backoff.randomization_factor, 0.0);
+        assert_eq!(backoff.max_elapsed_time, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_default_labels() {
+        assert!(Config::default().default_labels().is_empty());
+        let config = Config { default_request_id: true, ..Default::default() };
+        assert_eq!(config.default_labels(), HashMap::from([
+            ("pingap.headers.request_id".to_string(), "true".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn test_service_name_strategy() {
+        assert_eq!("compose".parse::<ServiceNameStrategy>().unwrap(), ServiceNameStrategy::Compose);
+        assert_eq!("Label".parse::<ServiceNameStrategy>().unwrap(), ServiceNameStrategy::Label);
+        let template = "template:{{ compose_project }}-{{ compose_service | default \"app\" }}";
+        assert_eq!(template.parse::<ServiceNameStrategy>().unwrap(),
+            ServiceNameStrategy::Template("{{ compose_project }}-{{ compose_service | default \"app\" }}".to_string()));
+        assert!("template:{{ image }}".parse::<ServiceNameStrategy>().is_err());
+        assert!("hostname".parse::<ServiceNameStrategy>().is_err());
+
+        let config = Config { service_name_strategy: ServiceNameStrategy::Compose, ..Default::default() };
+        assert_eq!(config.default_labels(), HashMap::from([
+            ("pingap.service.name".to_string(), "{{ compose_service }}".to_string()),
+        ]));
+        let config = Config { service_name_strategy: ServiceNameStrategy::Label, ..Default::default() };
+        assert!(config.default_labels().is_empty());
+    }
+
+    #[test]
+    fn test_env_or() {
+        unsafe {
+            env::set_var("TEST_ENV_OR_VALID", "42");
+            env::set_var("TEST_ENV_OR_INVALID", "forty-two");
+            env::remove_var("TEST_ENV_OR_MISSING");
+        }
+        
+        assert_eq!(env_or("TEST_ENV_OR_VALID", 1u32).unwrap(), 42);
+        assert_eq!(env_or("TEST_ENV_OR_MISSING", 1u32).unwrap(), 1);
+        
+        let err = env_or("TEST_ENV_OR_INVALID", 1u32).unwrap_err();
+        assert!(err.to_string().contains("TEST_ENV_OR_INVALID"));
+        
+        unsafe {
+            env::remove_var("TEST_ENV_OR_VALID");
+            env::remove_var("TEST_ENV_OR_INVALID");
+        }
+    }
+
+    #[test]
+    fn test_mode_parsing() {
+        assert_eq!("sync".parse::<Mode>().unwrap(), Mode::Sync);
+        assert_eq!("AUDIT".parse::<Mode>().unwrap(), Mode::Audit);
+        assert!("readonly".parse::<Mode>().is_err());
+        assert_eq!(Mode::default(), Mode::Sync);
+    }
+
+    #[test]
+    fn test_freeze_windows() {
+        let at = |h: u64, m: u64| UNIX_EPOCH + Duration::from_secs(19_000 * 86_400 + h * 3600 + m * 60);
+        let windows = "08:00-10:00, 22:30-01:00".parse::<FreezeWindows>().unwrap();
+        assert!(windows.contains(at(8, 0)));
+        assert!(windows.contains(at(9, 59)));
+        assert!(!windows.contains(at(10, 0)));
+        assert!(windows.contains(at(23, 0)));
+        assert!(windows.contains(at(0, 30)));
+        assert!(!windows.contains(at(1, 0)));
+        assert!(!FreezeWindows::default().contains(at(8, 0)));
+
+        assert_eq!("".parse::<FreezeWindows>().unwrap(), FreezeWindows::default());
+        assert!("08:00".parse::<FreezeWindows>().is_err());
+        assert!("08:00-24:00".parse::<FreezeWindows>().is_err());
+        assert!("8:0-9:00".parse::<FreezeWindows>().is_err());
+        assert!("09:00-09:00".parse::<FreezeWindows>().is_err());
+    }
+
+    #[test]
+    fn test_pingap_container() {
+        let labels = HashMap::from([("com.example.role".to_string(), "proxy".to_string())]);
+        let by_name = PingapContainer { name: Some("pingap".to_string()), label: None };
+        assert!(by_name.matches("/pingap", &HashMap::new()));
+        assert!(!by_name.matches("/pingap-1", &labels));
+
+        let label = |label: &str| PingapContainer { name: None, label: Some(PingapContainer::parse_label(label).unwrap()) };
+        assert!(label("com.example.role=proxy").matches("/edge", &labels));
+        assert!(label("com.example.role").matches("/edge", &labels));
+        assert!(!label("com.example.role=app").matches("/edge", &labels));
+        assert!(PingapContainer::parse_label("=proxy").is_err());
+        assert!(!PingapContainer::default().is_set());
+    }
+
+    #[test]
+    fn test_pingap_config_file() {
+        let pingap = PingapContainer { name: Some("pingap".to_string()), label: None };
+        unsafe {
+            env::set_var("PINGAP_RELOAD", "signal");
+            env::set_var("PINGAP_RELOAD_DEBOUNCE_MS", "250");
+        }
+        let file = PingapConfigFile::from_env("/etc/pingap/pingap.toml".to_string(), &pingap).unwrap();
+        assert_eq!(file.reload, Reload::Signal { container: "pingap".to_string(), signal: "SIGQUIT".to_string() });
+        assert_eq!(file.reload_debounce, Duration::from_millis(250));
+        // A signal needs a container to go to
+        assert!(PingapConfigFile::from_env("pingap.toml".to_string(), &PingapContainer::default()).is_err());
+        unsafe { env::set_var("PINGAP_RELOAD", "reboot"); }
+        assert!(PingapConfigFile::from_env("pingap.toml".to_string(), &pingap).is_err());
+        unsafe {
+            env::remove_var("PINGAP_RELOAD");
+            env::remove_var("PINGAP_RELOAD_DEBOUNCE_MS");
+        }
+        let file = PingapConfigFile::from_env("pingap.toml".to_string(), &PingapContainer::default()).unwrap();
+        assert_eq!(file.reload, Reload::Restart);
+        assert_eq!(file.reload_debounce, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_tenants() {
+        unsafe {
+            env::remove_var("TENANT_TEAM_X_ADMIN_URL");
+            env::set_var("TENANT_TEAM_X_ADMIN_HEADERS", "Authorization: Bearer team-x");
+            env::set_var("TENANT_TEAM_X_COMPOSE_PROJECTS", "shop, billing");
+        }
+        assert!(Tenant::from_env("team-x").is_err());
+        unsafe { env::set_var("TENANT_TEAM_X_ADMIN_URL", "http://pingap-x:6188/"); }
+        let tenant = Tenant::from_env("team-x").unwrap();
+        // Containers are bound to the tenant by their compose project
+        unsafe { env::remove_var("TENANT_TEAM_X_COMPOSE_PROJECTS"); }
+        assert!(Tenant::from_env("team-x").is_err());
+        unsafe { env::set_var("TENANT_TEAM_X_LABEL_PREFIX", "pingap"); }
+        assert!(Tenant::from_env("team-x").is_err());
+        unsafe {
+            env::remove_var("TENANT_TEAM_X_ADMIN_URL");
+            env::remove_var("TENANT_TEAM_X_ADMIN_HEADERS");
+            env::remove_var("TENANT_TEAM_X_LABEL_PREFIX");
+        }
+        assert_eq!(tenant.label_prefix, "team-x");
+        assert_eq!(tenant.compose_projects, ["shop", "billing"]);
+        assert_eq!(tenant.admin_base(), "http://pingap-x:6188");
+        assert_eq!(admin_base("http://Pingap:6188", Some("/admin/")), "http://pingap:6188/admin");
+
+        let config = Config {
+            pingap_admin_url: "http://pingap:6188".to_string(),
+            pingap_admin_headers: vec!["Authorization: Bearer default".to_string()],
+            pingap_admin_hmac_secret: Some("default".to_string()),
+            status_addr: Some("0.0.0.0:9090".to_string()),
+            docker_label_filters: vec!["pingap.enable=true".to_string(), "com.example.team".to_string()],
+            auto_discover: true,
+            tenants: vec![tenant.clone()],
+            ..Default::default()
+        };
+        let tenant_config = config.for_tenant(&tenant);
+        assert_eq!(tenant_config.pingap_admin_url, "http://pingap-x:6188/");
+        assert_eq!(tenant_config.pingap_admin_headers, ["Authorization: Bearer team-x"]);
+        // Unset credentials are shared
+        assert_eq!(tenant_config.pingap_admin_hmac_secret.as_deref(), Some("default"));
+        assert_eq!(tenant_config.status_addr, None);
+        assert_eq!(tenant_config.docker_label_filters, ["team-x.pingap.enable=true", "com.example.team"]);
+        assert!(!tenant_config.auto_discover && tenant_config.tenants.is_empty());
+        assert_eq!(config.tenant_label_prefixes(), ["team-x.pingap."]);
+
+        // DNS records only with a target of the tenant's own
+        let dns = DnsConfig {
+            zone: "example.com".to_string(),
+            target: "203.0.113.1".to_string(),
+            ttl: 300,
+            owner_id: "pingap-docker-provider".to_string(),
+            backend: DnsBackend::Cloudflare { api_token: "t".to_string(), zone_id: "z".to_string() },
+        };
+        let config = Config { dns: Some(dns), ..config };
+        assert_eq!(config.for_tenant(&tenant).dns, None);
+        let tenant = Tenant { dns_target: Some("203.0.113.2".to_string()), ..tenant };
+        let tenant_dns = config.for_tenant(&tenant).dns.unwrap();
+        assert_eq!(tenant_dns.target, "203.0.113.2");
+        assert_eq!(tenant_dns.owner_id, "pingap-docker-provider-team-x");
+    }
+
+    #[test]
+    fn test_self_expose() {
+        unsafe {
+            env::remove_var("PROVIDER_SELF_EXPOSE_AUTH");
+            env::set_var("PROVIDER_SELF_EXPOSE_ADDRESS", "provider:9090");
+        }
+        let host = || "provider.internal.example.com".to_string();
+        assert!(SelfExpose::from_env(host(), None).is_err());
+        // Never without credentials
+        assert!(SelfExpose::from_env(host(), Some("0.0.0.0:9090")).is_err());
+        unsafe { env::set_var("PROVIDER_SELF_EXPOSE_AUTH", "admin"); }
+        assert!(SelfExpose::from_env(host(), Some("0.0.0.0:9090")).is_err());
+        unsafe { env::set_var("PROVIDER_SELF_EXPOSE_AUTH", "admin:secret"); }
+        let expose = SelfExpose::from_env(host(), Some("0.0.0.0:9090")).unwrap();
+        unsafe {
+            env::remove_var("PROVIDER_SELF_EXPOSE_AUTH");
+            env::remove_var("PROVIDER_SELF_EXPOSE_ADDRESS");
+        }
+
+        let container = models::ContainerInfo {
+            id: SELF_SERVICE_NAME.to_string(),
+            name: format!("/{}", SELF_SERVICE_NAME),
+            image: SELF_SERVICE_NAME.to_string(),
+            labels: expose.labels(),
+            ip_address: None,
+            ports: Vec::new(),
+            networks: HashMap::new(),
+            ipv6_networks: HashMap::new(),
+        };
+        let (config, diagnostics) = container.parse_pingap_configs_with_diagnostics().unwrap().remove(0);
+        assert!(diagnostics.is_empty());
+        assert_eq!(config.name, SELF_SERVICE_NAME);
+        assert_eq!(config.upstreams, ["provider:9090"]);
+        assert_eq!(config.middleware_config.unwrap().basic_auth.as_deref(), Some("admin:secret"));
+    }
 }
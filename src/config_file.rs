@@ -0,0 +1,269 @@
+//! Pingap's TOML config file as the config backend (`PINGAP_CONFIG_FILE`),
+//! for setups that load Pingap's config from disk. The Admin API calls of
+//! [`PingapClient`](crate::pingap::PingapClient) are answered from the file
+//! instead, and each write asks the [`Reloader`] to reload Pingap: it waits
+//! for writes to stop for a moment, so a batch of them costs one reload.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Client, Method, Request, Response, StatusCode, Url};
+use serde_json::{json, Value};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use crate::docker::DockerClient;
+use crate::signing::RequestSigner;
+
+/// A reload waits for a quiet period, but never longer than this many of them.
+const MAX_QUIET_PERIODS: u32 = 10;
+
+/// How the [`Reloader`] gets Pingap to load the rewritten file.
+pub enum ReloadAction {
+    /// Send `signal` to the Pingap container through the Docker API
+    Signal { docker: Box<DockerClient>, container: String, signal: String },
+    /// POST Pingap's restart endpoint, with the Admin API client's headers and signing
+    Restart { client: Client, url: String, signer: Option<RequestSigner> },
+}
+
+impl ReloadAction {
+    async fn run(&self) -> Result<()> {
+        match self {
+            ReloadAction::Signal { docker, container, signal } => docker.signal_container(container, signal).await,
+            ReloadAction::Restart { client, url, signer } => {
+                let mut request = client.post(url).build()?;
+                if let Some(signer) = signer {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                    signer.sign(&mut request, now);
+                }
+                let resp = client.execute(request).await.context(format!("Failed to call {}", url))?;
+                if !resp.status().is_success() {
+                    let status = resp.status();
+                    return Err(anyhow!("Pingap restart endpoint error ({}): {}", status, resp.text().await.unwrap_or_default()));
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            ReloadAction::Signal { container, signal, .. } => format!("sent {} to container {}", signal, container),
+            ReloadAction::Restart { url, .. } => format!("called {}", url),
+        }
+    }
+}
+
+/// Reloads Pingap once the writes it was told about have been quiet for
+/// `debounce`.
+#[derive(Clone)]
+pub struct Reloader {
+    writes: Arc<Notify>,
+}
+
+impl Reloader {
+    pub fn spawn(action: ReloadAction, debounce: Duration) -> Self {
+        let writes = Arc::new(Notify::new());
+        let notified = writes.clone();
+        tokio::spawn(async move {
+            loop {
+                notified.notified().await;
+                // Each write pushes the reload back, up to a limit so a steady stream of them can't hold it off
+                let deadline = Instant::now() + debounce * MAX_QUIET_PERIODS;
+                loop {
+                    let quiet = (Instant::now() + debounce).min(deadline);
+                    tokio::select! {
+                        _ = notified.notified() => continue,
+                        _ = tokio::time::sleep_until(quiet) => break,
+                    }
+                }
+                match action.run().await {
+                    Ok(()) => info!("Reloaded Pingap's config file: {}", action.describe()),
+                    Err(e) => warn!("Failed to reload Pingap, it keeps serving its previous config until the next write: {:#}", e),
+                }
+            }
+        });
+        Self { writes }
+    }
+
+    /// Notes a write to the file.
+    pub fn written(&self) {
+        self.writes.notify_one();
+    }
+}
+
+pub struct ConfigFile {
+    path: PathBuf,
+    // Read-modify-writes of the file, one at a time
+    lock: Mutex<()>,
+    reloader: Option<Reloader>,
+}
+
+impl ConfigFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), lock: Mutex::new(()), reloader: None }
+    }
+
+    /// Reloads Pingap with `reloader` after writes.
+    pub fn with_reloader(mut self, reloader: Reloader) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// Answers `request`, meant for the Admin API at `base_url`, from the
+    /// file: `config` as a whole and `<section>/<name>` resources. Anything
+    /// else is a 404, like `basic`, which has no file equivalent.
+    pub async fn answer(&self, base_url: &str, request: &Request) -> Response {
+        let base = Url::parse(base_url).map(|url| url.path().trim_end_matches('/').to_string()).unwrap_or_default();
+        let path = request.url().path().strip_prefix(base.as_str()).unwrap_or_default().trim_matches('/').to_string();
+        let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+        let (status, body) = match self.handle(request.method(), &path, body).await {
+            Ok(Some(body)) => (StatusCode::OK, body),
+            Ok(None) => (StatusCode::NOT_FOUND, json!({ "message": format!("{} not found in {}", path, self.path.display()) })),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, json!({ "message": format!("{:#}", e) })),
+        };
+        let response = http::Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(body.to_string())
+            .expect("static response parts are valid");
+        Response::from(response)
+    }
+
+    /// The response body for `method` on `path`, None when there is no such resource.
+    async fn handle(&self, method: &Method, path: &str, body: &[u8]) -> Result<Option<Value>> {
+        let _guard = self.lock.lock().await;
+        let mut full = self.read().await?;
+        let resource = match path.split_once('/') {
+            Some((section, name)) if !name.is_empty() && !name.contains('/') => Some((section, name)),
+            _ => None,
+        };
+        match (method, path, resource) {
+            (&Method::GET, "config", _) => Ok(Some(full)),
+            (&Method::PUT, "config", _) => {
+                let full = serde_json::from_slice(body).context("Invalid config document")?;
+                self.write(&full).await?;
+                Ok(Some(json!({})))
+            },
+            (&Method::GET, _, Some((section, name))) => Ok(full[section].get(name).cloned()),
+            (&Method::POST, _, Some((section, name))) => {
+                let payload: Value = serde_json::from_slice(body).context(format!("Invalid {}/{} payload", section, name))?;
+                let entries = full.as_object_mut()
+                    .ok_or_else(|| anyhow!("{} is not a table of sections", self.path.display()))?
+                    .entry(section).or_insert_with(|| json!({}));
+                entries.as_object_mut()
+                    .ok_or_else(|| anyhow!("[{}] of {} is not a table", section, self.path.display()))?
+                    .insert(name.to_string(), payload);
+                self.write(&full).await?;
+                Ok(Some(json!({})))
+            },
+            (&Method::DELETE, _, Some((section, name))) => {
+                if full[section].get(name).is_none() {
+                    return Ok(None);
+                }
+                if let Some(entries) = full.get_mut(section).and_then(Value::as_object_mut) {
+                    entries.remove(name);
+                }
+                self.write(&full).await?;
+                Ok(Some(json!({})))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// The file as JSON, an empty config while it doesn't exist yet.
+    async fn read(&self) -> Result<Value> {
+        let text = match tokio::fs::read_to_string(&self.path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(json!({})),
+            Err(e) => return Err(e).context(format!("Failed to read {}", self.path.display())),
+        };
+        toml::from_str(&text).context(format!("Failed to decode {}", self.path.display()))
+    }
+
+    /// Replaces the file with `full` through a temporary file, so Pingap
+    /// never reads half of it, and schedules a reload.
+    async fn write(&self, full: &Value) -> Result<()> {
+        let text = toml::to_string_pretty(&without_nulls(full.clone()))
+            .context(format!("Failed to encode {}", self.path.display()))?;
+        let temporary = self.path.with_extension("toml.tmp");
+        tokio::fs::write(&temporary, text).await
+            .context(format!("Failed to write {}", temporary.display()))?;
+        tokio::fs::rename(&temporary, &self.path).await
+            .context(format!("Failed to replace {}", self.path.display()))?;
+        debug!("Wrote {}", self.path.display());
+        if let Some(reloader) = &self.reloader {
+            reloader.written();
+        }
+        Ok(())
+    }
+}
+
+/// `value` without the nulls TOML has no way to write, the same as leaving the fields out.
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(entries) => Value::Object(entries.into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| (key, without_nulls(value)))
+            .collect()),
+        Value::Array(values) => Value::Array(values.into_iter().filter(|value| !value.is_null()).map(without_nulls).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::PingapServiceConfigBuilder;
+    use crate::pingap::PingapClient;
+
+    fn file_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pingap-provider-{}-{}.toml", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_client_writes_the_file() {
+        let path = file_path("client");
+        std::fs::write(&path, "[servers.web]\naddr = \"0.0.0.0:80\"\nlocations = [\"static\"]\n").unwrap();
+        let client = PingapClient::new("http://pingap:3018/api".to_string()).with_config_file(ConfigFile::new(&path));
+        assert_eq!(client.backend(), "file");
+
+        let config = PingapServiceConfigBuilder::new("web", vec!["10.0.0.1:80".to_string()], "Host(`web.local`)").build();
+        client.apply_config(&config).await.unwrap();
+        let written: toml::Table = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["upstreams"]["web"]["addrs"].as_array().unwrap()[0].as_str(), Some("10.0.0.1:80"));
+        assert_eq!(written["locations"]["web"]["upstream"].as_str(), Some("web"));
+        // What the provider doesn't manage stays
+        assert_eq!(written["servers"]["web"]["addr"].as_str(), Some("0.0.0.0:80"));
+        assert_eq!(client.instance_id().await.unwrap(), None);
+
+        client.delete_config("web").await.unwrap();
+        let written: toml::Table = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(written["upstreams"].as_table().unwrap().is_empty());
+        assert!(written["locations"].as_table().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writes_debounced_into_one_reload() {
+        let mut server = mockito::Server::new_async().await;
+        let restart = server.mock("POST", "/restart").with_status(200).expect(1).create_async().await;
+        let action = ReloadAction::Restart { client: Client::new(), url: format!("{}/restart", server.url()), signer: None };
+        let path = file_path("reload");
+        let file = ConfigFile::new(&path).with_reloader(Reloader::spawn(action, Duration::from_millis(100)));
+
+        let base = "http://pingap:3018";
+        for i in 0..5 {
+            let request = Client::new().post(format!("{}/upstreams/web", base))
+                .json(&json!({ "addrs": [format!("10.0.0.{}:80", i)] }))
+                .build().unwrap();
+            assert_eq!(file.answer(base, &request).await.status(), StatusCode::OK);
+        }
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        restart.assert_async().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use tracing::warn;
+
+/// Time of the last Docker event the provider handled, kept in a file
+/// (`EVENT_CURSOR_PATH`) so that after a restart the events missed while it
+/// was down can be replayed with `since`.
+pub struct EventCursor {
+    path: PathBuf,
+    /// Unix seconds of the newest event seen
+    time: Option<i64>,
+    /// What the file holds
+    saved: Option<i64>,
+}
+
+impl EventCursor {
+    /// A missing file is a first start; an unreadable one is logged and
+    /// ignored, leaving the initial sync on its own.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let time = match fs::read_to_string(&path) {
+            Ok(content) => match content.trim().parse::<i64>() {
+                Ok(time) if time > 0 => Some(time),
+                _ => {
+                    warn!("Ignoring event cursor {}, expected unix seconds, got '{}'", path.display(), content.trim());
+                    None
+                },
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context(format!("Failed to read event cursor {}", path.display())),
+        };
+        Ok(Self { path, time, saved: time })
+    }
+
+    /// Where replay starts: the last event handled before the previous shutdown.
+    pub fn since(&self) -> Option<i64> {
+        self.saved
+    }
+
+    pub fn record(&mut self, time: i64) {
+        if time > self.time.unwrap_or_default() {
+            self.time = Some(time);
+        }
+    }
+
+    /// Writes the cursor if it moved, through a temporary file so a crash
+    /// can't leave a truncated one behind.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(time) = self.time.filter(|time| Some(*time) != self.saved) else {
+            return Ok(());
+        };
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, format!("{}\n", time))
+            .and_then(|()| fs::rename(&tmp, &self.path))
+            .context(format!("Failed to write event cursor {}", self.path.display()))?;
+        self.saved = Some(time);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pingap-cursor-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("events.cursor")
+    }
+
+    #[test]
+    fn test_cursor_survives_restart() {
+        let path = temp_path("restart");
+        let mut cursor = EventCursor::open(&path).unwrap();
+        assert_eq!(cursor.since(), None);
+
+        cursor.record(1_700_000_100);
+        // Events can arrive slightly out of order, the cursor never goes back
+        cursor.record(1_700_000_050);
+        cursor.save().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1700000100\n");
+
+        let cursor = EventCursor::open(&path).unwrap();
+        assert_eq!(cursor.since(), Some(1_700_000_100));
+    }
+
+    #[test]
+    fn test_garbled_cursor_ignored() {
+        let path = temp_path("garbled");
+        fs::write(&path, "yesterday").unwrap();
+        let mut cursor = EventCursor::open(&path).unwrap();
+        assert_eq!(cursor.since(), None);
+
+        // Nothing recorded, nothing written
+        cursor.save().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "yesterday");
+    }
+}
@@ -0,0 +1,40 @@
+use anyhow::{anyhow, Result};
+use crate::models::Slot;
+use crate::pingap::PingapClient;
+
+/// `pingap-docker-provider cutover <service> --to blue|green`: switches a
+/// blue/green service's location over to the upstream of the other slot.
+pub async fn run(pingap: &PingapClient, args: &[String]) -> Result<()> {
+    let (service, slot) = parse_args(args)?;
+    pingap.cutover(&service, slot).await
+}
+
+fn parse_args(args: &[String]) -> Result<(String, Slot)> {
+    let usage = || anyhow!("Usage: pingap-docker-provider cutover <service> --to blue|green");
+    match args {
+        [service, flag, slot] if flag == "--to" => Ok((service.clone(), slot.parse()?)),
+        [service, flag] => {
+            let slot = flag.strip_prefix("--to=").ok_or_else(usage)?;
+            Ok((service.clone(), slot.parse()?))
+        },
+        _ => Err(usage()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&args(&["shop", "--to", "green"])).unwrap(), ("shop".to_string(), Slot::Green));
+        assert_eq!(parse_args(&args(&["shop", "--to=blue"])).unwrap(), ("shop".to_string(), Slot::Blue));
+        assert!(parse_args(&args(&["shop", "--to", "red"])).is_err());
+        assert!(parse_args(&args(&["shop"])).is_err());
+        assert!(parse_args(&args(&["shop", "green"])).is_err());
+    }
+}
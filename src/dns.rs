@@ -0,0 +1,1243 @@
+//! DNS records for the routed hosts (`DNS_PROVIDER`), in the manner of
+//! external-dns: once Pingap serves a service, the exact and wildcard hosts
+//! of its rule and its `pingap.tls.domains` that lie in `DNS_ZONE` get a
+//! record pointing at `DNS_TARGET`, and once no service routes a host
+//! anymore its record is removed. Each record comes with a TXT ownership
+//! record naming the provider (`DNS_OWNER_ID`): records without one, or
+//! with another owner's, are never written or removed, and the ownership
+//! records tell a restarted provider which records it left behind.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use crate::config::{DnsBackend, DnsConfig};
+use crate::models::PingapServiceConfig;
+use crate::rule::{self, HostMatcher};
+use crate::signing::hex;
+
+const CLOUDFLARE_API_URL: &str = "https://api.cloudflare.com/client/v4";
+const ROUTE53_API_URL: &str = "https://route53.amazonaws.com";
+/// Route53 is a global service, its requests are signed for us-east-1
+const ROUTE53_REGION: &str = "us-east-1";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Seconds a TSIG-signed update stays valid, as BIND uses
+const TSIG_FUDGE: u16 = 300;
+/// How long a host whose record failed to change is left alone
+pub const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// First label of the TXT ownership record of a host
+const REGISTRY_PREFIX: &str = "_pingap-owner";
+/// Stands in for the `*` of a wildcard host in the name of its ownership record
+const REGISTRY_WILDCARD: &str = "_wildcard";
+
+/// The type of the records pointing at the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    A,
+    Aaaa,
+    Cname,
+    Txt,
+}
+
+impl RecordKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordKind::A => "A",
+            RecordKind::Aaaa => "AAAA",
+            RecordKind::Cname => "CNAME",
+            RecordKind::Txt => "TXT",
+        }
+    }
+
+    fn code(&self) -> u16 {
+        match self {
+            RecordKind::A => 1,
+            RecordKind::Aaaa => 28,
+            RecordKind::Cname => 5,
+            RecordKind::Txt => 16,
+        }
+    }
+}
+
+/// One record of a routed host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub kind: RecordKind,
+    pub value: String,
+    pub ttl: u32,
+}
+
+/// Records written and removed by one [`DnsRecords::sync`].
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub upserted: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Hosts no longer routed whose record had changed hands, forgotten without touching it
+    pub released: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Name of the TXT record telling who owns the record of `host`.
+fn registry_name(host: &str) -> String {
+    match host.strip_prefix("*.") {
+        Some(domain) => format!("{}.{}.{}", REGISTRY_PREFIX, REGISTRY_WILDCARD, domain),
+        None => format!("{}.{}", REGISTRY_PREFIX, host),
+    }
+}
+
+/// The host whose ownership record is named `name`.
+fn registry_host(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let host = name.strip_prefix(REGISTRY_PREFIX)?.strip_prefix('.')?;
+    Some(match host.strip_prefix(REGISTRY_WILDCARD).and_then(|rest| rest.strip_prefix('.')) {
+        Some(domain) => format!("*.{}", domain),
+        None => host.to_string(),
+    })
+}
+
+/// The hosts `config` routes that can have a record: exact hosts and
+/// wildcards (`*.example.com`) of its rule and its TLS domains.
+pub fn service_hosts(config: &PingapServiceConfig) -> BTreeSet<String> {
+    let mut hosts = BTreeSet::new();
+    if let Ok(route) = rule::parse_rule(&config.location.rule) {
+        for host in route.hosts {
+            match host {
+                HostMatcher::Exact(host) => { hosts.insert(host); },
+                HostMatcher::Wildcard(domain) => { hosts.insert(format!("*.{}", domain)); },
+                HostMatcher::Regex(_) => {},
+            }
+        }
+    }
+    let domains = config.tls_config.as_ref().and_then(|tls| tls.domains.as_ref());
+    for domain in domains.into_iter().flatten() {
+        let normalized = match domain.strip_prefix("*.") {
+            Some(domain) => rule::normalize_hostname(domain).map(|domain| format!("*.{}", domain)),
+            None => rule::normalize_hostname(domain),
+        };
+        if let Ok(domain) = normalized {
+            hosts.insert(domain);
+        }
+    }
+    hosts
+}
+
+/// Keeps one record per routed host of `DNS_ZONE` at the DNS provider.
+pub struct DnsRecords {
+    backend: Backend,
+    zone: String,
+    kind: RecordKind,
+    target: String,
+    ttl: u32,
+    /// Value of the ownership records of this provider
+    owner: String,
+    /// Syncs hold the lock, so they run one after the other in the order
+    /// they were started.
+    state: Mutex<SyncState>,
+}
+
+#[derive(Default)]
+struct SyncState {
+    /// Whether `registered` was read from the ownership records yet
+    loaded: bool,
+    /// Hosts whose record is in place
+    registered: BTreeSet<String>,
+    /// Host -> when changing its record last failed
+    failed: HashMap<String, Instant>,
+}
+
+impl DnsRecords {
+    pub fn new(config: &DnsConfig) -> Result<Self> {
+        let zone = rule::normalize_hostname(&config.zone).context("Invalid DNS_ZONE")?;
+        let (kind, target) = match config.target.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => (RecordKind::A, ip.to_string()),
+            Ok(IpAddr::V6(ip)) => (RecordKind::Aaaa, ip.to_string()),
+            Err(_) => (RecordKind::Cname, rule::normalize_hostname(&config.target).context("Invalid DNS_TARGET")?),
+        };
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build().context("Failed to build DNS HTTP client")?;
+        let backend = match &config.backend {
+            DnsBackend::Cloudflare { api_token, zone_id } => Backend::Cloudflare(Cloudflare {
+                client,
+                base_url: CLOUDFLARE_API_URL.to_string(),
+                api_token: api_token.clone(),
+                zone_id: zone_id.clone(),
+            }),
+            DnsBackend::Route53 { access_key_id, secret_access_key, session_token, hosted_zone_id } => Backend::Route53(Route53 {
+                client,
+                base_url: ROUTE53_API_URL.to_string(),
+                access_key_id: access_key_id.clone(),
+                secret_access_key: secret_access_key.clone(),
+                session_token: session_token.clone(),
+                hosted_zone_id: hosted_zone_id.trim_start_matches("/hostedzone/").to_string(),
+            }),
+            DnsBackend::Rfc2136 { server, tsig_key } => {
+                let tsig_key = tsig_key.as_ref()
+                    .map(|(name, secret)| {
+                        let secret = base64::engine::general_purpose::STANDARD.decode(secret.trim())
+                            .map_err(|e| anyhow!("RFC2136_TSIG_SECRET is not base64: {}", e))?;
+                        Ok::<_, anyhow::Error>((name.trim_end_matches('.').to_lowercase(), secret))
+                    })
+                    .transpose()?;
+                let server = match server.parse::<IpAddr>() {
+                    Ok(ip) => SocketAddr::new(ip, 53).to_string(),
+                    Err(_) if server.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) => server.clone(),
+                    Err(_) => format!("{}:53", server),
+                };
+                Backend::Rfc2136(Rfc2136 { server, zone: zone.clone(), tsig_key })
+            },
+        };
+        let owner = format!("heritage=pingap-docker-provider,owner={}", config.owner_id);
+        if owner.len() > 255 {
+            bail!("DNS_OWNER_ID is too long for a TXT record");
+        }
+        Ok(Self { backend, zone, kind, target, ttl: config.ttl, owner, state: Mutex::default() })
+    }
+
+    /// Whether `host` lies in the managed zone.
+    pub fn in_zone(&self, host: &str) -> bool {
+        host == self.zone || host.strip_suffix(&self.zone).is_some_and(|prefix| prefix.ends_with('.'))
+    }
+
+    fn record(&self, host: &str) -> Record {
+        Record { name: host.to_string(), kind: self.kind, value: self.target.clone(), ttl: self.ttl }
+    }
+
+    /// The ownership record of the record of `host`.
+    fn registry(&self, host: &str) -> Record {
+        Record { name: registry_name(host), kind: RecordKind::Txt, value: self.owner.clone(), ttl: self.ttl }
+    }
+
+    /// Writes records for the hosts of `desired` in the zone that have none
+    /// yet and removes the ones no longer desired. The first sync picks up
+    /// the records registered to this provider before, so the ones of hosts
+    /// that went away meanwhile are removed too. Hosts that failed are tried
+    /// again by the first sync after [`RETRY_DELAY`].
+    pub async fn sync(&self, desired: BTreeSet<String>) -> SyncReport {
+        self.sync_at(desired, Instant::now()).await
+    }
+
+    async fn sync_at(&self, desired: BTreeSet<String>, now: Instant) -> SyncReport {
+        let desired = desired.into_iter().filter(|host| self.in_zone(host)).collect::<BTreeSet<_>>();
+        let mut state = self.state.lock().await;
+        let mut report = SyncReport::default();
+        if !state.loaded {
+            match self.backend.owned(&self.owner).await {
+                Ok(names) => {
+                    let hosts = names.iter().filter_map(|name| registry_host(name)).filter(|host| self.in_zone(host));
+                    state.registered.extend(hosts);
+                    state.loaded = true;
+                },
+                Err(e) => {
+                    // Without them a record of ours could look like someone else's, so nothing is written yet
+                    report.failed.push(format!("Failed to read the DNS ownership records of {}: {:#}", self.zone, e));
+                    return report;
+                },
+            }
+        }
+        state.failed.retain(|_, failed_at| now.duration_since(*failed_at) < RETRY_DELAY);
+        let upserts = desired.difference(&state.registered).cloned().collect::<Vec<_>>();
+        let deletes = state.registered.difference(&desired).cloned().collect::<Vec<_>>();
+        for host in upserts {
+            if state.failed.contains_key(&host) {
+                continue;
+            }
+            match self.backend.claim(&self.record(&host), &self.registry(&host)).await {
+                Ok(()) => {
+                    state.registered.insert(host.clone());
+                    report.upserted.push(host);
+                },
+                Err(e) => {
+                    report.failed.push(format!("Failed to write {} record of {}: {:#}", self.kind.as_str(), host, e));
+                    state.failed.insert(host, now);
+                },
+            }
+        }
+        for host in deletes {
+            if state.failed.contains_key(&host) {
+                continue;
+            }
+            match self.backend.release(&self.record(&host), &self.registry(&host)).await {
+                Ok(true) => {
+                    state.registered.remove(&host);
+                    report.deleted.push(host);
+                },
+                Ok(false) => {
+                    state.registered.remove(&host);
+                    report.released.push(host);
+                },
+                Err(e) => {
+                    report.failed.push(format!("Failed to remove {} record of {}: {:#}", self.kind.as_str(), host, e));
+                    state.failed.insert(host, now);
+                },
+            }
+        }
+        report
+    }
+}
+
+enum Backend {
+    Cloudflare(Cloudflare),
+    Route53(Route53),
+    Rfc2136(Rfc2136),
+}
+
+impl Backend {
+    /// Writes `record` and its ownership record `owner`. A record of the
+    /// name and type that has no ownership record, or an ownership record
+    /// of another owner, is an error and stays as it is.
+    async fn claim(&self, record: &Record, owner: &Record) -> Result<()> {
+        match self {
+            Backend::Cloudflare(cloudflare) => cloudflare.claim(record, owner).await,
+            Backend::Route53(route53) => route53.claim(record, owner).await,
+            Backend::Rfc2136(rfc2136) => rfc2136.claim(record, owner).await,
+        }
+    }
+
+    /// Removes `record`, if it still has its value, and its ownership record
+    /// `owner`. False when the ownership record is gone or another owner's,
+    /// which leaves both alone.
+    async fn release(&self, record: &Record, owner: &Record) -> Result<bool> {
+        match self {
+            Backend::Cloudflare(cloudflare) => cloudflare.release(record, owner).await,
+            Backend::Route53(route53) => route53.release(record, owner).await,
+            Backend::Rfc2136(rfc2136) => rfc2136.release(record, owner).await,
+        }
+    }
+
+    /// Names of the TXT records of the zone holding `value`.
+    async fn owned(&self, value: &str) -> Result<Vec<String>> {
+        match self {
+            Backend::Cloudflare(cloudflare) => cloudflare.owned(value).await,
+            Backend::Route53(route53) => route53.owned(value).await,
+            Backend::Rfc2136(rfc2136) => rfc2136.owned(value).await,
+        }
+    }
+}
+
+struct Cloudflare {
+    client: Client,
+    base_url: String,
+    api_token: String,
+    zone_id: String,
+}
+
+impl Cloudflare {
+    async fn call(&self, request: RequestBuilder) -> Result<Value> {
+        let response = request.bearer_auth(&self.api_token).send().await.context("Cloudflare API request failed")?;
+        let status = response.status();
+        let body: Value = response.json().await.context(format!("Cloudflare API returned {} without a JSON body", status))?;
+        if !status.is_success() || body["success"] != json!(true) {
+            let errors = body["errors"].as_array().into_iter().flatten()
+                .map(|error| format!("{} ({})", error["message"].as_str().unwrap_or("unknown error"), error["code"]))
+                .collect::<Vec<_>>();
+            bail!("Cloudflare API returned {}: {}", status, errors.join(", "));
+        }
+        Ok(body["result"].clone())
+    }
+
+    fn records_url(&self) -> String {
+        format!("{}/zones/{}/dns_records", self.base_url, self.zone_id)
+    }
+
+    async fn find(&self, record: &Record) -> Result<Vec<Value>> {
+        let request = self.client.get(self.records_url()).query(&[("type", record.kind.as_str()), ("name", record.name.as_str())]);
+        Ok(self.call(request).await?.as_array().cloned().unwrap_or_default())
+    }
+
+    async fn upsert(&self, record: &Record) -> Result<()> {
+        let body = json!({
+            "type": record.kind.as_str(),
+            "name": record.name,
+            "content": record.value,
+            "ttl": record.ttl,
+            "proxied": false,
+        });
+        let existing = self.find(record).await?;
+        let request = match existing.first().and_then(|existing| existing["id"].as_str()) {
+            Some(id) => self.client.put(format!("{}/{}", self.records_url(), id)),
+            None => self.client.post(self.records_url()),
+        };
+        self.call(request.json(&body)).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, record: &Record) -> Result<()> {
+        for existing in self.find(record).await? {
+            let Some(id) = existing["id"].as_str() else {
+                continue;
+            };
+            if content(&existing) == record.value {
+                self.call(self.client.delete(format!("{}/{}", self.records_url(), id))).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn claim(&self, record: &Record, owner: &Record) -> Result<()> {
+        let registered = self.find(owner).await?;
+        if registered.is_empty() {
+            if !self.find(record).await?.is_empty() {
+                bail!("a {} record of {} exists that has no ownership record", record.kind.as_str(), record.name);
+            }
+            self.upsert(owner).await?;
+        } else if !registered.iter().any(|existing| content(existing) == owner.value) {
+            bail!("the {} record of {} belongs to another owner", record.kind.as_str(), record.name);
+        }
+        self.upsert(record).await
+    }
+
+    async fn release(&self, record: &Record, owner: &Record) -> Result<bool> {
+        if !self.find(owner).await?.iter().any(|existing| content(existing) == owner.value) {
+            return Ok(false);
+        }
+        self.delete(record).await?;
+        self.delete(owner).await?;
+        Ok(true)
+    }
+
+    async fn owned(&self, value: &str) -> Result<Vec<String>> {
+        const PAGE_SIZE: usize = 100;
+        let mut names = Vec::new();
+        for page in 1.. {
+            let request = self.client.get(self.records_url())
+                .query(&[("type", "TXT".to_string()), ("per_page", PAGE_SIZE.to_string()), ("page", page.to_string())]);
+            let records = self.call(request).await?.as_array().cloned().unwrap_or_default();
+            names.extend(records.iter()
+                .filter(|existing| content(existing) == value)
+                .filter_map(|existing| existing["name"].as_str().map(str::to_string)));
+            if records.len() < PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// `content` of a Cloudflare record, without the quotes of TXT records and the final dot of names.
+fn content(record: &Value) -> &str {
+    record["content"].as_str().unwrap_or_default().trim_matches('"').trim_end_matches('.')
+}
+
+struct Route53 {
+    client: Client,
+    base_url: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    hosted_zone_id: String,
+}
+
+impl Route53 {
+    /// A request to `path` of the API, signed with the credentials.
+    fn signed(&self, method: &str, path: &str, query: &[(&str, &str)], body: &str) -> Result<RequestBuilder> {
+        let mut query = query.iter().map(|(key, value)| format!("{}={}", uri_encode(key), uri_encode(value))).collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+        let url = match query.is_empty() {
+            true => reqwest::Url::parse(&format!("{}{}", self.base_url, path))?,
+            false => reqwest::Url::parse(&format!("{}{}?{}", self.base_url, path, query))?,
+        };
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let signed = sigv4(&self.access_key_id, &self.secret_access_key, self.session_token.as_deref(), method, &host, path, &query, body.as_bytes(), now);
+
+        let mut request = self.client.request(method.parse()?, url)
+            .header("x-amz-date", &signed.amz_date)
+            .header("authorization", signed.authorization);
+        if let Some(token) = &self.session_token {
+            request = request.header("x-amz-security-token", token);
+        }
+        Ok(request)
+    }
+
+    /// Sends one batch of (action, record) changes, which Route53 applies
+    /// all or none. `Some` with Route53's message when it turned the batch
+    /// down, like a DELETE of a record that is gone or has another value, or
+    /// a CREATE of one that exists.
+    async fn change(&self, changes: &[(&str, &Record)]) -> Result<Option<String>> {
+        let changes = changes.iter()
+            .map(|(action, record)| {
+                let value = match record.kind {
+                    RecordKind::Txt => format!("\"{}\"", record.value),
+                    _ => record.value.clone(),
+                };
+                format!(
+                    concat!(
+                        "<Change><Action>{}</Action><ResourceRecordSet><Name>{}</Name><Type>{}</Type><TTL>{}</TTL>",
+                        "<ResourceRecords><ResourceRecord><Value>{}</Value></ResourceRecord></ResourceRecords>",
+                        "</ResourceRecordSet></Change>",
+                    ),
+                    action, record.name, record.kind.as_str(), record.ttl, xml_escape(&value),
+                )
+            })
+            .collect::<String>();
+        let body = format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<ChangeResourceRecordSetsRequest xmlns="https://route53.amazonaws.com/doc/2013-04-01/"><ChangeBatch><Changes>{}"#,
+                "</Changes></ChangeBatch></ChangeResourceRecordSetsRequest>",
+            ),
+            changes,
+        );
+        let path = format!("/2013-04-01/hostedzone/{}/rrset", self.hosted_zone_id);
+        let response = self.signed("POST", &path, &[], &body)?
+            .header("content-type", "text/xml")
+            .body(body)
+            .send().await.context("Route53 API request failed")?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(None);
+        }
+        let text = response.text().await.unwrap_or_default();
+        let message = xml_values(&text, "Message").first().copied().unwrap_or(&text).to_string();
+        if text.contains("<Code>InvalidChangeBatch</Code>") {
+            return Ok(Some(message));
+        }
+        bail!("Route53 API returned {}: {}", status, message)
+    }
+
+    async fn claim(&self, record: &Record, owner: &Record) -> Result<()> {
+        // Replacing the ownership record by itself only goes through while it is ours
+        if self.change(&[("DELETE", owner), ("CREATE", owner), ("UPSERT", record)]).await?.is_none() {
+            return Ok(());
+        }
+        // Not registered yet: creating fails if either record exists
+        match self.change(&[("CREATE", owner), ("CREATE", record)]).await? {
+            None => Ok(()),
+            Some(message) => bail!("the {} record of {} exists without an ownership record of ours: {}", record.kind.as_str(), record.name, message),
+        }
+    }
+
+    async fn release(&self, record: &Record, owner: &Record) -> Result<bool> {
+        if self.change(&[("DELETE", owner), ("DELETE", record)]).await?.is_none() {
+            return Ok(true);
+        }
+        // The record is gone or was repointed, so only the ownership record goes, if it is ours
+        Ok(self.change(&[("DELETE", owner)]).await?.is_none())
+    }
+
+    async fn owned(&self, value: &str) -> Result<Vec<String>> {
+        let path = format!("/2013-04-01/hostedzone/{}/rrset", self.hosted_zone_id);
+        let wanted = format!("\"{}\"", xml_escape(value));
+        let mut names = Vec::new();
+        let mut next: Option<(String, String)> = None;
+        loop {
+            let query = match &next {
+                Some((name, kind)) => vec![("name", name.as_str()), ("type", kind.as_str())],
+                None => Vec::new(),
+            };
+            let response = self.signed("GET", &path, &query, "")?.send().await.context("Route53 API request failed")?;
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            if !status.is_success() {
+                bail!("Route53 API returned {}: {}", status, xml_values(&text, "Message").first().copied().unwrap_or(&text));
+            }
+            for set in text.split("<ResourceRecordSet>").skip(1) {
+                let ours = xml_values(set, "Type").first() == Some(&"TXT")
+                    && xml_values(set, "Value").iter().any(|found| found.replace("&quot;", "\"") == wanted);
+                if let (true, Some(name)) = (ours, xml_values(set, "Name").first()) {
+                    names.push(name.trim_end_matches('.').to_string());
+                }
+            }
+            next = match (xml_values(&text, "IsTruncated").first(), xml_values(&text, "NextRecordName").first(), xml_values(&text, "NextRecordType").first()) {
+                (Some(&"true"), Some(name), Some(kind)) => Some((name.to_string(), kind.to_string())),
+                _ => break,
+            };
+        }
+        Ok(names)
+    }
+}
+
+/// The contents of the `<tag>` elements of `xml`.
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(open.as_str()).skip(1).filter_map(|rest| rest.split(close.as_str()).next()).collect()
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Percent-encodes all but the unreserved characters, as SigV4 canonical queries want.
+fn uri_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+struct Signed {
+    amz_date: String,
+    authorization: String,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// `(YYYYMMDD, YYYYMMDD'T'HHMMSS'Z')` of a Unix time, in UTC.
+fn amz_date(secs: u64) -> (String, String) {
+    // Days to civil date, after Howard Hinnant's `civil_from_days`
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let seconds = secs % 86400;
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!("{}T{:02}{:02}{:02}Z", date, seconds / 3600, seconds % 3600 / 60, seconds % 60);
+    (date, time)
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// AWS Signature Version 4 of a Route53 request; `query` is canonical already.
+#[allow(clippy::too_many_arguments)]
+fn sigv4(access_key_id: &str, secret_access_key: &str, session_token: Option<&str>, method: &str, host: &str, path: &str, query: &str, body: &[u8], now: u64) -> Signed {
+    let (date, amz_date) = amz_date(now);
+    let mut headers = vec![("host", host.to_string()), ("x-amz-date", amz_date.clone())];
+    if let Some(token) = session_token {
+        headers.push(("x-amz-security-token", token.to_string()));
+    }
+    let canonical_headers = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect::<String>();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, signed_headers, sha256_hex(body));
+    let scope = format!("{}/{}/route53/aws4_request", date, ROUTE53_REGION);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+    let signature = hex(&hmac_sha256(&signing_key(secret_access_key, &date, ROUTE53_REGION, "route53"), string_to_sign.as_bytes()));
+    Signed {
+        authorization: format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", access_key_id, scope, signed_headers, signature),
+        amz_date,
+    }
+}
+
+struct Rfc2136 {
+    /// `host:port` of the primary name server
+    server: String,
+    zone: String,
+    /// TSIG key name and secret
+    tsig_key: Option<(String, Vec<u8>)>,
+}
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_TSIG: u16 = 250;
+const TYPE_AXFR: u16 = 252;
+const OPCODE_UPDATE: u16 = 5;
+const RCODE_YXRRSET: u8 = 7;
+const RCODE_NXRRSET: u8 = 8;
+const TSIG_ALGORITHM: &str = "hmac-sha256";
+
+/// Appends `name` in uncompressed wire format.
+fn push_name(message: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+}
+
+fn push_rr(message: &mut Vec<u8>, name: &str, kind: u16, class: u16, ttl: u32, rdata: &[u8]) {
+    push_name(message, name);
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&class.to_be_bytes());
+    message.extend_from_slice(&ttl.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(rdata);
+}
+
+fn rdata(record: &Record) -> Result<Vec<u8>> {
+    Ok(match record.kind {
+        RecordKind::A | RecordKind::Aaaa => match record.value.parse::<IpAddr>()? {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        },
+        RecordKind::Cname => {
+            let mut name = Vec::new();
+            push_name(&mut name, &record.value);
+            name
+        },
+        RecordKind::Txt => {
+            let text = record.value.as_bytes();
+            if text.len() > 255 {
+                bail!("TXT value of {} is longer than 255 bytes", record.name);
+            }
+            let mut rdata = vec![text.len() as u8];
+            rdata.extend_from_slice(text);
+            rdata
+        },
+    })
+}
+
+/// An UPDATE message for `zone` with the prerequisites and updates, both
+/// (name, type, class, ttl, rdata) records.
+fn update_message(id: u16, zone: &str, prerequisites: &[(&str, u16, u16, u32, Vec<u8>)], updates: &[(&str, u16, u16, u32, Vec<u8>)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(OPCODE_UPDATE << 11).to_be_bytes());
+    // One zone, the prerequisites, the updates, no additional records yet
+    for count in [1, prerequisites.len() as u16, updates.len() as u16, 0] {
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+    push_name(&mut message, zone);
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    for (name, kind, class, ttl, rdata) in prerequisites.iter().chain(updates) {
+        push_rr(&mut message, name, *kind, *class, *ttl, rdata);
+    }
+    message
+}
+
+/// A zone transfer (AXFR) query for `zone`.
+fn transfer_query(id: u16, zone: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(&id.to_be_bytes());
+    // No flags, one question
+    for field in [0u16, 1, 0, 0, 0] {
+        message.extend_from_slice(&field.to_be_bytes());
+    }
+    push_name(&mut message, zone);
+    message.extend_from_slice(&TYPE_AXFR.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    message
+}
+
+/// The name at `offset` of `message`, following compression pointers, and
+/// the offset right after it.
+fn read_name(message: &[u8], offset: usize) -> Result<(String, usize)> {
+    let truncated = || anyhow!("truncated name in DNS message");
+    let mut labels = Vec::new();
+    let (mut at, mut end, mut jumps) = (offset, None, 0);
+    loop {
+        let len = *message.get(at).ok_or_else(truncated)? as usize;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(at + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let low = *message.get(at + 1).ok_or_else(truncated)? as usize;
+            end.get_or_insert(at + 2);
+            jumps += 1;
+            if jumps > 128 {
+                bail!("compression loop in DNS message");
+            }
+            at = (len & 0x3f) << 8 | low;
+            continue;
+        }
+        let label = message.get(at + 1..at + 1 + len).ok_or_else(truncated)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        at += 1 + len;
+    }
+}
+
+/// The (name, type, rdata) answer records of `message`.
+fn answers(message: &[u8]) -> Result<Vec<(String, u16, &[u8])>> {
+    let count = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]) as usize;
+    if message.len() < 12 {
+        bail!("truncated DNS message");
+    }
+    let mut offset = 12;
+    for _ in 0..count(4) {
+        offset = read_name(message, offset)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..count(6) {
+        let (name, at) = read_name(message, offset)?;
+        let fixed = message.get(at..at + 10).ok_or_else(|| anyhow!("truncated record in DNS message"))?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let rdata = message.get(at + 10..at + 10 + len).ok_or_else(|| anyhow!("truncated record in DNS message"))?;
+        records.push((name, kind, rdata));
+        offset = at + 10 + len;
+    }
+    Ok(records)
+}
+
+/// The character strings of TXT `rdata`, joined.
+fn txt_text(rdata: &[u8]) -> String {
+    let mut text = Vec::new();
+    let mut at = 0;
+    while let Some(&len) = rdata.get(at) {
+        text.extend_from_slice(rdata.get(at + 1..at + 1 + len as usize).unwrap_or_default());
+        at += 1 + len as usize;
+    }
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+/// Appends a TSIG record (RFC 8945) signing `message` with `key`.
+fn sign_message(message: &mut Vec<u8>, key_name: &str, secret: &[u8], time_signed: u64) {
+    let time = &time_signed.to_be_bytes()[2..];
+    let mut signed = message.clone();
+    push_name(&mut signed, key_name);
+    signed.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    signed.extend_from_slice(&0u32.to_be_bytes());
+    push_name(&mut signed, TSIG_ALGORITHM);
+    signed.extend_from_slice(time);
+    signed.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    // No error, no other data
+    signed.extend_from_slice(&[0, 0, 0, 0]);
+    let mac = hmac_sha256(secret, &signed);
+
+    let mut rdata = Vec::new();
+    push_name(&mut rdata, TSIG_ALGORITHM);
+    rdata.extend_from_slice(time);
+    rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&mac);
+    rdata.extend_from_slice(&message[..2]);
+    rdata.extend_from_slice(&[0, 0, 0, 0]);
+    push_rr(message, key_name, TYPE_TSIG, CLASS_ANY, 0, &rdata);
+    let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+}
+
+fn rcode_name(rcode: u8) -> &'static str {
+    match rcode {
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => "unknown error",
+    }
+}
+
+impl Rfc2136 {
+    async fn claim(&self, record: &Record, owner: &Record) -> Result<()> {
+        let (kind, value, registry) = (record.kind.code(), rdata(record)?, rdata(owner)?);
+        // Ours already: the ownership record has to hold exactly our value
+        let replace = [
+            (record.name.as_str(), kind, CLASS_ANY, 0, Vec::new()),
+            (record.name.as_str(), kind, CLASS_IN, record.ttl, value.clone()),
+        ];
+        match self.update(&[(&owner.name, TYPE_TXT, CLASS_IN, 0, registry.clone())], &replace).await? {
+            RCODE_NXRRSET => {},
+            rcode => return self.checked(rcode),
+        }
+        // Not registered yet: neither the record nor an ownership record may exist
+        let create = [
+            (owner.name.as_str(), TYPE_TXT, CLASS_IN, owner.ttl, registry),
+            (record.name.as_str(), kind, CLASS_IN, record.ttl, value),
+        ];
+        match self.update(&[(&record.name, kind, CLASS_NONE, 0, Vec::new()), (&owner.name, TYPE_TXT, CLASS_NONE, 0, Vec::new())], &create).await? {
+            RCODE_YXRRSET => bail!("the {} record of {} exists without an ownership record of ours", record.kind.as_str(), record.name),
+            rcode => self.checked(rcode),
+        }
+    }
+
+    async fn release(&self, record: &Record, owner: &Record) -> Result<bool> {
+        let registry = rdata(owner)?;
+        // Deleting a value the record no longer has leaves it alone
+        let delete = [
+            (record.name.as_str(), record.kind.code(), CLASS_NONE, 0, rdata(record)?),
+            (owner.name.as_str(), TYPE_TXT, CLASS_NONE, 0, registry.clone()),
+        ];
+        match self.update(&[(&owner.name, TYPE_TXT, CLASS_IN, 0, registry)], &delete).await? {
+            RCODE_NXRRSET => Ok(false),
+            rcode => self.checked(rcode).map(|()| true),
+        }
+    }
+
+    fn checked(&self, rcode: u8) -> Result<()> {
+        match rcode {
+            0 => Ok(()),
+            rcode => bail!("name server {} refused the update: {}", self.server, rcode_name(rcode)),
+        }
+    }
+
+    /// Sends an update and returns the response code.
+    async fn update(&self, prerequisites: &[(&str, u16, u16, u32, Vec<u8>)], updates: &[(&str, u16, u16, u32, Vec<u8>)]) -> Result<u8> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let id = now.subsec_nanos() as u16;
+        let mut message = update_message(id, &self.zone, prerequisites, updates);
+        if let Some((key_name, secret)) = &self.tsig_key {
+            sign_message(&mut message, key_name, secret, now.as_secs());
+        }
+        let socket = UdpSocket::bind(if self.server.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" }).await?;
+        socket.connect(&self.server).await.context(format!("Failed to reach name server {}", self.server))?;
+        socket.send(&message).await?;
+        let mut response = [0u8; 512];
+        let received = tokio::time::timeout(REQUEST_TIMEOUT, socket.recv(&mut response)).await
+            .map_err(|_| anyhow!("name server {} did not answer within {:?}", self.server, REQUEST_TIMEOUT))??;
+        if received < 12 || response[..2] != id.to_be_bytes() {
+            bail!("name server {} sent an invalid answer", self.server);
+        }
+        Ok(response[3] & 0x0f)
+    }
+
+    /// Transfers the zone (AXFR over TCP, which the TSIG key has to be
+    /// allowed) for the TXT records holding `value`.
+    async fn owned(&self, value: &str) -> Result<Vec<String>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let id = now.subsec_nanos() as u16;
+        let mut query = transfer_query(id, &self.zone);
+        if let Some((key_name, secret)) = &self.tsig_key {
+            sign_message(&mut query, key_name, secret, now.as_secs());
+        }
+        let transfer = async {
+            let mut stream = TcpStream::connect(&self.server).await.context(format!("Failed to reach name server {}", self.server))?;
+            stream.write_all(&(query.len() as u16).to_be_bytes()).await?;
+            stream.write_all(&query).await?;
+            // The transfer starts and ends with the SOA record
+            let (mut names, mut soas) = (Vec::new(), 0);
+            while soas < 2 {
+                let len = stream.read_u16().await.context(format!("name server {} ended the zone transfer early", self.server))?;
+                let mut message = vec![0u8; len as usize];
+                stream.read_exact(&mut message).await?;
+                if message.len() < 12 || message[..2] != id.to_be_bytes() {
+                    bail!("name server {} sent an invalid answer", self.server);
+                }
+                if message[3] & 0x0f != 0 {
+                    bail!("name server {} refused the zone transfer: {}", self.server, rcode_name(message[3] & 0x0f));
+                }
+                for (name, kind, rdata) in answers(&message)? {
+                    match kind {
+                        TYPE_SOA => soas += 1,
+                        TYPE_TXT if txt_text(rdata) == value => names.push(name),
+                        _ => {},
+                    }
+                }
+            }
+            Ok(names)
+        };
+        tokio::time::timeout(REQUEST_TIMEOUT, transfer).await
+            .map_err(|_| anyhow!("zone transfer from {} took longer than {:?}", self.server, REQUEST_TIMEOUT))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{PingapServiceConfigBuilder, TlsConfig};
+
+    const OWNER: &str = "heritage=pingap-docker-provider,owner=test";
+
+    fn records(backend: Backend, target: &str) -> DnsRecords {
+        let (kind, target) = match target.parse::<IpAddr>() {
+            Ok(_) => (RecordKind::A, target.to_string()),
+            Err(_) => (RecordKind::Cname, target.to_string()),
+        };
+        DnsRecords { backend, zone: "example.com".to_string(), kind, target, ttl: 300, owner: OWNER.to_string(), state: Mutex::default() }
+    }
+
+    fn hosts(hosts: &[&str]) -> BTreeSet<String> {
+        hosts.iter().map(|host| host.to_string()).collect()
+    }
+
+    fn cloudflare(server: &mockito::Server) -> Backend {
+        Backend::Cloudflare(Cloudflare { client: Client::new(), base_url: server.url(), api_token: "token".to_string(), zone_id: "z1".to_string() })
+    }
+
+    /// Matches the Cloudflare lookup of the `kind` records of `name`.
+    fn lookup(kind: &str, name: &str) -> mockito::Matcher {
+        mockito::Matcher::AllOf(vec![
+            mockito::Matcher::UrlEncoded("type".into(), kind.into()),
+            mockito::Matcher::UrlEncoded("name".into(), name.into()),
+        ])
+    }
+
+    #[test]
+    fn test_service_hosts() {
+        let config = PingapServiceConfigBuilder::new("web", vec![], "Host(`app.example.com`) || Host(`*.apps.example.com`) || HostRegexp(`^api`)")
+            .with_tls(Some(true), Some(TlsConfig { enabled: true, domains: Some(vec!["WWW.example.com".to_string(), "*.example.com".to_string()]), ..TlsConfig::default() }))
+            .build();
+        assert_eq!(service_hosts(&config), hosts(&["*.apps.example.com", "*.example.com", "app.example.com", "www.example.com"]));
+    }
+
+    #[test]
+    fn test_amz_date_and_signing_key() {
+        assert_eq!(amz_date(1440938160), ("20150830".to_string(), "20150830T123600Z".to_string()));
+        assert_eq!(amz_date(951782400).1, "20000229T000000Z");
+        // Example of the AWS Signature Version 4 documentation
+        assert_eq!(hex(&signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam")),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn test_registry_names() {
+        assert_eq!(registry_name("app.example.com"), "_pingap-owner.app.example.com");
+        assert_eq!(registry_name("*.apps.example.com"), "_pingap-owner._wildcard.apps.example.com");
+        for host in ["app.example.com", "*.apps.example.com"] {
+            assert_eq!(registry_host(&registry_name(host)).as_deref(), Some(host));
+        }
+        assert_eq!(registry_host("_pingap-owner.App.example.com."), Some("app.example.com".to_string()));
+        assert_eq!(registry_host("app.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn test_cloudflare_records_follow_hosts() {
+        let mut server = mockito::Server::new_async().await;
+        let empty = r#"{"success":true,"errors":[],"result":[]}"#;
+        // Registered before a restart: old.example.com, not another provider's gone.example.com
+        let registry = server.mock("GET", "/zones/z1/dns_records")
+            .match_query(mockito::Matcher::UrlEncoded("per_page".into(), "100".into()))
+            .with_body(json!({ "success": true, "errors": [], "result": [
+                { "id": "t0", "name": "_pingap-owner.old.example.com", "content": format!("\"{}\"", OWNER) },
+                { "id": "t9", "name": "_pingap-owner.gone.example.com", "content": "\"heritage=pingap-docker-provider,owner=other\"" },
+            ] }).to_string())
+            .create_async().await;
+        let unregistered = server.mock("GET", "/zones/z1/dns_records")
+            .match_query(lookup("TXT", "_pingap-owner.app.example.com"))
+            .with_body(empty).expect(2).create_async().await;
+        let unused = server.mock("GET", "/zones/z1/dns_records")
+            .match_query(lookup("A", "app.example.com"))
+            .with_body(empty).expect(2).create_async().await;
+        let register = server.mock("POST", "/zones/z1/dns_records")
+            .match_body(mockito::Matcher::PartialJson(json!({ "type": "TXT", "name": "_pingap-owner.app.example.com", "content": OWNER })))
+            .with_body(r#"{"success":true,"errors":[],"result":{"id":"t1"}}"#).create_async().await;
+        let create = server.mock("POST", "/zones/z1/dns_records")
+            .match_header("authorization", "Bearer token")
+            .match_body(mockito::Matcher::PartialJson(json!({ "type": "A", "name": "app.example.com", "content": "203.0.113.7", "ttl": 300 })))
+            .with_body(r#"{"success":true,"errors":[],"result":{"id":"r1"}}"#).create_async().await;
+        let old_registry = server.mock("GET", "/zones/z1/dns_records")
+            .match_query(lookup("TXT", "_pingap-owner.old.example.com"))
+            .with_body(json!({ "success": true, "errors": [], "result": [{ "id": "t0", "content": OWNER }] }).to_string())
+            .expect(2).create_async().await;
+        let old = server.mock("GET", "/zones/z1/dns_records")
+            .match_query(lookup("A", "old.example.com"))
+            .with_body(r#"{"success":true,"errors":[],"result":[{"id":"r0","content":"203.0.113.7"}]}"#).create_async().await;
+        let delete_old = server.mock("DELETE", "/zones/z1/dns_records/r0")
+            .with_body(r#"{"success":true,"errors":[],"result":{"id":"r0"}}"#).create_async().await;
+        let unregister_old = server.mock("DELETE", "/zones/z1/dns_records/t0")
+            .with_body(r#"{"success":true,"errors":[],"result":{"id":"t0"}}"#).create_async().await;
+        let dns = records(cloudflare(&server), "203.0.113.7");
+
+        // Hosts outside the zone are left alone
+        let report = dns.sync(hosts(&["app.example.com", "app.example.org"])).await;
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(report.upserted, ["app.example.com"]);
+        assert_eq!(report.deleted, ["old.example.com"]);
+        for mock in [registry, unregistered, unused, register, create, old_registry, old, delete_old, unregister_old] {
+            mock.assert_async().await;
+        }
+        // Nothing changed, nothing to write
+        let report = dns.sync(hosts(&["app.example.com"])).await;
+        assert!(report.upserted.is_empty() && report.deleted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cloudflare_leaves_foreign_records() {
+        let mut server = mockito::Server::new_async().await;
+        let empty = r#"{"success":true,"errors":[],"result":[]}"#;
+        server.mock("GET", "/zones/z1/dns_records")
+            .match_query(mockito::Matcher::UrlEncoded("per_page".into(), "100".into()))
+            .with_body(empty).create_async().await;
+        // A hand-made record without an ownership record
+        server.mock("GET", "/zones/z1/dns_records").match_query(lookup("TXT", "_pingap-owner.www.example.com"))
+            .with_body(empty).create_async().await;
+        server.mock("GET", "/zones/z1/dns_records").match_query(lookup("A", "www.example.com"))
+            .with_body(r#"{"success":true,"errors":[],"result":[{"id":"r1","content":"198.51.100.1"}]}"#).create_async().await;
+        // Another provider's record
+        server.mock("GET", "/zones/z1/dns_records").match_query(lookup("TXT", "_pingap-owner.api.example.com"))
+            .with_body(r#"{"success":true,"errors":[],"result":[{"id":"t2","content":"\"heritage=pingap-docker-provider,owner=other\""}]}"#)
+            .create_async().await;
+        let writes = server.mock("POST", mockito::Matcher::Any).expect(0).create_async().await;
+        let dns = records(cloudflare(&server), "203.0.113.7");
+
+        let report = dns.sync(hosts(&["api.example.com", "www.example.com"])).await;
+        assert!(report.upserted.is_empty());
+        assert!(report.failed[0].contains("api.example.com belongs to another owner"), "{:?}", report.failed);
+        assert!(report.failed[1].contains("www.example.com exists that has no ownership record"), "{:?}", report.failed);
+        writes.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes_retried() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/zones/z1/dns_records")
+            .match_query(mockito::Matcher::UrlEncoded("per_page".into(), "100".into()))
+            .with_body(r#"{"success":true,"errors":[],"result":[]}"#).create_async().await;
+        let failing = server.mock("GET", "/zones/z1/dns_records")
+            .match_query(lookup("TXT", "_pingap-owner.app.example.com"))
+            .with_status(403)
+            .with_body(r#"{"success":false,"errors":[{"code":10000,"message":"Authentication error"}],"result":null}"#)
+            .expect(2).create_async().await;
+        let dns = records(cloudflare(&server), "203.0.113.7");
+        let now = Instant::now();
+        let report = dns.sync_at(hosts(&["app.example.com"]), now).await;
+        assert!(report.failed[0].contains("Authentication error"), "{:?}", report.failed);
+        // Left alone for a while, then tried again
+        assert!(dns.sync_at(hosts(&["app.example.com"]), now + Duration::from_secs(1)).await.failed.is_empty());
+        assert_eq!(dns.sync_at(hosts(&["app.example.com"]), now + RETRY_DELAY).await.failed.len(), 1);
+        failing.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_route53_changes_signed() {
+        let mut server = mockito::Server::new_async().await;
+        let registry = server.mock("GET", "/2013-04-01/hostedzone/Z1/rrset")
+            .match_header("authorization", mockito::Matcher::Regex(
+                r"^AWS4-HMAC-SHA256 Credential=AKID/\d{8}/us-east-1/route53/aws4_request, SignedHeaders=host;x-amz-date, Signature=[0-9a-f]{64}$".to_string()))
+            .with_body(format!(concat!(
+                "<ListResourceRecordSetsResponse><ResourceRecordSets>",
+                "<ResourceRecordSet><Name>_pingap-owner.old.example.com.</Name><Type>TXT</Type><TTL>300</TTL>",
+                "<ResourceRecords><ResourceRecord><Value>\"{}\"</Value></ResourceRecord></ResourceRecords></ResourceRecordSet>",
+                "<ResourceRecordSet><Name>old.example.com.</Name><Type>CNAME</Type><TTL>300</TTL>",
+                "<ResourceRecords><ResourceRecord><Value>proxy.example.net</Value></ResourceRecord></ResourceRecords></ResourceRecordSet>",
+                "</ResourceRecordSets><IsTruncated>false</IsTruncated><MaxItems>300</MaxItems></ListResourceRecordSetsResponse>",
+            ), OWNER))
+            .create_async().await;
+        // Not ours yet, so replacing the ownership record is turned down and both records are created
+        let not_ours = server.mock("POST", "/2013-04-01/hostedzone/Z1/rrset")
+            .match_body(mockito::Matcher::Regex("<Action>DELETE</Action><ResourceRecordSet><Name>_pingap-owner.app.example.com</Name>".to_string()))
+            .with_status(400)
+            .with_body("<ErrorResponse><Error><Code>InvalidChangeBatch</Code><Message>Tried to delete resource record set [name='_pingap-owner.app.example.com.', type='TXT'] but it was not found</Message></Error></ErrorResponse>")
+            .create_async().await;
+        let create = server.mock("POST", "/2013-04-01/hostedzone/Z1/rrset")
+            .match_header("authorization", mockito::Matcher::Regex(r"SignedHeaders=host;x-amz-date, Signature=[0-9a-f]{64}$".to_string()))
+            .match_body(mockito::Matcher::Regex(format!(concat!(
+                "<Action>CREATE</Action><ResourceRecordSet><Name>_pingap-owner.app.example.com</Name><Type>TXT</Type><TTL>300</TTL>.*<Value>\"{}\"</Value>.*",
+                "<Action>CREATE</Action><ResourceRecordSet><Name>app.example.com</Name><Type>CNAME</Type><TTL>300</TTL>.*<Value>proxy.example.net</Value>",
+            ), OWNER)))
+            .create_async().await;
+        let delete = server.mock("POST", "/2013-04-01/hostedzone/Z1/rrset")
+            .match_body(mockito::Matcher::Regex(concat!(
+                "<Action>DELETE</Action><ResourceRecordSet><Name>_pingap-owner.old.example.com</Name>.*",
+                "<Action>DELETE</Action><ResourceRecordSet><Name>old.example.com</Name>",
+            ).to_string()))
+            .create_async().await;
+        let route53 = Route53 {
+            client: Client::new(),
+            base_url: server.url(),
+            access_key_id: "AKID".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: None,
+            hosted_zone_id: "Z1".to_string(),
+        };
+        let dns = records(Backend::Route53(route53), "proxy.example.net");
+        let report = dns.sync(hosts(&["app.example.com"])).await;
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        assert_eq!(report.upserted, ["app.example.com"]);
+        assert_eq!(report.deleted, ["old.example.com"]);
+        for mock in [registry, not_ours, create, delete] {
+            mock.assert_async().await;
+        }
+    }
+
+    #[test]
+    fn test_tsig_known_vector() {
+        let mut message = update_message(0x1234, "example.com", &[], &[
+            ("app.example.com", 1, CLASS_ANY, 0, Vec::new()),
+            ("app.example.com", 1, CLASS_IN, 300, vec![203, 0, 113, 7]),
+        ]);
+        let secret = base64::engine::general_purpose::STANDARD.decode("c2hhcmVkIHNlY3JldA==").unwrap();
+        sign_message(&mut message, "update-key", &secret, 1_700_000_000);
+        // Built and signed separately, after RFC 2136 section 2 and RFC 8945 section 4.3.3
+        assert_eq!(hex(&message), concat!(
+            "123428000001000000020001076578616d706c6503636f6d000006000103617070076578616d706c6503636f6d00000100ff",
+            "00000000000003617070076578616d706c6503636f6d00000100010000012c0004cb0071070a7570646174652d6b6579",
+            "0000fa00ff00000000003d0b686d61632d7368613235360000006553f100012c0020",
+            "4fe5923d5130375a1dacfa25db62a8b2c61c97b5b06d940fba3cbb083d934861",
+            "123400000000",
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rfc2136_claims_with_prerequisites() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rfc2136 = Rfc2136 { server: server.local_addr().unwrap().to_string(), zone: "example.com".to_string(), tsig_key: None };
+        // The ownership record isn't ours (NXRRSET), then creating both goes through
+        let answer = tokio::spawn(async move {
+            let mut messages = Vec::new();
+            for rcode in [RCODE_NXRRSET, 0] {
+                let mut buf = [0u8; 512];
+                let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+                let mut reply = buf[..12].to_vec();
+                reply[2] |= 0x80;
+                reply[3] = rcode;
+                server.send_to(&reply, peer).await.unwrap();
+                messages.push(buf[..len].to_vec());
+            }
+            messages
+        });
+        let record = Record { name: "app.example.com".to_string(), kind: RecordKind::A, value: "203.0.113.7".to_string(), ttl: 300 };
+        let owner = Record { name: "_pingap-owner.app.example.com".to_string(), kind: RecordKind::Txt, value: OWNER.to_string(), ttl: 300 };
+        rfc2136.claim(&record, &owner).await.unwrap();
+
+        let messages = answer.await.unwrap();
+        let registry = rdata(&owner).unwrap();
+        let id = |message: &[u8]| u16::from_be_bytes([message[0], message[1]]);
+        assert_eq!(messages[0], update_message(id(&messages[0]), "example.com",
+            &[("_pingap-owner.app.example.com", TYPE_TXT, CLASS_IN, 0, registry.clone())],
+            &[("app.example.com", 1, CLASS_ANY, 0, Vec::new()), ("app.example.com", 1, CLASS_IN, 300, vec![203, 0, 113, 7])]));
+        assert_eq!(messages[1], update_message(id(&messages[1]), "example.com",
+            &[("app.example.com", 1, CLASS_NONE, 0, Vec::new()), ("_pingap-owner.app.example.com", TYPE_TXT, CLASS_NONE, 0, Vec::new())],
+            &[("_pingap-owner.app.example.com", TYPE_TXT, CLASS_IN, 300, registry), ("app.example.com", 1, CLASS_IN, 300, vec![203, 0, 113, 7])]));
+    }
+
+    #[tokio::test]
+    async fn test_zone_transfer_finds_owned_records() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rfc2136 = Rfc2136 { server: listener.local_addr().unwrap().to_string(), zone: "example.com".to_string(), tsig_key: None };
+        let txt = |value: &str| {
+            let mut rdata = vec![value.len() as u8];
+            rdata.extend_from_slice(value.as_bytes());
+            rdata
+        };
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut query = vec![0u8; len as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            assert_eq!(query, transfer_query(u16::from_be_bytes([query[0], query[1]]), "example.com"));
+            let header = |answers: u16| {
+                let mut message = query[..12].to_vec();
+                message[2] |= 0x80;
+                message[6..8].copy_from_slice(&answers.to_be_bytes());
+                message[4..6].copy_from_slice(&0u16.to_be_bytes());
+                message
+            };
+            let soa = |message: &mut Vec<u8>| push_rr(message, "example.com", TYPE_SOA, CLASS_IN, 300, &[0; 22]);
+            let mut first = header(3);
+            soa(&mut first);
+            // Named relative to the zone name of the SOA record at offset 12
+            first.extend_from_slice(b"\x0d_pingap-owner\x03app\xc0\x0c");
+            first.extend_from_slice(&[0, 16, 0, 1, 0, 0, 1, 44]);
+            first.extend_from_slice(&(txt(OWNER).len() as u16).to_be_bytes());
+            first.extend_from_slice(&txt(OWNER));
+            push_rr(&mut first, "_pingap-owner.web.example.com", TYPE_TXT, CLASS_IN, 300, &txt("heritage=pingap-docker-provider,owner=other"));
+            let mut last = header(1);
+            soa(&mut last);
+            for message in [first, last] {
+                stream.write_all(&(message.len() as u16).to_be_bytes()).await.unwrap();
+                stream.write_all(&message).await.unwrap();
+            }
+        });
+        assert_eq!(rfc2136.owned(OWNER).await.unwrap(), ["_pingap-owner.app.example.com"]);
+    }
+}
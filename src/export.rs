@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use reqwest::Method;
+use crate::maintenance::{local_addr, status_request};
+use crate::models::{LabelDiagnostic, PingapServiceConfig};
+use crate::redact;
+
+/// Format version written by `state export`; imports of newer files are refused.
+pub const STATE_VERSION: u32 = 1;
+
+/// Everything the sync loop tracks, as `state export` writes it to move the
+/// provider to another host or to rehearse a recovery.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProviderState {
+    pub version: u32,
+    /// Unix seconds of the export
+    pub exported_at: u64,
+    /// Service -> its replicas as the provider tracks them
+    pub services: BTreeMap<String, ServiceState>,
+    /// Pingap resources carrying the ownership marker, as `<section>/<name>`
+    #[serde(default)]
+    pub managed: Vec<String>,
+    /// Services put into maintenance through the status API
+    #[serde(default)]
+    pub maintenance: BTreeSet<String>,
+    /// Services whose writes wait for a freeze window to end
+    #[serde(default)]
+    pub held_back: BTreeSet<String>,
+    /// Resources left behind by failed applies whose removal is retried, as `<section>/<name>`
+    #[serde(default)]
+    pub orphans: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceState {
+    /// Config of the most recently started replica
+    pub config: PingapServiceConfig,
+    /// ContainerID -> upstream addresses; empty for retained services and tombstones
+    pub containers: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub weights: BTreeMap<String, u32>,
+    /// Whether Pingap had the service's location in place
+    pub applied: bool,
+    /// Seconds its tombstone had left (`TOMBSTONE_TTL_SECS`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone_secs: Option<u64>,
+    /// Optional labels of its containers that were left out of the config
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub label_diagnostics: Vec<LabelDiagnostic>,
+}
+
+impl ServiceState {
+    /// Whether the config lost credentials to [`ProviderState::redacted`].
+    pub fn is_redacted(&self) -> bool {
+        serde_json::to_string(&self.config).is_ok_and(|json| json.contains(redact::MASK))
+    }
+}
+
+impl ProviderState {
+    /// A copy with the credentials of every config masked (basic auth,
+    /// `Authorization` and similar headers), as the status API serves it.
+    pub fn redacted(&self) -> Result<Self> {
+        let mut state = self.clone();
+        for service in state.services.values_mut() {
+            let config = redact::value(&serde_json::to_value(&service.config)?);
+            service.config = serde_json::from_value(config).context("Failed to mask the credentials of a service")?;
+        }
+        Ok(state)
+    }
+
+    /// Parses an exported state, refusing files of a newer provider.
+    pub fn parse(json: &str) -> Result<Self> {
+        let state: Self = serde_json::from_str(json).context("Invalid provider state")?;
+        if state.version > STATE_VERSION {
+            bail!("Provider state version {} is newer than the supported version {}", state.version, STATE_VERSION);
+        }
+        Ok(state)
+    }
+}
+
+/// `pingap-docker-provider state export [<file>]|import <file>`: reads or
+/// restores the tracked state of the running provider through its status
+/// API (`STATUS_ADDR`). Without a file, `export` prints the state.
+pub async fn run(status_addr: Option<&str>, token: Option<&str>, args: &[String]) -> Result<()> {
+    let usage = || anyhow!("Usage: pingap-docker-provider state export [<file>] | state import <file>");
+    let (action, file) = match args {
+        [action] if action == "export" => (action, None),
+        [action, file] if action == "export" || action == "import" => (action, Some(file)),
+        _ => return Err(usage()),
+    };
+    let addr = status_addr
+        .ok_or_else(|| anyhow!("STATUS_ADDR must be set to reach the running provider"))?;
+    let url = format!("http://{}/state", local_addr(addr));
+
+    let request = match file {
+        Some(file) if action == "import" => {
+            let json = fs::read_to_string(file).context(format!("Failed to read provider state {}", file))?;
+            // Checked here too, so a broken file is reported before the provider sees it
+            ProviderState::parse(&json).context(format!("Failed to parse provider state {}", file))?;
+            status_request(Method::POST, &url, token).header("Content-Type", "application/json").body(json)
+        },
+        _ => status_request(Method::GET, &url, token),
+    };
+    let resp = request.send().await
+        .context(format!("Failed to reach the provider at {}", url))?;
+    let status = resp.status();
+    let text = resp.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(anyhow!("Provider refused the request ({}): {}", status, text.trim()));
+    }
+
+    match (action.as_str(), file) {
+        ("export", Some(file)) => {
+            let state = ProviderState::parse(&text)?;
+            let mut tmp = file.clone();
+            tmp.push_str(".tmp");
+            fs::write(&tmp, &text)
+                .and_then(|()| fs::rename(&tmp, file))
+                .context(format!("Failed to write provider state {}", file))?;
+            println!("Exported {} services to {}", state.services.len(), file);
+        },
+        _ => print!("{}", text),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::models::PingapServiceConfigBuilder;
+    use crate::status::{self, Status};
+
+    #[test]
+    fn test_parse_refuses_newer_version() {
+        let state = ProviderState { version: STATE_VERSION, ..Default::default() };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(ProviderState::parse(&json).unwrap(), state);
+
+        let newer = json.replace(&format!("\"version\":{}", STATE_VERSION), "\"version\":99");
+        assert!(ProviderState::parse(&newer).is_err());
+        assert!(ProviderState::parse("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let status = Arc::new(Status::new("sync"));
+        tokio::spawn(status::serve(listener, status.clone()));
+
+        let config = PingapServiceConfigBuilder::new("web", vec![], "Host(`web.local`)").build();
+        let state = ProviderState {
+            version: STATE_VERSION,
+            exported_at: 1_700_000_000,
+            services: BTreeMap::from([("web".to_string(), ServiceState {
+                config,
+                containers: BTreeMap::new(),
+                weights: BTreeMap::new(),
+                applied: true,
+                tombstone_secs: Some(120),
+                label_diagnostics: Vec::new(),
+            })]),
+            orphans: vec!["plugins/web-cors".to_string()],
+            ..Default::default()
+        };
+        status.set_provider_state(state.clone());
+
+        let dir = std::env::temp_dir().join(format!("pingap-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("state.json").to_string_lossy().to_string();
+        let args = |action: &str| vec![action.to_string(), file.clone()];
+        run(Some(&addr), None, &args("export")).await.unwrap();
+        assert_eq!(ProviderState::parse(&fs::read_to_string(&file).unwrap()).unwrap(), state);
+
+        run(Some(&addr), None, &args("import")).await.unwrap();
+        assert_eq!(status.take_state_import(), Some(state));
+
+        assert!(run(Some(&addr), None, &["import".to_string()]).await.is_err());
+        assert!(run(None, None, &args("export")).await.is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
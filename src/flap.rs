@@ -0,0 +1,190 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Detects crash-looping containers. A container that changes state
+/// `threshold` times within `window` is put on hold for `hold_down`; while
+/// held, its events are not forwarded to Pingap. Every further transition
+/// extends the hold, and once it expires the container is reconciled once
+/// with whatever state it ended up in.
+pub struct FlapDetector {
+    threshold: usize,
+    window: Duration,
+    hold_down: Duration,
+    containers: HashMap<String, FlapState>,
+}
+
+#[derive(Default)]
+struct FlapState {
+    transitions: VecDeque<Instant>,
+    held_until: Option<Instant>,
+}
+
+impl FlapDetector {
+    /// A `threshold` of 0 disables flap detection.
+    pub fn new(threshold: usize, window: Duration, hold_down: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            hold_down,
+            containers: HashMap::new(),
+        }
+    }
+
+    /// Records a start/stop transition and returns true if the event should be
+    /// suppressed because the container is flapping.
+    pub fn record(&mut self, container_id: &str, now: Instant) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+
+        let state = self.containers.entry(container_id.to_string()).or_default();
+        state.transitions.push_back(now);
+        while let Some(&oldest) = state.transitions.front() {
+            if now.duration_since(oldest) > self.window {
+                state.transitions.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if state.held_until.is_some() || state.transitions.len() >= self.threshold {
+            state.held_until = Some(now + self.hold_down);
+            return true;
+        }
+        false
+    }
+
+    /// Returns containers whose hold-down ended and releases them, so the
+    /// caller can reconcile their final state.
+    pub fn take_expired(&mut self, now: Instant) -> Vec<String> {
+        let mut expired = Vec::new();
+        for (id, state) in self.containers.iter_mut() {
+            if matches!(state.held_until, Some(until) if until <= now) {
+                state.held_until = None;
+                state.transitions.clear();
+                expired.push(id.clone());
+            }
+        }
+
+        // Forget quiet containers so the map doesn't grow with every container ever seen
+        let window = self.window;
+        self.containers.retain(|_, state| {
+            state.held_until.is_some()
+                || state.transitions.back().is_some_and(|&last| now.duration_since(last) <= window)
+        });
+
+        expired
+    }
+
+    /// Drops a removed container, held down or not: there is nothing left
+    /// to reconcile.
+    pub fn forget(&mut self, container_id: &str) {
+        self.containers.remove(container_id);
+    }
+
+    /// Containers currently held down.
+    pub fn flapping(&self) -> Vec<String> {
+        let mut ids = self.containers.iter()
+            .filter(|(_, state)| state.held_until.is_some())
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> FlapDetector {
+        FlapDetector::new(3, Duration::from_secs(60), Duration::from_secs(30))
+    }
+
+    #[test]
+    fn test_below_threshold_not_suppressed() {
+        let mut flap = detector();
+        let now = Instant::now();
+        assert!(!flap.record("c1", now));
+        assert!(!flap.record("c1", now + Duration::from_secs(1)));
+        assert!(flap.flapping().is_empty());
+    }
+
+    #[test]
+    fn test_threshold_triggers_hold_down() {
+        let mut flap = detector();
+        let now = Instant::now();
+        flap.record("c1", now);
+        flap.record("c1", now + Duration::from_secs(1));
+        assert!(flap.record("c1", now + Duration::from_secs(2)));
+        assert_eq!(flap.flapping(), vec!["c1".to_string()]);
+    }
+
+    #[test]
+    fn test_transitions_outside_window_ignored() {
+        let mut flap = detector();
+        let now = Instant::now();
+        flap.record("c1", now);
+        flap.record("c1", now + Duration::from_secs(61));
+        assert!(!flap.record("c1", now + Duration::from_secs(122)));
+    }
+
+    #[test]
+    fn test_hold_down_extends_and_expires() {
+        let mut flap = detector();
+        let now = Instant::now();
+        for i in 0..3 {
+            flap.record("c1", now + Duration::from_secs(i));
+        }
+        // Further transitions while held are suppressed and extend the hold
+        assert!(flap.record("c1", now + Duration::from_secs(20)));
+        assert!(flap.take_expired(now + Duration::from_secs(35)).is_empty());
+
+        let expired = flap.take_expired(now + Duration::from_secs(51));
+        assert_eq!(expired, vec!["c1".to_string()]);
+        assert!(flap.flapping().is_empty());
+
+        // Released containers start from a clean slate
+        assert!(!flap.record("c1", now + Duration::from_secs(52)));
+    }
+
+    #[test]
+    fn test_containers_tracked_independently() {
+        let mut flap = detector();
+        let now = Instant::now();
+        for i in 0..3 {
+            flap.record("c1", now + Duration::from_secs(i));
+        }
+        assert!(!flap.record("c2", now));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut flap = FlapDetector::new(0, Duration::from_secs(60), Duration::from_secs(30));
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(!flap.record("c1", now));
+        }
+    }
+
+    #[test]
+    fn test_removed_container_forgotten() {
+        let mut flap = detector();
+        let now = Instant::now();
+        for i in 0..3 {
+            flap.record("c1", now + Duration::from_secs(i));
+        }
+        flap.forget("c1");
+        assert!(flap.flapping().is_empty());
+        assert!(flap.take_expired(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn test_quiet_containers_forgotten() {
+        let mut flap = detector();
+        let now = Instant::now();
+        flap.record("c1", now);
+        flap.take_expired(now + Duration::from_secs(120));
+        assert!(flap.containers.is_empty());
+    }
+}
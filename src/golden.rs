@@ -0,0 +1,89 @@
+//! Golden tests of the label-to-config translation. Every directory in
+//! `tests/golden` holds the labels of one container (`labels.toml` or
+//! `labels.json`, a flat table of label -> value) and `expected.json`, the
+//! Pingap resources and label diagnostics they must turn into. Run with
+//! `BLESS=1` to write the current output as the expected one after an
+//! intended change, then review the diff.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use crate::models::ContainerInfo;
+use crate::pingap;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+const MAINTENANCE_PLUGIN: &str = "maintenance";
+
+fn read_labels(case: &Path) -> Result<HashMap<String, String>> {
+    let toml_path = case.join("labels.toml");
+    let json_path = case.join("labels.json");
+    let labels: BTreeMap<String, String> = if toml_path.exists() {
+        let content = fs::read_to_string(&toml_path)?;
+        toml::from_str(&content).context(format!("Failed to decode {}", toml_path.display()))?
+    } else if json_path.exists() {
+        let content = fs::read_to_string(&json_path)?;
+        serde_json::from_str(&content).context(format!("Failed to decode {}", json_path.display()))?
+    } else {
+        bail!("{} has neither labels.toml nor labels.json", case.display());
+    };
+    Ok(labels.into_iter().collect())
+}
+
+/// The resources and diagnostics of a container named after the case,
+/// attached to one network.
+fn render(case: &Path) -> Result<Value> {
+    let name = case.file_name().and_then(|name| name.to_str()).ok_or_else(|| anyhow!("Invalid case {}", case.display()))?;
+    let container = ContainerInfo {
+        id: format!("{}-id", name),
+        name: format!("/{}", name),
+        image: format!("golden/{}:1.0", name),
+        labels: read_labels(case)?,
+        ip_address: Some("172.18.0.2".to_string()),
+        ports: vec![8080],
+        networks: HashMap::from([("proxy".to_string(), "172.18.0.2".to_string())]),
+        ipv6_networks: HashMap::new(),
+    };
+    let parsed = container.parse_pingap_configs_with_diagnostics()?;
+    let mut diagnostics = parsed.iter()
+        .flat_map(|(_, diagnostics)| diagnostics.iter().map(ToString::to_string))
+        .collect::<Vec<_>>();
+    diagnostics.sort();
+    diagnostics.dedup();
+    let configs = parsed.into_iter().map(|(config, _)| config).collect::<Vec<_>>();
+    let config = pingap::desired_resources(&configs, &json!({}), MAINTENANCE_PLUGIN)?;
+    Ok(json!({ "diagnostics": diagnostics, "config": config }))
+}
+
+fn cases() -> Result<Vec<PathBuf>> {
+    let mut cases = fs::read_dir(FIXTURES).context(format!("Failed to list {}", FIXTURES))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    cases.sort();
+    Ok(cases)
+}
+
+#[test]
+fn test_golden_configs() {
+    let bless = std::env::var_os("BLESS").is_some();
+    let cases = cases().unwrap();
+    assert!(!cases.is_empty(), "No golden cases in {}", FIXTURES);
+
+    let mut failures = Vec::new();
+    for case in &cases {
+        let actual = render(case).unwrap_or_else(|e| panic!("{}: {:?}", case.display(), e));
+        let expected_path = case.join("expected.json");
+        if bless {
+            fs::write(&expected_path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).ok()
+            .and_then(|content| serde_json::from_str::<Value>(&content).ok());
+        if expected.as_ref() != Some(&actual) {
+            failures.push(format!("{}:\n{}", expected_path.display(), serde_json::to_string_pretty(&actual).unwrap()));
+        }
+    }
+    assert!(failures.is_empty(), "Golden files differ, rerun with BLESS=1 if the change is intended\n\n{}", failures.join("\n\n"));
+}
@@ -0,0 +1,218 @@
+//! gRPC control API (`GRPC_ADDR`) of `proto/provider.proto`: the services
+//! the provider tracks, forced resyncs and a stream of their changes. It
+//! takes the same `STATUS_API_TOKEN` (or loopback clients) as the status API.
+
+// Handlers return tonic's large Status as their error, so the helpers do too
+#![allow(clippy::result_large_err)]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::pin::Pin;
+use std::sync::Arc;
+use anyhow::Result;
+use futures::{stream, Stream, StreamExt};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Request, Response};
+use tracing::info;
+use crate::export::{ProviderState, ServiceState};
+use crate::status::Status;
+
+/// Code generated from `proto/provider.proto` by build.rs.
+pub mod proto {
+    tonic::include_proto!("pingap_docker_provider.v1");
+}
+
+use proto::provider_control_server::{ProviderControl, ProviderControlServer};
+use proto::service_change::Kind;
+
+/// Serves the control API on `listener` until the task is dropped.
+pub async fn serve(listener: TcpListener, status: Arc<Status>) -> Result<()> {
+    info!("gRPC control API listening on {}", listener.local_addr()?);
+    tonic::transport::Server::builder()
+        .add_service(ProviderControlServer::new(ControlApi { status }))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await?;
+    Ok(())
+}
+
+pub struct ControlApi {
+    status: Arc<Status>,
+}
+
+impl ControlApi {
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), tonic::Status> {
+        let authorization = request.metadata().get_all("authorization").iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+        match self.status.authorizes(&authorization, request.remote_addr().map(|addr| addr.ip())) {
+            true => Ok(()),
+            false => Err(tonic::Status::unauthenticated("missing or wrong bearer token, or not a loopback client without STATUS_API_TOKEN")),
+        }
+    }
+
+    fn state(&self) -> Result<ProviderState, tonic::Status> {
+        self.status.watch_provider_state().borrow().clone()
+            .ok_or_else(|| tonic::Status::unavailable("initial sync still running"))
+    }
+}
+
+fn service(name: &str, state: &ServiceState) -> proto::Service {
+    proto::Service {
+        name: name.to_string(),
+        container_ids: state.containers.keys().cloned().collect(),
+        upstream_addrs: state.containers.values().flatten().cloned().collect::<BTreeSet<_>>().into_iter().collect(),
+        rule: state.config.location.rule.clone(),
+        label_diagnostics: state.label_diagnostics.iter()
+            .map(|diagnostic| proto::LabelDiagnostic {
+                label: diagnostic.label.clone(),
+                value: diagnostic.value.clone(),
+                problem: diagnostic.problem.clone(),
+            })
+            .collect(),
+    }
+}
+
+/// What changed between two publishes of the applied services: new or
+/// reconfigured services are applied, ones with other replicas scaled.
+fn changes(old: &BTreeMap<String, ServiceState>, new: &BTreeMap<String, ServiceState>) -> Vec<proto::ServiceChange> {
+    let applied = |services: &BTreeMap<String, ServiceState>, name: &str| services.get(name).filter(|state| state.applied).cloned();
+    let mut changes = new.iter()
+        .filter(|(_, state)| state.applied)
+        .filter_map(|(name, state)| {
+            let kind = match applied(old, name) {
+                None => Kind::Applied,
+                Some(before) if before.config != state.config => Kind::Applied,
+                Some(before) if before.containers != state.containers => Kind::Scaled,
+                Some(_) => return None,
+            };
+            Some(proto::ServiceChange { kind: kind as i32, service: Some(service(name, state)) })
+        })
+        .collect::<Vec<_>>();
+    changes.extend(old.iter()
+        .filter(|(name, state)| state.applied && applied(new, name).is_none())
+        .map(|(name, _)| proto::ServiceChange {
+            kind: Kind::Removed as i32,
+            service: Some(proto::Service { name: name.clone(), ..Default::default() }),
+        }));
+    changes
+}
+
+#[tonic::async_trait]
+impl ProviderControl for ControlApi {
+    async fn list_services(&self, request: Request<proto::ListServicesRequest>) -> Result<Response<proto::ListServicesResponse>, tonic::Status> {
+        self.authorize(&request)?;
+        let services = self.state()?.services.iter()
+            .filter(|(_, state)| state.applied)
+            .map(|(name, state)| service(name, state))
+            .collect();
+        Ok(Response::new(proto::ListServicesResponse { services }))
+    }
+
+    async fn get_service(&self, request: Request<proto::GetServiceRequest>) -> Result<Response<proto::Service>, tonic::Status> {
+        self.authorize(&request)?;
+        let name = &request.get_ref().name;
+        match self.state()?.services.get(name) {
+            Some(state) => Ok(Response::new(service(name, state))),
+            None => Err(tonic::Status::not_found(format!("service {} is not managed by the provider", name))),
+        }
+    }
+
+    async fn force_resync(&self, request: Request<proto::ForceResyncRequest>) -> Result<Response<proto::ForceResyncResponse>, tonic::Status> {
+        self.authorize(&request)?;
+        match self.status.request_resync().await {
+            Ok(services) => Ok(Response::new(proto::ForceResyncResponse { services: services as u32 })),
+            Err(e) => Err(tonic::Status::internal(format!("{:#}", e))),
+        }
+    }
+
+    type WatchChangesStream = Pin<Box<dyn Stream<Item = Result<proto::ServiceChange, tonic::Status>> + Send>>;
+
+    async fn watch_changes(&self, request: Request<proto::WatchChangesRequest>) -> Result<Response<Self::WatchChangesStream>, tonic::Status> {
+        self.authorize(&request)?;
+        let mut states = self.status.watch_provider_state();
+        let current = states.borrow_and_update().as_ref().map(|state| state.services.clone()).unwrap_or_default();
+        let updates = stream::unfold((states, current), |(mut states, old)| async move {
+            states.changed().await.ok()?;
+            let new = states.borrow_and_update().as_ref().map(|state| state.services.clone()).unwrap_or_default();
+            let changes = changes(&old, &new);
+            Some((stream::iter(changes.into_iter().map(Ok)), (states, new)))
+        });
+        Ok(Response::new(Box::pin(updates.flatten())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::PingapServiceConfigBuilder;
+
+    fn state(addrs: &[&str]) -> ServiceState {
+        let addrs = addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>();
+        ServiceState {
+            config: PingapServiceConfigBuilder::new("web", addrs.clone(), "Host(`web.local`)").build(),
+            containers: addrs.iter().enumerate().map(|(i, addr)| (format!("c{}", i), vec![addr.clone()])).collect(),
+            weights: BTreeMap::new(),
+            applied: true,
+            tombstone_secs: None,
+            label_diagnostics: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_changes_between_publishes() {
+        let one = BTreeMap::from([("web".to_string(), state(&["10.0.0.1:80"]))]);
+        let kinds = |changes: Vec<proto::ServiceChange>| changes.iter().map(|change| (change.kind(), change.service.clone().unwrap().name)).collect::<Vec<_>>();
+        assert_eq!(kinds(changes(&BTreeMap::new(), &one)), [(Kind::Applied, "web".to_string())]);
+        assert!(changes(&one, &one).is_empty());
+
+        // Same config, another replica
+        let mut scaled = one.clone();
+        scaled.get_mut("web").unwrap().containers.insert("c1".to_string(), vec!["10.0.0.1:80".to_string()]);
+        assert_eq!(kinds(changes(&one, &scaled)), [(Kind::Scaled, "web".to_string())]);
+        let two = BTreeMap::from([("web".to_string(), state(&["10.0.0.1:80", "10.0.0.2:80"]))]);
+        assert_eq!(kinds(changes(&one, &two)), [(Kind::Applied, "web".to_string())]);
+
+        // Not applied counts as gone
+        let mut pending = one.clone();
+        pending.get_mut("web").unwrap().applied = false;
+        assert_eq!(kinds(changes(&one, &pending)), [(Kind::Removed, "web".to_string())]);
+        assert_eq!(kinds(changes(&one, &BTreeMap::new())), [(Kind::Removed, "web".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_control_api() {
+        let status = Arc::new(Status::new("sync").with_api_token(Some("t0ken".to_string())));
+        let api = ControlApi { status: status.clone() };
+        let authorized = |request: proto::GetServiceRequest| {
+            let mut request = Request::new(request);
+            request.metadata_mut().insert("authorization", "Bearer t0ken".parse().unwrap());
+            request
+        };
+        let get = |name: &str| proto::GetServiceRequest { name: name.to_string() };
+
+        let denied = api.get_service(Request::new(get("web"))).await.unwrap_err();
+        assert_eq!(denied.code(), tonic::Code::Unauthenticated);
+        assert_eq!(api.get_service(authorized(get("web"))).await.unwrap_err().code(), tonic::Code::Unavailable);
+
+        status.set_provider_state(ProviderState {
+            services: BTreeMap::from([("web".to_string(), state(&["10.0.0.1:80"]))]),
+            ..Default::default()
+        });
+        let web = api.get_service(authorized(get("web"))).await.unwrap().into_inner();
+        assert_eq!(web.container_ids, ["c0"]);
+        assert_eq!(web.upstream_addrs, ["10.0.0.1:80"]);
+        assert_eq!(web.rule, "Host(`web.local`)");
+        assert_eq!(api.get_service(authorized(get("api"))).await.unwrap_err().code(), tonic::Code::NotFound);
+
+        // A resync is answered by the sync loop
+        let mut resyncs = status.take_resync_requests().unwrap();
+        tokio::spawn(async move {
+            while let Some(reply) = resyncs.recv().await {
+                let _ = reply.send(Ok(3));
+            }
+        });
+        let mut request = Request::new(proto::ForceResyncRequest {});
+        request.metadata_mut().insert("authorization", "Bearer t0ken".parse().unwrap());
+        assert_eq!(api.force_resync(request).await.unwrap().into_inner().services, 3);
+    }
+}
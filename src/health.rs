@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// How long a probe waits for the TCP handshake.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Scores the upstream addresses of all services. An address that fails
+/// `evict_after` TCP probes in a row is left out of its upstream until a
+/// probe succeeds again. Failed Pingap writes are counted per address too,
+/// but only reported: they say more about Pingap than about the address.
+pub struct AddressHealth {
+    evict_after: u32,
+    addrs: HashMap<String, AddressState>,
+}
+
+#[derive(Default)]
+struct AddressState {
+    failed_probes: u32,
+    evicted: bool,
+    apply_errors: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Evicted,
+    Recovered,
+}
+
+impl AddressHealth {
+    pub fn new(evict_after: u32) -> Self {
+        Self { evict_after, addrs: HashMap::new() }
+    }
+
+    /// Records a probe result and returns whether the address changed state.
+    pub fn record_probe(&mut self, addr: &str, reachable: bool) -> Option<Transition> {
+        let state = self.addrs.entry(addr.to_string()).or_default();
+        if reachable {
+            state.failed_probes = 0;
+            if state.evicted {
+                state.evicted = false;
+                return Some(Transition::Recovered);
+            }
+            return None;
+        }
+
+        state.failed_probes += 1;
+        if !state.evicted && state.failed_probes >= self.evict_after.max(1) {
+            state.evicted = true;
+            return Some(Transition::Evicted);
+        }
+        None
+    }
+
+    pub fn record_apply_error(&mut self, addrs: &[String]) {
+        for addr in addrs {
+            self.addrs.entry(addr.clone()).or_default().apply_errors += 1;
+        }
+    }
+
+    pub fn is_evicted(&self, addr: &str) -> bool {
+        self.addrs.get(addr).is_some_and(|state| state.evicted)
+    }
+
+    /// Leaves evicted addresses out of an upstream, unless none would be left.
+    pub fn filter(&self, addrs: Vec<String>) -> Vec<String> {
+        let healthy = addrs.iter()
+            .filter(|addr| !self.is_evicted(addr))
+            .cloned()
+            .collect::<Vec<_>>();
+        if healthy.is_empty() { addrs } else { healthy }
+    }
+
+    /// Forgets addresses no replica uses anymore.
+    pub fn retain(&mut self, live: &HashSet<&str>) {
+        self.addrs.retain(|addr, _| live.contains(addr.as_str()));
+    }
+
+    pub fn evicted(&self) -> Vec<String> {
+        let mut evicted = self.addrs.iter()
+            .filter(|(_, state)| state.evicted)
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+        evicted.sort();
+        evicted
+    }
+
+    pub fn apply_errors(&self) -> BTreeMap<String, u64> {
+        self.addrs.iter()
+            .filter(|(_, state)| state.apply_errors > 0)
+            .map(|(addr, state)| (addr.clone(), state.apply_errors))
+            .collect()
+    }
+}
+
+/// Tries a TCP connection to every address concurrently and reports which answered.
+pub async fn probe_all(addrs: Vec<String>) -> Vec<(String, bool)> {
+    futures::future::join_all(addrs.into_iter().map(|addr| async move {
+        let reachable = matches!(tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr.as_str())).await, Ok(Ok(_)));
+        (addr, reachable)
+    })).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicted_after_consecutive_failures() {
+        let mut health = AddressHealth::new(3);
+        assert_eq!(health.record_probe("10.0.0.1:80", false), None);
+        assert_eq!(health.record_probe("10.0.0.1:80", false), None);
+        assert_eq!(health.record_probe("10.0.0.1:80", false), Some(Transition::Evicted));
+        assert_eq!(health.record_probe("10.0.0.1:80", false), None);
+        assert_eq!(health.evicted(), vec!["10.0.0.1:80".to_string()]);
+    }
+
+    #[test]
+    fn test_success_resets_and_recovers() {
+        let mut health = AddressHealth::new(2);
+        health.record_probe("a:80", false);
+        health.record_probe("a:80", true);
+        assert_eq!(health.record_probe("a:80", false), None);
+
+        health.record_probe("a:80", false);
+        assert!(health.is_evicted("a:80"));
+        assert_eq!(health.record_probe("a:80", true), Some(Transition::Recovered));
+        assert!(!health.is_evicted("a:80"));
+    }
+
+    #[test]
+    fn test_filter_never_empties_upstream() {
+        let mut health = AddressHealth::new(1);
+        health.record_probe("a:80", false);
+        let addrs = vec!["a:80".to_string(), "b:80".to_string()];
+        assert_eq!(health.filter(addrs), vec!["b:80".to_string()]);
+
+        health.record_probe("b:80", false);
+        let addrs = vec!["a:80".to_string(), "b:80".to_string()];
+        assert_eq!(health.filter(addrs.clone()), addrs);
+    }
+
+    #[test]
+    fn test_apply_errors_reported_not_evicted() {
+        let mut health = AddressHealth::new(1);
+        health.record_apply_error(&["a:80".to_string()]);
+        health.record_apply_error(&["a:80".to_string()]);
+        assert!(!health.is_evicted("a:80"));
+        assert_eq!(health.apply_errors()["a:80"], 2);
+
+        health.retain(&HashSet::new());
+        assert!(health.apply_errors().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe_all() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().to_string();
+
+        let results = probe_all(vec![open.clone(), "127.0.0.1:1".to_string()]).await;
+        assert_eq!(results, vec![(open, true), ("127.0.0.1:1".to_string(), false)]);
+    }
+}
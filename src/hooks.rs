@@ -0,0 +1,212 @@
+//! Config hook (`CONFIG_HOOK_SCRIPT`): a Lua script whose `transform`
+//! function sees every parsed service config with the container it comes
+//! from, before anything is applied, and may change or veto it. This is
+//! where organization policies live without forking the provider, e.g.
+//!
+//! ```lua
+//! function transform(config, container)
+//!   if not string.match(config.name, "^team%-") then
+//!     return false, "service names must start with team-"
+//!   end
+//!   config.middleware_config = config.middleware_config or {}
+//!   config.middleware_config.basic_auth = config.middleware_config.basic_auth or "admin:secret"
+//!   return config
+//! end
+//! ```
+//!
+//! `transform` returns the config to apply, nothing to keep it as is, or
+//! `false` (with an optional reason) to refuse the service. A script that
+//! fails or runs longer than [`TIME_BUDGET`] refuses it too, so a broken
+//! policy never lets a service through unchecked.
+
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context, Result};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, LuaSerdeExt, MultiValue, SerializeOptions, StdLib, Value};
+use serde_json::json;
+use crate::models::{ContainerInfo, PingapServiceConfig};
+use crate::rule;
+
+/// How long one `transform` call may run.
+pub const TIME_BUDGET: Duration = Duration::from_millis(200);
+/// Instructions between checks of the time budget.
+const BUDGET_CHECK_INSTRUCTIONS: u32 = 10_000;
+/// Memory a script may allocate, a single huge string is done in one instruction.
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+/// Base library functions that read files or compile code.
+const REMOVED_GLOBALS: [&str; 3] = ["dofile", "loadfile", "load"];
+
+/// What the hook made of a service config.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookOutcome {
+    Apply(Box<PingapServiceConfig>),
+    /// Refused, with the reason the script gave
+    Veto(Option<String>),
+}
+
+/// A loaded `CONFIG_HOOK_SCRIPT`. Scripts get Lua's base library without
+/// `dofile`, `loadfile` and `load`, and the table, string, math and utf8
+/// libraries: no file, OS or module access. They may use up to
+/// [`MEMORY_LIMIT`].
+pub struct ConfigHook {
+    path: String,
+    lua: Mutex<Lua>,
+}
+
+impl ConfigHook {
+    pub fn load(path: &str) -> Result<Self> {
+        let source = fs::read_to_string(path).context(format!("Failed to read config hook {}", path))?;
+        Self::from_source(path, &source)
+    }
+
+    pub fn from_source(path: &str, source: &str) -> Result<Self> {
+        let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8, LuaOptions::default())
+            .map_err(|e| anyhow!("Failed to start Lua: {}", e))?;
+        for name in REMOVED_GLOBALS {
+            lua.globals().set(name, Value::Nil).map_err(|e| anyhow!("Failed to start Lua: {}", e))?;
+        }
+        lua.set_memory_limit(MEMORY_LIMIT).map_err(|e| anyhow!("Failed to start Lua: {}", e))?;
+        lua.load(source).set_name(path).exec()
+            .map_err(|e| anyhow!("Failed to load config hook {}: {}", path, e))?;
+        lua.globals().get::<_, Function>("transform")
+            .map_err(|_| anyhow!("Config hook {} defines no transform(config, container) function", path))?;
+        Ok(Self { path: path.to_string(), lua: Mutex::new(lua) })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Runs `transform` on one service config of `container`. Errors are
+    /// scripts that failed, ran out of time or returned an unusable config.
+    pub fn transform(&self, config: &PingapServiceConfig, container: &ContainerInfo) -> Result<HookOutcome> {
+        let lua = self.lua.lock().unwrap();
+        let started = Instant::now();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(BUDGET_CHECK_INSTRUCTIONS), move |_, _| {
+            if started.elapsed() > TIME_BUDGET {
+                return Err(mlua::Error::RuntimeError(format!("ran longer than {:?}", TIME_BUDGET)));
+            }
+            Ok(())
+        });
+        let result = self.call(&lua, config, container);
+        lua.remove_hook();
+        result.map_err(|e| anyhow!("Config hook {} failed for service {} of container {}: {}", self.path, config.name, container.name, e))
+    }
+
+    fn call(&self, lua: &Lua, config: &PingapServiceConfig, container: &ContainerInfo) -> Result<HookOutcome> {
+        // Unset fields are nil rather than a null sentinel, so scripts can test them with `or`
+        let options = SerializeOptions::new().serialize_none_to_null(false).serialize_unit_to_null(false);
+        let metadata = json!({
+            "id": container.id,
+            "name": container.name.trim_start_matches('/'),
+            "image": container.image,
+            "labels": container.labels,
+            "networks": container.networks,
+        });
+        let transform: Function = lua.globals().get("transform")?;
+        let returned: MultiValue = transform.call((lua.to_value_with(config, options)?, lua.to_value_with(&metadata, options)?))?;
+        let mut returned = returned.into_iter();
+        match returned.next().unwrap_or(Value::Nil) {
+            Value::Nil => Ok(HookOutcome::Apply(Box::new(config.clone()))),
+            Value::Boolean(false) => {
+                let reason = returned.next().and_then(|reason| lua.from_value::<String>(reason).ok());
+                Ok(HookOutcome::Veto(reason))
+            }
+            value @ Value::Table(_) => {
+                let transformed: PingapServiceConfig = lua.from_value(value)
+                    .map_err(|e| anyhow!("returned an invalid config: {}", e))?;
+                rule::parse_rule(&transformed.location.rule)
+                    .map_err(|e| anyhow!("returned an invalid rule: {}", e))?;
+                Ok(HookOutcome::Apply(Box::new(transformed)))
+            }
+            other => Err(anyhow!("returned a {}, expected a config table, nil or false", other.type_name())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::models::PingapServiceConfigBuilder;
+
+    fn container() -> ContainerInfo {
+        ContainerInfo {
+            id: "c1".to_string(),
+            name: "/shop-web-1".to_string(),
+            image: "shop/web:1.4".to_string(),
+            labels: HashMap::from([("team".to_string(), "shop".to_string())]),
+            ip_address: Some("10.0.0.1".to_string()),
+            ports: vec![80],
+            networks: HashMap::new(),
+            ipv6_networks: HashMap::new(),
+        }
+    }
+
+    fn config(name: &str) -> PingapServiceConfig {
+        PingapServiceConfigBuilder::new(name, vec!["10.0.0.1:80".to_string()], "Host(`shop.local`)").build()
+    }
+
+    #[test]
+    fn test_transform_changes_or_vetoes() {
+        let hook = ConfigHook::from_source("policy.lua", r#"
+            function transform(config, container)
+              if container.labels.team == nil then
+                return false, "containers need a team label"
+              end
+              if config.name == "legacy" then
+                return
+              end
+              config.name = container.labels.team .. "-" .. config.name
+              config.middleware_config = config.middleware_config or {}
+              config.middleware_config.basic_auth = "admin:secret"
+              return config
+            end
+        "#).unwrap();
+
+        let HookOutcome::Apply(transformed) = hook.transform(&config("web"), &container()).unwrap() else {
+            panic!("vetoed");
+        };
+        assert_eq!(transformed.name, "shop-web");
+        assert_eq!(transformed.upstreams, ["10.0.0.1:80"]);
+        assert_eq!(transformed.middleware_config.unwrap().basic_auth.as_deref(), Some("admin:secret"));
+        assert_eq!(hook.transform(&config("legacy"), &container()).unwrap(), HookOutcome::Apply(Box::new(config("legacy"))));
+
+        let unlabeled = ContainerInfo { labels: HashMap::new(), ..container() };
+        assert_eq!(hook.transform(&config("web"), &unlabeled).unwrap(),
+            HookOutcome::Veto(Some("containers need a team label".to_string())));
+    }
+
+    #[test]
+    fn test_broken_hooks_fail() {
+        assert!(ConfigHook::from_source("empty.lua", "x = 1").is_err());
+        assert!(ConfigHook::from_source("syntax.lua", "function transform(").is_err());
+        // No escape from the sandbox
+        let hook = ConfigHook::from_source("io.lua", "function transform(config) io.open('/etc/passwd') end").unwrap();
+        assert!(hook.transform(&config("web"), &container()).is_err());
+        for source in [
+            "function transform(config) dofile('/etc/hostname') end",
+            "function transform(config) loadfile('/etc/hostname') end",
+            "function transform(config) load('return 1')() end",
+        ] {
+            let hook = ConfigHook::from_source("load.lua", source).unwrap();
+            assert!(hook.transform(&config("web"), &container()).is_err(), "{}", source);
+        }
+        let hook = ConfigHook::from_source("memory.lua", "function transform(config) local s = string.rep('x', 2^31) end").unwrap();
+        let err = hook.transform(&config("web"), &container()).unwrap_err();
+        assert!(format!("{:#}", err).contains("memory"), "{:#}", err);
+        // The state is still usable after running out of memory
+        let hook = ConfigHook::from_source("memory.lua", "function transform(config) if #config.name > 0 then string.rep('x', 2^31) end end").unwrap();
+        assert!(hook.transform(&config("web"), &container()).is_err());
+        assert!(hook.transform(&config(""), &container()).is_ok());
+
+        let hook = ConfigHook::from_source("loop.lua", "function transform(config) while true do end end").unwrap();
+        let err = hook.transform(&config("web"), &container()).unwrap_err();
+        assert!(format!("{:#}", err).contains("ran longer than"), "{:#}", err);
+        let hook = ConfigHook::from_source("rule.lua", "function transform(config) config.location.rule = 'Method(`GET`)' return config end").unwrap();
+        assert!(hook.transform(&config("web"), &container()).unwrap_err().to_string().contains("invalid rule"));
+        assert!(ConfigHook::from_source("number.lua", "function transform() return 1 end").unwrap()
+            .transform(&config("web"), &container()).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! Event journal (`JOURNAL_PATH`): the Docker events the provider acted on
+//! and the writes they led to, kept in SQLite so `journal query` can tell
+//! when a route last changed and why.
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use tracing::warn;
+use crate::models::parse_duration;
+
+/// Old entries are removed at most this often.
+const PRUNE_INTERVAL_MS: u64 = 60 * 60 * 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS journal (
+        id INTEGER PRIMARY KEY,
+        timestamp_ms INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        container TEXT,
+        service TEXT,
+        action TEXT NOT NULL,
+        outcome TEXT,
+        detail TEXT
+    );
+    CREATE INDEX IF NOT EXISTS journal_timestamp ON journal (timestamp_ms);
+    CREATE INDEX IF NOT EXISTS journal_container ON journal (container);
+    CREATE INDEX IF NOT EXISTS journal_service ON journal (service);
+";
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// One row of the journal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub timestamp_ms: u64,
+    /// `event` for a Docker event, `write` for a Pingap write
+    pub kind: String,
+    pub container: Option<String>,
+    pub service: Option<String>,
+    /// The Docker action (`start`, `die`...) or the write (`apply`, `remove`...)
+    pub action: String,
+    /// `success` or `error`, for writes
+    pub outcome: Option<String>,
+    /// The container name of an event, the error of a failed write
+    pub detail: Option<String>,
+}
+
+impl Entry {
+    /// An event at `timestamp_ms`, or now when Docker didn't tell (zero).
+    pub fn event(timestamp_ms: u64, container: &str, name: &str, action: &str) -> Self {
+        Self {
+            timestamp_ms: if timestamp_ms == 0 { now_ms() } else { timestamp_ms },
+            kind: "event".to_string(),
+            container: Some(container.to_string()),
+            service: None,
+            action: action.to_string(),
+            outcome: None,
+            detail: Some(name.trim_start_matches('/').to_string()),
+        }
+    }
+
+    /// A write for `service`; `container` is empty when no single container caused it.
+    pub fn write(service: &str, container: &str, operation: &str, result: &Result<()>) -> Self {
+        Self {
+            timestamp_ms: now_ms(),
+            kind: "write".to_string(),
+            container: (!container.is_empty()).then(|| container.to_string()),
+            service: Some(service.to_string()),
+            action: operation.to_string(),
+            outcome: Some(if result.is_ok() { "success" } else { "error" }.to_string()),
+            detail: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+/// What `journal query` selects; every filter that is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// A container ID prefix or a container name
+    pub container: Option<String>,
+    /// A service, matching its writes and the events of the containers written for it
+    pub service: Option<String>,
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+    pub outcome: Option<String>,
+    /// The most recent entries kept
+    pub limit: usize,
+}
+
+struct Inner {
+    conn: Connection,
+    pruned_ms: u64,
+}
+
+/// The SQLite journal. Entries older than `retention` are removed; a zero
+/// `retention` keeps them all.
+pub struct Journal {
+    inner: Mutex<Inner>,
+    retention: Duration,
+}
+
+impl Journal {
+    pub fn open(path: &str, retention: Duration) -> Result<Self> {
+        let conn = Connection::open(path).context(format!("Failed to open journal {}", path))?;
+        // Lets `journal query` read while the provider writes
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .and_then(|()| conn.busy_timeout(Duration::from_secs(5)))
+            .and_then(|()| conn.execute_batch(SCHEMA))
+            .context(format!("Failed to set up journal {}", path))?;
+        let journal = Self { inner: Mutex::new(Inner { conn, pruned_ms: 0 }), retention };
+        journal.prune(now_ms())?;
+        Ok(journal)
+    }
+
+    /// Opens an existing journal without writing to it.
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context(format!("Failed to open journal {}", path))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(Self { inner: Mutex::new(Inner { conn, pruned_ms: u64::MAX }), retention: Duration::ZERO })
+    }
+
+    /// Adds an entry. A failure is logged, never passed on: what it
+    /// describes already happened.
+    pub fn record(&self, entry: &Entry) {
+        if let Err(e) = self.insert(entry).and_then(|()| self.prune(now_ms())) {
+            warn!("Failed to write journal entry for {} {}: {:?}", entry.kind, entry.action, e);
+        }
+    }
+
+    fn insert(&self, entry: &Entry) -> Result<()> {
+        self.inner.lock().unwrap().conn.execute(
+            "INSERT INTO journal (timestamp_ms, kind, container, service, action, outcome, detail) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![entry.timestamp_ms as i64, entry.kind, entry.container, entry.service, entry.action, entry.outcome, entry.detail],
+        )?;
+        Ok(())
+    }
+
+    fn prune(&self, now_ms: u64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if self.retention.is_zero() || now_ms < inner.pruned_ms.saturating_add(PRUNE_INTERVAL_MS) {
+            return Ok(());
+        }
+        let cutoff = now_ms.saturating_sub(self.retention.as_millis() as u64);
+        inner.conn.execute("DELETE FROM journal WHERE timestamp_ms < ?1", params![cutoff as i64])?;
+        inner.pruned_ms = now_ms;
+        Ok(())
+    }
+
+    /// The most recent entries `filter` selects, oldest first, each with its
+    /// time as UTC `YYYY-MM-DDTHH:MM:SS.SSSZ`.
+    pub fn query(&self, filter: &Filter) -> Result<Vec<(String, Entry)>> {
+        let inner = self.inner.lock().unwrap();
+        let mut statement = inner.conn.prepare("
+            SELECT strftime('%Y-%m-%dT%H:%M:%fZ', timestamp_ms / 1000.0, 'unixepoch'),
+                   timestamp_ms, kind, container, service, action, outcome, detail
+            FROM journal
+            WHERE (?1 IS NULL OR substr(container, 1, length(?1)) = ?1
+                   OR container IN (SELECT container FROM journal WHERE kind = 'event' AND detail = ?1))
+              AND (?2 IS NULL OR service = ?2
+                   OR (kind = 'event' AND container IN (SELECT container FROM journal WHERE service = ?2)))
+              AND (?3 IS NULL OR timestamp_ms >= ?3)
+              AND (?4 IS NULL OR timestamp_ms <= ?4)
+              AND (?5 IS NULL OR outcome = ?5)
+            ORDER BY timestamp_ms DESC, id DESC
+            LIMIT ?6")?;
+        let rows = statement.query_map(params![
+            filter.container, filter.service,
+            filter.since_ms.map(|ms| ms as i64), filter.until_ms.map(|ms| ms as i64),
+            filter.outcome, filter.limit as i64,
+        ], |row| Ok((row.get(0)?, Entry {
+            timestamp_ms: row.get::<_, i64>(1)? as u64,
+            kind: row.get(2)?,
+            container: row.get(3)?,
+            service: row.get(4)?,
+            action: row.get(5)?,
+            outcome: row.get(6)?,
+            detail: row.get(7)?,
+        })))?;
+        let mut entries = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Unix milliseconds of a `--since`/`--until` value: a duration ago
+    /// (`2h`), Unix seconds, or a UTC date and time SQLite understands
+    /// (`2026-10-16 08:00`).
+    fn time_ms(&self, value: &str, now_ms: u64) -> Result<u64> {
+        if let Ok(ago) = parse_duration(value) {
+            return Ok(now_ms.saturating_sub(ago.as_millis() as u64));
+        }
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Ok(seconds * 1000);
+        }
+        let inner = self.inner.lock().unwrap();
+        let seconds: Option<i64> = inner.conn
+            .query_row("SELECT CAST(strftime('%s', ?1) AS INTEGER)", params![value], |row| row.get(0))
+            .optional()?
+            .flatten();
+        seconds.map(|seconds| seconds.max(0) as u64 * 1000)
+            .ok_or_else(|| anyhow!("Invalid time '{}', expected a duration like 2h, Unix seconds or a date like 2026-10-16T08:00:00", value))
+    }
+}
+
+/// `pingap-docker-provider journal query [--container <id or name>]
+/// [--service <service>] [--since <time>] [--until <time>]
+/// [--outcome success|error] [--limit <n>]`: prints the journal of
+/// `JOURNAL_PATH`, oldest first.
+pub fn run(path: Option<&str>, args: &[String]) -> Result<()> {
+    let usage = || anyhow!("Usage: pingap-docker-provider journal query [--container <id or name>] [--service <service>] [--since <time>] [--until <time>] [--outcome success|error] [--limit <n>]");
+    let path = path.ok_or_else(|| anyhow!("JOURNAL_PATH must be set to read the journal"))?;
+    let Some((command, options)) = args.split_first() else {
+        return Err(usage());
+    };
+    if command != "query" {
+        return Err(usage());
+    }
+    let journal = Journal::open_read_only(path)?;
+    let now = now_ms();
+    let mut filter = Filter { limit: 100, ..Default::default() };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(usage)?;
+        match option.as_str() {
+            "--container" => filter.container = Some(value.clone()),
+            "--service" => filter.service = Some(value.clone()),
+            "--since" => filter.since_ms = Some(journal.time_ms(value, now)?),
+            "--until" => filter.until_ms = Some(journal.time_ms(value, now)?),
+            "--outcome" if value == "success" || value == "error" => filter.outcome = Some(value.clone()),
+            "--limit" => filter.limit = value.parse().map_err(|_| anyhow!("Invalid --limit '{}'", value))?,
+            _ => return Err(usage()),
+        }
+    }
+
+    for (time, entry) in journal.query(&filter)? {
+        let container = entry.container.as_deref().map(|id| id.chars().take(12).collect::<String>());
+        let line = match entry.kind.as_str() {
+            "event" => format!("{}  event  {} {} ({})", time, entry.action,
+                entry.detail.as_deref().unwrap_or_default(), container.unwrap_or_default()),
+            _ => format!("{}  write  {} {} {}{}{}", time, entry.action,
+                entry.service.as_deref().unwrap_or_default(), entry.outcome.as_deref().unwrap_or_default(),
+                container.map(|id| format!(" (for {})", id)).unwrap_or_default(),
+                entry.detail.map(|error| format!(": {}", error)).unwrap_or_default()),
+        };
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("pingap-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("journal.db").to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let path = temp_path("query");
+        let journal = Journal::open(&path, Duration::ZERO).unwrap();
+        let at = |mut entry: Entry, timestamp_ms: u64| {
+            entry.timestamp_ms = timestamp_ms;
+            journal.record(&entry);
+        };
+        at(Entry::event(0, "3f2a1b4c5d6e7f80", "/web-1", "start"), 1_700_000_000_000);
+        at(Entry::write("web", "3f2a1b4c5d6e7f80", "apply", &Ok(())), 1_700_000_001_000);
+        at(Entry::event(0, "9a8b7c6d5e4f3a2b", "api-1", "start"), 1_700_000_002_000);
+        at(Entry::write("api", "9a8b7c6d5e4f3a2b", "apply", &Err(anyhow!("503"))), 1_700_000_003_000);
+        at(Entry::event(0, "3f2a1b4c5d6e7f80", "web-1", "die"), 1_700_000_004_000);
+        at(Entry::write("web", "", "remove", &Ok(())), 1_700_000_005_000);
+
+        let reader = Journal::open_read_only(&path).unwrap();
+        let query = |filter: Filter| reader.query(&Filter { limit: 100, ..filter }).unwrap()
+            .into_iter().map(|(_, entry)| format!("{} {}", entry.kind, entry.action)).collect::<Vec<_>>();
+
+        let all = reader.query(&Filter { limit: 100, ..Default::default() }).unwrap();
+        assert_eq!(all.len(), 6);
+        assert_eq!(all[0].0, "2023-11-14T22:13:20.000Z");
+        assert_eq!(all[0].1.detail.as_deref(), Some("web-1"));
+
+        // The service's writes and the events of its containers
+        assert_eq!(query(Filter { service: Some("web".into()), ..Default::default() }),
+            ["event start", "write apply", "event die", "write remove"]);
+        assert_eq!(query(Filter { container: Some("web-1".into()), ..Default::default() }),
+            ["event start", "write apply", "event die"]);
+        assert_eq!(query(Filter { container: Some("9a8b".into()), ..Default::default() }), ["event start", "write apply"]);
+        assert_eq!(query(Filter { outcome: Some("error".into()), ..Default::default() }), ["write apply"]);
+        assert_eq!(query(Filter { since_ms: Some(1_700_000_004_000), until_ms: Some(1_700_000_004_000), ..Default::default() }), ["event die"]);
+        // The most recent ones, oldest first
+        assert_eq!(reader.query(&Filter { limit: 2, ..Default::default() }).unwrap().iter()
+            .map(|(_, entry)| entry.action.as_str()).collect::<Vec<_>>(), ["die", "remove"]);
+
+        assert_eq!(reader.time_ms("1700000000", 0).unwrap(), 1_700_000_000_000);
+        assert_eq!(reader.time_ms("2023-11-14 22:13:20", 0).unwrap(), 1_700_000_000_000);
+        assert_eq!(reader.time_ms("2h", 1_700_000_000_000).unwrap(), 1_699_992_800_000);
+        assert!(reader.time_ms("yesterday", 0).is_err());
+        let _ = fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap());
+    }
+
+    #[test]
+    fn test_old_entries_pruned() {
+        let path = temp_path("prune");
+        let journal = Journal::open(&path, Duration::from_secs(3600)).unwrap();
+        // Fixed times well after the prune run by open()
+        let now = now_ms() + 10 * PRUNE_INTERVAL_MS;
+        let at = |timestamp_ms: u64| Entry { timestamp_ms, ..Entry::write("web", "", "apply", &Ok(())) };
+        journal.insert(&at(now - 2 * 3600 * 1000)).unwrap();
+        journal.insert(&at(now - 60 * 1000)).unwrap();
+        journal.prune(now).unwrap();
+        let kept = journal.query(&Filter { limit: 10, ..Default::default() }).unwrap();
+        assert_eq!(kept.iter().map(|(_, entry)| entry.timestamp_ms).collect::<Vec<_>>(), [now - 60 * 1000]);
+
+        // Not again within the prune interval
+        journal.insert(&at(now - 2 * 3600 * 1000)).unwrap();
+        journal.prune(now + PRUNE_INTERVAL_MS - 1).unwrap();
+        assert_eq!(journal.query(&Filter { limit: 10, ..Default::default() }).unwrap().len(), 2);
+        let _ = fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap());
+    }
+}
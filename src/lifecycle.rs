@@ -0,0 +1,146 @@
+//! Commands run around the writes of a service (`HOOK_PRE_APPLY`,
+//! `HOOK_POST_APPLY`, `HOOK_POST_DELETE`) and when `POLICY_FILE` refuses
+//! one (`HOOK_POLICY_VIOLATION`), for integrations such as DNS updates,
+//! CMDB registration or alerting. Each runs with `sh -c`, gets the service
+//! as JSON on stdin and `PINGAP_HOOK_EVENT`, `PINGAP_SERVICE` and
+//! `PINGAP_CONTAINER_ID` in its environment.
+
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+use crate::changelog::ACTOR;
+
+/// Characters of a failed hook's output kept in its error.
+const MAX_OUTPUT_CHARS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    PreApply,
+    PostApply,
+    PostDelete,
+    PolicyViolation,
+}
+
+impl HookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::PreApply => "pre_apply",
+            HookEvent::PostApply => "post_apply",
+            HookEvent::PostDelete => "post_delete",
+            HookEvent::PolicyViolation => "policy_violation",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LifecycleHooks {
+    pub pre_apply: Option<String>,
+    pub post_apply: Option<String>,
+    pub post_delete: Option<String>,
+    pub policy_violation: Option<String>,
+    /// How long a command may run before it is killed
+    pub timeout: Duration,
+}
+
+impl LifecycleHooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_apply.is_none() && self.post_apply.is_none() && self.post_delete.is_none() && self.policy_violation.is_none()
+    }
+
+    fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::PreApply => self.pre_apply.as_deref(),
+            HookEvent::PostApply => self.post_apply.as_deref(),
+            HookEvent::PostDelete => self.post_delete.as_deref(),
+            HookEvent::PolicyViolation => self.policy_violation.as_deref(),
+        }
+    }
+
+    /// Runs the command of `event` for `service`, if one is set. Errors are
+    /// commands that could not start, exited non-zero or ran out of time.
+    pub async fn run(&self, event: HookEvent, service: &str, input: &Value) -> Result<()> {
+        let Some(command) = self.command(event) else {
+            return Ok(());
+        };
+        let container_id = ACTOR.try_with(|actor| actor.clone()).unwrap_or_default();
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("PINGAP_HOOK_EVENT", event.as_str())
+            .env("PINGAP_SERVICE", service)
+            .env("PINGAP_CONTAINER_ID", container_id)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context(format!("Failed to start {} hook", event.as_str()))?;
+
+        let input = serde_json::to_vec(input)?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let run = async {
+            // A command that doesn't read its input closes the pipe early, that's fine
+            let _ = stdin.write_all(&input).await;
+            drop(stdin);
+            child.wait_with_output().await
+        };
+        let output = tokio::time::timeout(self.timeout, run).await
+            .map_err(|_| anyhow!("{} hook for service {} ran longer than {:?} and was killed", event.as_str(), service, self.timeout))?
+            .context(format!("Failed to run {} hook", event.as_str()))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !stdout.trim().is_empty() {
+            debug!("{} hook for service {}: {}", event.as_str(), service, stdout.trim());
+        }
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let detail = if stderr.trim().is_empty() { stdout } else { stderr };
+        Err(anyhow!("{} hook for service {} failed ({}): {}", event.as_str(), service, output.status,
+            detail.trim().chars().take(MAX_OUTPUT_CHARS).collect::<String>()))
+    }
+
+    /// Runs a hook whose failure can't undo the write it follows: it is logged.
+    pub async fn notify(&self, event: HookEvent, service: &str, input: &Value) {
+        if let Err(e) = self.run(event, service, input).await {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hooks(pre_apply: &str) -> LifecycleHooks {
+        LifecycleHooks { pre_apply: Some(pre_apply.to_string()), timeout: Duration::from_secs(5), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_hook_gets_service_and_config() {
+        let out = std::env::temp_dir().join(format!("pingap-lifecycle-hook-{}", std::process::id()));
+        let hooks = hooks(&format!("echo \"$PINGAP_HOOK_EVENT $PINGAP_SERVICE $PINGAP_CONTAINER_ID $(cat)\" > {}", out.display()));
+        ACTOR.scope("c1".to_string(), hooks.run(HookEvent::PreApply, "web", &json!({ "name": "web" }))).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap().trim(), r#"pre_apply web c1 {"name":"web"}"#);
+        std::fs::remove_file(&out).unwrap();
+
+        // Events without a command pass
+        hooks.run(HookEvent::PostDelete, "web", &json!({})).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failing_hooks() {
+        let err = hooks("echo 'no CMDB entry' >&2; exit 3").run(HookEvent::PreApply, "web", &json!({})).await.unwrap_err();
+        assert!(err.to_string().contains("no CMDB entry"), "{}", err);
+
+        let slow = LifecycleHooks { timeout: Duration::from_millis(100), ..hooks("sleep 5") };
+        let err = slow.run(HookEvent::PreApply, "web", &json!({})).await.unwrap_err();
+        assert!(err.to_string().contains("was killed"), "{}", err);
+    }
+}
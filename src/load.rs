@@ -0,0 +1,105 @@
+use std::collections::{HashMap, HashSet};
+use bollard::container::{MemoryStatsStats, Stats};
+
+/// What a container uses, from one Docker stats sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Load {
+    /// CPU cores busy, e.g. 1.5 for one and a half
+    pub cpu: f64,
+    /// Share of its memory limit in use, without the reclaimable page cache
+    pub memory: f64,
+}
+
+impl Load {
+    /// None if the sample lacks the previous CPU reading (a container that
+    /// just started) or the host's CPU time.
+    pub fn from_stats(stats: &Stats) -> Option<Self> {
+        let (cpu, precpu) = (&stats.cpu_stats, &stats.precpu_stats);
+        let system_delta = cpu.system_cpu_usage?.checked_sub(precpu.system_cpu_usage?).filter(|delta| *delta > 0)?;
+        let cpu_delta = cpu.cpu_usage.total_usage.saturating_sub(precpu.cpu_usage.total_usage);
+        let cores = cpu.online_cpus
+            .or_else(|| cpu.cpu_usage.percpu_usage.as_ref().map(|usage| usage.len() as u64))
+            .unwrap_or(1);
+
+        let memory = &stats.memory_stats;
+        let cache = match memory.stats {
+            Some(MemoryStatsStats::V1(stats)) => stats.total_inactive_file,
+            Some(MemoryStatsStats::V2(stats)) => stats.inactive_file,
+            None => 0,
+        };
+        let memory = match (memory.usage, memory.limit) {
+            (Some(usage), Some(limit)) if limit > 0 => usage.saturating_sub(cache) as f64 / limit as f64,
+            _ => 0.0,
+        };
+        Some(Self { cpu: cpu_delta as f64 / system_delta as f64 * cores as f64, memory })
+    }
+}
+
+/// Per-container upstream weights from their load (`LOAD_WEIGHT_INTERVAL_SECS`):
+/// an idle replica gets `max_weight`, one using `cpu_cores` or more gets 1,
+/// linearly in between. With `memory`, the memory share counts too and the
+/// higher of both loads decides.
+pub struct LoadWeights {
+    max_weight: u32,
+    cpu_cores: f64,
+    memory: bool,
+    /// ContainerID -> current weight
+    weights: HashMap<String, u32>,
+}
+
+impl LoadWeights {
+    pub fn new(max_weight: u32, cpu_cores: f64, memory: bool) -> Self {
+        Self { max_weight: max_weight.max(1), cpu_cores, memory, weights: HashMap::new() }
+    }
+
+    fn weight_for(&self, load: Load) -> u32 {
+        let cpu = if self.cpu_cores > 0.0 { load.cpu / self.cpu_cores } else { 0.0 };
+        let busy = if self.memory { cpu.max(load.memory) } else { cpu };
+        let weight = (self.max_weight as f64 * (1.0 - busy.clamp(0.0, 1.0))).round() as u32;
+        weight.max(1)
+    }
+
+    /// Records a container's load and returns whether its weight changed.
+    pub fn record(&mut self, container_id: &str, load: Load) -> bool {
+        let weight = self.weight_for(load);
+        let previous = self.weight(container_id);
+        self.weights.insert(container_id.to_string(), weight);
+        weight != previous
+    }
+
+    /// Weight of a container's addresses; idle until its load is known.
+    pub fn weight(&self, container_id: &str) -> u32 {
+        self.weights.get(container_id).copied().unwrap_or(self.max_weight)
+    }
+
+    /// Forgets containers that are no replicas anymore.
+    pub fn retain(&mut self, live: &HashSet<&str>) {
+        self.weights.retain(|id, _| live.contains(id.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inverse_cpu_weights() {
+        let mut weights = LoadWeights::new(10, 2.0, false);
+        assert_eq!(weights.weight("c1"), 10);
+        assert!(weights.record("c1", Load { cpu: 0.5, memory: 0.9 }));
+        assert_eq!(weights.weight("c1"), 8);
+        // The same weight again is no change
+        assert!(!weights.record("c1", Load { cpu: 0.45, memory: 0.1 }));
+        assert!(!weights.record("c3", Load { cpu: 0.0, memory: 0.0 }));
+        // Saturated replicas keep a little traffic
+        assert!(weights.record("c2", Load { cpu: 3.0, memory: 0.0 }));
+        assert_eq!(weights.weight("c2"), 1);
+
+        let mut weights = LoadWeights::new(10, 2.0, true);
+        weights.record("c1", Load { cpu: 0.5, memory: 0.9 });
+        assert_eq!(weights.weight("c1"), 1);
+
+        weights.retain(&HashSet::new());
+        assert_eq!(weights.weight("c1"), 10);
+    }
+}
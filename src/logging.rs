@@ -0,0 +1,158 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use tracing_subscriber::fmt::Formatter;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
+
+/// Installs the global subscriber at `info` and returns the control used to
+/// change its level once the config is loaded and at runtime.
+pub fn init() -> LogControl {
+    let builder = FmtSubscriber::builder()
+        .with_env_filter(EnvFilter::new("info"))
+        .with_filter_reloading();
+    let control = LogControl::new(builder.reload_handle());
+    tracing::subscriber::set_global_default(builder.finish()).expect("setting default subscriber failed");
+    control
+}
+
+/// Switches the log filter at runtime, so SIGUSR2 can turn debug logging on
+/// and off without a restart.
+pub struct LogControl {
+    handle: Handle<EnvFilter, Formatter>,
+    level: String,
+    debug: bool,
+}
+
+impl LogControl {
+    fn new(handle: Handle<EnvFilter, Formatter>) -> Self {
+        Self { handle, level: "info".to_string(), debug: false }
+    }
+
+    fn reload(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives)
+            .context(format!("Invalid log level '{}'", directives))?;
+        self.handle.reload(filter).context("Failed to reload log filter")
+    }
+
+    /// Sets the configured level, any `EnvFilter` directive such as `warn`
+    /// or `pingap_docker_provider=debug`.
+    pub fn set_level(&mut self, level: &str) -> Result<()> {
+        self.reload(level)?;
+        self.level = level.to_string();
+        self.debug = false;
+        Ok(())
+    }
+
+    /// Switches between `debug` and the configured level, returning whether
+    /// debug logging is now on.
+    pub fn toggle_debug(&mut self) -> Result<bool> {
+        let debug = !self.debug;
+        self.reload(if debug { "debug" } else { &self.level })?;
+        self.debug = debug;
+        Ok(debug)
+    }
+}
+
+/// Groups identical errors, so an unreachable Pingap logs one line per
+/// `window` rather than one per service and retry (`ERROR_LOG_WINDOW_SECS`).
+/// The first error of a group is logged in full, the ones repeating it
+/// within the window are only counted and summed up once it ends.
+pub struct ErrorGroups {
+    window: Duration,
+    /// Root cause -> the errors it caused since it was last logged
+    groups: HashMap<String, ErrorGroup>,
+}
+
+struct ErrorGroup {
+    since: Instant,
+    suppressed: usize,
+    services: BTreeSet<String>,
+}
+
+impl ErrorGroups {
+    /// A `window` of 0 logs every error.
+    pub fn new(window: Duration) -> Self {
+        Self { window, groups: HashMap::new() }
+    }
+
+    /// Records an error of `service` with the root cause `cause`, returning
+    /// whether to log it in full.
+    pub fn record(&mut self, cause: &str, service: &str, now: Instant) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+        match self.groups.get_mut(cause) {
+            Some(group) if now.duration_since(group.since) < self.window => {
+                group.suppressed += 1;
+                group.services.insert(service.to_string());
+                false
+            },
+            _ => {
+                let services = BTreeSet::from([service.to_string()]);
+                self.groups.insert(cause.to_string(), ErrorGroup { since: now, suppressed: 0, services });
+                true
+            },
+        }
+    }
+
+    /// Ends the groups whose window is over, returning a line for each one
+    /// that held errors back.
+    pub fn take_summaries(&mut self, now: Instant) -> Vec<String> {
+        let window = self.window;
+        let mut summaries = Vec::new();
+        self.groups.retain(|cause, group| {
+            if now.duration_since(group.since) < window {
+                return true;
+            }
+            if group.suppressed > 0 {
+                summaries.push(format!("Pingap writes keep failing with '{}': {} services pending, suppressed {} identical errors in the last {}s (see the status API for details)",
+                    cause, group.services.len(), group.suppressed, window.as_secs()));
+            }
+            false
+        });
+        summaries.sort();
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggle_debug_and_back() {
+        let builder = FmtSubscriber::builder()
+            .with_env_filter(EnvFilter::new("info"))
+            .with_filter_reloading();
+        let mut control = LogControl::new(builder.reload_handle());
+        // The filter only exists as long as the subscriber does
+        let _subscriber = builder.finish();
+
+        control.set_level("warn").unwrap();
+        assert!(control.toggle_debug().unwrap());
+        assert!(!control.toggle_debug().unwrap());
+        assert_eq!(control.level, "warn");
+        assert!(control.set_level("pingap=loud").is_err());
+    }
+
+    #[test]
+    fn test_identical_errors_grouped() {
+        let start = Instant::now();
+        let mut groups = ErrorGroups::new(Duration::from_secs(60));
+        assert!(groups.record("connection refused", "web", start));
+        assert!(!groups.record("connection refused", "api", start + Duration::from_secs(1)));
+        assert!(!groups.record("connection refused", "web", start + Duration::from_secs(2)));
+        // Another cause is news
+        assert!(groups.record("invalid upstream", "shop", start + Duration::from_secs(3)));
+        assert!(groups.take_summaries(start + Duration::from_secs(30)).is_empty());
+
+        let summaries = groups.take_summaries(start + Duration::from_secs(61));
+        assert_eq!(summaries, vec!["Pingap writes keep failing with 'connection refused': 2 services pending, suppressed 2 identical errors in the last 60s (see the status API for details)"]);
+        assert!(groups.record("connection refused", "web", start + Duration::from_secs(62)));
+
+        let mut every = ErrorGroups::new(Duration::ZERO);
+        assert!(every.record("connection refused", "web", start));
+        assert!(every.record("connection refused", "web", start));
+    }
+}
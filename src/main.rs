@@ -1,156 +1,331 @@
+mod audit;
+mod backup;
+mod blob;
+mod changelog;
 mod config;
+mod config_file;
+mod cursor;
+mod cutover;
+mod metrics;
 mod models;
+mod dns;
 mod docker;
+mod export;
+mod flap;
+#[cfg(test)]
+mod golden;
+mod grpc;
+mod health;
+mod hooks;
+mod journal;
+mod lifecycle;
+mod load;
+mod logging;
+mod maintenance;
 mod pingap;
+mod plugins;
+mod policy;
+mod profile;
+mod provider;
+mod prune;
+mod redact;
+mod rule;
+mod schema;
+mod secrets;
+mod signing;
+mod simulate;
+mod state;
+mod status;
+mod template;
+mod tui;
+mod verify;
 
-use crate::config::Config;
+use crate::backup::Backups;
+use crate::changelog::ChangeLog;
+use crate::config::{Config, Mode, Reload, SelfExpose};
+use crate::config_file::{ConfigFile, ReloadAction, Reloader};
+use crate::cursor::EventCursor;
+use crate::dns::DnsRecords;
 use crate::docker::DockerClient;
+use crate::hooks::ConfigHook;
+use crate::journal::Journal;
 use crate::pingap::PingapClient;
-use anyhow::Result;
-use futures::StreamExt;
-use tracing::{info, error, warn, Level};
-use tracing_subscriber::FmtSubscriber;
-use tokio::signal;
+use crate::policy::Policy;
+use crate::profile::Profiles;
+use crate::secrets::Secrets;
+use crate::signing::RequestSigner;
+use crate::provider::Provider;
+use crate::status::Status;
+use anyhow::{Result, Context};
+use std::sync::Arc;
+use tracing::{info, info_span, warn, Instrument};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // 1. Setup Logging
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO) // Default, will be overridden by env var if we parse it
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    // 1. Setup Logging (info until the config is loaded)
+    let mut log = logging::init();
+
+    // Soak test against a mock Admin API, needs neither Docker nor Pingap
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("--simulate") {
+        let level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "warn".to_string());
+        if let Err(e) = log.set_level(&level.to_lowercase()) {
+            warn!("Keeping log level info: {:?}", e);
+        }
+        return simulate::run(&args[1..]).await;
+    }
+    // JSON Schema of the supported labels, for IDEs and CI linters
+    if args.first().map(String::as_str) == Some("labels-schema") {
+        return schema::run(&args[1..]);
+    }
 
     // 2. Load Config
-    let config = Config::from_env()?;
-    
-    // Adjust log level based on config
-    // Note: tracing_subscriber env filter is better for this, but for simplicity:
-    if config.log_level.to_lowercase() == "debug" {
-        // Re-init or just rely on RUST_LOG env var which tracing-subscriber uses by default if configured with env_filter
-        // For now, let's just log startup
+    let mut config = Config::from_env()?;
+    if let Err(e) = log.set_level(&config.log_level.to_lowercase()) {
+        warn!("Keeping log level info: {:?}", e);
+    }
+
+    // `pingap-docker-provider maintenance enable|disable <service>` talks to a running provider
+    if args.first().map(String::as_str) == Some("maintenance") {
+        return maintenance::run(config.status_addr.as_deref(), config.status_api_token.as_deref(), &args[1..]).await;
+    }
+    // `pingap-docker-provider tui` shows a running provider's status until `q`
+    if args.first().map(String::as_str) == Some("tui") {
+        return tui::run(config.status_addr.as_deref(), &args[1..]).await;
+    }
+    // `pingap-docker-provider state export [<file>]|import <file>` moves a running provider's tracked state
+    if args.first().map(String::as_str) == Some("state") {
+        return export::run(config.status_addr.as_deref(), config.status_api_token.as_deref(), &args[1..]).await;
+    }
+    // `pingap-docker-provider journal query [filters]` reads the journal of JOURNAL_PATH
+    if args.first().map(String::as_str) == Some("journal") {
+        return journal::run(config.journal_path.as_deref(), &args[1..]);
+    }
+    // `pingap-docker-provider verify <service>` requests a running provider's service through Pingap
+    if args.first().map(String::as_str) == Some("verify") {
+        return verify::run(config.status_addr.as_deref(), config.status_api_token.as_deref(), config.pingap_proxy_url.as_deref(), &args[1..]).await;
     }
 
     info!("Starting pingap-docker-provider");
-    info!("Pingap Admin URL: {}", config.pingap_admin_url);
+    if config.auto_discover && !config.pingap_container.is_set() {
+        warn!("AUTO_DISCOVER is on without PINGAP_CONTAINER_NAME or PINGAP_CONTAINER_LABEL, so an unlabeled Pingap container would be routed through itself");
+    }
+    if config.auto_discover && !config.docker_label_filters.is_empty() {
+        warn!("DOCKER_LABEL_FILTERS only lets labeled containers through, AUTO_DISCOVER won't see unlabeled ones");
+    }
 
     // 3. Initialize Clients
-    let docker = DockerClient::new(config.docker_host.clone())?;
-    let pingap = PingapClient::new(config.pingap_admin_url.clone());
-
-    // State tracking: ContainerID -> ServiceName
-    // This ensures we know which service to remove even if 'die' event lacks attributes or container is gone.
-    let mut container_services: std::collections::HashMap<String, String> = std::collections::HashMap::new();
-
-    // 4. Initial Synchronization
-    info!("Performing initial synchronization...");
-    let containers = docker.get_running_containers().await?;
-    for container in containers {
-        match container.parse_pingap_config() {
-            Ok(Some(service_config)) => {
-                info!("Found enabled container: {} -> Service: {}", container.name, service_config.name);
-                if let Err(e) = pingap.apply_config(&service_config).await {
-                    error!("Failed to apply config for {}: {:?}", container.name, e);
-                } else {
-                    container_services.insert(container.id.clone(), service_config.name.clone());
-                }
-            },
-            Ok(None) => {
-                // Not enabled, ignore
-            },
-            Err(e) => {
-                warn!("Failed to parse labels for container {}: {:?}", container.name, e);
+    let secrets = Arc::new(Secrets::new(config.secrets.clone()));
+    config.resolve_secrets(&secrets).await?;
+    if !config.secrets.renew_interval.is_zero() {
+        let secrets = secrets.clone();
+        let mut ticker = tokio::time::interval(config.secrets.renew_interval);
+        tokio::spawn(async move {
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                secrets.renew().await;
             }
-        }
+        });
     }
-    info!("Initial synchronization complete. Tracking {} services.", container_services.len());
-
-    // 5. Event Loop
-    let mut events = docker.subscribe_to_events().await;
-    
-    info!("Listening for Docker events...");
-    
-    loop {
-        tokio::select! {
-            event = events.next() => {
-                match event {
-                    Some(Ok(msg)) => {
-                        let action = msg.action.unwrap_or_default();
-                        let actor = msg.actor.unwrap_or_default();
-                        let attributes = actor.attributes.unwrap_or_default();
-                        let container_id = actor.id.unwrap_or_default();
-                        
-                        match action.as_str() {
-                            "start" => {
-                                info!("Container started: {}", container_id);
-                                // Inspect to get fresh details
-                                match docker.inspect_container(&container_id).await {
-                                    Ok(container) => {
-                                        match container.parse_pingap_config() {
-                                            Ok(Some(service_config)) => {
-                                                info!("Applying config for new container: {}", container.name);
-                                                if let Err(e) = pingap.apply_config(&service_config).await {
-                                                    error!("Failed to apply config for {}: {:?}", container.name, e);
-                                                } else {
-                                                    container_services.insert(container.id.clone(), service_config.name.clone());
-                                                }
-                                            },
-                                            Ok(None) => {}, // Ignore
-                                            Err(e) => warn!("Invalid labels on {}: {:?}", container.name, e),
-                                        }
-                                    },
-                                    Err(e) => error!("Failed to inspect started container {}: {:?}", container_id, e),
-                                }
-                            },
-                            "die" | "stop" => {
-                                info!("Container stopped/died: {}", container_id);
-                                
-                                // Try to get service name from state first
-                                let service_name_opt = container_services.remove(&container_id);
-                                
-                                let service_name = if let Some(name) = service_name_opt {
-                                    info!("Found service {} in state for container {}", name, container_id);
-                                    Some(name)
-                                } else {
-                                    // Fallback to attributes if not in state (e.g. started before we started listening and failed sync?)
-                                    let name = attributes.get("name").cloned().unwrap_or_default();
-                                    let s_name = attributes.get("pingap.service.name")
-                                        .cloned()
-                                        .unwrap_or_else(|| name.trim_start_matches('/').to_string());
-                                        
-                                    let enabled = attributes.get("pingap.enable").map(|v| v.as_str()) == Some("true");
-                                    if enabled {
-                                        Some(s_name)
-                                    } else {
-                                        None
-                                    }
-                                };
-                                
-                                if let Some(service_name) = service_name {
-                                    info!("Removing config for service: {}", service_name);
-                                    if let Err(e) = pingap.delete_config(&service_name).await {
-                                        error!("Failed to delete config for {}: {:?}", service_name, e);
-                                    }
-                                }
-                            },
-                            _ => {}
-                        }
-                    },
-                    Some(Err(e)) => {
-                        error!("Docker event stream error: {:?}", e);
-                    },
-                    None => {
-                        warn!("Docker event stream ended.");
-                        break;
-                    }
-                }
-            },
-            _ = signal::ctrl_c() => {
-                info!("Received shutdown signal");
-                break;
-            }
+    let profiles = config.profiles_file.as_deref().map(Profiles::load).transpose()?.unwrap_or_default();
+    if let Some(path) = &config.profiles_file {
+        info!("Loaded label profiles {} from {}", profiles.names().join(", "), path);
+    }
+    let docker = docker_client(&config, profiles.clone(), secrets.clone()).await?
+        .with_tenant_label_prefixes(config.tenant_label_prefixes());
+    docker.check_permissions().await?;
+    let mut pingap = pingap_client(&config)?;
+    info!("Pingap Admin URL: {}", pingap.base_url());
+    if let Some(path) = &config.change_log_path {
+        let change_log = ChangeLog::open(path, config.change_log_max_bytes, config.change_log_keep)?;
+        pingap = pingap.with_change_log(Arc::new(change_log));
+    }
+    if let Some(dir) = &config.backup_dir {
+        pingap = pingap.with_backups(Arc::new(Backups::open(dir, config.backup_keep)?));
+    }
+
+    let hook = config.config_hook_script.as_deref().map(ConfigHook::load).transpose()?.map(Arc::new);
+    if let Some(hook) = &hook {
+        info!("Running service configs through config hook {}", hook.path());
+    }
+
+    // One provider per tenant next to the default one, see Config::for_tenant
+    let mut tenants = Vec::new();
+    for tenant in &config.tenants {
+        let tenant_config = config.for_tenant(tenant);
+        let docker = docker_client(&tenant_config, profiles.clone(), secrets.clone()).await?
+            .with_label_prefix(&tenant.label_prefix)
+            .with_tenant_projects(tenant.compose_projects.clone());
+        let pingap = pingap_client(&tenant_config)?;
+        info!("Routing the {}.pingap.* labels of tenant {} to {}", tenant.label_prefix, tenant.name, pingap.base_url());
+        tenants.push((tenant.name.clone(), tenant_config, docker, pingap));
+    }
+
+    // One-off cleanup: `pingap-docker-provider prune [--dry-run]`
+    if args.first().map(String::as_str) == Some("prune") {
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        return prune::run(&docker, &pingap, hook.as_deref(), dry_run).await;
+    }
+    // What a sync would change, without writing: `pingap-docker-provider plan`
+    if args.first().map(String::as_str) == Some("plan") {
+        return state::run(&docker, &pingap, hook.as_deref(), config.adopt_existing).await;
+    }
+    // Undo a bad change: `pingap-docker-provider restore <backup file>|latest [--dry-run] [--prune]`
+    if args.first().map(String::as_str) == Some("restore") {
+        return backup::run(&pingap, config.backup_dir.as_deref(), &args[1..]).await;
+    }
+    // Blue/green switch: `pingap-docker-provider cutover <service> --to green`
+    if args.first().map(String::as_str) == Some("cutover") {
+        return cutover::run(&pingap, &args[1..]).await;
+    }
+
+    let status = serve_status(&config).await?;
+    let mut tenant_statuses = Vec::new();
+    for (_, tenant_config, _, _) in &tenants {
+        tenant_statuses.push(serve_status(tenant_config).await?);
+    }
+
+    if config.mode == Mode::Audit {
+        let tenant_audits = tenants.iter().zip(tenant_statuses).map(|((name, tenant_config, docker, pingap), status)| {
+            audit::run(docker, pingap, hook.as_deref(), status, tenant_config.audit_interval)
+                .instrument(info_span!("tenant", name = %name))
+        });
+        let (result, _) = tokio::try_join!(
+            audit::run(&docker, &pingap, hook.as_deref(), status, config.audit_interval),
+            futures::future::try_join_all(tenant_audits),
+        )?;
+        return Ok(result);
+    }
+
+    if let Some(addr) = &config.grpc_addr {
+        let listener = tokio::net::TcpListener::bind(addr).await
+            .context(format!("Failed to bind gRPC control API to {}", addr))?;
+        tokio::spawn(grpc::serve(listener, status.clone()));
+    }
+    let cursor = config.event_cursor_path.as_ref().map(EventCursor::open).transpose()?;
+    let policy = config.policy_file.as_deref().map(Policy::load).transpose()?.map(Arc::new);
+    if let Some(policy) = &policy {
+        info!("Checking routes against {} rules of policy {}", policy.rule_count(), policy.path());
+    }
+    let dns = config.dns.as_ref().map(DnsRecords::new).transpose()?.map(Arc::new);
+    if let Some(dns_config) = &config.dns {
+        info!("Keeping DNS records of the routed hosts in zone {} pointed at {}", dns_config.zone, dns_config.target);
+    }
+    let journal = config.journal_path.as_deref()
+        .map(|path| Journal::open(path, config.journal_retention)).transpose()?.map(Arc::new);
+    let mut tenant_runs = Vec::new();
+    for ((name, tenant_config, docker, pingap), status) in tenants.into_iter().zip(tenant_statuses) {
+        // Each tenant keeps the records of its own hosts, pointed at its own Pingap
+        let tenant_dns = tenant_config.dns.as_ref().map(DnsRecords::new).transpose()?.map(Arc::new);
+        if let Some(dns_config) = &tenant_config.dns {
+            info!("Keeping DNS records of the hosts of tenant {} pointed at {}", name, dns_config.target);
         }
+        let mut provider = Provider::new(tenant_config, docker, pingap, status)
+            .with_config_hook(hook.clone())
+            .with_policy(policy.clone())
+            .with_dns_records(tenant_dns)
+            .with_journal(journal.clone());
+        tenant_runs.push(async move {
+            provider.initial_sync().await?;
+            provider.run().await
+        }.instrument(info_span!("tenant", name = %name)));
+    }
+    let mut provider = Provider::new(config, docker, pingap, status)
+        .with_log_control(log)
+        .with_config_hook(hook)
+        .with_policy(policy)
+        .with_dns_records(dns)
+        .with_journal(journal);
+    if let Some(cursor) = cursor {
+        provider = provider.with_event_cursor(cursor);
     }
 
+    tokio::try_join!(
+        async {
+            // 4. Initial Synchronization
+            provider.initial_sync().await?;
+
+            // 5. Event Loop
+            provider.run().await
+        },
+        futures::future::try_join_all(tenant_runs),
+    )?;
+
     info!("Shutting down.");
     Ok(())
 }
+
+/// The Docker client of a tenant's provider, or of the default one.
+async fn docker_client(config: &Config, profiles: Profiles, secrets: Arc<Secrets>) -> Result<DockerClient> {
+    DockerClient::new(config.docker_host.clone())?
+        .with_minimal_permissions(config.docker_minimal_permissions)
+        .with_event_filters(config.docker_event_actions.clone(), config.docker_label_filters.clone())
+        .with_inspect_cache_ttl(config.inspect_cache_ttl)
+        .with_cache_limits(&config.resources)
+        .with_default_labels(config.default_labels())
+        .with_auto_discover(config.auto_discover.then(|| config.auto_discover_host.clone()))
+        .with_expose_by_default(config.expose_by_default)
+        .with_pingap_container(config.pingap_container.clone())
+        .with_profiles(profiles)
+        .with_secrets(secrets)
+        .with_self_expose(config.self_expose.as_ref().map(SelfExpose::labels))
+        .negotiate_version().await
+}
+
+/// The Admin API client of a tenant's provider, or of the default one.
+fn pingap_client(config: &Config) -> Result<PingapClient> {
+    let mut pingap = PingapClient::new(config.pingap_admin_url.clone())
+        .with_retry_policy(config.retry.clone())
+        .with_mirror_ttl(config.pingap_mirror_ttl)
+        .with_timeouts(config.connect_timeout, config.request_timeout)?
+        .with_connection_pool(config.connection_pool.clone())?
+        .with_maintenance_plugin(config.maintenance_plugin.clone())
+        .with_adopt_existing(config.adopt_existing)
+        .with_max_config_bytes(config.resources.pingap_config_max_bytes);
+    if let Some(prefix) = &config.pingap_admin_path_prefix {
+        pingap = pingap.with_path_prefix(prefix);
+    }
+    if let Some(proxy) = &config.pingap_http_proxy {
+        pingap = pingap.with_proxy(proxy, config.no_proxy.as_deref())?;
+    }
+    if !config.pingap_admin_headers.is_empty() {
+        pingap = pingap.with_headers(&config.pingap_admin_headers)?;
+    }
+    if let Some(secret) = &config.pingap_admin_hmac_secret {
+        pingap = pingap.with_request_signing(RequestSigner::new(&config.pingap_admin_hmac_header, secret)?);
+        info!("Signing Admin API requests to {} in the {} header", config.pingap_admin_url, config.pingap_admin_hmac_header);
+    }
+    if !config.lifecycle_hooks.is_empty() {
+        pingap = pingap.with_lifecycle_hooks(Arc::new(config.lifecycle_hooks.clone()));
+    }
+    if let Some(file) = &config.pingap_config_file {
+        let action = match &file.reload {
+            Reload::Signal { container, signal } => Some(ReloadAction::Signal {
+                docker: Box::new(DockerClient::new(config.docker_host.clone())?),
+                container: container.clone(),
+                signal: signal.clone(),
+            }),
+            Reload::Restart => Some(pingap.restart_action()),
+            Reload::None => None,
+        };
+        let mut backend = ConfigFile::new(&file.path);
+        if let Some(action) = action {
+            backend = backend.with_reloader(Reloader::spawn(action, file.reload_debounce));
+        }
+        pingap = pingap.with_config_file(backend);
+        info!("Writing Pingap's config file {} instead of using the Admin API", file.path);
+    }
+    Ok(pingap)
+}
+
+/// The status of a provider, served on its `status_addr` if set.
+async fn serve_status(config: &Config) -> Result<Arc<Status>> {
+    let status = Arc::new(Status::new(config.mode.as_str()).with_api_token(config.status_api_token.clone()));
+    if let Some(addr) = &config.status_addr {
+        let listener = tokio::net::TcpListener::bind(addr).await
+            .context(format!("Failed to bind status API to {}", addr))?;
+        tokio::spawn(status::serve(listener, status.clone()));
+    }
+    Ok(status)
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Method, RequestBuilder};
+
+/// `pingap-docker-provider maintenance enable|disable <service>`: asks the
+/// running provider, through its status API (`STATUS_ADDR`), to answer a
+/// service's requests with the maintenance plugin or to restore its routing.
+pub async fn run(status_addr: Option<&str>, token: Option<&str>, args: &[String]) -> Result<()> {
+    let (method, service) = match args {
+        [action, service] if action == "enable" => (Method::POST, service),
+        [action, service] if action == "disable" => (Method::DELETE, service),
+        _ => return Err(anyhow!("Usage: pingap-docker-provider maintenance enable|disable <service>")),
+    };
+    let addr = status_addr
+        .ok_or_else(|| anyhow!("STATUS_ADDR must be set to reach the running provider"))?;
+
+    let url = format!("http://{}/maintenance/{}", local_addr(addr), service);
+    let resp = status_request(method, &url, token).send().await
+        .context(format!("Failed to reach the provider at {}", url))?;
+    let status = resp.status();
+    let text = resp.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(anyhow!("Provider refused the request ({}): {}", status, text.trim()));
+    }
+    print!("{}", text);
+    Ok(())
+}
+
+/// A request to the running provider's status API, carrying `STATUS_API_TOKEN` if set.
+pub fn status_request(method: Method, url: &str, token: Option<&str>) -> RequestBuilder {
+    let request = reqwest::Client::new().request(method, url);
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+/// The status API usually listens on all interfaces, reach it over loopback then.
+pub fn local_addr(addr: &str) -> String {
+    match addr.rsplit_once(':') {
+        Some(("0.0.0.0" | "[::]" | "", port)) => format!("127.0.0.1:{}", port),
+        _ => addr.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::status::{self, Status};
+
+    #[test]
+    fn test_local_addr() {
+        assert_eq!(local_addr("0.0.0.0:8080"), "127.0.0.1:8080");
+        assert_eq!(local_addr("[::]:8080"), "127.0.0.1:8080");
+        assert_eq!(local_addr(":8080"), "127.0.0.1:8080");
+        assert_eq!(local_addr("10.0.0.5:8080"), "10.0.0.5:8080");
+    }
+
+    #[tokio::test]
+    async fn test_enable_and_disable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let status = Arc::new(Status::new("sync"));
+        tokio::spawn(status::serve(listener, status.clone()));
+
+        let args = |action: &str| vec![action.to_string(), "web".to_string()];
+        run(Some(&addr), None, &args("enable")).await.unwrap();
+        assert!(status.maintenance_requests().contains("web"));
+        run(Some(&addr), None, &args("disable")).await.unwrap();
+        assert!(status.maintenance_requests().is_empty());
+
+        assert!(run(Some(&addr), None, &args("toggle")).await.is_err());
+        assert!(run(None, None, &args("enable")).await.is_err());
+
+        // With a token set, only requests carrying it get through
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let status = Arc::new(Status::new("sync").with_api_token(Some("s3cr3t".to_string())));
+        tokio::spawn(status::serve(listener, status.clone()));
+        let error = run(Some(&addr), None, &args("enable")).await.unwrap_err().to_string();
+        assert!(error.contains("401"), "{}", error);
+        run(Some(&addr), Some("s3cr3t"), &args("enable")).await.unwrap();
+        assert!(status.maintenance_requests().contains("web"));
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Minimal Prometheus-style registry. Series are keyed by metric name plus a
+/// sorted label set, which is all the provider needs without pulling in a
+/// full metrics stack.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+    gauges: Mutex<BTreeMap<String, BTreeMap<String, f64>>>,
+    histograms: Mutex<BTreeMap<String, BTreeMap<String, Histogram>>>,
+}
+
+/// Upper bounds (seconds) of the histogram buckets, sized for "a route
+/// appears within X seconds" objectives.
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket of [`LATENCY_BUCKETS`], not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+fn label_key(labels: &[(&str, &str)]) -> String {
+    let mut labels = labels.to_vec();
+    labels.sort();
+    labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn series(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{{{}}}", name, labels)
+    }
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    pub fn add(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        *self.counters.lock().unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .entry(label_key(labels))
+            .or_default() += value;
+    }
+
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.gauges.lock().unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .insert(label_key(labels), value);
+    }
+
+    /// Drops all series of a gauge, used before re-publishing a full set of
+    /// labeled values so stale label combinations disappear.
+    pub fn reset_gauge(&self, name: &str) {
+        self.gauges.lock().unwrap().remove(name);
+    }
+
+    /// Records a duration in seconds.
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], seconds: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(name.to_string())
+            .or_default()
+            .entry(label_key(labels))
+            .or_default();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        self.counters.lock().unwrap()
+            .get(name)
+            .and_then(|s| s.get(&label_key(labels)))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Renders all series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, values) in self.counters.lock().unwrap().iter() {
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{} {}", series(name, labels), value);
+            }
+        }
+        for (name, values) in self.gauges.lock().unwrap().iter() {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{} {}", series(name, labels), value);
+            }
+        }
+        for (name, values) in self.histograms.lock().unwrap().iter() {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (labels, histogram) in values {
+                let with_le = |le: &str| match labels.as_str() {
+                    "" => format!("le=\"{}\"", le),
+                    labels => format!("{},le=\"{}\"", labels, le),
+                };
+                let mut cumulative = 0;
+                for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                    cumulative += count;
+                    let _ = writeln!(out, "{} {}", series(&format!("{}_bucket", name), &with_le(&le.to_string())), cumulative);
+                }
+                let _ = writeln!(out, "{} {}", series(&format!("{}_bucket", name), &with_le("+Inf")), histogram.count);
+                let _ = writeln!(out, "{} {}", series(&format!("{}_sum", name), labels), histogram.sum);
+                let _ = writeln!(out, "{} {}", series(&format!("{}_count", name), labels), histogram.count);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_increments() {
+        let metrics = Metrics::default();
+        metrics.inc("applies_total", &[("outcome", "success")]);
+        metrics.inc("applies_total", &[("outcome", "success")]);
+        metrics.inc("applies_total", &[("outcome", "error")]);
+
+        assert_eq!(metrics.counter("applies_total", &[("outcome", "success")]), 2);
+        assert_eq!(metrics.counter("applies_total", &[("outcome", "error")]), 1);
+        assert_eq!(metrics.counter("applies_total", &[("outcome", "unknown")]), 0);
+    }
+
+    #[test]
+    fn test_label_order_does_not_matter() {
+        let metrics = Metrics::default();
+        metrics.inc("events_total", &[("a", "1"), ("b", "2")]);
+        metrics.inc("events_total", &[("b", "2"), ("a", "1")]);
+
+        assert_eq!(metrics.counter("events_total", &[("a", "1"), ("b", "2")]), 2);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.inc("applies_total", &[("outcome", "success")]);
+        metrics.set_gauge("services", &[], 3.0);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE applies_total counter"));
+        assert!(text.contains("applies_total{outcome=\"success\"} 1"));
+        assert!(text.contains("# TYPE services gauge"));
+        assert!(text.contains("services 3"));
+    }
+
+    #[test]
+    fn test_reset_gauge() {
+        let metrics = Metrics::default();
+        metrics.set_gauge("drift", &[("kind", "missing")], 2.0);
+        metrics.reset_gauge("drift");
+        metrics.set_gauge("drift", &[("kind", "orphaned")], 1.0);
+
+        let text = metrics.render();
+        assert!(!text.contains("missing"));
+        assert!(text.contains("drift{kind=\"orphaned\"} 1"));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let metrics = Metrics::default();
+        metrics.observe("apply_seconds", &[("backend", "api")], 0.3);
+        metrics.observe("apply_seconds", &[("backend", "api")], 0.7);
+        metrics.observe("apply_seconds", &[("backend", "api")], 120.0);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE apply_seconds histogram"));
+        assert!(text.contains("apply_seconds_bucket{backend=\"api\",le=\"0.25\"} 0\n"));
+        assert!(text.contains("apply_seconds_bucket{backend=\"api\",le=\"0.5\"} 1\n"));
+        assert!(text.contains("apply_seconds_bucket{backend=\"api\",le=\"60\"} 2\n"));
+        assert!(text.contains("apply_seconds_bucket{backend=\"api\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("apply_seconds_sum{backend=\"api\"} 121\n"));
+        assert!(text.contains("apply_seconds_count{backend=\"api\"} 3\n"));
+    }
+
+    #[test]
+    fn test_label_values_are_escaped() {
+        let metrics = Metrics::default();
+        metrics.inc("errors_total", &[("reason", "bad \"quote\"")]);
+
+        assert!(metrics.render().contains(r#"errors_total{reason="bad \"quote\""} 1"#));
+    }
+}
@@ -1,44 +1,211 @@
-use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use anyhow::{Result, anyhow};
+use crate::blob;
+use crate::plugins;
+use crate::redact;
+use crate::rule;
+use crate::secrets;
+use crate::template;
 
-const LABEL_ENABLE: &str = "pingap.enable";
-const LABEL_SERVICE_NAME: &str = "pingap.service.name";
-const LABEL_SERVICE_ADDRESS: &str = "pingap.service.address";
-const LABEL_SERVICE_PORT: &str = "pingap.service.port";
-const LABEL_DOCKER_NETWORK: &str = "pingap.docker.network";
-const LABEL_HTTP_RULE: &str = "pingap.http.rule";
-const LABEL_HTTP_PRIORITY: &str = "pingap.http.priority";
-const LABEL_HTTP_HOST: &str = "pingap.http.host";
-const LABEL_HTTP_PATHS: &str = "pingap.http.paths";
-const LABEL_MIDDLEWARES: &str = "pingap.http.middlewares";
-const LABEL_TLS_ENABLED: &str = "pingap.http.tls.enabled";
-
-// Phase 2: Load Balancing & Health Checks
-const LABEL_UPSTREAM_WEIGHT: &str = "pingap.upstream.weight";
-const LABEL_UPSTREAM_STRATEGY: &str = "pingap.upstream.strategy";
-const LABEL_HEALTH_CHECK_PATH: &str = "pingap.health_check.path";
-const LABEL_HEALTH_CHECK_INTERVAL: &str = "pingap.health_check.interval";
-const LABEL_HEALTH_CHECK_TIMEOUT: &str = "pingap.health_check.timeout";
-
-// Phase 3: Essential Middlewares
-const LABEL_MIDDLEWARE_STRIP_PREFIX: &str = "pingap.middleware.strip_prefix";
-const LABEL_MIDDLEWARE_ADD_PREFIX: &str = "pingap.middleware.add_prefix";
-const LABEL_HEADERS_CUSTOM_REQUEST: &str = "pingap.headers.custom_request";
-const LABEL_HEADERS_CUSTOM_RESPONSE: &str = "pingap.headers.custom_response";
-const LABEL_HEADERS_CORS_ENABLE: &str = "pingap.headers.cors.enable";
-const LABEL_MIDDLEWARE_COMPRESS: &str = "pingap.middleware.compress";
-
-// Phase 4: Security & Advanced
-const LABEL_MIDDLEWARE_RATELIMIT_AVERAGE: &str = "pingap.middleware.ratelimit.average";
-const LABEL_MIDDLEWARE_RATELIMIT_BURST: &str = "pingap.middleware.ratelimit.burst";
-const LABEL_MIDDLEWARE_BASIC_AUTH: &str = "pingap.middleware.basic_auth";
-const LABEL_MIDDLEWARE_REDIRECT_SCHEME: &str = "pingap.middleware.redirect_scheme";
-const LABEL_MIDDLEWARE_REDIRECT_REGEX: &str = "pingap.middleware.redirect_regex";
-const LABEL_TLS_REDIRECT: &str = "pingap.tls.redirect";
-const LABEL_TLS_DOMAINS: &str = "pingap.tls.domains";
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Value a label takes. Templates (`{{ ... }}`) are allowed in all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelType {
+    Bool,
+    Integer,
+    Text,
+    /// Like `10s`, `500ms`, `1m30s` or `2h`
+    Duration,
+    /// Comma-separated strings
+    List,
+    /// One of these values
+    OneOf(&'static [&'static str]),
+    /// Comma-separated values out of these
+    ListOf(&'static [&'static str]),
+    /// A number from 0 to 1, like `0.1`
+    Ratio,
+}
+
+/// A supported label: how it is parsed and validated, and what
+/// `labels-schema` documents about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LabelSpec {
+    pub name: &'static str,
+    pub kind: LabelType,
+    pub default: Option<&'static str>,
+    pub example: &'static str,
+    pub description: &'static str,
+}
+
+/// Declares the `pingap.*` label constants with their type, default, example
+/// and description, and builds [`KNOWN_LABELS`] and [`LABEL_SPECS`] from them.
+/// The typed label accessors of [`ContainerInfo`] validate against these
+/// specs, so parsing, diagnostics, the schema and the docs can't disagree.
+macro_rules! labels {
+    ($($vis:vis $name:ident = $label:literal, $kind:expr, $default:expr, $example:literal, $description:literal;)*) => {
+        $($vis const $name: &str = $label;)*
+
+        /// Every `pingap.*` label the provider understands; anything else under that
+        /// prefix is most likely a typo.
+        const KNOWN_LABELS: &[&str] = &[$($name),*];
+
+        /// Type, default, example and description of every label in [`KNOWN_LABELS`].
+        pub const LABEL_SPECS: &[LabelSpec] = &[$(LabelSpec {
+            name: $name,
+            kind: { use LabelType::*; $kind },
+            default: $default,
+            example: $example,
+            description: $description,
+        }),*];
+    };
+}
+
+labels! {
+    pub LABEL_ENABLE = "pingap.enable", Bool, Some("false"), "true",
+        "Enable Pingap routing for this container";
+    pub LABEL_SERVICE_NAME = "pingap.service.name", Text, None, "api-v1",
+        "Unique service name (default: SERVICE_NAME_STRATEGY)";
+    pub LABEL_SERVICE_ADDRESS = "pingap.service.address", Text, None, "192.168.1.10:3000",
+        "Full upstream address override (IP:PORT)";
+    LABEL_SERVICE_ADDRESS_MODE = "pingap.service.address_mode", OneOf(&["ip", "dns"]), Some("ip"), "dns",
+        "Register the container IP, or its DNS name so restarts don't leave stale IPs";
+    LABEL_SERVICE_PORT = "pingap.service.port", Integer, None, "8080",
+        "Port to proxy to (default: first exposed port)";
+    LABEL_DOCKER_NETWORK = "pingap.docker.network", Text, None, "proxy-net",
+        "Network whose address is registered for multi-network containers";
+    LABEL_DOCKER_NETWORKS = "pingap.docker.networks", List, None, "frontend,backend",
+        "Networks whose addresses are all registered as upstream addresses";
+    LABEL_DOCKER_IP_FAMILY = "pingap.docker.ip_family", OneOf(&["ipv4", "ipv6", "dual"]), Some("ipv4"), "dual",
+        "Address family to register";
+    LABEL_HTTP_RULE = "pingap.http.rule", Text, None, "Host(`api.com`) && PathPrefix(`/v1`)",
+        "Explicit routing rule";
+    LABEL_HTTP_PRIORITY = "pingap.http.priority", Integer, None, "10",
+        "Rule priority, higher wins";
+    LABEL_LOCATION_MAX_CONCURRENCY = "pingap.location.max_concurrency", Integer, None, "500",
+        "Requests the service's location handles at once, Pingap answers more with 429";
+    pub LABEL_HTTP_HOST = "pingap.http.host", Text, None, "app.example.com",
+        "Route by hostname, a leading wildcard label is supported";
+    LABEL_HTTP_HOST_REGEXP = "pingap.http.host_regexp", Text, None, r"^(www|api)\.example\.com$",
+        "Route by hostname regex";
+    LABEL_HTTP_PATHS = "pingap.http.paths", List, None, "/api,=/healthz",
+        "Route by path: prefix by default, =/exact or ~regex";
+    LABEL_HTTP_MATCH_HEADER = "pingap.http.match.header", List, None, "X-Beta:true",
+        "Only route requests carrying these headers, as Name:value";
+    LABEL_HTTP_MATCH_COOKIE = "pingap.http.match.cookie", List, None, "beta=1",
+        "Only route requests carrying these cookies, as name=value";
+    LABEL_MIDDLEWARES = "pingap.http.middlewares", List, None, "site-cache,common-cors",
+        "Plugins defined in Pingap to run after the generated ones (legacy)";
+    LABEL_TLS_ENABLED = "pingap.http.tls.enabled", Bool, Some("false"), "true",
+        "Enable TLS for this route";
+    LABEL_ON_STOP = "pingap.on_stop", OneOf(&["remove", "drain", "keep"]), Some("remove"), "drain",
+        "What a stopped container does to its service";
+    LABEL_DEPENDS_ON = "pingap.depends_on", List, None, "api,auth",
+        "Services that must exist in Pingap before this service's route is activated";
+    LABEL_WARMUP = "pingap.warmup", Duration, None, "30s",
+        "Keep a started container's address out of its upstream this long, from 1s to 1h; with a Docker HEALTHCHECK counted from it turning healthy";
+    LABEL_MAINTENANCE = "pingap.maintenance", Bool, Some("false"), "true",
+        "Answer requests with the maintenance plugin instead of the upstream";
+    LABEL_DEPLOYMENT_SLOT = "pingap.deployment.slot", OneOf(&["blue", "green"]), None, "green",
+        "Blue/green slot the container belongs to";
+
+    // Phase 2: Load Balancing & Health Checks
+    LABEL_UPSTREAM_WEIGHT = "pingap.upstream.weight", Integer, None, "10",
+        "Weight of the container's addresses among the replicas of its service";
+    LABEL_UPSTREAM_WEIGHTS = "pingap.upstream.weights", List, None, "3,1",
+        "Weights of the replicas of a scaled compose service by container number, the last one for any further replica";
+    LABEL_UPSTREAM_STRATEGY = "pingap.upstream.strategy", OneOf(&["round_robin", "hash", "random"]), None, "hash",
+        "Load balancing algorithm";
+    LABEL_UPSTREAM_RETRIES = "pingap.upstream.retries", Integer, None, "2",
+        "Times Pingap retries a failed request";
+    LABEL_UPSTREAM_RETRY_ON = "pingap.upstream.retry_on", ListOf(RETRY_CONDITIONS), None, "5xx,timeout",
+        "Failures that are retried";
+    LABEL_UPSTREAM_FAILOVER = "pingap.upstream.failover", Bool, None, "true",
+        "Retry on another address of the upstream instead of the same one";
+    LABEL_UPSTREAM_MAX_CONNECTIONS = "pingap.upstream.max_connections", Integer, None, "100",
+        "Requests proxied to the upstream at once, Pingap answers more with 429";
+    LABEL_UPSTREAM_REF = "pingap.upstream.ref", Text, None, "api",
+        "Upstream shared with every other service naming it, instead of one of the service's own";
+    LABEL_HEALTH_CHECK_PATH = "pingap.health_check.path", Text, None, "/health",
+        "Health check endpoint path";
+    LABEL_HEALTH_CHECK_INTERVAL = "pingap.health_check.interval", Duration, None, "10s",
+        "Time between health checks, from 1s to 1h";
+    LABEL_HEALTH_CHECK_TIMEOUT = "pingap.health_check.timeout", Duration, None, "5s",
+        "Health check timeout, from 100ms to 5m";
+
+    // Phase 3: Essential Middlewares
+    LABEL_MIDDLEWARE_STRIP_PREFIX = "pingap.middleware.strip_prefix", Text, None, "/api",
+        "Remove path prefix before proxying";
+    LABEL_MIDDLEWARE_ADD_PREFIX = "pingap.middleware.add_prefix", Text, None, "/v1",
+        "Add path prefix before proxying";
+    LABEL_HEADERS_CUSTOM_REQUEST = "pingap.headers.custom_request", List, None, "X-Custom: value,X-Another: val",
+        "Request headers to add, as Name: value";
+    LABEL_HEADERS_CUSTOM_RESPONSE = "pingap.headers.custom_response", List, None, "X-Served-By: Pingap",
+        "Response headers to add, as Name: value";
+    LABEL_HEADERS_CORS_ENABLE = "pingap.headers.cors.enable", Bool, Some("false"), "true",
+        "Enable basic CORS support";
+    pub LABEL_HEADERS_REQUEST_ID = "pingap.headers.request_id", Bool, None, "true",
+        "Tag each request with an X-Request-Id header (default: DEFAULT_REQUEST_ID)";
+    pub LABEL_HEADERS_FORWARDED = "pingap.headers.forwarded", Bool, None, "true",
+        "Send X-Forwarded-* headers to the upstream (default: DEFAULT_FORWARDED_HEADERS)";
+    LABEL_MIDDLEWARE_COMPRESS = "pingap.middleware.compress", Bool, Some("false"), "true",
+        "Enable response compression";
+    LABEL_OBSERVABILITY_TRACING = "pingap.observability.tracing", Bool, Some("false"), "true",
+        "Trace requests of this route with Pingap's OpenTelemetry exporter";
+    LABEL_OBSERVABILITY_SAMPLING = "pingap.observability.sampling", Ratio, None, "0.1",
+        "Share of this route's requests that are traced and logged";
+
+    // Phase 4: Security & Advanced
+    LABEL_MIDDLEWARE_RATELIMIT_AVERAGE = "pingap.middleware.ratelimit.average", Integer, None, "100",
+        "Average requests per second per client IP";
+    LABEL_MIDDLEWARE_RATELIMIT_BURST = "pingap.middleware.ratelimit.burst", Integer, None, "50",
+        "Burst size for the rate limiter";
+    pub LABEL_MIDDLEWARE_BASIC_AUTH = "pingap.middleware.basic_auth", List, None, "user:pass",
+        "Basic authentication credentials as user:password";
+    LABEL_MIDDLEWARE_FORWARD_AUTH_ADDRESS = "pingap.middleware.forward_auth.address", Text, None, "http://auth/verify",
+        "URL asked whether to let each request through; a host naming a service is resolved to that service's current address";
+    LABEL_MIDDLEWARE_REDIRECT_SCHEME = "pingap.middleware.redirect_scheme", Text, None, "https",
+        "Force a redirect to this scheme";
+    LABEL_MIDDLEWARE_REDIRECT_REGEX = "pingap.middleware.redirect_regex", Text, None, "^http://old/(.*)->https://new/$1",
+        "Regex-based redirect, as pattern->replacement";
+    LABEL_MIDDLEWARE_ORDER = "pingap.middleware.order", ListOf(plugins::ORDERED_MIDDLEWARES), None, "strip_prefix,compress,auth,ratelimit",
+        "Plugin middlewares to run first, in this order";
+    LABEL_TLS_REDIRECT = "pingap.tls.redirect", Bool, Some("false"), "true",
+        "Redirect HTTP to HTTPS";
+    LABEL_TLS_DOMAINS = "pingap.tls.domains", List, None, "example.com,api.example.com",
+        "SAN domains for the certificate";
+    LABEL_TLS_MIN_VERSION = "pingap.tls.min_version", OneOf(&["1.1", "1.2", "1.3"]), None, "1.2",
+        "Lowest TLS version the Pingap servers routing the service accept";
+    LABEL_TLS_CIPHERS = "pingap.tls.ciphers", List, None, "ECDHE-ECDSA-AES128-GCM-SHA256,TLS_AES_128_GCM_SHA256",
+        "Ciphers the Pingap servers routing the service offer, TLS_* names being TLS 1.3 suites";
+    LABEL_HTTP3_ENABLE = "pingap.http3.enable", Bool, Some("false"), "true",
+        "Serve HTTP/3 on the Pingap servers routing the service";
+    pub LABEL_PROFILE = "pingap.profile", Text, None, "api",
+        "Bundle of labels out of PROFILES_FILE that the container's own labels build on";
+    pub LABEL_CONFIG = "pingap.config", Text, None, "{\"middleware\":{\"compress\":true}}",
+        "The service's other labels as one JSON or YAML document, keys relative to pingap.";
+}
+
+// `pingap.services.<service>.<key>` declares one of several services of a container
+pub const LABEL_SERVICES_PREFIX: &str = "pingap.services.";
+// `pingap.errors.<status>`: error page for one response status
+pub const LABEL_ERRORS_PREFIX: &str = "pingap.errors.";
+
+// Labels set by Docker itself, used to derive DNS names
+const LABEL_COMPOSE_SERVICE: &str = "com.docker.compose.service";
+const LABEL_COMPOSE_CONTAINER_NUMBER: &str = "com.docker.compose.container-number";
+const LABEL_SWARM_SERVICE_NAME: &str = "com.docker.swarm.service.name";
+pub const LABEL_COMPOSE_PROJECT: &str = "com.docker.compose.project";
+
+/// Labels that decide which requests reach a service.
+const ROUTING_LABELS: &[&str] = &[
+    LABEL_HTTP_RULE, LABEL_HTTP_HOST, LABEL_HTTP_HOST_REGEXP, LABEL_HTTP_PATHS, LABEL_HTTP_MATCH_HEADER, LABEL_HTTP_MATCH_COOKIE,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PingapServiceConfig {
     pub name: String,
     pub upstreams: Vec<String>,
@@ -51,26 +218,282 @@ pub struct PingapServiceConfig {
     pub middleware_config: Option<MiddlewareConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tls_config: Option<TlsConfig>,
+    #[serde(default)]
+    pub on_stop: StopPolicy,
+    /// Services that must exist in Pingap before this one's route is activated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// How long the address of a replica started while the provider runs
+    /// stays out of the upstream
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "optional_duration")]
+    pub warmup: Option<Duration>,
+    /// Requests are answered by the maintenance plugin instead of the upstream
+    #[serde(default)]
+    pub maintenance: bool,
+    /// Set for one slot of a blue/green service: `name` is then the slot's
+    /// upstream, the location is shared by both slots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<Deployment>,
+}
+
+impl PingapServiceConfig {
+    /// Name of the Pingap location that routes to this service.
+    pub fn location_name(&self) -> &str {
+        match &self.deployment {
+            Some(deployment) => &deployment.service,
+            None => &self.name,
+        }
+    }
+
+    /// Name of the Pingap upstream the location routes to: the one of
+    /// `pingap.upstream.ref`, shared with every service naming it, or else
+    /// the service's own.
+    pub fn upstream_name(&self) -> &str {
+        self.upstream_config.as_ref()
+            .and_then(|upstream| upstream.shared.as_deref())
+            .unwrap_or(&self.name)
+    }
+
+    /// Whether `other` differs from this config in its upstream addresses
+    /// and their weight at most, as the replicas of a scaled compose service do.
+    pub fn same_except_addrs(&self, other: &Self) -> bool {
+        self.without_addrs() == other.without_addrs()
+    }
+
+    fn without_addrs(&self) -> Self {
+        let upstream_config = self.upstream_config.clone()
+            .map(|upstream| UpstreamConfig { weight: None, ..upstream })
+            .filter(|upstream| *upstream != UpstreamConfig::default());
+        Self { upstreams: Vec::new(), upstream_config, ..self.clone() }
+    }
+}
+
+/// Builds a [`PingapServiceConfig`] from its name, addresses and routing
+/// rule; every other part is optional and left unset unless given.
+#[derive(Debug, Clone)]
+pub struct PingapServiceConfigBuilder {
+    config: PingapServiceConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl PingapServiceConfigBuilder {
+    pub fn new(name: impl Into<String>, upstreams: Vec<String>, rule: impl Into<String>) -> Self {
+        Self {
+            config: PingapServiceConfig {
+                name: name.into(),
+                upstreams,
+                location: PingapLocation { rule: rule.into(), priority: None, middlewares: None, tls: None, max_concurrency: None },
+                upstream_config: None,
+                health_check: None,
+                middleware_config: None,
+                tls_config: None,
+                on_stop: StopPolicy::default(),
+                depends_on: Vec::new(),
+                warmup: None,
+                maintenance: false,
+                deployment: None,
+            },
+        }
+    }
+
+    pub fn with_priority(mut self, priority: Option<i32>) -> Self {
+        self.config.location.priority = priority;
+        self
+    }
+
+    pub fn with_max_concurrency(mut self, max_concurrency: Option<u32>) -> Self {
+        self.config.location.max_concurrency = max_concurrency;
+        self
+    }
+
+    pub fn with_middlewares(mut self, middlewares: Option<Vec<String>>, config: Option<MiddlewareConfig>) -> Self {
+        self.config.location.middlewares = middlewares;
+        self.config.middleware_config = config;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: Option<bool>, config: Option<TlsConfig>) -> Self {
+        self.config.location.tls = tls;
+        self.config.tls_config = config;
+        self
+    }
+
+    pub fn with_upstream_config(mut self, config: Option<UpstreamConfig>) -> Self {
+        self.config.upstream_config = config;
+        self
+    }
+
+    pub fn with_health_check(mut self, health_check: Option<HealthCheckConfig>) -> Self {
+        self.config.health_check = health_check;
+        self
+    }
+
+    pub fn with_on_stop(mut self, on_stop: StopPolicy) -> Self {
+        self.config.on_stop = on_stop;
+        self
+    }
+
+    pub fn with_depends_on(mut self, depends_on: Vec<String>) -> Self {
+        self.config.depends_on = depends_on;
+        self
+    }
+
+    pub fn with_warmup(mut self, warmup: Option<Duration>) -> Self {
+        self.config.warmup = warmup;
+        self
+    }
+
+    pub fn with_maintenance(mut self, maintenance: bool) -> Self {
+        self.config.maintenance = maintenance;
+        self
+    }
+
+    /// Makes the service one slot of a blue/green deployment: the built
+    /// config is then named after the slot's upstream.
+    pub fn with_deployment(mut self, deployment: Option<Deployment>) -> Self {
+        self.config.deployment = deployment;
+        self
+    }
+
+    pub fn build(self) -> PingapServiceConfig {
+        let mut config = self.config;
+        if let Some(deployment) = &config.deployment {
+            config.name = slot_upstream_name(&deployment.service, deployment.slot);
+        }
+        config
+    }
+}
+
+/// One of the two container groups of a blue/green service (`pingap.deployment.slot`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Slot {
+    Blue,
+    Green,
+}
+
+impl FromStr for Slot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "blue" => Ok(Self::Blue),
+            "green" => Ok(Self::Green),
+            other => Err(anyhow!("unknown deployment slot '{}', expected 'blue' or 'green'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Blue => "blue",
+            Self::Green => "green",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Deployment {
+    /// The blue/green service, which names the shared location
+    pub service: String,
+    pub slot: Slot,
+}
+
+/// Upstream of one slot of a blue/green service.
+pub fn slot_upstream_name(service: &str, slot: Slot) -> String {
+    format!("{}-{}", service, slot)
+}
+
+/// What a stopped container does to its service's Pingap resources (`pingap.on_stop`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopPolicy {
+    /// Delete the upstream and location once the last replica is gone
+    #[default]
+    Remove,
+    /// Drop the container's address from the upstream, keep the location
+    Drain,
+    /// Leave everything untouched until an explicit prune
+    Keep,
+}
+
+impl FromStr for StopPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "remove" => Ok(Self::Remove),
+            "drain" => Ok(Self::Drain),
+            "keep" => Ok(Self::Keep),
+            other => Err(anyhow!("unknown stop policy '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct UpstreamConfig {
+    /// Weight of this replica's addresses, from `pingap.upstream.weight` or
+    /// its entry of `pingap.upstream.weights`
     #[serde(skip_serializing_if = "Option::is_none")]
     pub weight: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub strategy: Option<String>, // "round_robin", "hash", "random"
+    /// Times a failed request is retried
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// Failures that are retried, see [`RETRY_CONDITIONS`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_on: Option<Vec<String>>,
+    /// Retry on another address of the upstream instead of the same one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failover: Option<bool>,
+    /// Requests proxied to the upstream at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<u32>,
+    /// Upstream shared with the other services naming it, see [`PingapServiceConfig::upstream_name`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Prefix of the upstreams `pingap.upstream.ref` shares, apart from the
+/// services' own upstreams named like the service.
+pub const SHARED_UPSTREAM_PREFIX: &str = "shared-";
+
+/// Failures `pingap.upstream.retry_on` can name.
+pub const RETRY_CONDITIONS: &[&str] = &["connect", "timeout", "5xx"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HealthCheckConfig {
     pub path: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub interval: Option<String>, // e.g. "10s"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub timeout: Option<String>,  // e.g. "5s"
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "optional_duration")]
+    pub interval: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "optional_duration")]
+    pub timeout: Option<Duration>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+const HEALTH_CHECK_INTERVAL_RANGE: RangeInclusive<Duration> = Duration::from_secs(1)..=Duration::from_secs(3600);
+const WARMUP_RANGE: RangeInclusive<Duration> = Duration::from_secs(1)..=Duration::from_secs(3600);
+const HEALTH_CHECK_TIMEOUT_RANGE: RangeInclusive<Duration> = Duration::from_millis(100)..=Duration::from_secs(300);
+
+/// Durations in the form Pingap reads, see [`format_duration`].
+mod optional_duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_str(&format_duration(*duration)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| parse_duration(&value).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct MiddlewareConfig {
     // Phase 3: Path Manipulation
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -85,10 +508,22 @@ pub struct MiddlewareConfig {
     pub custom_response_headers: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cors_enabled: Option<bool>,
+    /// Tag requests with an `X-Request-Id` (`pingap.headers.request_id`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<bool>,
+    /// Set the `X-Forwarded-*` headers towards the upstream (`pingap.headers.forwarded`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_headers: Option<bool>,
     
     // Phase 3: Performance
     #[serde(skip_serializing_if = "Option::is_none")]
     pub compress: Option<bool>,
+    /// Trace the route's requests (`pingap.observability.tracing`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracing: Option<bool>,
+    /// Share of the route's requests traced and logged (`pingap.observability.sampling`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<f64>,
     
     // Phase 4: Rate Limiting
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -99,24 +534,50 @@ pub struct MiddlewareConfig {
     // Phase 4: Authentication
     #[serde(skip_serializing_if = "Option::is_none")]
     pub basic_auth: Option<String>,
+    /// URL or service name requests are checked against (`pingap.middleware.forward_auth.address`),
+    /// see [`plugins::resolve_forward_auth`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_auth: Option<String>,
     
     // Phase 4: Redirects
     #[serde(skip_serializing_if = "Option::is_none")]
     pub redirect_scheme: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub redirect_regex: Option<String>,
+
+    /// Response status -> error page (`pingap.errors.<status>`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_pages: Option<BTreeMap<u16, String>>,
+
+    /// Plugin middlewares to run first, in this order (`pingap.middleware.order`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<Vec<String>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct TlsConfig {
     pub enabled: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub redirect: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub domains: Option<Vec<String>>,
+    /// Lowest TLS version of the servers routing the service, e.g. `1.2`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ciphers: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http3: Option<bool>,
+}
+
+impl TlsConfig {
+    /// Whether the servers routing the service have anything to change.
+    pub fn has_server_options(&self) -> bool {
+        self.min_version.is_some() || self.ciphers.is_some() || self.http3 == Some(true)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PingapLocation {
     pub rule: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -125,76 +586,697 @@ pub struct PingapLocation {
     pub middlewares: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tls: Option<bool>,
+    /// Requests the location handles at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<u32>,
+}
+
+/// An optional label whose value could not be used. The rest of the config
+/// is still applied, the label is dropped (or, for flags, read as false).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelDiagnostic {
+    pub label: String,
+    pub value: String,
+    pub problem: String,
+}
+
+impl fmt::Display for LabelDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}='{}': {}", self.label, self.value, self.problem)
+    }
+}
+
+fn is_known_label(label: &str) -> bool {
+    KNOWN_LABELS.contains(&label) || label.strip_prefix(LABEL_ERRORS_PREFIX).is_some_and(|status| !status.is_empty())
+}
+
+/// Type of a label the provider understands, services-prefixed and error page
+/// labels included.
+pub fn label_type(label: &str) -> Option<LabelType> {
+    let label = match label.strip_prefix(LABEL_SERVICES_PREFIX).and_then(|rest| rest.split_once('.')) {
+        Some((service, key)) => indexed_label(key, &format!("{}{}.", LABEL_SERVICES_PREFIX, service)),
+        None => label.to_string(),
+    };
+    if label.starts_with(LABEL_ERRORS_PREFIX) {
+        return is_known_label(&label).then_some(LabelType::Text);
+    }
+    LABEL_SPECS.iter().find(|spec| spec.name == label).map(|spec| spec.kind)
+}
+
+/// The spec of a label the provider reads. Every label must be declared in
+/// `labels!`, which a missing spec here would mean it isn't.
+fn label_spec(label: &str) -> &'static LabelSpec {
+    LABEL_SPECS.iter()
+        .find(|spec| spec.name == label)
+        .unwrap_or_else(|| panic!("label {} is not declared in labels!", label))
+}
+
+/// `'a', 'b' or 'c'`
+fn alternatives(values: &[&str]) -> String {
+    let quoted = values.iter().map(|value| format!("'{}'", value)).collect::<Vec<_>>();
+    match quoted.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        _ => quoted.concat(),
+    }
+}
+
+/// Parses durations like "10s", "500ms" or "1m30s": whole numbers, each
+/// followed by a unit out of `ms`, `s`, `m` and `h`.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let invalid = || anyhow!("expected a duration like '10s', '1m30s' or '500ms'");
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut millis: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let unit = rest[digits..].find(|c: char| c.is_ascii_digit()).map_or(rest.len(), |end| digits + end);
+        let number = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        let unit_millis = match &rest[digits..unit] {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            _ => return Err(invalid()),
+        };
+        millis = number.checked_mul(unit_millis).and_then(|part| millis.checked_add(part))
+            .ok_or_else(|| anyhow!("duration is too long"))?;
+        rest = &rest[unit..];
+    }
+    Ok(Duration::from_millis(millis))
+}
+
+/// A duration in the largest unit that keeps it whole, like "90s" or
+/// "1500ms", which is how Pingap's config expects it.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    match millis {
+        0 => "0s".to_string(),
+        _ if millis.is_multiple_of(3_600_000) => format!("{}h", millis / 3_600_000),
+        _ if millis.is_multiple_of(60_000) => format!("{}m", millis / 60_000),
+        _ if millis.is_multiple_of(1000) => format!("{}s", millis / 1000),
+        _ => format!("{}ms", millis),
+    }
+}
+
+/// "Name: value" with a token-only header name.
+fn is_valid_header(entry: &str) -> bool {
+    match entry.split_once(':') {
+        Some((name, value)) => {
+            let name = name.trim();
+            !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+                && !value.trim().is_empty()
+        },
+        None => false,
+    }
+}
+
+/// The `<service>` parts of `pingap.services.<service>.*` labels.
+fn indexed_services(labels: &HashMap<String, String>) -> BTreeSet<&str> {
+    labels.keys()
+        .filter_map(|key| key.strip_prefix(LABEL_SERVICES_PREFIX)?.split_once('.'))
+        .map(|(service, _)| service)
+        .filter(|service| !service.is_empty())
+        .collect()
+}
+
+/// The plain label a `pingap.services.<service>.<key>` label stands for.
+/// `port`, `rule`, `host` and the like are shorthands, any other key is
+/// read relative to `pingap.` (e.g. `middleware.compress`). Keys that are
+/// neither keep their full name, so they are reported as unknown.
+fn indexed_label(key: &str, prefix: &str) -> String {
+    let label = match key {
+        "name" => LABEL_SERVICE_NAME,
+        "address" => LABEL_SERVICE_ADDRESS,
+        "port" => LABEL_SERVICE_PORT,
+        "rule" => LABEL_HTTP_RULE,
+        "priority" => LABEL_HTTP_PRIORITY,
+        "host" => LABEL_HTTP_HOST,
+        "host_regexp" => LABEL_HTTP_HOST_REGEXP,
+        "paths" => LABEL_HTTP_PATHS,
+        other => {
+            let label = format!("pingap.{}", other);
+            return if is_known_label(&label) && label != LABEL_ENABLE {
+                label
+            } else {
+                format!("{}{}", prefix, other)
+            };
+        }
+    };
+    label.to_string()
+}
+
+/// `pingap.services.<service>.name`, or `<base name>-<service>`.
+fn indexed_service_name(labels: &HashMap<String, String>, container_name: &str, service: &str) -> String {
+    labels.get(&format!("{}{}.name", LABEL_SERVICES_PREFIX, service))
+        .cloned()
+        .unwrap_or_else(|| format!("{}-{}", base_service_name(labels, container_name), service))
+}
+
+/// `pingap.service.name`, or the container name. A name rendered empty by
+/// a template (e.g. `{{ compose_service }}` outside of compose) counts as unset.
+fn base_service_name(labels: &HashMap<String, String>, container_name: &str) -> String {
+    labels.get(LABEL_SERVICE_NAME)
+        .filter(|name| !name.trim().is_empty())
+        .cloned()
+        .unwrap_or_else(|| container_name.trim_start_matches('/').to_string())
+}
+
+/// Whether the labels name every service they declare themselves, rather
+/// than leaving it to the container name.
+pub fn names_every_service(labels: &HashMap<String, String>) -> bool {
+    let expanded = blob::merged(labels).ok().flatten();
+    let labels = expanded.as_ref().unwrap_or(labels);
+    let indexed = indexed_services(labels);
+    labels.contains_key(LABEL_SERVICE_NAME)
+        || (!indexed.is_empty() && indexed.iter().all(|service| labels.contains_key(&format!("{}{}.name", LABEL_SERVICES_PREFIX, service))))
+}
+
+/// A container known only by its name and labels.
+fn unnamed_container(name: &str, labels: HashMap<String, String>) -> ContainerInfo {
+    ContainerInfo {
+        id: String::new(),
+        name: name.to_string(),
+        image: String::new(),
+        labels,
+        ip_address: None,
+        ports: Vec::new(),
+        networks: HashMap::new(),
+        ipv6_networks: HashMap::new(),
+    }
+}
+
+/// Checks an upstream address is `<host>:<port>`: an IPv4 address, a
+/// bracketed IPv6 address or a DNS name, and a port Pingap can connect to.
+pub fn check_address(address: &str) -> Result<()> {
+    let (host, port) = address.rsplit_once(':')
+        .ok_or_else(|| anyhow!("expected <host>:<port>"))?;
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => return Err(anyhow!("port '{}' is not in 1-65535", port)),
+        Ok(_) => {},
+    }
+    if let Some(ip) = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        return ip.parse::<std::net::Ipv6Addr>().map(|_| ()).map_err(|_| anyhow!("'{}' is not an IPv6 address", ip));
+    }
+    let is_name = !host.is_empty() && host.len() <= 253 && host.split('.').all(|part| {
+        !part.is_empty() && !part.starts_with('-')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    });
+    if !is_name {
+        return Err(anyhow!("'{}' is neither an IP address nor a DNS name", host));
+    }
+    Ok(())
+}
+
+/// Checks a label template against the variables every container provides.
+pub fn check_template(value: &str) -> Result<()> {
+    let value = secrets::without_references(value);
+    template::render(&value, &unnamed_container("", HashMap::new()).template_vars()).map(|_| ())
+}
+
+/// Whether `AUTO_DISCOVER` picks up a container. Only `pingap.enable` may be
+/// set, any other `pingap.*` label means it is configured by hand; an explicit
+/// `pingap.enable` wins over `expose_by_default` either way.
+pub fn auto_discovered(labels: &HashMap<String, String>, expose_by_default: bool) -> bool {
+    if labels.keys().any(|key| key.starts_with("pingap.") && key != LABEL_ENABLE) {
+        return false;
+    }
+    match labels.get(LABEL_ENABLE) {
+        Some(enable) => enable.trim() == "true",
+        None => expose_by_default,
+    }
+}
+
+/// The labels a tenant's provider sees (`TENANTS`): its `<prefix>.pingap.*`
+/// labels as `pingap.*`, without the default tenant's `pingap.*` labels,
+/// which it must never pick up.
+pub fn tenant_labels(labels: HashMap<String, String>, label_prefix: &str) -> HashMap<String, String> {
+    let prefix = format!("{}.", label_prefix);
+    labels.into_iter()
+        .filter(|(key, _)| !key.starts_with("pingap."))
+        .map(|(key, value)| match key.strip_prefix(&prefix).filter(|key| key.starts_with("pingap.")) {
+            Some(key) => (key.to_string(), value),
+            None => (key, value),
+        })
+        .collect()
+}
+
+/// Labels `AUTO_DISCOVER` gives a container without any `pingap.*` label:
+/// routed by the host template, on its first exposed port as usual, with
+/// the path of an HTTP `HEALTHCHECK` as its health check.
+pub fn discovered_labels(host_template: &str, healthcheck: Option<&[String]>) -> HashMap<String, String> {
+    let mut labels = HashMap::from([
+        (LABEL_ENABLE.to_string(), "true".to_string()),
+        (LABEL_HTTP_HOST.to_string(), host_template.to_string()),
+    ]);
+    if let Some(path) = healthcheck.and_then(healthcheck_path) {
+        labels.insert(LABEL_HEALTH_CHECK_PATH.to_string(), path);
+    }
+    labels
+}
+
+/// The path an HTTP health check command requests, e.g. `/health` for
+/// `CMD-SHELL curl -f http://localhost:8080/health || exit 1`.
+fn healthcheck_path(test: &[String]) -> Option<String> {
+    // `NONE` disables the image's check, `CMD` runs the arguments as they are
+    let command = match test.split_first() {
+        Some((kind, command)) if kind == "CMD" || kind == "CMD-SHELL" => command,
+        _ => return None,
+    };
+    command.iter()
+        .flat_map(|arg| arg.split_whitespace())
+        .map(|word| word.trim_matches(|c| c == '"' || c == '\''))
+        .find_map(|word| word.strip_prefix("http://").or_else(|| word.strip_prefix("https://")))
+        .map(|url| url.find('/').map_or("/", |start| &url[start..]).to_string())
+}
+
+/// Names of the services an enabled container declares, as far as they can
+/// be told from the labels alone (e.g. a Docker event's attributes).
+/// Templated names are rendered without the container ID.
+pub fn declared_service_names(labels: &HashMap<String, String>, container_name: &str) -> Vec<String> {
+    if labels.get(LABEL_ENABLE).map(|v| v.as_str()) != Some("true") {
+        return Vec::new();
+    }
+    let expanded = blob::merged(labels).ok().flatten();
+    let labels = expanded.as_ref().unwrap_or(labels);
+    let rendered = unnamed_container(container_name, labels.clone()).render_templates().ok();
+    let labels = rendered.as_ref().map_or(labels, |container| &container.labels);
+    let slot = labels.get(LABEL_DEPLOYMENT_SLOT).and_then(|slot| slot.parse().ok());
+    let with_slot = |service: String| match slot {
+        Some(slot) => slot_upstream_name(&service, slot),
+        None => service,
+    };
+    let indexed = indexed_services(labels);
+    if indexed.is_empty() {
+        return vec![with_slot(base_service_name(labels, container_name))];
+    }
+    indexed.into_iter()
+        .map(|service| with_slot(indexed_service_name(labels, container_name, service)))
+        .collect()
 }
 
 pub struct ContainerInfo {
-    #[allow(dead_code)]
     pub id: String,
     pub name: String,
+    pub image: String, // image reference the container was created from, e.g. "shop/web:1.4"
     pub labels: HashMap<String, String>,
     pub ip_address: Option<String>,
     pub ports: Vec<u16>,
     pub networks: HashMap<String, String>, // network name -> IP address
+    pub ipv6_networks: HashMap<String, String>, // network name -> global IPv6 address
 }
 
 impl ContainerInfo {
-    pub fn parse_pingap_config(&self) -> Result<Option<PingapServiceConfig>> {
-        // Check if enabled
-        if self.labels.get(LABEL_ENABLE).map(|v| v.as_str()) != Some("true") {
-            return Ok(None);
+    /// Where the container comes from: its compose project, or the image
+    /// repository (without tag) for containers started outside of compose.
+    /// Containers of the same origin naming the same service are replicas.
+    pub fn origin(&self) -> String {
+        if let Some(project) = self.labels.get(LABEL_COMPOSE_PROJECT) {
+            return format!("compose project {}", project);
         }
+        let image = self.image.split('@').next().unwrap_or_default();
+        let repository = match image.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => repository,
+            _ => image,
+        };
+        format!("image {}", repository)
+    }
 
-        // Get Service Name
-        let name = self.labels.get(LABEL_SERVICE_NAME)
-            .cloned()
-            .unwrap_or_else(|| self.name.trim_start_matches('/').to_string());
+    fn diagnose(&self, diagnostics: &mut Vec<LabelDiagnostic>, label: &str, problem: String) {
+        diagnostics.push(LabelDiagnostic {
+            label: label.to_string(),
+            value: redact::label_value(label, self.labels.get(label).map(String::as_str).unwrap_or_default()),
+            problem,
+        });
+    }
 
-        // Get IP Address (with network override support)
-        let ip = if let Some(network_name) = self.labels.get(LABEL_DOCKER_NETWORK) {
-            // User specified a specific network
-            self.networks.get(network_name)
-                .ok_or_else(|| anyhow!("Container {} is not connected to network '{}'. Available networks: {:?}", 
-                    self.name, network_name, self.networks.keys().collect::<Vec<_>>()))?
-                .clone()
-        } else {
-            // Use default IP (first network or primary IP)
-            self.ip_address.clone()
-                .or_else(|| self.networks.values().next().cloned())
-                .ok_or_else(|| anyhow!("No IP address found for container {}", self.name))?
+    fn number_label<T>(&self, label: &str, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        debug_assert_eq!(label_spec(label).kind, LabelType::Integer, "{}", label);
+        let value = self.labels.get(label)?;
+        match value.trim().parse::<T>() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.diagnose(diagnostics, label, format!("ignored, not a valid number: {}", e));
+                None
+            }
+        }
+    }
+
+    /// A concurrency limit: a number above 0, as 0 would let no request through.
+    fn limit_label(&self, label: &str, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<u32> {
+        match self.number_label::<u32>(label, diagnostics)? {
+            0 => {
+                self.diagnose(diagnostics, label, "ignored, must be above 0".to_string());
+                None
+            },
+            limit => Some(limit),
+        }
+    }
+
+    fn ratio_label(&self, label: &str, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<f64> {
+        debug_assert_eq!(label_spec(label).kind, LabelType::Ratio, "{}", label);
+        let value = self.labels.get(label)?;
+        match value.trim().parse::<f64>() {
+            Ok(ratio) if (0.0..=1.0).contains(&ratio) => Some(ratio),
+            _ => {
+                self.diagnose(diagnostics, label, "ignored, expected a number from 0 to 1".to_string());
+                None
+            }
+        }
+    }
+
+    fn flag_label(&self, label: &str, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<bool> {
+        debug_assert_eq!(label_spec(label).kind, LabelType::Bool, "{}", label);
+        let value = self.labels.get(label)?;
+        if value != "true" && value != "false" {
+            self.diagnose(diagnostics, label, "expected 'true' or 'false', treated as false".to_string());
+        }
+        Some(value == "true")
+    }
+
+    fn duration_label(&self, label: &str, range: RangeInclusive<Duration>, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<Duration> {
+        debug_assert_eq!(label_spec(label).kind, LabelType::Duration, "{}", label);
+        let value = self.labels.get(label)?;
+        match parse_duration(value) {
+            Ok(duration) if range.contains(&duration) => Some(duration),
+            Ok(_) => {
+                let problem = format!("ignored, expected from {} to {}", format_duration(*range.start()), format_duration(*range.end()));
+                self.diagnose(diagnostics, label, problem);
+                None
+            },
+            Err(e) => {
+                self.diagnose(diagnostics, label, format!("ignored, {}", e));
+                None
+            },
+        }
+    }
+
+    /// One of the values the label's spec allows, or its default when the
+    /// label is unset or has another value.
+    /// `pingap.middleware.forward_auth.address`, if it is an HTTP(S) URL or a service name.
+    fn forward_auth_label(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<String> {
+        let value = self.labels.get(LABEL_MIDDLEWARE_FORWARD_AUTH_ADDRESS)?.trim();
+        match plugins::forward_auth_url(value) {
+            Ok(_) => Some(value.to_string()),
+            Err(e) => {
+                self.diagnose(diagnostics, LABEL_MIDDLEWARE_FORWARD_AUTH_ADDRESS, format!("ignored, {}", e));
+                None
+            },
+        }
+    }
+
+    fn one_of_label(&self, label: &str, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<&'static str> {
+        let spec = label_spec(label);
+        let LabelType::OneOf(values) = spec.kind else {
+            panic!("{} is not a one-of label", label);
         };
+        let Some(value) = self.labels.get(label) else {
+            return spec.default;
+        };
+        match values.iter().find(|allowed| **allowed == value.trim()) {
+            Some(allowed) => Some(allowed),
+            None => {
+                self.diagnose(diagnostics, label, format!("ignored, expected {}", alternatives(values)));
+                spec.default
+            },
+        }
+    }
 
-        // Get Port (with explicit override support)
-        let port = if let Some(port_str) = self.labels.get(LABEL_SERVICE_PORT) {
-            port_str.parse::<u16>()
-                .map_err(|e| anyhow!("Invalid port '{}': {}", port_str, e))?
+    /// The entries of a comma-separated label that its spec allows.
+    fn list_of_label(&self, label: &str, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<Vec<String>> {
+        let LabelType::ListOf(values) = label_spec(label).kind else {
+            panic!("{} is not a list-of label", label);
+        };
+        let (known, unknown): (Vec<_>, Vec<_>) = self.list_label(label)?.into_iter()
+            .partition(|entry| values.contains(&entry.as_str()));
+        if !unknown.is_empty() {
+            self.diagnose(diagnostics, label, format!("ignored {:?}, expected some of {}", unknown, values.join(", ")));
+        }
+        (!known.is_empty()).then_some(known)
+    }
+
+    /// The non-empty entries of a comma-separated label.
+    fn list_label(&self, label: &str) -> Option<Vec<String>> {
+        debug_assert!(matches!(label_spec(label).kind, LabelType::List | LabelType::ListOf(_)), "{}", label);
+        let value = self.labels.get(label)?;
+        Some(value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+    }
+
+    /// `pingap.tls.ciphers`: cipher names separated by commas or, as
+    /// OpenSSL writes them, colons.
+    fn ciphers_label(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<Vec<String>> {
+        let (valid, invalid): (Vec<_>, Vec<_>) = self.list_label(LABEL_TLS_CIPHERS)?.iter()
+            .flat_map(|entry| entry.split(':'))
+            .map(|cipher| cipher.trim().to_string())
+            .filter(|cipher| !cipher.is_empty())
+            .partition(|cipher| cipher.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        if !invalid.is_empty() {
+            self.diagnose(diagnostics, LABEL_TLS_CIPHERS, format!("ignored {:?}, not cipher names", invalid));
+        }
+        (!valid.is_empty()).then_some(valid)
+    }
+
+    /// `pingap.middleware.order`: known middlewares, each once.
+    fn middleware_order_label(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<Vec<String>> {
+        let value = self.labels.get(LABEL_MIDDLEWARE_ORDER)?;
+        let mut order = Vec::new();
+        for entry in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            if !plugins::ORDERED_MIDDLEWARES.contains(&entry) {
+                self.diagnose(diagnostics, LABEL_MIDDLEWARE_ORDER, format!(
+                    "ignored '{}', expected some of {}", entry, plugins::ORDERED_MIDDLEWARES.join(", ")));
+            } else if order.iter().any(|m| m == entry) {
+                self.diagnose(diagnostics, LABEL_MIDDLEWARE_ORDER, format!("'{}' listed twice, the first one counts", entry));
+            } else {
+                if entry == plugins::STRIP_PREFIX && !order.is_empty() {
+                    self.diagnose(diagnostics, LABEL_MIDDLEWARE_ORDER, format!(
+                        "'{}' runs before {} all the same, Pingap rewrites the path before the plugins", entry, order.join(", ")));
+                }
+                order.push(entry.to_string());
+            }
+        }
+        (!order.is_empty()).then_some(order)
+    }
+
+    /// `pingap.errors.<status>` labels with a 4xx/5xx status and a page.
+    fn error_pages_label(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<BTreeMap<u16, String>> {
+        let mut labels = self.labels.iter()
+            .filter_map(|(label, page)| Some((label, label.strip_prefix(LABEL_ERRORS_PREFIX)?, page.trim())))
+            .collect::<Vec<_>>();
+        labels.sort();
+        let mut pages = BTreeMap::new();
+        for (label, status, page) in labels {
+            match status.parse::<u16>() {
+                Ok(status) if (400..600).contains(&status) && !page.is_empty() => {
+                    pages.insert(status, page.to_string());
+                },
+                Ok(status) if (400..600).contains(&status) => {
+                    self.diagnose(diagnostics, label, "ignored, expected the path of an error page".to_string());
+                },
+                _ => self.diagnose(diagnostics, label, "ignored, expected pingap.errors.<status> with a 4xx or 5xx status".to_string()),
+            }
+        }
+        (!pages.is_empty()).then_some(pages)
+    }
+
+    fn header_list_label(&self, label: &str, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<Vec<String>> {
+        let value = self.labels.get(label)?;
+        let (valid, malformed): (Vec<_>, Vec<_>) = value.split(',')
+            .map(|s| s.trim().to_string())
+            .partition(|entry| is_valid_header(entry));
+        if !malformed.is_empty() {
+            let malformed = malformed.iter().map(|entry| redact::header(entry)).collect::<Vec<_>>();
+            self.diagnose(diagnostics, label,
+                format!("ignored malformed headers {:?}, expected 'Name: value'", malformed));
+        }
+        (!valid.is_empty()).then_some(valid)
+    }
+
+    pub fn parse_pingap_configs(&self) -> Result<Vec<PingapServiceConfig>> {
+        Ok(self.parse_pingap_configs_with_diagnostics()?.into_iter().map(|(config, _)| config).collect())
+    }
+
+    /// Every service a container declares: one, or one per
+    /// `pingap.services.<service>.*` group. Also reports the optional labels
+    /// that were left out because their value was unusable.
+    pub fn parse_pingap_configs_with_diagnostics(&self) -> Result<Vec<(PingapServiceConfig, Vec<LabelDiagnostic>)>> {
+        // Check if enabled
+        if self.labels.get(LABEL_ENABLE).map(|v| v.as_str()) != Some("true") {
+            return Ok(Vec::new());
+        }
+        let expanded;
+        let container = match blob::merged(&self.labels)
+            .map_err(|e| anyhow!("Invalid {} on container {}: {}", LABEL_CONFIG, self.name, e))? {
+            Some(labels) => {
+                expanded = self.with_labels(labels);
+                &expanded
+            },
+            None => self,
+        };
+        let rendered;
+        let container = if container.labels.iter().any(|(k, v)| k.starts_with("pingap.") && v.contains("{{")) {
+            rendered = container.render_templates()?;
+            &rendered
         } else {
-            // Auto-detect first exposed port
-            *self.ports.first()
-                .ok_or_else(|| anyhow!("No exposed ports found for container {}. Use {} label to specify port explicitly.", 
-                    self.name, LABEL_SERVICE_PORT))?
+            container
         };
 
-        // Build upstream address (override if LABEL_SERVICE_ADDRESS is set)
-        let address = self.labels.get(LABEL_SERVICE_ADDRESS)
-            .cloned()
-            .unwrap_or_else(|| format!("{}:{}", ip, port));
+        let indexed = indexed_services(&container.labels);
+        if indexed.is_empty() {
+            return Ok(vec![container.parse_rendered()?]);
+        }
+        indexed.into_iter()
+            .map(|service| container.indexed_service(service).parse_rendered())
+            .collect()
+    }
 
-        // Build routing rule (supports explicit rule, or simplified host/paths)
+    /// Values available to `{{ ... }}` templates in `pingap.*` labels.
+    fn template_vars(&self) -> HashMap<&'static str, String> {
+        let label = |key: &str| self.labels.get(key).cloned().unwrap_or_default();
+        HashMap::from([
+            ("container_name", self.name.trim_start_matches('/').to_string()),
+            ("container_id", self.id.clone()),
+            ("compose_service", label(LABEL_COMPOSE_SERVICE)),
+            ("compose_project", label(LABEL_COMPOSE_PROJECT)),
+            ("swarm_service", label(LABEL_SWARM_SERVICE_NAME)),
+        ])
+    }
+
+    /// A copy of the container with every templated `pingap.*` label rendered.
+    fn render_templates(&self) -> Result<ContainerInfo> {
+        let vars = self.template_vars();
+        let labels = self.labels.iter()
+            .map(|(key, value)| {
+                if !key.starts_with("pingap.") || !value.contains("{{") {
+                    return Ok((key.clone(), value.clone()));
+                }
+                let rendered = template::render(value, &vars)
+                    .map_err(|e| anyhow!("Invalid template in {} on container {}: {}", key, self.name, e))?;
+                Ok((key.clone(), rendered))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(self.with_labels(labels))
+    }
+
+    /// A copy of the container with other labels.
+    fn with_labels(&self, labels: HashMap<String, String>) -> ContainerInfo {
+        ContainerInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            image: self.image.clone(),
+            labels,
+            ip_address: self.ip_address.clone(),
+            ports: self.ports.clone(),
+            networks: self.networks.clone(),
+            ipv6_networks: self.ipv6_networks.clone(),
+        }
+    }
+
+    fn parse_rendered(&self) -> Result<(PingapServiceConfig, Vec<LabelDiagnostic>)> {
+        let upstreams = self.resolve_address()?;
+        let rule = self.build_rule()?;
+
+        let mut diagnostics = Vec::new();
+
+        let mut unknown = self.labels.keys()
+            .filter(|k| k.starts_with("pingap.") && !is_known_label(k))
+            .collect::<Vec<_>>();
+        unknown.sort();
+        for label in unknown {
+            self.diagnose(&mut diagnostics, label, "unknown label, ignored".to_string());
+        }
+        // Profiles are expanded by the Docker client, one left over isn't defined
+        if self.labels.contains_key(LABEL_PROFILE) {
+            self.diagnose(&mut diagnostics, LABEL_PROFILE, "unknown profile, not in PROFILES_FILE, ignored".to_string());
+        }
+
+        let priority = self.number_label::<i32>(LABEL_HTTP_PRIORITY, &mut diagnostics);
+        let max_concurrency = self.limit_label(LABEL_LOCATION_MAX_CONCURRENCY, &mut diagnostics);
+        let (middlewares, middleware_config) = self.build_middlewares(&mut diagnostics);
+        let (tls, tls_config) = self.build_tls(&mut diagnostics);
+        let upstream_config = self.upstream_config(&mut diagnostics);
+        let health_check = self.health_check(&mut diagnostics);
+
+        let on_stop = self.one_of_label(LABEL_ON_STOP, &mut diagnostics)
+            .and_then(|policy| policy.parse().ok())
+            .unwrap_or_default();
+        let depends_on = self.list_label(LABEL_DEPENDS_ON).unwrap_or_default();
+        let warmup = self.duration_label(LABEL_WARMUP, WARMUP_RANGE, &mut diagnostics);
+        let maintenance = self.flag_label(LABEL_MAINTENANCE, &mut diagnostics).unwrap_or(false);
+
+        // Each slot of a blue/green service gets an upstream of its own
+        let name = base_service_name(&self.labels, &self.name);
+        let deployment = self.one_of_label(LABEL_DEPLOYMENT_SLOT, &mut diagnostics)
+            .and_then(|slot| slot.parse().ok())
+            .map(|slot| Deployment { service: name.clone(), slot });
+
+        let config = PingapServiceConfigBuilder::new(name, upstreams, rule)
+            .with_priority(priority)
+            .with_max_concurrency(max_concurrency)
+            .with_middlewares(middlewares, middleware_config)
+            .with_tls(tls, tls_config)
+            .with_upstream_config(upstream_config)
+            .with_health_check(health_check)
+            .with_on_stop(on_stop)
+            .with_depends_on(depends_on)
+            .with_warmup(warmup)
+            .with_maintenance(maintenance)
+            .with_deployment(deployment)
+            .build();
+        Ok((config, diagnostics))
+    }
+
+    /// The upstream addresses: `pingap.service.address`, which needs no IP
+    /// or exposed port, or else the container's own addresses.
+    pub fn resolve_address(&self) -> Result<Vec<String>> {
+        let addresses = match self.labels.get(LABEL_SERVICE_ADDRESS) {
+            Some(address) => vec![address.clone()],
+            None => self.upstream_addresses()?,
+        };
+        for address in &addresses {
+            check_address(address)
+                .map_err(|e| anyhow!("Container {}: invalid upstream address '{}': {}", self.name, address, e))?;
+        }
+        Ok(addresses)
+    }
+
+    /// The routing rule: `pingap.http.rule`, or one built from the host,
+    /// host regexp and paths aliases, narrowed down by the header and cookie
+    /// matches. Rules Pingap cannot express are rejected.
+    pub fn build_rule(&self) -> Result<String> {
         let rule = if let Some(explicit_rule) = self.labels.get(LABEL_HTTP_RULE) {
             // User provided explicit rule like "Host(`example.com`) && PathPrefix(`/api`)"
             explicit_rule.clone()
         } else {
-            // Try simplified aliases
-            let host_rule = self.labels.get(LABEL_HTTP_HOST)
-                .map(|h| format!("Host(`{}`)", h));
+            // Try simplified aliases (wildcards like "*.example.com" are validated here)
+            let host = self.labels.get(LABEL_HTTP_HOST)
+                .map(|h| rule::parse_host(h).map(|host| host.to_rule()))
+                .transpose()
+                .map_err(|e| anyhow!("Container {}: invalid {}: {}", self.name, LABEL_HTTP_HOST, e))?;
+            let host_regexp = self.labels.get(LABEL_HTTP_HOST_REGEXP)
+                .map(|re| rule::parse_host_regex(re).map(|_| format!("HostRegexp(`{}`)", re)))
+                .transpose()
+                .map_err(|e| anyhow!("Container {}: invalid {}: {}", self.name, LABEL_HTTP_HOST_REGEXP, e))?;
+            let host_rule = match (host, host_regexp) {
+                (Some(h), Some(re)) => Some(format!("({} || {})", h, re)),
+                (h, re) => h.or(re),
+            };
             
+            // Each entry may carry a match modifier: "/api*" prefix, "=/healthz" exact, "~^/v[0-9]+/" regex
             let path_rules = self.labels.get(LABEL_HTTP_PATHS)
                 .map(|paths| {
                     paths.split(',')
-                        .map(|p| format!("PathPrefix(`{}`)", p.trim()))
-                        .collect::<Vec<_>>()
-                        .join(" || ")
-                });
+                        .map(|p| rule::parse_path_entry(p).map(|m| m.to_rule()))
+                        .collect::<Result<Vec<_>>>()
+                        .map(|rules| rules.join(" || "))
+                })
+                .transpose()
+                .map_err(|e| anyhow!("Container {}: invalid {}: {}", self.name, LABEL_HTTP_PATHS, e))?;
 
             match (host_rule, path_rules) {
                 (Some(h), Some(p)) => format!("{} && ({})", h, p),
@@ -203,131 +1285,270 @@ impl ContainerInfo {
                 (None, None) => {
                     return Err(anyhow!(
                         "Container {} has pingap.enable=true but no routing rule. \
-                        Provide one of: {}, {}, or {}",
-                        self.name, LABEL_HTTP_RULE, LABEL_HTTP_HOST, LABEL_HTTP_PATHS
+                        Provide one of: {}, {}, {}, or {}",
+                        self.name, LABEL_HTTP_RULE, LABEL_HTTP_HOST, LABEL_HTTP_HOST_REGEXP, LABEL_HTTP_PATHS
                     ));
                 }
             }
         };
 
-        // Get Priority
-        let priority = self.labels.get(LABEL_HTTP_PRIORITY)
-            .and_then(|p| p.parse::<i32>().ok());
+        // Header and cookie matches narrow the route down, e.g. to beta testers
+        let mut value_rules = Vec::new();
+        for (label, parse) in [
+            (LABEL_HTTP_MATCH_HEADER, rule::parse_header_entry as fn(&str) -> Result<String>),
+            (LABEL_HTTP_MATCH_COOKIE, rule::parse_cookie_entry),
+        ] {
+            for entry in self.labels.get(label).into_iter().flat_map(|entries| entries.split(',')) {
+                value_rules.push(parse(entry)
+                    .map_err(|e| anyhow!("Container {}: invalid {}: {}", self.name, label, e))?);
+            }
+        }
+        let rule = if value_rules.is_empty() {
+            rule
+        } else if rule.contains("||") {
+            format!("({}) && {}", rule, value_rules.join(" && "))
+        } else {
+            format!("{} && {}", rule, value_rules.join(" && "))
+        };
 
-        // Get Middlewares
-        let middlewares = self.labels.get(LABEL_MIDDLEWARES)
-            .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
+        // Reject rules Pingap cannot express before anything is sent
+        rule::parse_rule(&rule)
+            .map_err(|e| anyhow!("Container {}: {}", self.name, e))?;
+        Ok(rule)
+    }
 
-        // Get TLS
-        let tls = self.labels.get(LABEL_TLS_ENABLED)
-            .map(|v| v == "true");
+    /// Phase 3: the middlewares of `pingap.middlewares` and the middleware
+    /// config of the other middleware labels.
+    pub fn build_middlewares(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> (Option<Vec<String>>, Option<MiddlewareConfig>) {
+        (self.list_label(LABEL_MIDDLEWARES), self.middleware_config(diagnostics))
+    }
 
-        // Phase 2: Upstream Configuration
-        let upstream_config = {
-            let weight = self.labels.get(LABEL_UPSTREAM_WEIGHT)
-                .and_then(|w| w.parse::<u32>().ok());
-            
-            let strategy = self.labels.get(LABEL_UPSTREAM_STRATEGY)
-                .map(|s| s.clone());
+    /// Phase 4: `pingap.http.tls.enabled` and, when it is on, the TLS config.
+    pub fn build_tls(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> (Option<bool>, Option<TlsConfig>) {
+        let tls = self.flag_label(LABEL_TLS_ENABLED, diagnostics);
+        let tls_config = (tls == Some(true)).then(|| TlsConfig {
+            enabled: true,
+            redirect: self.flag_label(LABEL_TLS_REDIRECT, diagnostics),
+            domains: self.list_label(LABEL_TLS_DOMAINS),
+            min_version: self.one_of_label(LABEL_TLS_MIN_VERSION, diagnostics).map(str::to_string),
+            ciphers: self.ciphers_label(diagnostics),
+            http3: self.flag_label(LABEL_HTTP3_ENABLE, diagnostics),
+        });
+        (tls, tls_config)
+    }
 
-            if weight.is_some() || strategy.is_some() {
-                Some(UpstreamConfig { weight, strategy })
-            } else {
-                None
+    /// Phase 2: Health Check Configuration, None without a health check path.
+    fn health_check(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<HealthCheckConfig> {
+        self.labels.get(LABEL_HEALTH_CHECK_PATH)
+            .map(|path| HealthCheckConfig {
+                path: path.clone(),
+                interval: self.duration_label(LABEL_HEALTH_CHECK_INTERVAL, HEALTH_CHECK_INTERVAL_RANGE, diagnostics),
+                timeout: self.duration_label(LABEL_HEALTH_CHECK_TIMEOUT, HEALTH_CHECK_TIMEOUT_RANGE, diagnostics),
+            })
+    }
+
+    /// Phase 2: Upstream Configuration, None without any upstream label.
+    fn upstream_config(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<UpstreamConfig> {
+        let config = UpstreamConfig {
+            weight: self.number_label::<u32>(LABEL_UPSTREAM_WEIGHT, diagnostics)
+                .or(self.replica_weight_label(diagnostics)),
+            strategy: self.one_of_label(LABEL_UPSTREAM_STRATEGY, diagnostics).map(str::to_string),
+            retries: self.number_label::<u32>(LABEL_UPSTREAM_RETRIES, diagnostics),
+            retry_on: self.list_of_label(LABEL_UPSTREAM_RETRY_ON, diagnostics),
+            failover: self.flag_label(LABEL_UPSTREAM_FAILOVER, diagnostics),
+            max_connections: self.limit_label(LABEL_UPSTREAM_MAX_CONNECTIONS, diagnostics),
+            shared: self.shared_upstream_label(diagnostics),
+        };
+        (config != UpstreamConfig::default()).then_some(config)
+    }
+
+    /// `pingap.upstream.ref`, in the namespace of [`SHARED_UPSTREAM_PREFIX`]
+    /// so it can't name the upstream of a service. A blue/green slot always
+    /// has an upstream of its own.
+    fn shared_upstream_label(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<String> {
+        let name = self.labels.get(LABEL_UPSTREAM_REF).map(|name| name.trim())?;
+        if name.is_empty() || name.contains('/') {
+            self.diagnose(diagnostics, LABEL_UPSTREAM_REF, format!("ignored, {:?} is no upstream name", name));
+            return None;
+        }
+        if self.labels.contains_key(LABEL_DEPLOYMENT_SLOT) {
+            self.diagnose(diagnostics, LABEL_UPSTREAM_REF, format!("ignored, {} gives each slot an upstream of its own", LABEL_DEPLOYMENT_SLOT));
+            return None;
+        }
+        match name.starts_with(SHARED_UPSTREAM_PREFIX) {
+            true => Some(name.to_string()),
+            false => Some(format!("{}{}", SHARED_UPSTREAM_PREFIX, name)),
+        }
+    }
+
+    /// The entry of `pingap.upstream.weights` for the container's compose
+    /// container number (1 outside of compose), the last entry past the end.
+    fn replica_weight_label(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<u32> {
+        let weights = self.list_label(LABEL_UPSTREAM_WEIGHTS)?;
+        let weights = match weights.iter().map(|weight| weight.parse::<u32>()).collect::<Result<Vec<_>, _>>() {
+            Ok(weights) => weights,
+            Err(e) => {
+                self.diagnose(diagnostics, LABEL_UPSTREAM_WEIGHTS, format!("ignored, expected comma-separated integers: {}", e));
+                return None;
             }
         };
+        let number = self.labels.get(LABEL_COMPOSE_CONTAINER_NUMBER)
+            .and_then(|number| number.trim().parse::<usize>().ok())
+            .unwrap_or(1);
+        weights.get(number.saturating_sub(1)).or(weights.last()).copied()
+    }
 
-        // Phase 2: Health Check Configuration
-        let health_check = self.labels.get(LABEL_HEALTH_CHECK_PATH)
-            .map(|path| HealthCheckConfig {
-                path: path.clone(),
-                interval: self.labels.get(LABEL_HEALTH_CHECK_INTERVAL).cloned(),
-                timeout: self.labels.get(LABEL_HEALTH_CHECK_TIMEOUT).cloned(),
-            });
-
-        // Phase 3 & 4: Middleware Configuration
-        let middleware_config = {
-            let strip_prefix = self.labels.get(LABEL_MIDDLEWARE_STRIP_PREFIX).cloned();
-            let add_prefix = self.labels.get(LABEL_MIDDLEWARE_ADD_PREFIX).cloned();
-            
-            let custom_request_headers = self.labels.get(LABEL_HEADERS_CUSTOM_REQUEST)
-                .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
-            
-            let custom_response_headers = self.labels.get(LABEL_HEADERS_CUSTOM_RESPONSE)
-                .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
-            
-            let cors_enabled = self.labels.get(LABEL_HEADERS_CORS_ENABLE)
-                .map(|v| v == "true");
-            
-            let compress = self.labels.get(LABEL_MIDDLEWARE_COMPRESS)
-                .map(|v| v == "true");
-            
-            let ratelimit_average = self.labels.get(LABEL_MIDDLEWARE_RATELIMIT_AVERAGE)
-                .and_then(|v| v.parse::<u32>().ok());
-            
-            let ratelimit_burst = self.labels.get(LABEL_MIDDLEWARE_RATELIMIT_BURST)
-                .and_then(|v| v.parse::<u32>().ok());
-            
-            let basic_auth = self.labels.get(LABEL_MIDDLEWARE_BASIC_AUTH).cloned();
-            
-            let redirect_scheme = self.labels.get(LABEL_MIDDLEWARE_REDIRECT_SCHEME).cloned();
-            
-            let redirect_regex = self.labels.get(LABEL_MIDDLEWARE_REDIRECT_REGEX).cloned();
-            
-            // Only create MiddlewareConfig if at least one middleware is configured
-            if strip_prefix.is_some() || add_prefix.is_some() || custom_request_headers.is_some() ||
-               custom_response_headers.is_some() || cors_enabled.is_some() || compress.is_some() ||
-               ratelimit_average.is_some() || ratelimit_burst.is_some() || basic_auth.is_some() ||
-               redirect_scheme.is_some() || redirect_regex.is_some() {
-                Some(MiddlewareConfig {
-                    strip_prefix,
-                    add_prefix,
-                    custom_request_headers,
-                    custom_response_headers,
-                    cors_enabled,
-                    compress,
-                    ratelimit_average,
-                    ratelimit_burst,
-                    basic_auth,
-                    redirect_scheme,
-                    redirect_regex,
-                })
-            } else {
-                None
+    /// Phase 3 & 4: Middleware Configuration, None unless at least one
+    /// middleware is configured (an order alone configures none).
+    fn middleware_config(&self, diagnostics: &mut Vec<LabelDiagnostic>) -> Option<MiddlewareConfig> {
+        let config = MiddlewareConfig {
+            strip_prefix: self.labels.get(LABEL_MIDDLEWARE_STRIP_PREFIX).cloned(),
+            add_prefix: self.labels.get(LABEL_MIDDLEWARE_ADD_PREFIX).cloned(),
+            custom_request_headers: self.header_list_label(LABEL_HEADERS_CUSTOM_REQUEST, diagnostics),
+            custom_response_headers: self.header_list_label(LABEL_HEADERS_CUSTOM_RESPONSE, diagnostics),
+            cors_enabled: self.flag_label(LABEL_HEADERS_CORS_ENABLE, diagnostics),
+            request_id: self.flag_label(LABEL_HEADERS_REQUEST_ID, diagnostics),
+            forwarded_headers: self.flag_label(LABEL_HEADERS_FORWARDED, diagnostics),
+            compress: self.flag_label(LABEL_MIDDLEWARE_COMPRESS, diagnostics),
+            tracing: self.flag_label(LABEL_OBSERVABILITY_TRACING, diagnostics),
+            sampling: self.ratio_label(LABEL_OBSERVABILITY_SAMPLING, diagnostics),
+            ratelimit_average: self.number_label::<u32>(LABEL_MIDDLEWARE_RATELIMIT_AVERAGE, diagnostics),
+            ratelimit_burst: self.number_label::<u32>(LABEL_MIDDLEWARE_RATELIMIT_BURST, diagnostics),
+            basic_auth: self.labels.get(LABEL_MIDDLEWARE_BASIC_AUTH).cloned(),
+            forward_auth: self.forward_auth_label(diagnostics),
+            redirect_scheme: self.labels.get(LABEL_MIDDLEWARE_REDIRECT_SCHEME).cloned(),
+            redirect_regex: self.labels.get(LABEL_MIDDLEWARE_REDIRECT_REGEX).cloned(),
+            error_pages: self.error_pages_label(diagnostics),
+            order: self.middleware_order_label(diagnostics),
+        };
+        (config != MiddlewareConfig { order: config.order.clone(), ..Default::default() }).then_some(config)
+    }
+
+    /// One `pingap.services.<service>.*` group seen as a container of its
+    /// own: the shared `pingap.*` labels, overridden by the group's labels.
+    /// A group with its own routing labels doesn't inherit the shared ones.
+    fn indexed_service(&self, service: &str) -> ContainerInfo {
+        let prefix = format!("{}{}.", LABEL_SERVICES_PREFIX, service);
+        let own = self.labels.iter()
+            .filter_map(|(key, value)| key.strip_prefix(&prefix).map(|key| (indexed_label(key, &prefix), value.clone())))
+            .collect::<HashMap<_, _>>();
+        let routed = own.keys().any(|key| ROUTING_LABELS.contains(&key.as_str()));
+
+        let mut labels = self.labels.iter()
+            .filter(|(key, _)| !key.starts_with(LABEL_SERVICES_PREFIX))
+            .filter(|(key, _)| !(routed && ROUTING_LABELS.contains(&key.as_str())))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<HashMap<_, _>>();
+        labels.insert(LABEL_SERVICE_NAME.to_string(), indexed_service_name(&self.labels, &self.name, service));
+        labels.extend(own);
+        self.with_labels(labels)
+    }
+
+    /// The container's own addresses: its IPs (or DNS name) with the
+    /// service port.
+    fn upstream_addresses(&self) -> Result<Vec<String>> {
+        // Get address mode: "ip" (default) pins the current container IP,
+        // "dns" registers a name resolved by Docker's embedded DNS instead
+        let address_mode = self.labels.get(LABEL_SERVICE_ADDRESS_MODE)
+            .map(|m| m.as_str())
+            .unwrap_or("ip");
+
+        // Get host part of the upstream addresses (IPs with network override support, or DNS name)
+        let hosts = match address_mode {
+            "ip" => self.resolve_ips()?,
+            "dns" => vec![self.dns_name()],
+            other => {
+                return Err(anyhow!("Invalid {} '{}' on container {}. Expected 'ip' or 'dns'",
+                    LABEL_SERVICE_ADDRESS_MODE, other, self.name));
             }
         };
 
-        // Phase 4: TLS Advanced Configuration
-        let tls_config = if tls == Some(true) {
-            let redirect = self.labels.get(LABEL_TLS_REDIRECT)
-                .map(|v| v == "true");
-            
-            let domains = self.labels.get(LABEL_TLS_DOMAINS)
-                .map(|s| s.split(',').map(|s| s.trim().to_string()).collect());
-            
-            Some(TlsConfig {
-                enabled: true,
-                redirect,
-                domains,
-            })
+        // Get Port (with explicit override support)
+        let port = if let Some(port_str) = self.labels.get(LABEL_SERVICE_PORT) {
+            port_str.parse::<u16>()
+                .map_err(|e| anyhow!("Invalid port '{}' on container {}: {}", port_str, self.name, e))?
         } else {
-            None
+            // Auto-detect first exposed port
+            *self.ports.first()
+                .ok_or_else(|| anyhow!("No exposed ports found for container {}. Use {} label to specify port explicitly.", 
+                    self.name, LABEL_SERVICE_PORT))?
         };
 
-        Ok(Some(PingapServiceConfig {
-            name,
-            upstreams: vec![address],
-            location: PingapLocation {
-                rule,
-                priority,
-                middlewares,
-                tls,
-            },
-            upstream_config,
-            health_check,
-            middleware_config,
-            tls_config,
-        }))
+        Ok(hosts.iter().map(|host| format!("{}:{}", host, port)).collect())
+    }
+
+    /// Hosts to register for the container: its primary IP by default, or
+    /// its address on each network of `pingap.docker.networks` (or the single
+    /// `pingap.docker.network`), in the families `pingap.docker.ip_family` asks for.
+    /// IPv6 addresses come bracketed, ready to take a port.
+    fn resolve_ips(&self) -> Result<Vec<String>> {
+        let (ipv4, ipv6) = match self.labels.get(LABEL_DOCKER_IP_FAMILY).map(|f| f.trim()).unwrap_or("ipv4") {
+            "ipv4" => (true, false),
+            "ipv6" => (false, true),
+            "dual" => (true, true),
+            other => {
+                return Err(anyhow!("Invalid {} '{}' on container {}. Expected 'ipv4', 'ipv6' or 'dual'",
+                    LABEL_DOCKER_IP_FAMILY, other, self.name));
+            }
+        };
+        let network_names = match (self.labels.get(LABEL_DOCKER_NETWORKS), self.labels.get(LABEL_DOCKER_NETWORK)) {
+            (Some(list), _) => list.split(',').map(str::trim).filter(|n| !n.is_empty()).collect::<Vec<_>>(),
+            (None, Some(network_name)) => vec![network_name.as_str()],
+            (None, None) => Vec::new(),
+        };
+
+        let mut hosts = Vec::new();
+        if network_names.is_empty() {
+            // Use default IP (first network or primary IP), and the IPv6 of the same network
+            let primary = self.ip_address.clone().or_else(|| self.networks.values().next().cloned());
+            if ipv4 {
+                hosts.extend(primary.clone());
+            }
+            if ipv6 {
+                let primary_network = self.networks.iter()
+                    .find(|(_, ip)| Some(*ip) == primary.as_ref())
+                    .map(|(network_name, _)| network_name);
+                hosts.extend(primary_network.and_then(|n| self.ipv6_networks.get(n))
+                    .or_else(|| self.ipv6_networks.values().next())
+                    .map(|ip| format!("[{}]", ip)));
+            }
+            if hosts.is_empty() {
+                return Err(anyhow!("No IP address found for container {}", self.name));
+            }
+            return Ok(hosts);
+        }
+
+        for network_name in &network_names {
+            let (v4, v6) = (self.networks.get(*network_name), self.ipv6_networks.get(*network_name));
+            if v4.is_none() && v6.is_none() {
+                return Err(anyhow!("Container {} is not connected to network '{}'. Available networks: {:?}",
+                    self.name, network_name, self.networks.keys().chain(self.ipv6_networks.keys()).collect::<BTreeSet<_>>()));
+            }
+            if ipv4 {
+                hosts.extend(v4.cloned());
+            }
+            if ipv6 {
+                hosts.extend(v6.map(|ip| format!("[{}]", ip)));
+            }
+        }
+        if hosts.is_empty() {
+            return Err(anyhow!("Container {} has no {} address on networks {:?}",
+                self.name, if ipv6 { "IPv6" } else { "IPv4" }, network_names));
+        }
+        Ok(hosts)
+    }
+
+    /// DNS name other containers on a shared network can reach this one by.
+    /// Swarm services resolve to all tasks via `tasks.<service>`, compose
+    /// services by their service name, anything else by container name.
+    fn dns_name(&self) -> String {
+        if let Some(service) = self.labels.get(LABEL_SWARM_SERVICE_NAME) {
+            format!("tasks.{}", service)
+        } else if let Some(service) = self.labels.get(LABEL_COMPOSE_SERVICE) {
+            service.clone()
+        } else {
+            self.name.trim_start_matches('/').to_string()
+        }
     }
 }
 
@@ -335,10 +1556,18 @@ impl ContainerInfo {
 mod tests {
     use super::*;
 
+    impl ContainerInfo {
+        /// The first service a container declares, most tests only need one.
+        fn parse_pingap_config(&self) -> Result<Option<PingapServiceConfig>> {
+            Ok(self.parse_pingap_configs()?.into_iter().next())
+        }
+    }
+
     fn create_test_container(labels: HashMap<String, String>) -> ContainerInfo {
         ContainerInfo {
             id: "test123".to_string(),
             name: "/test-container".to_string(),
+            image: "shop/web:1.4".to_string(),
             labels,
             ip_address: Some("192.168.1.100".to_string()),
             ports: vec![8080],
@@ -346,6 +1575,7 @@ mod tests {
                 ("bridge".to_string(), "172.17.0.2".to_string()),
                 ("custom".to_string(), "192.168.1.100".to_string()),
             ]),
+            ipv6_networks: HashMap::new(),
         }
     }
 
@@ -398,6 +1628,47 @@ mod tests {
         assert_eq!(config.upstreams[0], "172.17.0.2:8080");
     }
 
+    #[test]
+    fn test_multiple_networks() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_DOCKER_NETWORKS.to_string(), "custom, bridge".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+
+        let config = create_test_container(labels.clone()).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.upstreams, ["192.168.1.100:8080", "172.17.0.2:8080"]);
+
+        labels.insert(LABEL_DOCKER_NETWORKS.to_string(), "bridge,nonexistent".to_string());
+        assert!(create_test_container(labels).parse_pingap_config().is_err());
+    }
+
+    #[test]
+    fn test_ip_family() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        let parse = |labels: &HashMap<String, String>| {
+            let mut container = create_test_container(labels.clone());
+            container.ipv6_networks.insert("custom".to_string(), "fd00::64".to_string());
+            container.parse_pingap_config().map(|config| config.unwrap().upstreams)
+        };
+
+        labels.insert(LABEL_DOCKER_IP_FAMILY.to_string(), "dual".to_string());
+        assert_eq!(parse(&labels).unwrap(), ["192.168.1.100:8080", "[fd00::64]:8080"]);
+
+        labels.insert(LABEL_DOCKER_NETWORKS.to_string(), "bridge,custom".to_string());
+        assert_eq!(parse(&labels).unwrap(), ["172.17.0.2:8080", "192.168.1.100:8080", "[fd00::64]:8080"]);
+
+        labels.insert(LABEL_DOCKER_IP_FAMILY.to_string(), "ipv6".to_string());
+        assert_eq!(parse(&labels).unwrap(), ["[fd00::64]:8080"]);
+
+        labels.insert(LABEL_DOCKER_NETWORKS.to_string(), "bridge".to_string());
+        assert!(parse(&labels).is_err());
+
+        labels.insert(LABEL_DOCKER_IP_FAMILY.to_string(), "ipv5".to_string());
+        assert!(parse(&labels).is_err());
+    }
+
     #[test]
     fn test_priority() {
         let mut labels = HashMap::new();
@@ -424,6 +1695,38 @@ mod tests {
         assert_eq!(uc.strategy, Some("hash".to_string()));
     }
 
+    #[test]
+    fn test_replica_weights() {
+        let weight = |labels: &[(&str, &str)]| {
+            let mut labels = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+            labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+            labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+            let (config, diagnostics) = create_test_container(labels).parse_pingap_configs_with_diagnostics().unwrap().remove(0);
+            (config.upstream_config.and_then(|upstream| upstream.weight), diagnostics.len())
+        };
+        assert_eq!(weight(&[(LABEL_UPSTREAM_WEIGHTS, "3,1"), (LABEL_COMPOSE_CONTAINER_NUMBER, "1")]), (Some(3), 0));
+        assert_eq!(weight(&[(LABEL_UPSTREAM_WEIGHTS, "3,1"), (LABEL_COMPOSE_CONTAINER_NUMBER, "2")]), (Some(1), 0));
+        // Further replicas take the last weight
+        assert_eq!(weight(&[(LABEL_UPSTREAM_WEIGHTS, "3, 1"), (LABEL_COMPOSE_CONTAINER_NUMBER, "5")]), (Some(1), 0));
+        assert_eq!(weight(&[(LABEL_UPSTREAM_WEIGHTS, "3,1")]), (Some(3), 0));
+        // The container's own weight wins
+        assert_eq!(weight(&[(LABEL_UPSTREAM_WEIGHTS, "3,1"), (LABEL_UPSTREAM_WEIGHT, "7")]), (Some(7), 0));
+        assert_eq!(weight(&[(LABEL_UPSTREAM_WEIGHTS, "3,heavy")]), (None, 1));
+
+        // Replicas differing in weight alone share the location
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        labels.insert(LABEL_UPSTREAM_WEIGHTS.to_string(), "3,1".to_string());
+        let first = create_test_container(labels.clone()).parse_pingap_config().unwrap().unwrap();
+        labels.insert(LABEL_COMPOSE_CONTAINER_NUMBER.to_string(), "2".to_string());
+        let second = create_test_container(labels.clone()).parse_pingap_config().unwrap().unwrap();
+        assert!(first.same_except_addrs(&second));
+        labels.insert(LABEL_UPSTREAM_STRATEGY.to_string(), "hash".to_string());
+        let hashed = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert!(!first.same_except_addrs(&hashed));
+    }
+
     #[test]
     fn test_health_check() {
         let mut labels = HashMap::new();
@@ -435,7 +1738,7 @@ mod tests {
         let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
         let hc = config.health_check.unwrap();
         assert_eq!(hc.path, "/health");
-        assert_eq!(hc.interval, Some("10s".to_string()));
+        assert_eq!(hc.interval, Some(Duration::from_secs(10)));
     }
 
     #[test]
@@ -468,6 +1771,32 @@ mod tests {
         let tls = config.tls_config.unwrap();
         assert!(tls.enabled);
         assert_eq!(tls.redirect, Some(true));
+        assert!(!tls.has_server_options());
+    }
+
+    #[test]
+    fn test_tls_server_options() {
+        let labels = HashMap::from([
+            (LABEL_ENABLE.to_string(), "true".to_string()),
+            (LABEL_HTTP_HOST.to_string(), "app.local".to_string()),
+            (LABEL_TLS_ENABLED.to_string(), "true".to_string()),
+            (LABEL_TLS_MIN_VERSION.to_string(), "1.3".to_string()),
+            (LABEL_TLS_CIPHERS.to_string(), "ECDHE-RSA-AES128-GCM-SHA256:ECDHE-RSA-AES256-GCM-SHA384, TLS_AES_256_GCM_SHA384,bad cipher".to_string()),
+            (LABEL_HTTP3_ENABLE.to_string(), "true".to_string()),
+        ]);
+        let (config, diagnostics) = create_test_container(labels.clone()).parse_pingap_configs_with_diagnostics().unwrap().remove(0);
+        let tls = config.tls_config.unwrap();
+        assert_eq!(tls.min_version.as_deref(), Some("1.3"));
+        assert_eq!(tls.ciphers.unwrap(), ["ECDHE-RSA-AES128-GCM-SHA256", "ECDHE-RSA-AES256-GCM-SHA384", "TLS_AES_256_GCM_SHA384"]);
+        assert_eq!(tls.http3, Some(true));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].label, LABEL_TLS_CIPHERS);
+
+        let mut labels = labels;
+        labels.insert(LABEL_TLS_MIN_VERSION.to_string(), "1.0".to_string());
+        let (config, diagnostics) = create_test_container(labels).parse_pingap_configs_with_diagnostics().unwrap().remove(0);
+        assert_eq!(config.tls_config.unwrap().min_version, None);
+        assert!(diagnostics.iter().any(|diagnostic| diagnostic.label == LABEL_TLS_MIN_VERSION));
     }
 
     #[test]
@@ -595,4 +1924,738 @@ mod tests {
         // Invalid priority should be None
         assert_eq!(config.location.priority, None);
     }
+
+    #[test]
+    fn test_dns_address_mode_compose_service() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        labels.insert(LABEL_SERVICE_ADDRESS_MODE.to_string(), "dns".to_string());
+        labels.insert(LABEL_COMPOSE_SERVICE.to_string(), "web".to_string());
+        
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.upstreams[0], "web:8080");
+    }
+
+    #[test]
+    fn test_dns_address_mode_swarm_service() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        labels.insert(LABEL_SERVICE_ADDRESS_MODE.to_string(), "dns".to_string());
+        labels.insert(LABEL_SWARM_SERVICE_NAME.to_string(), "myservice".to_string());
+        labels.insert(LABEL_COMPOSE_SERVICE.to_string(), "web".to_string());
+        
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.upstreams[0], "tasks.myservice:8080");
+    }
+
+    #[test]
+    fn test_dns_address_mode_without_ip() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        labels.insert(LABEL_SERVICE_ADDRESS_MODE.to_string(), "dns".to_string());
+        
+        let mut container = create_test_container(labels);
+        container.ip_address = None;
+        container.networks.clear();
+        
+        // Falls back to the container name and does not need an IP at all
+        let config = container.parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.upstreams[0], "test-container:8080");
+    }
+
+    #[test]
+    fn test_invalid_address_mode() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        labels.insert(LABEL_SERVICE_ADDRESS_MODE.to_string(), "hostname".to_string());
+        
+        assert!(create_test_container(labels).parse_pingap_config().is_err());
+    }
+
+    #[test]
+    fn test_wildcard_host() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "*.example.com".to_string());
+        
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.location.rule, "Host(`*.example.com`)");
+    }
+
+    #[test]
+    fn test_invalid_wildcard_host() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "api.*.example.com".to_string());
+        
+        assert!(create_test_container(labels).parse_pingap_config().is_err());
+    }
+
+    #[test]
+    fn test_host_regexp() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST_REGEXP.to_string(), "^(www|api)\\.example\\.com$".to_string());
+        labels.insert(LABEL_HTTP_PATHS.to_string(), "/v1".to_string());
+        
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.location.rule, "HostRegexp(`^(www|api)\\.example\\.com$`) && (PathPrefix(`/v1`))");
+    }
+
+    #[test]
+    fn test_host_and_host_regexp_combined() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "example.com".to_string());
+        labels.insert(LABEL_HTTP_HOST_REGEXP.to_string(), "^.+\\.example\\.org$".to_string());
+        
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.location.rule, "(Host(`example.com`) || HostRegexp(`^.+\\.example\\.org$`))");
+    }
+
+    #[test]
+    fn test_invalid_host_regexp() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST_REGEXP.to_string(), "(unclosed".to_string());
+        
+        assert!(create_test_container(labels).parse_pingap_config().is_err());
+    }
+
+    #[test]
+    fn test_unsupported_explicit_rule() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_RULE.to_string(), "Method(`GET`)".to_string());
+        
+        assert!(create_test_container(labels).parse_pingap_config().is_err());
+    }
+
+    #[test]
+    fn test_path_match_modes() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_PATHS.to_string(), "/api*,=/healthz,~^/v[0-9]+/".to_string());
+        
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(
+            config.location.rule,
+            "PathPrefix(`/api`) || Path(`/healthz`) || PathRegexp(`^/v[0-9]+/`)"
+        );
+    }
+
+    #[test]
+    fn test_invalid_path_entry() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_PATHS.to_string(), "/api,~[".to_string());
+        
+        assert!(create_test_container(labels).parse_pingap_config().is_err());
+    }
+
+    #[test]
+    fn test_header_and_cookie_match() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        labels.insert(LABEL_HTTP_PATHS.to_string(), "/api,/v1".to_string());
+        labels.insert(LABEL_HTTP_MATCH_HEADER.to_string(), "X-Beta:true".to_string());
+        labels.insert(LABEL_HTTP_MATCH_COOKIE.to_string(), "beta=1".to_string());
+
+        let config = create_test_container(labels.clone()).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(
+            config.location.rule,
+            "(Host(`app.local`) && (PathPrefix(`/api`) || PathPrefix(`/v1`))) && Header(`X-Beta`, `true`) && Cookie(`beta`, `1`)"
+        );
+
+        // A route that only beta testers get must never be widened to everyone
+        labels.insert(LABEL_HTTP_MATCH_HEADER.to_string(), "X-Beta".to_string());
+        assert!(create_test_container(labels).parse_pingap_config().is_err());
+    }
+
+    fn diagnostics_for(extra: &[(&str, &str)]) -> (PingapServiceConfig, Vec<LabelDiagnostic>) {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        for (k, v) in extra {
+            labels.insert(k.to_string(), v.to_string());
+        }
+        create_test_container(labels).parse_pingap_configs_with_diagnostics().unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_valid_labels_have_no_diagnostics() {
+        let (_, diagnostics) = diagnostics_for(&[
+            (LABEL_HTTP_PRIORITY, "10"),
+            (LABEL_UPSTREAM_STRATEGY, "hash"),
+            (LABEL_HEALTH_CHECK_PATH, "/health"),
+            (LABEL_HEALTH_CHECK_INTERVAL, "10s"),
+            (LABEL_HEADERS_CUSTOM_RESPONSE, "X-Served-By: Pingap,X-API-Version: 2.0"),
+            (LABEL_MIDDLEWARE_COMPRESS, "false"),
+        ]);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_numbers_are_reported() {
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_HTTP_PRIORITY, "high"),
+            (LABEL_MIDDLEWARE_RATELIMIT_AVERAGE, "-5"),
+        ]);
+        assert_eq!(config.location.priority, None);
+        assert_eq!(config.middleware_config.and_then(|m| m.ratelimit_average), None);
+
+        let labels = diagnostics.iter().map(|d| d.label.as_str()).collect::<Vec<_>>();
+        assert_eq!(labels, vec![LABEL_HTTP_PRIORITY, LABEL_MIDDLEWARE_RATELIMIT_AVERAGE]);
+        assert_eq!(diagnostics[0].value, "high");
+    }
+
+    #[test]
+    fn test_retry_labels() {
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_UPSTREAM_RETRIES, "2"),
+            (LABEL_UPSTREAM_RETRY_ON, "5xx, timeout,4xx"),
+            (LABEL_UPSTREAM_FAILOVER, "true"),
+        ]);
+        let upstream = config.upstream_config.unwrap();
+        assert_eq!(upstream.retries, Some(2));
+        assert_eq!(upstream.retry_on, Some(vec!["5xx".to_string(), "timeout".to_string()]));
+        assert_eq!(upstream.failover, Some(true));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].label, LABEL_UPSTREAM_RETRY_ON);
+    }
+
+    #[test]
+    fn test_undefined_profile_reported() {
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_PROFILE, "static"), (LABEL_MIDDLEWARE_COMPRESS, "true")]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].label, LABEL_PROFILE);
+        assert_eq!(config.middleware_config.and_then(|m| m.compress), Some(true));
+    }
+
+    #[test]
+    fn test_shared_upstream_label() {
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_UPSTREAM_REF, " api ")]);
+        assert_eq!(config.upstream_name(), "shared-api");
+        assert!(diagnostics.is_empty());
+        // Already in the namespace
+        assert_eq!(diagnostics_for(&[(LABEL_UPSTREAM_REF, "shared-api")]).0.upstream_name(), "shared-api");
+        assert_eq!(diagnostics_for(&[]).0.upstream_name(), "test-container");
+
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_UPSTREAM_REF, "api"), (LABEL_DEPLOYMENT_SLOT, "blue")]);
+        assert_eq!(config.upstream_name(), config.name);
+        assert_eq!(diagnostics[0].label, LABEL_UPSTREAM_REF);
+        assert_eq!(diagnostics_for(&[(LABEL_UPSTREAM_REF, "a/b")]).1.len(), 1);
+    }
+
+    #[test]
+    fn test_concurrency_labels() {
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_UPSTREAM_MAX_CONNECTIONS, "100"),
+            (LABEL_LOCATION_MAX_CONCURRENCY, "500"),
+        ]);
+        assert_eq!(config.upstream_config.unwrap().max_connections, Some(100));
+        assert_eq!(config.location.max_concurrency, Some(500));
+        assert!(diagnostics.is_empty());
+
+        // No request would get through a limit of 0
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_LOCATION_MAX_CONCURRENCY, "0"), (LABEL_UPSTREAM_MAX_CONNECTIONS, "lots")]);
+        assert_eq!(config.location.max_concurrency, None);
+        assert!(config.upstream_config.is_none());
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn test_middleware_order_drops_unknown_and_repeated_entries() {
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_MIDDLEWARE_COMPRESS, "true"),
+            (LABEL_MIDDLEWARE_ORDER, "strip_prefix,rewrite,compress,auth,compress"),
+        ]);
+        assert_eq!(config.middleware_config.and_then(|m| m.order), Some(vec!["strip_prefix".to_string(), "compress".to_string(), "auth".to_string()]));
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].problem.contains("'rewrite'"));
+        assert!(diagnostics[1].problem.contains("listed twice"));
+
+        // The path is rewritten before any plugin runs
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_MIDDLEWARE_COMPRESS, "true"), (LABEL_MIDDLEWARE_ORDER, "compress,strip_prefix")]);
+        assert_eq!(config.middleware_config.and_then(|m| m.order), Some(vec!["compress".to_string(), "strip_prefix".to_string()]));
+        assert!(diagnostics[0].problem.contains("runs before compress"));
+    }
+
+    #[test]
+    fn test_invalid_flag_reported_and_read_as_false() {
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_HEADERS_CORS_ENABLE, "yes")]);
+        assert_eq!(config.middleware_config.and_then(|m| m.cors_enabled), Some(false));
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].problem.contains("'true' or 'false'"));
+    }
+
+    #[test]
+    fn test_malformed_headers_dropped() {
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_HEADERS_CUSTOM_REQUEST, "X-Good: yes,broken,Bad Name: x"),
+        ]);
+        let headers = config.middleware_config.and_then(|m| m.custom_request_headers);
+        assert_eq!(headers, Some(vec!["X-Good: yes".to_string()]));
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].problem.contains("broken"));
+    }
+
+    #[test]
+    fn test_diagnostics_mask_credentials() {
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_HEADERS_CUSTOM_REQUEST, "Authorization: Bearer abc,broken"),
+            ("pingap.middleware.auth_token", "abc"),
+        ]);
+        let headers = config.middleware_config.and_then(|m| m.custom_request_headers);
+        assert_eq!(headers, Some(vec!["Authorization: Bearer abc".to_string()]));
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics.iter().all(|d| !d.value.contains("abc")));
+        assert!(diagnostics.iter().any(|d| d.value == "Authorization: ***,broken"));
+    }
+
+    #[test]
+    fn test_unknown_pingap_label_reported() {
+        let (_, diagnostics) = diagnostics_for(&[
+            ("pingap.http.hots", "typo.local"),
+            ("com.example.other", "ignored"),
+        ]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].label, "pingap.http.hots");
+        assert!(diagnostics[0].problem.contains("unknown label"));
+    }
+
+    #[test]
+    fn test_invalid_duration_and_strategy_dropped() {
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_HEALTH_CHECK_PATH, "/health"),
+            (LABEL_HEALTH_CHECK_TIMEOUT, "5 seconds"),
+            (LABEL_UPSTREAM_STRATEGY, "least_conn"),
+        ]);
+        assert_eq!(config.health_check.unwrap().timeout, None);
+        assert!(config.upstream_config.is_none());
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration(" 1m30s ").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("1h500ms").unwrap(), Duration::from_millis(3_600_500));
+        for junk in ["", "ten seconds", "10", "s", "10sec", "1.5s", "-1s", "99999999999999999h"] {
+            assert!(parse_duration(junk).is_err(), "{}", junk);
+        }
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+        assert_eq!(format_duration(Duration::from_secs(120)), "2m");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+
+        let (config, diagnostics) = diagnostics_for(&[
+            (LABEL_HEALTH_CHECK_PATH, "/health"),
+            (LABEL_HEALTH_CHECK_INTERVAL, "1m30s"),
+            (LABEL_HEALTH_CHECK_TIMEOUT, "10m"),
+        ]);
+        let health_check = config.health_check.unwrap();
+        assert_eq!(health_check.interval, Some(Duration::from_secs(90)));
+        assert_eq!(health_check.timeout, None);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].problem, "ignored, expected from 100ms to 5m");
+    }
+
+    #[test]
+    fn test_on_stop_policy() {
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_ON_STOP, "drain")]);
+        assert_eq!(config.on_stop, StopPolicy::Drain);
+        assert!(diagnostics.is_empty());
+
+        let (config, _) = diagnostics_for(&[]);
+        assert_eq!(config.on_stop, StopPolicy::Remove);
+
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_ON_STOP, "pause")]);
+        assert_eq!(config.on_stop, StopPolicy::Remove);
+        assert_eq!(diagnostics[0].label, LABEL_ON_STOP);
+    }
+
+    #[test]
+    fn test_templated_labels_rendered() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_SERVICE_NAME.to_string(), "{{ container_name }}".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), r#"{{ container_name | replace "-" "." }}.local"#.to_string());
+        let container = create_test_container(labels);
+
+        let config = container.parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.name, "test-container");
+        assert_eq!(config.location.rule, "Host(`test.container.local`)");
+    }
+
+    #[test]
+    fn test_invalid_template_rejected() {
+        let mut labels = HashMap::new();
+        labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+        labels.insert(LABEL_HTTP_HOST.to_string(), "{{ container_name | shout }}.local".to_string());
+        let container = create_test_container(labels);
+
+        let err = container.parse_pingap_config().unwrap_err();
+        assert!(err.to_string().contains(LABEL_HTTP_HOST));
+    }
+
+    fn multi_port_labels() -> HashMap<String, String> {
+        HashMap::from([
+            (LABEL_ENABLE.to_string(), "true".to_string()),
+            (LABEL_HTTP_HOST.to_string(), "app.local".to_string()),
+            (LABEL_MIDDLEWARE_COMPRESS.to_string(), "true".to_string()),
+            ("pingap.services.web.port".to_string(), "8080".to_string()),
+            ("pingap.services.metrics.port".to_string(), "9090".to_string()),
+            ("pingap.services.metrics.paths".to_string(), "/metrics".to_string()),
+            ("pingap.services.metrics.middleware.compress".to_string(), "false".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_indexed_services() {
+        let configs = create_test_container(multi_port_labels()).parse_pingap_configs_with_diagnostics().unwrap();
+        assert_eq!(configs.len(), 2);
+
+        let (metrics, diagnostics) = &configs[0];
+        assert!(diagnostics.is_empty());
+        assert_eq!(metrics.name, "test-container-metrics");
+        assert_eq!(metrics.upstreams, vec!["192.168.1.100:9090"]);
+        // Own routing labels replace the shared host
+        assert_eq!(metrics.location.rule, "PathPrefix(`/metrics`)");
+        assert_eq!(metrics.middleware_config.as_ref().unwrap().compress, Some(false));
+
+        let (web, _) = &configs[1];
+        assert_eq!(web.name, "test-container-web");
+        assert_eq!(web.upstreams, vec!["192.168.1.100:8080"]);
+        assert_eq!(web.location.rule, "Host(`app.local`)");
+        assert_eq!(web.middleware_config.as_ref().unwrap().compress, Some(true));
+    }
+
+    #[test]
+    fn test_indexed_service_names() {
+        let mut labels = multi_port_labels();
+        labels.insert(LABEL_SERVICE_NAME.to_string(), "shop".to_string());
+        labels.insert("pingap.services.metrics.name".to_string(), "shop-prometheus".to_string());
+
+        let names = create_test_container(labels.clone()).parse_pingap_configs().unwrap()
+            .into_iter()
+            .map(|config| config.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["shop-prometheus", "shop-web"]);
+        assert_eq!(declared_service_names(&labels, "/test-container"), names);
+
+        labels.retain(|key, _| !key.starts_with(LABEL_SERVICES_PREFIX));
+        assert_eq!(declared_service_names(&labels, "/test-container"), vec!["shop"]);
+
+        labels.insert(LABEL_SERVICE_NAME.to_string(), "{{ compose_service | default \"app\" }}".to_string());
+        assert_eq!(declared_service_names(&labels, "/test-container"), vec!["app"]);
+    }
+
+    #[test]
+    fn test_unknown_indexed_label_reported() {
+        let mut labels = multi_port_labels();
+        labels.insert("pingap.services.web.prot".to_string(), "80".to_string());
+        labels.insert("pingap.services.web.enable".to_string(), "false".to_string());
+
+        let configs = create_test_container(labels).parse_pingap_configs_with_diagnostics().unwrap();
+        let (_, diagnostics) = &configs[1];
+        let labels = diagnostics.iter().map(|d| d.label.as_str()).collect::<Vec<_>>();
+        assert_eq!(labels, vec!["pingap.services.web.enable", "pingap.services.web.prot"]);
+    }
+
+    #[test]
+    fn test_compose_service_name() {
+        // The default name SERVICE_NAME_STRATEGY=compose gives every container
+        let mut labels = HashMap::from([
+            (LABEL_ENABLE.to_string(), "true".to_string()),
+            (LABEL_HTTP_HOST.to_string(), "app.local".to_string()),
+            (LABEL_SERVICE_NAME.to_string(), "{{ compose_service }}".to_string()),
+            (LABEL_COMPOSE_SERVICE.to_string(), "web".to_string()),
+        ]);
+        let config = create_test_container(labels.clone()).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.name, "web");
+
+        // Outside of compose the name renders empty and the container name is used
+        labels.remove(LABEL_COMPOSE_SERVICE);
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.name, "test-container");
+    }
+
+    #[test]
+    fn test_origin() {
+        let mut container = create_test_container(HashMap::new());
+        assert_eq!(container.origin(), "image shop/web");
+        container.image = "registry.local:5000/shop/web@sha256:0123".to_string();
+        assert_eq!(container.origin(), "image registry.local:5000/shop/web");
+        container.labels.insert(LABEL_COMPOSE_PROJECT.to_string(), "shop".to_string());
+        assert_eq!(container.origin(), "compose project shop");
+    }
+
+    #[test]
+    fn test_discovered_labels() {
+        let shell = ["CMD-SHELL", "curl -f http://localhost:8080/healthz || exit 1"].map(String::from);
+        let labels = discovered_labels("{{ container_name }}.localhost", Some(&shell));
+        assert_eq!(labels[LABEL_HEALTH_CHECK_PATH], "/healthz");
+        let config = create_test_container(labels).parse_pingap_config().unwrap().unwrap();
+        assert_eq!(config.location.rule, "Host(`test-container.localhost`)");
+        assert_eq!(config.upstreams, ["192.168.1.100:8080"]);
+        assert_eq!(config.health_check.unwrap().path, "/healthz");
+
+        let exec = ["CMD", "wget", "-q", "--spider", "http://127.0.0.1"].map(String::from);
+        assert_eq!(healthcheck_path(&exec).as_deref(), Some("/"));
+        // Not an HTTP check, or no check at all
+        assert_eq!(healthcheck_path(&["CMD", "pg_isready"].map(String::from)), None);
+        assert_eq!(healthcheck_path(&["NONE".to_string()]), None);
+        assert!(!discovered_labels("app.localhost", None).contains_key(LABEL_HEALTH_CHECK_PATH));
+    }
+
+    #[test]
+    fn test_auto_discovered_precedence() {
+        let enable = |value: &str| HashMap::from([(LABEL_ENABLE.to_string(), value.to_string())]);
+        // Unlabeled containers follow the provider default
+        assert!(auto_discovered(&HashMap::new(), true));
+        assert!(!auto_discovered(&HashMap::new(), false));
+        // The container's own pingap.enable overrides it both ways
+        assert!(!auto_discovered(&enable("false"), true));
+        assert!(auto_discovered(&enable("true"), false));
+        assert!(!auto_discovered(&enable("no"), true));
+        // Labels beyond pingap.enable are left to the regular parser
+        let mut labels = enable("true");
+        labels.insert(LABEL_HTTP_HOST.to_string(), "app.local".to_string());
+        assert!(!auto_discovered(&labels, true));
+    }
+
+    #[test]
+    fn test_names_every_service() {
+        let mut labels = HashMap::from([("pingap.services.web.port".to_string(), "80".to_string())]);
+        assert!(!names_every_service(&labels));
+        labels.insert("pingap.services.web.name".to_string(), "shop".to_string());
+        assert!(names_every_service(&labels));
+        labels.insert("pingap.services.admin.port".to_string(), "81".to_string());
+        assert!(!names_every_service(&labels));
+        labels.insert(LABEL_SERVICE_NAME.to_string(), "shop".to_string());
+        assert!(names_every_service(&labels));
+        assert!(!names_every_service(&HashMap::new()));
+    }
+
+    #[test]
+    fn test_depends_on() {
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_DEPENDS_ON, "api, auth,")]);
+        assert_eq!(config.depends_on, vec!["api".to_string(), "auth".to_string()]);
+        assert!(diagnostics.is_empty());
+
+        let (config, _) = diagnostics_for(&[]);
+        assert!(config.depends_on.is_empty());
+    }
+
+    /// Every declared label's documented example changes the parsed
+    /// config: none of them is parsed but never applied.
+    #[test]
+    fn test_every_label_example_applied() {
+        // Labels that only take effect next to another one
+        let context = HashMap::from([
+            (LABEL_HEALTH_CHECK_INTERVAL, (LABEL_HEALTH_CHECK_PATH, "/health")),
+            (LABEL_HEALTH_CHECK_TIMEOUT, (LABEL_HEALTH_CHECK_PATH, "/health")),
+            (LABEL_TLS_REDIRECT, (LABEL_TLS_ENABLED, "true")),
+            (LABEL_TLS_DOMAINS, (LABEL_TLS_ENABLED, "true")),
+            (LABEL_TLS_MIN_VERSION, (LABEL_TLS_ENABLED, "true")),
+            (LABEL_TLS_CIPHERS, (LABEL_TLS_ENABLED, "true")),
+            (LABEL_HTTP3_ENABLE, (LABEL_TLS_ENABLED, "true")),
+            (LABEL_MIDDLEWARE_ORDER, (LABEL_MIDDLEWARE_COMPRESS, "true")),
+        ]);
+        let parse = |labels: &[(&str, &str)]| {
+            let mut container = create_test_container(labels.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect());
+            container.ports = vec![80];
+            for network in ["proxy-net", "frontend", "backend"] {
+                container.networks.insert(network.to_string(), "10.0.0.2".to_string());
+            }
+            container.ipv6_networks.insert("custom".to_string(), "fd00::2".to_string());
+            container.parse_pingap_configs_with_diagnostics().unwrap().remove(0)
+        };
+
+        // Profiles come from PROFILES_FILE, the Docker client expands them
+        for spec in LABEL_SPECS.iter().filter(|spec| spec.name != LABEL_ENABLE && spec.name != LABEL_PROFILE) {
+            let mut labels = vec![(LABEL_ENABLE, "true"), (LABEL_HTTP_HOST, "app.local")];
+            labels.extend(context.get(spec.name).copied());
+            let (without, _) = parse(&labels);
+            labels.push((spec.name, spec.example));
+            let (with, diagnostics) = parse(&labels);
+            assert!(diagnostics.is_empty(), "{}: {:?}", spec.name, diagnostics);
+            assert_ne!(without, with, "{}={} is not applied", spec.name, spec.example);
+        }
+    }
+
+    #[test]
+    fn test_one_of_label_falls_back_to_default() {
+        assert_eq!(alternatives(&["ip", "dns"]), "'ip' or 'dns'");
+        assert_eq!(alternatives(&["remove", "drain", "keep"]), "'remove', 'drain' or 'keep'");
+
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_UPSTREAM_STRATEGY, " random ")]);
+        assert_eq!(config.upstream_config.unwrap().strategy.as_deref(), Some("random"));
+        assert!(diagnostics.is_empty());
+
+        let (_, diagnostics) = diagnostics_for(&[(LABEL_ON_STOP, "pause")]);
+        assert_eq!(diagnostics[0].problem, "ignored, expected 'remove', 'drain' or 'keep'");
+    }
+
+    #[test]
+    fn test_readme_documents_every_label() {
+        let readme = include_str!("../README.md");
+        for spec in LABEL_SPECS {
+            assert!(readme.contains(&format!("`{}`", spec.name)), "{} is missing from the README", spec.name);
+        }
+    }
+
+    #[test]
+    fn test_deployment_slot() {
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_SERVICE_NAME, "shop"), (LABEL_DEPLOYMENT_SLOT, "green")]);
+        assert!(diagnostics.is_empty());
+        assert_eq!(config.name, "shop-green");
+        assert_eq!(config.location_name(), "shop");
+        assert_eq!(config.deployment.unwrap().slot, Slot::Green);
+
+        let (config, diagnostics) = diagnostics_for(&[(LABEL_SERVICE_NAME, "shop"), (LABEL_DEPLOYMENT_SLOT, "red")]);
+        assert_eq!(config.name, "shop");
+        assert!(config.deployment.is_none());
+        assert_eq!(diagnostics[0].label, LABEL_DEPLOYMENT_SLOT);
+
+        let labels = HashMap::from([
+            (LABEL_ENABLE.to_string(), "true".to_string()),
+            (LABEL_SERVICE_NAME.to_string(), "shop".to_string()),
+            (LABEL_DEPLOYMENT_SLOT.to_string(), "blue".to_string()),
+        ]);
+        assert_eq!(declared_service_names(&labels, "/shop-1"), vec!["shop-blue"]);
+    }
+
+    #[test]
+    fn test_parse_parts() {
+        let container = create_test_container(HashMap::from([
+            (LABEL_HTTP_HOST.to_string(), "app.local".to_string()),
+            (LABEL_HTTP_PATHS.to_string(), "/api*".to_string()),
+            (LABEL_HTTP_MATCH_HEADER.to_string(), "X-Beta:true".to_string()),
+            (LABEL_TLS_ENABLED.to_string(), "true".to_string()),
+            (LABEL_TLS_REDIRECT.to_string(), "yes".to_string()),
+            (LABEL_MIDDLEWARES.to_string(), "auth, compress".to_string()),
+        ]));
+        assert_eq!(container.resolve_address().unwrap(), ["192.168.1.100:8080"]);
+        let rule = container.build_rule().unwrap();
+        assert!(rule.starts_with("Host(`app.local`) && ("), "{}", rule);
+        assert!(rule.contains("X-Beta"), "{}", rule);
+
+        let mut diagnostics = Vec::new();
+        let (tls, tls_config) = container.build_tls(&mut diagnostics);
+        assert_eq!(tls, Some(true));
+        assert_eq!(tls_config.unwrap().redirect, Some(false));
+        assert_eq!(diagnostics[0].label, LABEL_TLS_REDIRECT);
+        let (middlewares, middleware_config) = container.build_middlewares(&mut diagnostics);
+        assert_eq!(middlewares, Some(vec!["auth".to_string(), "compress".to_string()]));
+        assert!(middleware_config.is_none());
+
+        let mut labels = container.labels.clone();
+        labels.insert(LABEL_SERVICE_ADDRESS.to_string(), "backend:9000".to_string());
+        labels.remove(LABEL_HTTP_HOST);
+        labels.remove(LABEL_HTTP_PATHS);
+        let container = ContainerInfo { ip_address: None, ports: Vec::new(), ..create_test_container(labels) };
+        assert_eq!(container.resolve_address().unwrap(), ["backend:9000"]);
+        assert!(container.build_rule().is_err());
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let config = PingapServiceConfigBuilder::new("shop", vec!["10.0.0.1:80".to_string()], "Host(`shop.local`)").build();
+        assert_eq!(config.location.rule, "Host(`shop.local`)");
+        assert_eq!(config.on_stop, StopPolicy::Remove);
+        assert!(config.upstream_config.is_none() && !config.maintenance);
+
+        let config = PingapServiceConfigBuilder::new("shop", Vec::new(), "Host(`shop.local`)")
+            .with_priority(Some(5))
+            .with_maintenance(true)
+            .with_deployment(Some(Deployment { service: "shop".to_string(), slot: Slot::Blue }))
+            .build();
+        assert_eq!(config.name, "shop-blue");
+        assert_eq!(config.location_name(), "shop");
+        assert_eq!(config.location.priority, Some(5));
+        assert!(config.maintenance);
+    }
+
+    #[test]
+    fn test_invalid_upstream_addresses() {
+        for (label, value) in [
+            (LABEL_SERVICE_PORT, "0"),
+            (LABEL_SERVICE_ADDRESS, "backend"),
+            (LABEL_SERVICE_ADDRESS, "10.0.0.5:70000"),
+            (LABEL_SERVICE_ADDRESS, "fd00::5:9000"),
+            (LABEL_SERVICE_ADDRESS, "back end:9000"),
+        ] {
+            let container = create_test_container(HashMap::from([
+                (LABEL_ENABLE.to_string(), "true".to_string()),
+                (LABEL_HTTP_HOST.to_string(), "app.local".to_string()),
+                (label.to_string(), value.to_string()),
+            ]));
+            let error = container.parse_pingap_configs().unwrap_err().to_string();
+            assert!(error.contains("/test-container"), "{}", error);
+        }
+        for address in ["10.0.0.5:9000", "[fd00::5]:9000", "tasks.web_app:80", "backend:9000"] {
+            assert!(check_address(address).is_ok(), "{}", address);
+        }
+    }
+
+    mod fuzz {
+        use super::*;
+        use proptest::prelude::*;
+        use proptest::sample::select;
+
+        /// Values that are wrong for most labels
+        fn noise() -> impl Strategy<Value = String> {
+            prop_oneof![
+                "\\PC{0,16}",
+                "-?[0-9]{1,6}",
+                "[a-z0-9.:/*,\\[\\]_-]{0,24}",
+                "\\{\\{ ?[a-z_.]{0,10} ?\\}\\}",
+                "[{}\\[\\]\":a-z0-9 ,]{0,24}",
+                Just(String::new()),
+                Just("true".to_string()),
+            ]
+        }
+
+        /// A label the provider knows with its example or noise, one of
+        /// another service of the container, or an unknown one
+        fn label() -> impl Strategy<Value = (String, String)> {
+            let names = LABEL_SPECS.iter().filter(|spec| spec.name != LABEL_ENABLE).collect::<Vec<_>>();
+            prop_oneof![
+                6 => select(names).prop_flat_map(|spec| (Just(spec.name.to_string()), prop_oneof![Just(spec.example.to_string()), noise()])),
+                1 => ("pingap\\.services\\.[ab]\\.(name|http\\.host|http\\.paths|service\\.port|service\\.address)", noise()),
+                1 => ("pingap\\.[a-z_.0-9]{0,20}", noise()),
+                1 => ("\\PC{0,12}", noise()),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn test_fuzzed_labels(labels in proptest::collection::vec(label(), 0..12)) {
+                let mut labels = labels.into_iter().collect::<HashMap<_, _>>();
+                labels.insert(LABEL_ENABLE.to_string(), "true".to_string());
+                let container = create_test_container(labels);
+                match container.parse_pingap_configs_with_diagnostics() {
+                    Ok(services) => for (config, diagnostics) in services {
+                        for address in &config.upstreams {
+                            prop_assert!(check_address(address).is_ok(), "{} from {:?}", address, container.labels);
+                        }
+                        prop_assert!(rule::parse_rule(&config.location.rule).is_ok(), "{} from {:?}", config.location.rule, container.labels);
+                        for diagnostic in diagnostics {
+                            prop_assert!(diagnostic.label.starts_with("pingap.") && !diagnostic.problem.is_empty(), "{}", diagnostic);
+                        }
+                    },
+                    // Errors name the container they come from
+                    Err(e) => prop_assert!(e.to_string().contains(&container.name), "{} from {:?}", e, container.labels),
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,283 @@
+use anyhow::{bail, Result};
+use base64::Engine;
+use serde_json::{json, Value};
+use url::Url;
+use crate::models::{MiddlewareConfig, PingapServiceConfig};
+use crate::pingap::MANAGED_REMARK;
+
+/// Middlewares that run as Pingap plugins of the service's location, in the
+/// order they are attached unless `pingap.middleware.order` says otherwise.
+pub const PLUGIN_MIDDLEWARES: &[&str] = &["observability", "request_id", "redirect", "forward_auth", "auth", "ratelimit", "cors", "headers", "errors", "compress"];
+
+/// `pingap.middleware.strip_prefix`, which is the location's path rewrite
+/// rather than a plugin. Pingap rewrites the path before it runs the
+/// location's plugins, so it always comes first.
+pub const STRIP_PREFIX: &str = "strip_prefix";
+
+/// What `pingap.middleware.order` may list: [`STRIP_PREFIX`] and the plugin middlewares.
+pub const ORDERED_MIDDLEWARES: &[&str] = &[STRIP_PREFIX, "observability", "request_id", "redirect", "forward_auth", "auth", "ratelimit", "cors", "headers", "errors", "compress"];
+
+/// Name of the plugin generated for `middleware` of a location.
+pub fn plugin_name(location: &str, middleware: &str) -> String {
+    format!("{}-{}", location, middleware)
+}
+
+fn payload(middleware: &str, config: &MiddlewareConfig) -> Option<Value> {
+    let mut payload = match middleware {
+        "observability" => {
+            let tracing = config.tracing.unwrap_or(false);
+            if !tracing && config.sampling.is_none() {
+                return None;
+            }
+            json!({ "category": "telemetry", "tracing": tracing, "sampling_ratio": config.sampling.unwrap_or(1.0) })
+        },
+        "request_id" => {
+            config.request_id.filter(|enabled| *enabled)?;
+            json!({ "category": "request_id", "algorithm": "uuid", "header_name": "X-Request-Id" })
+        },
+        "redirect" => {
+            config.redirect_scheme.as_deref().filter(|scheme| *scheme == "https")?;
+            json!({ "category": "redirect", "http_to_https": true })
+        },
+        "forward_auth" => {
+            let address = config.forward_auth.as_deref()?;
+            json!({ "category": "forward_auth", "address": forward_auth_url(address).ok()?.as_str() })
+        },
+        "auth" => {
+            let credentials = config.basic_auth.as_deref()?.split(',')
+                .map(str::trim)
+                .filter(|credential| !credential.is_empty())
+                .map(|credential| base64::engine::general_purpose::STANDARD.encode(credential))
+                .collect::<Vec<_>>();
+            json!({ "category": "basic_auth", "authorizations": credentials })
+        },
+        "ratelimit" => {
+            let average = config.ratelimit_average?;
+            json!({ "category": "limit", "type": "rate", "tag": "ip", "max_count": average, "interval": "1s" })
+        },
+        "cors" => {
+            config.cors_enabled.filter(|enabled| *enabled)?;
+            json!({ "category": "cors", "allow_origin": "*" })
+        },
+        "headers" => {
+            let headers = config.custom_response_headers.as_ref()?;
+            json!({ "category": "response_headers", "add_headers": headers })
+        },
+        "errors" => {
+            let pages = config.error_pages.as_ref()?;
+            json!({ "category": "error_page", "pages": pages })
+        },
+        "compress" => {
+            config.compress.filter(|enabled| *enabled)?;
+            json!({ "category": "compression", "gzip_level": 6, "br_level": 6, "zstd_level": 3 })
+        },
+        _ => return None,
+    };
+    payload["remark"] = Value::String(MANAGED_REMARK.to_string());
+    Some(payload)
+}
+
+/// The plugins generated for a service's middleware labels as (name,
+/// payload), in the order its location runs them: the ones listed in
+/// `pingap.middleware.order` first, the others as in [`PLUGIN_MIDDLEWARES`].
+pub fn service_plugins(config: &PingapServiceConfig) -> Vec<(String, Value)> {
+    let Some(middleware) = &config.middleware_config else {
+        return Vec::new();
+    };
+    let mut order = middleware.order.iter().flatten().map(String::as_str).collect::<Vec<_>>();
+    for name in PLUGIN_MIDDLEWARES {
+        if !order.contains(name) {
+            order.push(name);
+        }
+    }
+    order.into_iter()
+        .filter_map(|name| payload(name, middleware).map(|payload| (plugin_name(config.location_name(), name), payload)))
+        .collect()
+}
+
+/// The plugins Pingap has that were generated for the locations of
+/// `configs` but that the services no longer ask for, e.g. after a
+/// middleware label was removed. Only plugins with the ownership marker count.
+pub fn stale_plugins(configs: &[PingapServiceConfig], actual: &Value) -> Vec<String> {
+    configs.iter()
+        .flat_map(|config| {
+            let wanted = service_plugins(config).into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            PLUGIN_MIDDLEWARES.iter()
+                .map(|middleware| plugin_name(config.location_name(), middleware))
+                .filter(move |name| !wanted.contains(name))
+        })
+        .filter(|name| actual["plugins"].get(name).is_some_and(|plugin| plugin["remark"] == MANAGED_REMARK))
+        .collect()
+}
+
+/// Plugins of `location` the provider generated for it, as opposed to the
+/// maintenance plugin or ones added by hand.
+pub fn generated_plugins(location_name: &str, location: &Value) -> Vec<String> {
+    let generated = PLUGIN_MIDDLEWARES.iter().map(|name| plugin_name(location_name, name)).collect::<Vec<_>>();
+    location["plugins"].as_array().into_iter().flatten()
+        .filter_map(Value::as_str)
+        .filter(|plugin| generated.iter().any(|name| name == plugin))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The URL of a `pingap.middleware.forward_auth.address`; a bare name like
+/// `auth` stands for `http://auth/`.
+pub fn forward_auth_url(address: &str) -> Result<Url> {
+    let url = match address.contains("://") {
+        true => Url::parse(address),
+        false => Url::parse(&format!("http://{}/", address)),
+    };
+    match url {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => Ok(url),
+        Ok(_) => bail!("expected an http(s) URL or a service name"),
+        Err(e) => bail!("expected an http(s) URL or a service name: {}", e),
+    }
+}
+
+/// The service a forward auth address names by its host, e.g. `auth` of
+/// `http://auth/verify`; resolved or not, it is tried as a service name.
+pub fn forward_auth_service(address: &str) -> Option<String> {
+    forward_auth_url(address).ok()?.host_str().map(str::to_string)
+}
+
+/// `address` with its host and port replaced by `upstream` (`ip:port`), the
+/// current address of the service the host names.
+pub fn resolve_forward_auth(address: &str, upstream: &str) -> Option<String> {
+    let url = forward_auth_url(address).ok()?;
+    let query = url.query().map(|query| format!("?{}", query)).unwrap_or_default();
+    Some(format!("{}://{}{}{}", url.scheme(), upstream, url.path(), query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::models::ContainerInfo;
+
+    fn parse(labels: &[(&str, &str)]) -> PingapServiceConfig {
+        let mut all = HashMap::from([
+            ("pingap.enable".to_string(), "true".to_string()),
+            ("pingap.http.host".to_string(), "app.local".to_string()),
+        ]);
+        all.extend(labels.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        let container = ContainerInfo {
+            id: "c1".to_string(),
+            name: "app".to_string(),
+            image: String::new(),
+            labels: all,
+            ip_address: Some("10.0.0.1".to_string()),
+            ports: vec![80],
+            networks: HashMap::new(),
+            ipv6_networks: HashMap::new(),
+        };
+        container.parse_pingap_configs().unwrap().remove(0)
+    }
+
+    fn names(config: &PingapServiceConfig) -> Vec<String> {
+        service_plugins(config).into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn test_default_and_labeled_order() {
+        let labels = [
+            ("pingap.middleware.compress", "true"),
+            ("pingap.middleware.basic_auth", "admin:secret"),
+            ("pingap.middleware.ratelimit.average", "100"),
+        ];
+        assert_eq!(names(&parse(&labels)), ["app-auth", "app-ratelimit", "app-compress"]);
+
+        let mut ordered = labels.to_vec();
+        ordered.push(("pingap.middleware.order", "compress, ratelimit"));
+        assert_eq!(names(&parse(&ordered)), ["app-compress", "app-ratelimit", "app-auth"]);
+    }
+
+    #[test]
+    fn test_plugin_payloads() {
+        let plugins = service_plugins(&parse(&[
+            ("pingap.middleware.basic_auth", "admin:secret,ops:pw"),
+            ("pingap.middleware.redirect_scheme", "https"),
+            ("pingap.headers.cors.enable", "false"),
+        ]));
+        assert_eq!(plugins, vec![
+            ("app-redirect".to_string(), json!({ "category": "redirect", "http_to_https": true, "remark": MANAGED_REMARK })),
+            ("app-auth".to_string(), json!({
+                "category": "basic_auth",
+                "authorizations": ["YWRtaW46c2VjcmV0", "b3BzOnB3"],
+                "remark": MANAGED_REMARK,
+            })),
+        ]);
+    }
+
+    #[test]
+    fn test_observability_plugin() {
+        assert!(service_plugins(&parse(&[("pingap.observability.tracing", "false")])).is_empty());
+        assert_eq!(service_plugins(&parse(&[
+            ("pingap.observability.tracing", "true"),
+            ("pingap.observability.sampling", "0.1"),
+            ("pingap.middleware.compress", "true"),
+        ]))[0], ("app-observability".to_string(), json!({
+            "category": "telemetry",
+            "tracing": true,
+            "sampling_ratio": 0.1,
+            "remark": MANAGED_REMARK,
+        })));
+
+        // Sampling alone thins out the request log without tracing
+        let config = parse(&[("pingap.observability.sampling", "1.5")]);
+        assert!(service_plugins(&config).is_empty());
+        let config = parse(&[("pingap.observability.sampling", "0.25")]);
+        assert_eq!(service_plugins(&config)[0].1["tracing"], false);
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let config = parse(&[
+            ("pingap.errors.502", "/maintenance.html"),
+            ("pingap.errors.404", " /missing.html "),
+            ("pingap.errors.200", "/ok.html"),
+            ("pingap.errors.503", ""),
+        ]);
+        assert_eq!(service_plugins(&config), vec![
+            ("app-errors".to_string(), json!({
+                "category": "error_page",
+                "pages": { "404": "/missing.html", "502": "/maintenance.html" },
+                "remark": MANAGED_REMARK,
+            })),
+        ]);
+    }
+
+    #[test]
+    fn test_forward_auth() {
+        let config = parse(&[("pingap.middleware.forward_auth.address", "auth")]);
+        assert_eq!(service_plugins(&config), vec![
+            ("app-forward_auth".to_string(), json!({ "category": "forward_auth", "address": "http://auth/", "remark": MANAGED_REMARK })),
+        ]);
+        assert!(parse(&[("pingap.middleware.forward_auth.address", "ftp://auth/")]).middleware_config.is_none());
+
+        assert_eq!(forward_auth_service("http://auth:9000/verify").as_deref(), Some("auth"));
+        assert_eq!(resolve_forward_auth("http://auth/verify?rd=1", "10.0.0.5:4181").as_deref(), Some("http://10.0.0.5:4181/verify?rd=1"));
+        assert_eq!(resolve_forward_auth("auth", "[fd00::5]:4181").as_deref(), Some("http://[fd00::5]:4181/"));
+    }
+
+    #[test]
+    fn test_generated_plugins_skip_foreign_ones() {
+        let location = json!({ "plugins": ["maintenance", "app-compress", "my-plugin", "app-auth"] });
+        assert_eq!(generated_plugins("app", &location), ["app-compress", "app-auth"]);
+        assert!(generated_plugins("app", &json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_stale_plugins() {
+        let config = parse(&[("pingap.middleware.compress", "true")]);
+        let actual = json!({ "plugins": {
+            "app-compress": { "category": "compression", "remark": MANAGED_REMARK },
+            "app-auth": { "category": "basic_auth", "remark": MANAGED_REMARK },
+            // Made by hand, or another location's
+            "app-cors": { "category": "cors" },
+            "other-auth": { "category": "basic_auth", "remark": MANAGED_REMARK },
+        } });
+        assert_eq!(stale_plugins(&[config], &actual), ["app-auth"]);
+        assert_eq!(ORDERED_MIDDLEWARES[1..], *PLUGIN_MIDDLEWARES);
+    }
+}
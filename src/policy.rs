@@ -0,0 +1,274 @@
+//! Exposure policy (`POLICY_FILE`): JSON rules every service's route is
+//! checked against before it is applied, e.g. "admin paths on public hosts
+//! need basic auth". A service breaking a rule is not applied.
+
+use std::fs;
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use crate::models::PingapServiceConfig;
+use crate::rule::{self, HostMatcher, PathMatcher};
+
+/// What a rule demands of the routes it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Requirement {
+    /// `pingap.middleware.basic_auth`
+    BasicAuth,
+    /// `pingap.http.tls.enabled`
+    Tls,
+    /// `pingap.middleware.ratelimit.average`
+    Ratelimit,
+}
+
+impl Requirement {
+    fn met_by(&self, config: &PingapServiceConfig) -> bool {
+        let middleware = config.middleware_config.as_ref();
+        match self {
+            Requirement::BasicAuth => middleware.is_some_and(|m| m.basic_auth.is_some()),
+            Requirement::Tls => config.tls_config.as_ref().is_some_and(|tls| tls.enabled),
+            Requirement::Ratelimit => middleware.is_some_and(|m| m.ratelimit_average.is_some()),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Requirement::BasicAuth => "basic_auth",
+            Requirement::Tls => "tls",
+            Requirement::Ratelimit => "ratelimit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Host globs like `*.example.com`; none matches every host
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Path prefixes like `/admin`; none matches every path
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Matching routes are refused outright
+    #[serde(default)]
+    pub deny: bool,
+    #[serde(default)]
+    pub require: Vec<Requirement>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    rules: Vec<Rule>,
+}
+
+/// A service refused by a rule of the policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyViolation {
+    pub service: String,
+    /// Name of the container the service comes from
+    pub container: String,
+    pub rule: String,
+    /// What the rule demands, e.g. "requires basic_auth"
+    pub problem: String,
+}
+
+/// A loaded `POLICY_FILE`.
+pub struct Policy {
+    path: String,
+    rules: Vec<(Rule, Vec<Regex>)>,
+}
+
+impl Policy {
+    /// Reads and checks the rules; a file that can't be used stops the provider from starting.
+    pub fn load(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).context(format!("Failed to read policy file {}", path))?;
+        Self::parse(path, &content)
+    }
+
+    pub fn parse(path: &str, content: &str) -> Result<Self> {
+        let file: PolicyFile = serde_json::from_str(content).context(format!("Invalid policy file {}", path))?;
+        let rules = file.rules.into_iter()
+            .map(|rule| {
+                if !rule.deny && rule.require.is_empty() {
+                    bail!("Policy rule {} neither denies nor requires anything", rule.name);
+                }
+                let hosts = rule.hosts.iter().map(|host| host_glob(host)).collect::<Result<Vec<_>>>()?;
+                Ok((rule, hosts))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { path: path.to_string(), rules })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// The rules `config`'s route breaks, as violations of `container`. A
+    /// route that can't be parsed can't be checked, so it is a violation too.
+    pub fn check(&self, config: &PingapServiceConfig, container: &str) -> Vec<PolicyViolation> {
+        let route = match rule::parse_rule(&config.location.rule) {
+            Ok(route) => route,
+            Err(e) => return vec![PolicyViolation {
+                service: config.name.clone(),
+                container: container.to_string(),
+                rule: "unparsable-route".to_string(),
+                problem: format!("route can't be checked: {:#}", e),
+            }],
+        };
+        self.rules.iter()
+            .filter(|(rule, hosts)| matches_hosts(&rule.hosts, hosts, &route.hosts) && matches_paths(&rule.paths, &route.paths))
+            .filter_map(|(rule, _)| {
+                let problem = if rule.deny {
+                    "denies the route".to_string()
+                } else {
+                    let missing = rule.require.iter()
+                        .filter(|requirement| !requirement.met_by(config))
+                        .map(Requirement::as_str)
+                        .collect::<Vec<_>>();
+                    if missing.is_empty() {
+                        return None;
+                    }
+                    format!("requires {}", missing.join(", "))
+                };
+                Some(PolicyViolation {
+                    service: config.name.clone(),
+                    container: container.to_string(),
+                    rule: rule.name.clone(),
+                    problem: match &rule.description {
+                        Some(description) => format!("{} ({})", problem, description),
+                        None => problem,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+/// `*` stands for any characters, hosts compare case-insensitively.
+fn host_glob(glob: &str) -> Result<Regex> {
+    let pattern = regex::escape(&glob.to_ascii_lowercase()).replace("\\*", ".*");
+    Regex::new(&format!("^{}$", pattern)).map_err(|e| anyhow!("Invalid policy host {}: {}", glob, e))
+}
+
+/// A route without hosts answers every host, and what a host regex matches
+/// can't be told, so both count as matching. A wildcard route matches every
+/// glob some host below its domain matches.
+fn matches_hosts(patterns: &[String], globs: &[Regex], hosts: &[HostMatcher]) -> bool {
+    globs.is_empty() || hosts.is_empty() || hosts.iter().any(|host| match host {
+        HostMatcher::Exact(host) => globs.iter().any(|glob| glob.is_match(&host.to_ascii_lowercase())),
+        HostMatcher::Wildcard(domain) => patterns.iter().any(|pattern| glob_matches_below(pattern, domain)),
+        HostMatcher::Regex(_) => true,
+    })
+}
+
+/// Whether the host glob `pattern` matches any `<name>.<domain>`. A glob
+/// without `*` has to be such a host itself. Otherwise any text can stand
+/// before its last literal part, so that part only has to line up with the
+/// end of `.<domain>`: `*.admin.example.com` and `*.com` both match below
+/// `example.com`.
+fn glob_matches_below(pattern: &str, domain: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let suffix = format!(".{}", domain.to_ascii_lowercase());
+    match pattern.rsplit_once('*') {
+        None => pattern.len() > suffix.len() && pattern.ends_with(&suffix),
+        Some((_, last)) => suffix.ends_with(last) || last.ends_with(&suffix),
+    }
+}
+
+/// A route prefix matches a rule prefix below or above it: `/` serves `/admin`
+/// too. Path regexes count as matching, like host regexes.
+fn matches_paths(prefixes: &[String], paths: &[PathMatcher]) -> bool {
+    prefixes.is_empty() || paths.is_empty() || paths.iter().any(|path| match path {
+        PathMatcher::Prefix(path) => prefixes.iter().any(|prefix| path.starts_with(prefix.as_str()) || prefix.starts_with(path.as_str())),
+        PathMatcher::Exact(path) => prefixes.iter().any(|prefix| path.starts_with(prefix.as_str())),
+        PathMatcher::Regex(_) => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{MiddlewareConfig, PingapServiceConfigBuilder};
+
+    const POLICY: &str = r#"{
+        "rules": [
+            { "name": "admin-auth", "description": "admin UIs stay private", "hosts": ["*.example.com"], "paths": ["/admin"], "require": ["basic_auth"] },
+            { "name": "no-internal", "hosts": ["*.internal"], "deny": true }
+        ]
+    }"#;
+
+    fn service(rule: &str) -> PingapServiceConfig {
+        PingapServiceConfigBuilder::new("web", vec!["10.0.0.1:80".to_string()], rule).build()
+    }
+
+    #[test]
+    fn test_rules_checked_against_route() {
+        let policy = Policy::parse("policy.json", POLICY).unwrap();
+        assert_eq!(policy.rule_count(), 2);
+
+        let violations = policy.check(&service("Host(`shop.example.com`) && PathPrefix(`/admin/users`)"), "shop");
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rule, "admin-auth");
+        assert_eq!(violations[0].problem, "requires basic_auth (admin UIs stay private)");
+        // A route on / serves /admin as well, one without a host answers every host
+        assert_eq!(policy.check(&service("Host(`shop.example.com`)"), "shop").len(), 1);
+        assert_eq!(policy.check(&service("PathPrefix(`/admin`)"), "shop").len(), 2);
+
+        assert!(policy.check(&service("Host(`shop.example.com`) && PathPrefix(`/api`)"), "shop").is_empty());
+        assert!(policy.check(&service("Host(`shop.example.org`) && PathPrefix(`/admin`)"), "shop").is_empty());
+        let mut protected = service("Host(`shop.example.com`) && PathPrefix(`/admin`)");
+        protected.middleware_config = Some(MiddlewareConfig { basic_auth: Some("admin:secret".to_string()), ..Default::default() });
+        assert!(policy.check(&protected, "shop").is_empty());
+
+        let denied = policy.check(&service("Host(`db.internal`)"), "db");
+        assert_eq!(denied[0].problem, "denies the route");
+    }
+
+    #[test]
+    fn test_wildcard_route_checked_against_hosts_below_it() {
+        let policy = Policy::parse("policy.json", r#"{
+            "rules": [
+                { "name": "admin-host", "hosts": ["admin.example.com"], "require": ["basic_auth"] },
+                { "name": "admin-subdomains", "hosts": ["*.admin.example.com"], "deny": true },
+                { "name": "no-internal", "hosts": ["*.internal"], "deny": true }
+            ]
+        }"#).unwrap();
+
+        let violations = policy.check(&service("Host(`*.example.com`) && PathPrefix(`/admin`)"), "shop");
+        let rules = violations.iter().map(|violation| violation.rule.as_str()).collect::<Vec<_>>();
+        assert_eq!(rules, vec!["admin-host", "admin-subdomains"]);
+        // The route serves example.com's subdomains, none of them matches these
+        assert!(policy.check(&service("Host(`*.example.org`)"), "shop").is_empty());
+        assert!(policy.check(&service("Host(`*.shop.example.com`)"), "shop").iter().all(|violation| violation.rule != "admin-host"));
+
+        assert!(glob_matches_below("*.example.com", "example.com"));
+        assert!(glob_matches_below("*", "example.com"));
+        assert!(glob_matches_below("a*min.example.com", "example.com"));
+        assert!(!glob_matches_below("example.com", "example.com"));
+        assert!(!glob_matches_below("*.internal", "example.com"));
+    }
+
+    #[test]
+    fn test_unparsable_route_violates() {
+        let policy = Policy::parse("policy.json", POLICY).unwrap();
+        let violations = policy.check(&service("Host(`shop.example.com`"), "shop");
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rule, "unparsable-route");
+        assert!(violations[0].problem.starts_with("route can't be checked"));
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!(Policy::parse("p", r#"{ "rules": [{ "name": "empty" }] }"#).is_err());
+        assert!(Policy::parse("p", r#"{ "rules": [{ "name": "x", "deny": true, "typo": 1 }] }"#).is_err());
+        assert!(Policy::parse("p", r#"{ "rules": [{ "name": "x", "require": ["mfa"] }] }"#).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Label profiles (`PROFILES_FILE`): named bundles of labels a container
+//! picks with `pingap.profile=<name>`, so common kinds of services (an SPA,
+//! an API) don't repeat the same dozen labels.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use anyhow::{Context, Result};
+use crate::blob;
+use crate::models::{LABEL_CONFIG, LABEL_PROFILE};
+
+/// A loaded `PROFILES_FILE`.
+#[derive(Debug, Clone, Default)]
+pub struct Profiles {
+    /// Profile name -> its labels
+    profiles: BTreeMap<String, Vec<(String, String)>>,
+}
+
+impl Profiles {
+    /// Reads the profiles; a file that can't be used stops the provider from starting.
+    pub fn load(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).context(format!("Failed to read profiles file {}", path))?;
+        Self::parse(&content).context(format!("Invalid profiles file {}", path))
+    }
+
+    /// Profiles as a JSON object or YAML mapping of `pingap.config` documents by name.
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(Self { profiles: blob::expand_named(content)? })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.keys().map(String::as_str).collect()
+    }
+
+    /// `labels` on top of the labels of the profile they pick. Labels the
+    /// container sets, on their own or in `pingap.config`, win over the
+    /// profile's. A profile that isn't defined is left in place, for the
+    /// label parser to report.
+    pub fn apply(&self, mut labels: HashMap<String, String>) -> HashMap<String, String> {
+        let Some(profile) = labels.get(LABEL_PROFILE).and_then(|name| self.profiles.get(name.trim())) else {
+            return labels;
+        };
+        let documented = labels.get(LABEL_CONFIG)
+            .and_then(|document| blob::expand(document).ok())
+            .unwrap_or_default();
+        let mut merged = profile.iter()
+            .filter(|(label, _)| !documented.iter().any(|(own, _)| own == label))
+            .cloned()
+            .collect::<HashMap<_, _>>();
+        labels.remove(LABEL_PROFILE);
+        merged.extend(labels);
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = r#"{
+        "spa": { "middleware": { "compress": true } },
+        "api": { "headers.cors.enable": true, "middleware.ratelimit.average": 100 }
+    }"#;
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_profile_labels_lose_to_own_labels() {
+        let profiles = Profiles::parse(PROFILES).unwrap();
+        assert_eq!(profiles.names(), vec!["api", "spa"]);
+
+        let applied = profiles.apply(labels(&[
+            ("pingap.profile", "api"),
+            ("pingap.middleware.ratelimit.average", "10"),
+            ("pingap.config", r#"{"headers": {"cors": {"enable": false}}}"#),
+        ]));
+        assert_eq!(applied.get("pingap.middleware.ratelimit.average").map(String::as_str), Some("10"));
+        assert!(!applied.contains_key("pingap.headers.cors.enable"));
+        assert!(!applied.contains_key("pingap.profile"));
+
+        // Left for the parser to report
+        let unknown = labels(&[("pingap.profile", "static")]);
+        assert_eq!(profiles.apply(unknown.clone()), unknown);
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        let error = Profiles::parse("{\n  \"api\": { \"middleware\": { \"compres\": true } }\n}").unwrap_err();
+        assert!(error.to_string().contains("unknown label pingap.middleware.compres at line 2"), "{}", error);
+        assert!(Profiles::parse(r#"{"api": {"enable": true}}"#).is_err());
+        assert!(Profiles::parse(r#"["api"]"#).is_err());
+    }
+}
@@ -0,0 +1,3739 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use backoff::backoff::Backoff;
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, StreamExt};
+use serde_json::Value;
+use tokio::signal;
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tracing::{info, error, warn, debug};
+use crate::changelog::ACTOR;
+use crate::audit::Drift;
+use crate::config::{Config, ConflictPolicy, DriftPolicy, HostnamePolicy, ServiceNameStrategy};
+use crate::cursor::EventCursor;
+use crate::dns::{self, DnsRecords};
+use crate::docker::DockerClient;
+use crate::export::{self, ProviderState, ServiceState};
+use crate::flap::FlapDetector;
+use crate::health::{self, AddressHealth, Transition};
+use crate::hooks::{ConfigHook, HookOutcome};
+use crate::journal::{Entry, Journal};
+use crate::load::{Load, LoadWeights};
+use crate::logging::{ErrorGroups, LogControl};
+use crate::models::{self, ContainerInfo, LabelDiagnostic, PingapServiceConfig, StopPolicy, LABEL_COMPOSE_PROJECT};
+use crate::lifecycle::HookEvent;
+use crate::pingap::{Ownership, PingapClient};
+use crate::policy::{Policy, PolicyViolation};
+use crate::plugins;
+use crate::rule::{self, HostMatcher};
+use crate::state::Action;
+use crate::status::{RouteConflict, ServiceConflict, Status};
+
+/// Sync mode: keeps Pingap in line with the labels of running containers.
+pub struct Provider {
+    config: Config,
+    docker: DockerClient,
+    pingap: Arc<PingapClient>,
+    status: Arc<Status>,
+    // State tracking: ContainerID -> ServiceNames (several with `pingap.services.<service>.*` labels)
+    // This ensures we know which service to remove even if 'die' event lacks attributes or container is gone.
+    container_services: HashMap<String, BTreeSet<String>>,
+    // Service name -> the running containers behind it
+    replicas: HashMap<String, Replicas>,
+    // Service name -> when the tombstone of a service whose last container stopped expires (`TOMBSTONE_TTL_SECS`)
+    tombstones: HashMap<String, Instant>,
+    // Service name -> the upstream it shares with other services (`pingap.upstream.ref`), counting its users
+    shared_upstreams: HashMap<String, String>,
+    // Service name -> the forward auth address last written for it, resolved from the service it names
+    forward_auth: HashMap<String, String>,
+    // ContainerID -> (container name, labels that were dropped from its config)
+    label_diagnostics: HashMap<String, (String, Vec<LabelDiagnostic>)>,
+    flap: FlapDetector,
+    // Repeated identical write errors, logged once per `ERROR_LOG_WINDOW_SECS`
+    error_groups: ErrorGroups,
+    // Compose project -> stops collected so a whole stack going down is removed in one operation
+    project_stops: HashMap<String, ProjectStops>,
+    // Service name -> since when its first apply waits for its `pingap.depends_on` services
+    waiting: HashMap<String, Instant>,
+    // Services put into maintenance through the status API, as last picked up
+    maintenance: BTreeSet<String>,
+    // Whether a `FREEZE_WINDOWS` window is open, as of the last check
+    frozen: bool,
+    // Services whose writes are held back until the freeze window ends
+    held_back: BTreeSet<String>,
+    in_flight: InFlight,
+    done_tx: mpsc::Sender<OperationDone>,
+    done_rx: mpsc::Receiver<OperationDone>,
+    health: AddressHealth,
+    probe_tx: mpsc::Sender<Vec<(String, bool)>>,
+    probe_rx: mpsc::Receiver<Vec<(String, bool)>>,
+    /// Upstream weights of replicas by their load, see `LOAD_WEIGHT_INTERVAL_SECS`
+    loads: LoadWeights,
+    load_tx: mpsc::Sender<Vec<(String, Load)>>,
+    load_rx: mpsc::Receiver<Vec<(String, Load)>>,
+    // Toggled by SIGUSR2, absent when the provider doesn't own the global subscriber
+    log: Option<LogControl>,
+    // Time of the last handled event, kept across restarts (`EVENT_CURSOR_PATH`)
+    cursor: Option<EventCursor>,
+    // Set while the events missed before the initial sync are being replayed
+    replay: Option<Replay>,
+    // ContainerID -> when its last die/stop was handled
+    recent_stops: HashMap<String, Instant>,
+    // Time of the Docker event being handled, None outside of events
+    event_time: Option<SystemTime>,
+    // Service -> time of the oldest Docker event whose change isn't in Pingap yet
+    pending_since: HashMap<String, SystemTime>,
+    // ContainerID -> (container name, origin) of the replicas, to tell them from name collisions
+    origins: HashMap<String, (String, String)>,
+    // ContainerID -> services it was refused because unrelated containers run them
+    conflicts: HashMap<String, Vec<ServiceConflict>>,
+    /// ContainerID -> services of it whose routes overlap other services' routes
+    route_conflicts: HashMap<String, Vec<RouteConflict>>,
+    /// ContainerID -> services of it refused by `POLICY_FILE`
+    policy_violations: HashMap<String, Vec<PolicyViolation>>,
+    /// Services the initial sync left alone because Pingap has them without
+    /// the ownership marker, never deleted for a stopped container's labels
+    unowned: HashSet<String>,
+    // Start time of the Pingap process, as of the last poll
+    pingap_instance: Option<String>,
+    /// Applied services whose resources someone else changed in Pingap, as of the last poll
+    drift: Vec<Drift>,
+    /// (service, ContainerID) -> replicas whose address is kept out of the upstream (`pingap.warmup`)
+    warming: HashMap<(String, String), Warmup>,
+    /// Hosts whose DNS resolution has been checked against `HOSTNAME_EXPECTED_IPS`
+    checked_hosts: HashSet<String>,
+    /// `CONFIG_HOOK_SCRIPT`, run on every parsed service config
+    hook: Option<Arc<ConfigHook>>,
+    /// `DNS_PROVIDER` records of the hosts of applied services
+    dns: Option<Arc<DnsRecords>>,
+    /// `POLICY_FILE`, checked before a service is applied
+    policy: Option<Arc<Policy>>,
+    /// `JOURNAL_PATH`, the events acted on and the writes they led to
+    journal: Option<Arc<Journal>>,
+}
+
+/// Where a replica with `pingap.warmup` is in its warmup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Warmup {
+    /// Its Docker health check hasn't passed yet; the warmup starts once it does
+    AwaitingHealth(Duration),
+    Until(Instant),
+}
+
+/// How long after a container's stop another `die` or `stop` for it is a duplicate.
+const STOP_DEDUP_WINDOW: Duration = Duration::from_secs(5);
+
+/// What a Docker container event means for the container's services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContainerAction {
+    Started,
+    /// `die` or `stop`; Docker sends both for a regular shutdown
+    ServiceRemoved,
+    /// The container's health check passed
+    Healthy,
+    /// `destroy`: the container is gone for good
+    Destroyed,
+}
+
+impl ContainerAction {
+    fn from_event(action: &str) -> Option<Self> {
+        match action {
+            "start" => Some(Self::Started),
+            "die" | "stop" => Some(Self::ServiceRemoved),
+            "health_status: healthy" => Some(Self::Healthy),
+            "destroy" => Some(Self::Destroyed),
+            _ => None,
+        }
+    }
+}
+
+/// Events from the previous run's cursor up to the initial sync, replayed
+/// before live events.
+struct Replay {
+    since: i64,
+    /// When the initial sync listed the running containers
+    until: i64,
+    /// The containers it found running
+    running: HashSet<String>,
+}
+
+/// Result of a Pingap write that ran in the background.
+struct OperationDone {
+    /// (service name, container ID) pairs the write covered
+    targets: Vec<(String, String)>,
+    generation: u64,
+    operation: &'static str,
+    /// Upstream addresses the write sent to Pingap
+    addrs: Vec<String>,
+    /// Set when the write removed a whole compose project
+    project: Option<String>,
+    result: Result<()>,
+}
+
+/// The running containers of one service. `docker compose up --scale`
+/// starts several containers with the same labels, which share one upstream.
+struct Replicas {
+    /// Config of the most recently started replica
+    config: PingapServiceConfig,
+    /// ContainerID -> upstream addresses
+    addrs: BTreeMap<String, Vec<String>>,
+    /// ContainerID -> weight its labels give its addresses (`pingap.upstream.weight(s)`)
+    weights: BTreeMap<String, u32>,
+    /// Whether the location for `config` is in place, so replicas joining or
+    /// leaving only need the upstream addresses updated
+    applied: bool,
+}
+
+impl Replicas {
+    /// The service config with its upstream pointing at every replica.
+    fn merged_config(&self) -> PingapServiceConfig {
+        let addrs = self.addrs.values().flatten().cloned().collect::<BTreeSet<_>>();
+        PingapServiceConfig {
+            upstreams: addrs.into_iter().collect(),
+            ..self.config.clone()
+        }
+    }
+}
+
+/// Why `host` doesn't lead to the proxy, or None if it resolves to one of `expected`.
+async fn resolution_problem(host: &str, expected: &[IpAddr]) -> Option<String> {
+    let join = |ips: &mut dyn Iterator<Item = &IpAddr>| ips.map(ToString::to_string).collect::<Vec<_>>().join(", ");
+    match tokio::net::lookup_host((host, 0)).await {
+        Ok(addrs) => {
+            let ips = addrs.map(|addr| addr.ip()).collect::<BTreeSet<_>>();
+            (!ips.iter().any(|ip| expected.contains(ip)))
+                .then(|| format!("resolves to {} instead of the proxy ({})", join(&mut ips.iter()), join(&mut expected.iter())))
+        }
+        Err(e) => Some(format!("does not resolve ({})", e)),
+    }
+}
+
+/// Stops of one compose project collected until it has been quiet for a moment.
+struct ProjectStops {
+    deadline: Instant,
+    /// (service name, container ID)
+    targets: Vec<(String, String)>,
+}
+
+/// A spawned write, shared by every service it covers.
+struct Running {
+    generation: u64,
+    abort: AbortHandle,
+    done: Shared<BoxFuture<'static, ()>>,
+}
+
+/// The Pingap write currently running for each service. A newer desired
+/// state aborts the older write, so a stale config can't land after a fresher one.
+#[derive(Default)]
+struct InFlight {
+    next_generation: u64,
+    tasks: HashMap<String, Running>,
+    /// Services whose write was cancelled along with another service's, as
+    /// part of one write for both, and that nothing has been written for since
+    abandoned: BTreeSet<String>,
+}
+
+impl InFlight {
+    /// Spawns `op` as the latest write for `services` and returns whether an
+    /// unfinished older write was cancelled for any of them. Older tasks are
+    /// aborted right away and awaited before `op` starts; other services an
+    /// aborted one was writing end up in `abandoned`.
+    fn start<F>(&mut self, services: &[String], op: impl FnOnce(u64) -> F) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.next_generation += 1;
+        let generation = self.next_generation;
+
+        let previous = services.iter()
+            .filter_map(|service| self.tasks.remove(service))
+            .collect::<Vec<_>>();
+        let superseded = previous.iter().any(|running| !running.abort.is_finished());
+        for running in &previous {
+            if !running.abort.is_finished() {
+                // The other services of a cancelled write never hear back from it
+                let others = self.tasks.iter()
+                    .filter(|(_, other)| other.generation == running.generation)
+                    .map(|(service, _)| service.clone())
+                    .collect::<Vec<_>>();
+                for service in others {
+                    self.tasks.remove(&service);
+                    self.abandoned.insert(service);
+                }
+            }
+            running.abort.abort();
+        }
+        for service in services {
+            self.abandoned.remove(service);
+        }
+
+        let op = op(generation);
+        let handle = tokio::spawn(async move {
+            for running in previous {
+                running.done.await;
+            }
+            op.await;
+        });
+        let abort = handle.abort_handle();
+        let done = handle.map(|_| ()).boxed().shared();
+        for service in services {
+            self.tasks.insert(service.clone(), Running { generation, abort: abort.clone(), done: done.clone() });
+        }
+        superseded
+    }
+
+    /// Marks a write as done for `service`, returning false if a newer one has started since.
+    fn finish(&mut self, service: &str, generation: u64) -> bool {
+        match self.tasks.get(service) {
+            Some(running) if running.generation == generation => {
+                self.tasks.remove(service);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+fn record_outcome(status: &Status, backend: &str, operation: &str, result: &Result<()>) {
+    let outcome = if result.is_ok() { "success" } else { "error" };
+    status.metrics.inc("pingap_provider_operations_total", &[("operation", operation), ("outcome", outcome)]);
+    status.metrics.inc("pingap_provider_backend_writes_total", &[("backend", backend), ("outcome", outcome)]);
+}
+
+/// Splits configs ordered by dependencies into waves that can be applied
+/// concurrently: each service lands one wave after the last of its
+/// dependencies in `configs`.
+fn dependency_waves(configs: Vec<PingapServiceConfig>) -> Vec<Vec<PingapServiceConfig>> {
+    let mut wave_of: HashMap<String, usize> = HashMap::new();
+    let mut waves: Vec<Vec<PingapServiceConfig>> = Vec::new();
+    for config in configs {
+        let wave = config.depends_on.iter()
+            .filter_map(|dependency| wave_of.get(dependency))
+            .map(|wave| wave + 1)
+            .max()
+            .unwrap_or(0);
+        wave_of.insert(config.name.clone(), wave);
+        if waves.len() <= wave {
+            waves.resize_with(wave + 1, Vec::new);
+        }
+        waves[wave].push(config);
+    }
+    waves
+}
+
+/// Resident set size of the process, where `/proc` has it (Linux).
+fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status.lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim().trim_end_matches("kB").trim()
+        .parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+impl Provider {
+    pub fn new(config: Config, docker: DockerClient, pingap: PingapClient, status: Arc<Status>) -> Self {
+        let flap = FlapDetector::new(config.flap_threshold, config.flap_window, config.flap_hold_down);
+        let error_groups = ErrorGroups::new(config.error_log_window);
+        let (done_tx, done_rx) = mpsc::channel(config.resources.channel_capacity);
+        let (probe_tx, probe_rx) = mpsc::channel(config.resources.channel_capacity);
+        let health = AddressHealth::new(config.address_evict_after);
+        let (load_tx, load_rx) = mpsc::channel(config.resources.channel_capacity);
+        let loads = LoadWeights::new(config.load_weight_max, config.load_weight_cpu_cores, config.load_weight_memory);
+        Self {
+            config,
+            docker,
+            pingap: Arc::new(pingap),
+            status,
+            container_services: HashMap::new(),
+            replicas: HashMap::new(),
+            tombstones: HashMap::new(),
+            shared_upstreams: HashMap::new(),
+            forward_auth: HashMap::new(),
+            label_diagnostics: HashMap::new(),
+            flap,
+            error_groups,
+            project_stops: HashMap::new(),
+            waiting: HashMap::new(),
+            maintenance: BTreeSet::new(),
+            frozen: false,
+            held_back: BTreeSet::new(),
+            in_flight: InFlight::default(),
+            done_tx,
+            done_rx,
+            health,
+            probe_tx,
+            probe_rx,
+            loads,
+            load_tx,
+            load_rx,
+            log: None,
+            cursor: None,
+            replay: None,
+            recent_stops: HashMap::new(),
+            event_time: None,
+            pending_since: HashMap::new(),
+            origins: HashMap::new(),
+            conflicts: HashMap::new(),
+            route_conflicts: HashMap::new(),
+            policy_violations: HashMap::new(),
+            unowned: HashSet::new(),
+            pingap_instance: None,
+            drift: Vec::new(),
+            warming: HashMap::new(),
+            checked_hosts: HashSet::new(),
+            hook: None,
+            policy: None,
+            dns: None,
+            journal: None,
+        }
+    }
+
+    /// Runs every parsed service config through `hook` before it is applied.
+    pub fn with_config_hook(mut self, hook: Option<Arc<ConfigHook>>) -> Self {
+        self.hook = hook;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Option<Arc<Policy>>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Keeps a DNS record for every host of the applied services.
+    pub fn with_dns_records(mut self, dns: Option<Arc<DnsRecords>>) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    /// Journals the Docker events acted on and the writes they led to.
+    pub fn with_journal(mut self, journal: Option<Arc<Journal>>) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Lets SIGUSR2 toggle debug logging.
+    pub fn with_log_control(mut self, log: LogControl) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Replays the Docker events missed since `cursor` was last saved, and
+    /// keeps it up to date.
+    pub fn with_event_cursor(mut self, cursor: EventCursor) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+
+    /// Runs a Pingap write for `service` in the background, cancelling any
+    /// write for the same service that is still in progress.
+    fn spawn_operation(&mut self, service: String, container_id: String, operation: &'static str, config: Option<PingapServiceConfig>) {
+        self.note_pending(std::slice::from_ref(&service));
+        if self.held_by_freeze(operation) {
+            debug!("Holding back {} of service {} until the freeze window ends", operation, service);
+            self.held_back.insert(service);
+            return;
+        }
+        self.held_back.remove(&service);
+        let upstream = if config.is_none() { self.release_upstream(&service) } else { None };
+        let pingap = self.pingap.clone();
+        let done_tx = self.done_tx.clone();
+        let service_name = service.clone();
+        let addrs = config.as_ref().map(|config| config.upstreams.clone()).unwrap_or_default();
+        let superseded = self.in_flight.start(std::slice::from_ref(&service), move |generation| async move {
+            // The change log attributes the writes to the container that caused them
+            let result = ACTOR.scope(container_id.clone(), async {
+                match &config {
+                    Some(config) if operation == "scale" => pingap.scale(config).await,
+                    Some(config) => pingap.apply_config(config).await,
+                    None => pingap.delete_service(&service_name, upstream.as_deref()).await,
+                }
+            }).await;
+            let targets = vec![(service_name, container_id)];
+            let _ = done_tx.send(OperationDone { targets, generation, operation, addrs, project: None, result }).await;
+        });
+        if superseded {
+            info!("Cancelled in-flight Pingap write for service {}, a newer state arrived", service);
+            self.status.metrics.inc("pingap_provider_superseded_operations_total", &[]);
+        }
+        self.respawn_abandoned();
+    }
+
+    /// Writes the services whose write was cancelled along with another
+    /// service's again, each on its own.
+    fn respawn_abandoned(&mut self) {
+        for service in std::mem::take(&mut self.in_flight.abandoned) {
+            info!("Writing service {} again, its write was cancelled along with another service's", service);
+            self.spawn_replicas_write(&service, String::new());
+        }
+    }
+
+    /// Brings Pingap in line with the replicas of `service`: only the upstream
+    /// addresses when its location is already in place, a full apply when it
+    /// is not, and a delete once no replica is left. A full apply waits for
+    /// the service's dependencies.
+    fn spawn_replicas_write(&mut self, service: &str, container_id: String) {
+        self.note_pending(&[service.to_string()]);
+        let write = self.replicas.get(service)
+            .map(|replicas| (if replicas.applied { "scale" } else { "apply" }, self.desired_config(replicas)));
+        match write {
+            Some((operation, mut config)) => {
+                if operation == "apply" && self.hold_for_dependencies(&config) {
+                    return;
+                }
+                config.upstreams = self.without_warming(service, config.upstreams);
+                if config.upstreams.is_empty() {
+                    debug!("Every replica of service {} is warming up, writing it later", service);
+                    return;
+                }
+                config.upstreams = self.health.filter(config.upstreams);
+                config.upstreams = self.weigh_addrs(service, config.upstreams);
+                self.note_forward_auth(&config);
+                self.spawn_operation(service.to_string(), container_id, operation, Some(config));
+            },
+            None => {
+                self.waiting.remove(service);
+                self.forward_auth.remove(service);
+                self.spawn_operation(service.to_string(), container_id, "delete", None);
+            },
+        }
+        self.refresh_forward_auth(service);
+    }
+
+    /// Remembers the forward auth address written with `config`.
+    fn note_forward_auth(&mut self, config: &PingapServiceConfig) {
+        match config.middleware_config.as_ref().and_then(|middleware| middleware.forward_auth.clone()) {
+            Some(address) => self.forward_auth.insert(config.name.clone(), address),
+            None => self.forward_auth.remove(&config.name),
+        };
+    }
+
+    /// Writes the services whose `pingap.middleware.forward_auth.address`
+    /// names `service` again once its address changed, e.g. because the auth
+    /// container was recreated with another IP. Their location is rewritten
+    /// in full, the plugin holding the address comes with it.
+    fn refresh_forward_auth(&mut self, service: &str) {
+        let stale = self.replicas.iter()
+            .filter(|(name, replicas)| name.as_str() != service && replicas.applied && !replicas.addrs.is_empty())
+            .filter(|(_, replicas)| {
+                let address = replicas.config.middleware_config.as_ref().and_then(|middleware| middleware.forward_auth.as_deref());
+                address.and_then(plugins::forward_auth_service).as_deref() == Some(service)
+            })
+            .filter(|(name, replicas)| {
+                let desired = self.desired_config(replicas).middleware_config.and_then(|middleware| middleware.forward_auth);
+                self.forward_auth.get(name.as_str()) != desired.as_ref()
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in stale {
+            info!("Forward auth service {} moved, writing service {} again", service, name);
+            if let Some(replicas) = self.replicas.get_mut(&name) {
+                replicas.applied = false;
+            }
+            self.spawn_replicas_write(&name, String::new());
+        }
+    }
+
+    /// The address a forward auth target `service` is reached at: one of
+    /// its replicas that is warmed up and not evicted.
+    fn forward_auth_upstream(&self, service: &str) -> Option<String> {
+        let replicas = self.replicas.get(service)?;
+        let addrs = replicas.addrs.values().flatten().cloned().collect::<BTreeSet<_>>().into_iter().collect();
+        self.health.filter(self.without_warming(service, addrs)).into_iter().min()
+    }
+
+    /// Brings Pingap to the desired state of every tracked service in one
+    /// background write: what the replicas ask for is compared with Pingap's
+    /// config, only the differences are written and managed resources no
+    /// service asks for anymore are deleted. Retained services and ones
+    /// waiting for their dependencies are left as Pingap has them.
+    fn spawn_converge(&mut self) {
+        let mut services = self.replicas.keys().cloned().collect::<Vec<_>>();
+        services.sort();
+        self.note_pending(&services);
+        if self.held_by_freeze("apply") {
+            debug!("Holding back {} services until the freeze window ends", services.len());
+            self.held_back.extend(services);
+            return;
+        }
+        let mut configs = Vec::new();
+        let mut hands_off = BTreeSet::new();
+        for service in &services {
+            let replicas = &self.replicas[service];
+            let retained = replicas.addrs.is_empty();
+            let mut config = self.desired_config(replicas);
+            config.upstreams = self.without_warming(service, config.upstreams);
+            if retained || config.upstreams.is_empty() || self.hold_for_dependencies(&config) {
+                hands_off.insert(config.name.clone());
+                hands_off.insert(config.upstream_name().to_string());
+                hands_off.insert(config.location_name().to_string());
+                hands_off.extend(plugins::service_plugins(&config).into_iter().map(|(name, _)| name));
+                continue;
+            }
+            self.held_back.remove(service);
+            config.upstreams = self.health.filter(config.upstreams);
+            config.upstreams = self.weigh_addrs(service, config.upstreams);
+            self.note_forward_auth(&config);
+            configs.push(config);
+        }
+
+        let pingap = self.pingap.clone();
+        let adopt = self.config.adopt_existing;
+        let done_tx = self.done_tx.clone();
+        let written = configs.iter().map(|config| config.name.clone()).collect::<Vec<_>>();
+        let superseded = self.in_flight.start(&written, move |generation| async move {
+            // Each service gets its own result, one failing doesn't fail the others
+            let mut failures = match pingap.converge(&configs, &hands_off, adopt).await {
+                Ok(convergence) => {
+                    if convergence.actions.is_empty() {
+                        debug!("Pingap already matches the desired state");
+                    } else {
+                        info!("Converged Pingap with {} actions: {}", convergence.actions.len(),
+                            convergence.actions.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "));
+                    }
+                    convergence.failures
+                },
+                Err(e) => configs.iter()
+                    .map(|config| (config.name.clone(), anyhow!("{:#}", e)))
+                    .collect(),
+            };
+            for config in &configs {
+                let result = failures.remove(&config.name).map_or(Ok(()), Err);
+                let targets = vec![(config.name.clone(), String::new())];
+                let addrs = config.upstreams.clone();
+                let _ = done_tx.send(OperationDone { targets, generation, operation: "converge", addrs, project: None, result }).await;
+            }
+            // Stale services and resources no tracked service owns
+            for (name, e) in failures {
+                error!("Failed to converge {}: {:?}", name, e);
+            }
+        });
+        if superseded {
+            self.status.metrics.inc("pingap_provider_superseded_operations_total", &[]);
+        }
+        self.respawn_abandoned();
+    }
+
+    /// What Pingap should serve for a service: its replicas merged, in
+    /// maintenance if its label or the status API asks for it.
+    fn desired_config(&self, replicas: &Replicas) -> PingapServiceConfig {
+        let mut config = replicas.merged_config();
+        config.maintenance |= self.maintenance.contains(&config.name);
+        // A shared upstream holds the addresses of every service using it
+        if config.upstream_name() != config.name {
+            let mut addrs = self.replicas.values()
+                .filter(|other| other.config.name != config.name && other.config.upstream_name() == config.upstream_name())
+                .flat_map(|other| other.addrs.values().flatten().cloned())
+                .collect::<BTreeSet<_>>();
+            addrs.extend(config.upstreams.drain(..));
+            config.upstreams = addrs.into_iter().collect();
+        }
+        // A forward auth service is followed to where it runs now; until it runs, its name is left to Docker's DNS
+        if let Some(middleware) = config.middleware_config.as_mut() {
+            let resolved = middleware.forward_auth.as_deref().and_then(|address| {
+                let upstream = self.forward_auth_upstream(&plugins::forward_auth_service(address)?)?;
+                plugins::resolve_forward_auth(address, &upstream)
+            });
+            if resolved.is_some() {
+                middleware.forward_auth = resolved;
+            }
+        }
+        config
+    }
+
+    /// Drops `service` from the users of its upstream and returns the
+    /// upstream to delete with it: its own one, or its shared upstream
+    /// (`pingap.upstream.ref`) once no other service uses it. While others
+    /// still do, one of them is written again so the shared upstream loses
+    /// the addresses of `service`.
+    fn release_upstream(&mut self, service: &str) -> Option<String> {
+        let Some(upstream) = self.shared_upstreams.remove(service) else {
+            return Some(service.to_string());
+        };
+        let user = self.shared_upstreams.iter()
+            .filter(|(_, shared)| **shared == upstream)
+            .map(|(user, _)| user.clone())
+            .min();
+        let Some(user) = user else {
+            info!("Service {} was the last one using upstream {}, removing it", service, upstream);
+            return Some(upstream);
+        };
+        debug!("Keeping upstream {} of service {} for {}", upstream, service, user);
+        if self.replicas.contains_key(&user) {
+            self.spawn_replicas_write(&user, String::new());
+        }
+        None
+    }
+
+    /// Whether `operation` has to wait for the freeze window to end. Removals
+    /// go through with `FREEZE_ALLOW_DELETES`.
+    fn held_by_freeze(&self, operation: &str) -> bool {
+        self.frozen && !(self.config.freeze_allow_deletes && operation == "delete")
+    }
+
+    /// Opens or closes the freeze window as of `now`. Closing it writes the
+    /// current state of every service whose changes were held back.
+    fn update_freeze(&mut self, now: SystemTime) {
+        let frozen = self.config.freeze_windows.contains(now);
+        if frozen == self.frozen {
+            return;
+        }
+        self.frozen = frozen;
+        if frozen {
+            info!("Freeze window started, holding back Pingap writes{}",
+                if self.config.freeze_allow_deletes { " except removals" } else { "" });
+        } else {
+            let held_back = std::mem::take(&mut self.held_back);
+            info!("Freeze window ended, writing {} held back services", held_back.len());
+            for service in held_back {
+                self.spawn_replicas_write(&service, String::new());
+            }
+        }
+        self.publish_status();
+    }
+
+    /// Picks up services put into or out of maintenance through the status
+    /// API and rewrites their locations.
+    fn sync_maintenance(&mut self) {
+        let requested = self.status.maintenance_requests();
+        if requested == self.maintenance {
+            return;
+        }
+        let changed = requested.symmetric_difference(&self.maintenance).cloned().collect::<Vec<_>>();
+        self.maintenance = requested;
+        for service in changed {
+            let entering = self.maintenance.contains(&service);
+            info!("Service {} {} maintenance", service, if entering { "enters" } else { "leaves" });
+            // Services without a running container pick it up when one starts
+            if let Some(replicas) = self.replicas.get_mut(&service) {
+                replicas.applied = false;
+                self.spawn_replicas_write(&service, String::new());
+            }
+        }
+        self.publish_status();
+    }
+
+    /// Services answered by the maintenance plugin, by label or through the API.
+    fn services_in_maintenance(&self) -> Vec<String> {
+        let mut services = self.maintenance.clone();
+        services.extend(self.replicas.values()
+            .filter(|replicas| replicas.config.maintenance)
+            .map(|replicas| replicas.config.name.clone()));
+        services.into_iter().collect()
+    }
+
+    /// Whether a `pingap.depends_on` service is in Pingap, applied by the
+    /// provider or configured there some other way.
+    fn dependency_ready(&self, service: &str) -> bool {
+        self.replicas.get(service).is_some_and(|replicas| replicas.applied)
+            || self.pingap.mirror_has_service(service)
+    }
+
+    fn missing_dependencies(&self, config: &PingapServiceConfig) -> Vec<String> {
+        config.depends_on.iter()
+            .filter(|dependency| !self.dependency_ready(dependency))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns true if the route of `config` has to wait for missing
+    /// dependencies, so a frontend doesn't serve 502s until its API exists.
+    /// Past `DEPENDENCY_TIMEOUT_SECS` it is activated anyway.
+    fn hold_for_dependencies(&mut self, config: &PingapServiceConfig) -> bool {
+        let missing = self.missing_dependencies(config);
+        if missing.is_empty() {
+            self.waiting.remove(&config.name);
+            return false;
+        }
+        let now = Instant::now();
+        let since = *self.waiting.entry(config.name.clone()).or_insert_with(|| {
+            info!("Holding back service {} until {:?} exist in Pingap", config.name, missing);
+            now
+        });
+        let timeout = self.config.dependency_timeout;
+        if timeout.is_zero() || now.duration_since(since) < timeout {
+            return true;
+        }
+        warn!("Dependencies {:?} of service {} still missing after {:?}, activating it anyway", missing, config.name, timeout);
+        self.waiting.remove(&config.name);
+        false
+    }
+
+    /// Applies the waiting services whose dependencies arrived or whose wait timed out.
+    fn release_waiting(&mut self) {
+        let mut services = self.waiting.keys().cloned().collect::<Vec<_>>();
+        services.sort();
+        for service in services {
+            if !self.replicas.contains_key(&service) {
+                self.waiting.remove(&service);
+                continue;
+            }
+            let ready = self.missing_dependencies(&self.replicas[&service].config).is_empty();
+            let expired = !self.config.dependency_timeout.is_zero()
+                && self.waiting[&service].elapsed() >= self.config.dependency_timeout;
+            if ready || expired {
+                self.spawn_replicas_write(&service, String::new());
+            }
+        }
+    }
+
+    /// Orders services so each comes after the services it depends on.
+    /// Returns that order and the services whose dependencies neither Pingap
+    /// nor `configs` provide (including dependency cycles).
+    fn order_by_dependencies(&self, mut pending: Vec<PingapServiceConfig>) -> (Vec<PingapServiceConfig>, Vec<PingapServiceConfig>) {
+        let mut ordered: Vec<PingapServiceConfig> = Vec::new();
+        loop {
+            let (ready, blocked): (Vec<_>, Vec<_>) = pending.into_iter().partition(|config| {
+                config.depends_on.iter().all(|dependency| {
+                    self.dependency_ready(dependency) || ordered.iter().any(|c| c.name == *dependency)
+                })
+            });
+            pending = blocked;
+            if ready.is_empty() {
+                return (ordered, pending);
+            }
+            ordered.extend(ready);
+        }
+    }
+
+    /// Records a running container of a service. A config that differs in
+    /// more than its addresses means the location has to be written again.
+    fn add_replica(&mut self, container_id: &str, config: PingapServiceConfig) {
+        self.cancel_project_stop(&config.name, container_id);
+        if self.tombstones.remove(&config.name).is_some() {
+            info!("Container {} brings back service {} from its tombstone", container_id, config.name);
+            self.status.metrics.inc("pingap_provider_tombstones_total", &[("outcome", "revived")]);
+        }
+        let shared = config.upstream_config.as_ref().and_then(|upstream| upstream.shared.clone());
+        if self.replicas.contains_key(&config.name) && self.shared_upstreams.get(&config.name) != shared.as_ref() {
+            // The location moves to another upstream, the one it leaves may have no users left
+            if let Some(upstream) = self.release_upstream(&config.name) {
+                self.pingap.add_orphans(&[format!("upstreams/{}", upstream)]);
+            }
+        }
+        match shared {
+            Some(upstream) => self.shared_upstreams.insert(config.name.clone(), upstream),
+            None => self.shared_upstreams.remove(&config.name),
+        };
+        let addrs = config.upstreams.clone();
+        let replicas = self.replicas.entry(config.name.clone()).or_insert_with(|| Replicas {
+            config: config.clone(),
+            addrs: BTreeMap::new(),
+            weights: BTreeMap::new(),
+            applied: false,
+        });
+        if !replicas.config.same_except_addrs(&config) {
+            replicas.applied = false;
+        }
+        match config.upstream_config.as_ref().and_then(|upstream| upstream.weight) {
+            Some(weight) => replicas.weights.insert(container_id.to_string(), weight),
+            None => replicas.weights.remove(container_id),
+        };
+        replicas.config = config;
+        replicas.addrs.insert(container_id.to_string(), addrs);
+    }
+
+    /// Adds a started container to its service, unless containers of another
+    /// origin already run a service of that name: that is a name collision,
+    /// not a replica, so the running ones keep the service and the conflict
+    /// is reported instead. Returns false if the container was refused.
+    fn claim_service(&mut self, container: &ContainerInfo, config: PingapServiceConfig) -> bool {
+        let origin = container.origin();
+        let running = self.replicas.get(&config.name)
+            .map(|replicas| replicas.addrs.keys()
+                .filter(|id| **id != container.id)
+                .filter_map(|id| self.origins.get(id))
+                .filter(|(_, running_origin)| *running_origin != origin)
+                .cloned()
+                .collect::<BTreeMap<_, _>>())
+            .unwrap_or_default();
+        if running.is_empty() {
+            if !self.check_policy(container, &config) || !self.check_hostnames(container, &config)
+                || !self.check_upstream(container, &config) || !self.check_routes(container, &config) {
+                return false;
+            }
+            self.origins.insert(container.id.clone(), (container.name.clone(), origin));
+            self.add_replica(&container.id, config);
+            return true;
+        }
+
+        let holders = running.iter().map(|(name, origin)| format!("{} ({})", name, origin)).collect::<Vec<_>>().join(", ");
+        error!("Service name conflict: container {} ({}) resolves to service {}, which {} already run. \
+            Not overwriting it; give one of them another {} label.",
+            container.name, origin, config.name, holders, models::LABEL_SERVICE_NAME);
+        self.status.record_error(format!("Service {} of container {} conflicts with {}", config.name, container.name, holders));
+        let conflicts = self.conflicts.entry(container.id.clone()).or_default();
+        conflicts.retain(|conflict| conflict.service != config.name);
+        conflicts.push(ServiceConflict {
+            service: config.name,
+            container: container.name.clone(),
+            origin,
+            running,
+        });
+        false
+    }
+
+    /// Refuses a service whose upstream would be another one's: a shared
+    /// upstream (`pingap.upstream.ref`) named like a service with an upstream
+    /// of its own, or a service named like a shared upstream in use. Their
+    /// addresses would be merged and both would keep rewriting it.
+    fn check_upstream(&mut self, container: &ContainerInfo, config: &PingapServiceConfig) -> bool {
+        let upstream = config.upstream_name();
+        let taken_by = match upstream == config.name {
+            true => self.shared_upstreams.iter()
+                .find(|(service, shared)| **service != config.name && **shared == config.name)
+                .map(|(service, _)| service.clone()),
+            false => self.replicas.get(upstream)
+                .filter(|replicas| replicas.config.upstream_name() == upstream)
+                .map(|replicas| replicas.config.name.clone()),
+        };
+        let Some(other) = taken_by else {
+            return true;
+        };
+        let message = format!("Service {} of container {} would write upstream {}, which is service {}'s; not applying it, rename one of them",
+            config.name, container.name, upstream, other);
+        error!("{}", message);
+        self.status.record_error(message);
+        false
+    }
+
+    /// Refuses a service whose route breaks a rule of `POLICY_FILE`,
+    /// reporting every violation and running `HOOK_POLICY_VIOLATION` for it.
+    fn check_policy(&mut self, container: &ContainerInfo, config: &PingapServiceConfig) -> bool {
+        let Some(policy) = self.policy.clone() else {
+            return true;
+        };
+        let violations = policy.check(config, &container.name);
+        let known = self.policy_violations.entry(container.id.clone()).or_default();
+        known.retain(|violation| violation.service != config.name);
+        if violations.is_empty() {
+            if known.is_empty() {
+                self.policy_violations.remove(&container.id);
+            }
+            return true;
+        }
+        for violation in &violations {
+            let message = format!("Policy rule {} refuses service {} of container {}: {}",
+                violation.rule, violation.service, violation.container, violation.problem);
+            error!("{}", message);
+            self.status.record_error(message);
+            self.status.metrics.inc("pingap_provider_policy_violations_total", &[("rule", &violation.rule)]);
+            if self.config.lifecycle_hooks.policy_violation.is_some() {
+                let hooks = self.config.lifecycle_hooks.clone();
+                let input = serde_json::json!(violation);
+                let service = violation.service.clone();
+                tokio::spawn(async move { hooks.notify(HookEvent::PolicyViolation, &service, &input).await });
+            }
+        }
+        known.extend(violations);
+        false
+    }
+
+    /// Looks for services routing the same host and path at the same
+    /// priority as `config`, which would leave Pingap to pick one of them.
+    /// Under `CONFLICT_POLICY=block` the service that has the route keeps it
+    /// and false is returned; otherwise the overlap is only reported.
+    fn check_routes(&mut self, container: &ContainerInfo, config: &PingapServiceConfig) -> bool {
+        let conflicts = self.route_conflicts.entry(container.id.clone()).or_default();
+        conflicts.retain(|conflict| conflict.service != config.name);
+        let Ok(route) = rule::parse_rule(&config.location.rule) else {
+            return true;
+        };
+        let priority = config.location.priority.unwrap_or_default();
+        let blocked = self.config.conflict_policy == ConflictPolicy::Block;
+        let mut overlaps = self.replicas.iter()
+            .filter(|(service, replicas)| **service != config.name && replicas.config.location.priority.unwrap_or_default() == priority)
+            .filter_map(|(service, replicas)| {
+                let other = rule::parse_rule(&replicas.config.location.rule).ok()?;
+                Some((service.clone(), route.overlap(&other)?))
+            })
+            .collect::<Vec<_>>();
+        overlaps.sort();
+        for (service, shared) in overlaps {
+            let message = format!("Route {} of service {} (container {}) is also routed by service {} at the same priority",
+                shared, config.name, container.name, service);
+            if blocked {
+                error!("{}; not applying it, CONFLICT_POLICY is block", message);
+            } else {
+                warn!("{}; which one answers is up to Pingap", message);
+            }
+            self.status.record_error(message);
+            conflicts.push(RouteConflict {
+                service: config.name.clone(),
+                container: container.name.clone(),
+                conflicts_with: service,
+                route: shared,
+                blocked,
+            });
+        }
+        let refused = blocked && conflicts.iter().any(|conflict| conflict.service == config.name);
+        if conflicts.is_empty() {
+            self.route_conflicts.remove(&container.id);
+        }
+        !refused
+    }
+
+    /// Refuses a service whose hosts have underscores under a strict
+    /// `HOSTNAME_POLICY`, and checks in the background that hosts not seen
+    /// before resolve to the proxy (`HOSTNAME_EXPECTED_IPS`): a host that
+    /// doesn't silently sends its traffic elsewhere.
+    fn check_hostnames(&mut self, container: &ContainerInfo, config: &PingapServiceConfig) -> bool {
+        let Ok(route) = rule::parse_rule(&config.location.rule) else {
+            return true;
+        };
+        if self.config.hostname_policy == HostnamePolicy::Strict {
+            let underscored = route.hosts.iter()
+                .filter_map(|host| match host {
+                    HostMatcher::Exact(host) | HostMatcher::Wildcard(host) => host.contains('_').then_some(host.as_str()),
+                    HostMatcher::Regex(_) => None,
+                })
+                .collect::<Vec<_>>();
+            if !underscored.is_empty() {
+                let message = format!("Service {} of container {} routes hosts with underscores ({}); not applying it, HOSTNAME_POLICY is strict",
+                    config.name, container.name, underscored.join(", "));
+                error!("{}", message);
+                self.status.record_error(message);
+                return false;
+            }
+        }
+        if self.config.hostname_expected_ips.is_empty() {
+            return true;
+        }
+        for host in &route.hosts {
+            let HostMatcher::Exact(host) = host else {
+                continue;
+            };
+            if !self.checked_hosts.insert(host.clone()) {
+                continue;
+            }
+            let (host, service) = (host.clone(), config.name.clone());
+            let expected = self.config.hostname_expected_ips.clone();
+            let status = self.status.clone();
+            tokio::spawn(async move {
+                if let Some(problem) = resolution_problem(&host, &expected).await {
+                    let message = format!("Host {} of service {} {}, its requests won't reach Pingap", host, service, problem);
+                    warn!("{}", message);
+                    status.record_error(message);
+                }
+            });
+        }
+        true
+    }
+
+    /// Forgets a stopped container of a service, returning true if other
+    /// replicas still back it.
+    fn remove_replica(&mut self, service: &str, container_id: &str) -> bool {
+        let Some(replicas) = self.replicas.get_mut(service) else {
+            return false;
+        };
+        replicas.addrs.remove(container_id);
+        replicas.weights.remove(container_id);
+        if replicas.addrs.is_empty() {
+            self.replicas.remove(service);
+            return false;
+        }
+        true
+    }
+
+    /// Forgets a stopped container but keeps the service tracked even without
+    /// replicas, for the `drain` and `keep` stop policies. Returns false if
+    /// the container wasn't a known replica.
+    fn detach_replica(&mut self, service: &str, container_id: &str) -> bool {
+        self.replicas.get_mut(service)
+            .is_some_and(|replicas| {
+                replicas.weights.remove(container_id);
+                replicas.addrs.remove(container_id).is_some()
+            })
+    }
+
+    /// Turns an applied service whose last container stopped into a
+    /// tombstone if `TOMBSTONE_TTL_SECS` is set: Pingap keeps serving it as
+    /// it is until the tombstone expires, so a container started again in
+    /// time only writes its addresses. Returns false if nothing was buried.
+    fn bury(&mut self, service: &str, container_id: &str, now: Instant) -> bool {
+        let last = self.replicas.get(service).is_some_and(|replicas| {
+            replicas.applied && replicas.addrs.len() == 1 && replicas.addrs.contains_key(container_id)
+        });
+        if self.config.tombstone_ttl.is_zero() || !last {
+            return false;
+        }
+        self.detach_replica(service, container_id);
+        self.tombstones.insert(service.to_string(), now + self.config.tombstone_ttl);
+        info!("Container {} was the last of service {}, keeping it as a tombstone for {}s",
+            container_id, service, self.config.tombstone_ttl.as_secs());
+        self.status.metrics.inc("pingap_provider_tombstones_total", &[("outcome", "buried")]);
+        true
+    }
+
+    /// Deletes the services whose tombstone expired without a container
+    /// starting again.
+    fn purge_tombstones(&mut self, now: Instant) {
+        let expired = self.tombstones.iter()
+            .filter(|(_, expires)| **expires <= now)
+            .map(|(service, _)| service.clone())
+            .collect::<Vec<_>>();
+        for service in expired {
+            self.tombstones.remove(&service);
+            if !self.replicas.get(&service).is_some_and(|replicas| replicas.addrs.is_empty()) {
+                continue;
+            }
+            info!("Tombstone of service {} expired, removing its config", service);
+            self.status.metrics.inc("pingap_provider_tombstones_total", &[("outcome", "purged")]);
+            self.replicas.remove(&service);
+            self.spawn_operation(service, String::new(), "delete", None);
+        }
+    }
+
+    /// Service -> seconds until its tombstone expires.
+    fn tombstone_expiries(&self, now: Instant) -> BTreeMap<String, u64> {
+        self.tombstones.iter()
+            .map(|(service, expires)| (service.clone(), expires.saturating_duration_since(now).as_secs()))
+            .collect()
+    }
+
+    /// The tracked state as `state export` writes it.
+    fn export_state(&self, now: Instant) -> ProviderState {
+        let tombstones = self.tombstone_expiries(now);
+        ProviderState {
+            version: export::STATE_VERSION,
+            exported_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            services: self.replicas.iter()
+                .map(|(service, replicas)| (service.clone(), ServiceState {
+                    config: replicas.config.clone(),
+                    containers: replicas.addrs.clone(),
+                    weights: replicas.weights.clone(),
+                    applied: replicas.applied,
+                    tombstone_secs: tombstones.get(service).copied(),
+                    // Replicas share their labels, so the first one's diagnostics stand for all
+                    label_diagnostics: replicas.addrs.keys()
+                        .find_map(|id| self.label_diagnostics.get(id))
+                        .map(|(_, diagnostics)| diagnostics.clone())
+                        .unwrap_or_default(),
+                }))
+                .collect(),
+            managed: self.pingap.mirrored_resources(true).unwrap_or_default(),
+            maintenance: self.maintenance.clone(),
+            held_back: self.held_back.clone(),
+            orphans: self.pingap.orphans(),
+        }
+    }
+
+    /// Merges a state exported by `state export`, e.g. on another host.
+    /// Services the provider tracks already keep their live state, and
+    /// replicas are found in Docker, so only services no container runs
+    /// (retained ones and tombstones) are taken over, together with the
+    /// maintenance requests and the removals still to be retried.
+    fn import_state(&mut self, state: ProviderState, now: Instant) {
+        let mut restored = Vec::new();
+        for (service, imported) in state.services {
+            if self.replicas.contains_key(&service) || !imported.containers.is_empty() {
+                continue;
+            }
+            // Written back, the masked credentials would lock everyone out
+            if imported.is_redacted() {
+                warn!("Not importing service {}: its credentials were masked by the status API, its container's next start brings it back", service);
+                continue;
+            }
+            // After a move Pingap may not have what the old host applied
+            let applied = imported.applied && self.pingap.mirror_has_service(&service);
+            if let Some(secs) = imported.tombstone_secs {
+                self.tombstones.insert(service.clone(), now + Duration::from_secs(secs));
+            }
+            if imported.config.upstream_name() != service {
+                self.shared_upstreams.insert(service.clone(), imported.config.upstream_name().to_string());
+            }
+            self.replicas.insert(service.clone(), Replicas {
+                config: imported.config,
+                addrs: BTreeMap::new(),
+                weights: BTreeMap::new(),
+                applied,
+            });
+            restored.push(service);
+        }
+        for service in &state.maintenance {
+            self.status.set_maintenance(service, true);
+        }
+        self.pingap.add_orphans(&state.orphans);
+        // Only a resource the provider created may be overwritten
+        if let Some(unmarked) = self.pingap.mirrored_resources(false) {
+            for resource in state.managed.iter().filter(|resource| unmarked.contains(resource)) {
+                warn!("Imported state owns {}, but Pingap has it without the ownership marker; set ADOPT_EXISTING=true to take it over", resource);
+            }
+        }
+        let message = format!("Imported provider state exported at {}: restored services {:?}, {} maintenance requests, {} removals to retry",
+            state.exported_at, restored, state.maintenance.len(), state.orphans.len());
+        info!("{}", message);
+        self.status.record_event(message);
+        if !state.held_back.is_empty() {
+            // Compared with Pingap again, writes still due are held back while frozen
+            self.spawn_converge();
+        }
+        self.publish_status();
+    }
+
+    /// Services left in Pingap by `pingap.on_stop=drain|keep` that no running container backs.
+    fn retained_services(&self) -> Vec<String> {
+        let mut retained = self.replicas.iter()
+            .filter(|(service, replicas)| replicas.addrs.is_empty() && !self.tombstones.contains_key(*service))
+            .map(|(service, _)| service.clone())
+            .collect::<Vec<_>>();
+        retained.sort();
+        retained
+    }
+
+    /// Service -> the dependencies it is waiting for.
+    fn waiting_for_dependencies(&self) -> BTreeMap<String, Vec<String>> {
+        self.waiting.keys()
+            .filter_map(|service| self.replicas.get(service))
+            .map(|replicas| (replicas.config.name.clone(), self.missing_dependencies(&replicas.config)))
+            .collect()
+    }
+
+    /// Removes the retained services, the explicit prune for the `drain` and
+    /// `keep` stop policies requested with `POST /prune`.
+    fn prune_retained(&mut self) {
+        for service in self.retained_services() {
+            info!("Pruning config for service {}, no container runs it anymore", service);
+            self.replicas.remove(&service);
+            self.spawn_operation(service, String::new(), "delete", None);
+        }
+    }
+
+    /// Records that Pingap now serves `service` with all of its current replicas.
+    fn mark_applied(&mut self, service: &str) {
+        self.unowned.remove(service);
+        if let Some(replicas) = self.replicas.get_mut(service) {
+            replicas.applied = true;
+            for container_id in replicas.addrs.keys() {
+                self.container_services.entry(container_id.clone()).or_default().insert(service.to_string());
+            }
+        }
+    }
+
+    /// Hosts of the services Pingap serves, which should have DNS records.
+    fn dns_hosts(&self) -> BTreeSet<String> {
+        self.replicas.values()
+            .filter(|replicas| replicas.applied)
+            .flat_map(|replicas| dns::service_hosts(&replicas.config))
+            .collect()
+    }
+
+    /// Brings the DNS records in line with the hosts of the services Pingap
+    /// serves, in the background.
+    fn sync_dns(&self) {
+        let Some(dns) = self.dns.clone() else {
+            return;
+        };
+        let desired = self.dns_hosts();
+        let status = self.status.clone();
+        tokio::spawn(async move {
+            let report = dns.sync(desired).await;
+            for host in &report.upserted {
+                info!("DNS record of {} points at the proxy", host);
+                status.metrics.inc("pingap_provider_dns_changes_total", &[("action", "upsert")]);
+            }
+            for host in &report.deleted {
+                info!("Removed DNS record of {}", host);
+                status.metrics.inc("pingap_provider_dns_changes_total", &[("action", "delete")]);
+            }
+            for host in &report.released {
+                warn!("Left the DNS record of {} alone, its ownership record is gone or another owner's", host);
+            }
+            for message in report.failed {
+                warn!("{}", message);
+                status.metrics.inc("pingap_provider_dns_changes_total", &[("action", "failed")]);
+                status.record_error(message);
+            }
+        });
+    }
+
+    /// Removes all services of a compose project that went down in one
+    /// background operation (a single PUT of the full config with batch apply).
+    fn spawn_project_delete(&mut self, project: String, targets: Vec<(String, String)>) {
+        self.note_pending(&targets.iter().map(|(service, _)| service.clone()).collect::<Vec<_>>());
+        if self.held_by_freeze("delete") {
+            self.held_back.extend(targets.into_iter().map(|(service, _)| service));
+            return;
+        }
+        for (service, _) in &targets {
+            self.held_back.remove(service);
+        }
+        let pingap = self.pingap.clone();
+        let done_tx = self.done_tx.clone();
+        let batch = self.config.batch_apply;
+        let services = targets.iter().map(|(service, _)| service.clone()).collect::<Vec<_>>();
+        let operation = if batch { "delete_batch" } else { "delete" };
+        // The batch sees in Pingap's config whether other locations still use a shared upstream
+        let upstreams = services.iter().map(|service| self.release_upstream(service)).collect::<Vec<_>>();
+        let names = services.clone();
+        let actor = targets.iter().map(|(_, container_id)| container_id.as_str()).collect::<Vec<_>>().join(",");
+        let superseded = self.in_flight.start(&services, move |generation| async move {
+            let result = ACTOR.scope(actor, async {
+                if batch {
+                    pingap.delete_batch(&names).await
+                } else {
+                    let mut result = Ok(());
+                    for (service, upstream) in names.iter().zip(&upstreams) {
+                        if let Err(e) = pingap.delete_service(service, upstream.as_deref()).await {
+                            result = Err(e);
+                        }
+                    }
+                    result
+                }
+            }).await;
+            let _ = done_tx.send(OperationDone { targets, generation, operation, addrs: Vec::new(), project: Some(project), result }).await;
+        });
+        if superseded {
+            self.status.metrics.inc("pingap_provider_superseded_operations_total", &[]);
+        }
+        self.respawn_abandoned();
+    }
+
+    /// Starts the event-to-apply clock of `services` when a Docker event
+    /// changes them. A change waiting behind an earlier one keeps the earlier start.
+    fn note_pending(&mut self, services: &[String]) {
+        let Some(event_time) = self.event_time else {
+            return;
+        };
+        for service in services {
+            self.pending_since.entry(service.clone()).or_insert(event_time);
+        }
+    }
+
+    /// Observes the time from the Docker event to its change being in Pingap.
+    fn observe_applied(&mut self, service: &str, now: SystemTime) {
+        if let Some(since) = self.pending_since.remove(service) {
+            let seconds = now.duration_since(since).unwrap_or_default().as_secs_f64();
+            self.status.metrics.observe("pingap_provider_event_to_apply_seconds", &[("backend", self.pingap.backend())], seconds);
+        }
+    }
+
+    fn handle_done(&mut self, done: OperationDone) {
+        record_outcome(&self.status, self.pingap.backend(), done.operation, &done.result);
+        if let Some(journal) = &self.journal {
+            for (service, container) in &done.targets {
+                journal.record(&Entry::write(service, container, done.operation, &done.result));
+            }
+        }
+        if done.result.is_err() {
+            self.health.record_apply_error(&done.addrs);
+        }
+        let current = done.targets.into_iter()
+            .filter(|(service, _)| self.in_flight.finish(service, done.generation))
+            .collect::<Vec<_>>();
+        if current.is_empty() {
+            debug!("Ignoring result of superseded {}", done.operation);
+            return;
+        }
+        let services = current.iter().map(|(service, _)| service.as_str()).collect::<Vec<_>>().join(", ");
+        match done.result {
+            Ok(()) => {
+                for (service, _) in &current {
+                    self.observe_applied(service, SystemTime::now());
+                }
+                if let Some(project) = &done.project {
+                    info!("Compose project {}: removed {} services ({})", project, current.len(), services);
+                }
+                if matches!(done.operation, "apply" | "scale" | "converge") {
+                    for (service, _) in &current {
+                        self.mark_applied(service);
+                    }
+                    self.release_waiting();
+                }
+                self.sync_dns();
+            },
+            Err(e) => {
+                if self.error_groups.record(&e.root_cause().to_string(), &services, Instant::now()) {
+                    error!("Failed to {} config for service {}: {:?}", done.operation, services, e);
+                }
+                self.status.record_error(format!("Failed to {} config for service {}: {}", done.operation, services, e));
+            },
+        }
+    }
+
+    /// Handles the results of background writes that already finished,
+    /// without waiting for the others. Returns how many were handled.
+    pub fn poll_writes(&mut self) -> usize {
+        let mut handled = 0;
+        while let Ok(done) = self.done_rx.try_recv() {
+            self.handle_done(done);
+            handled += 1;
+        }
+        handled
+    }
+
+    /// Handles background writes until none is left. Returns false if some
+    /// were still running after `timeout`.
+    pub async fn drain_writes(&mut self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.in_flight.tasks.is_empty() {
+            match tokio::time::timeout_at(deadline, self.done_rx.recv()).await {
+                Ok(Some(done)) => self.handle_done(done),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Service -> the containers tracked as its replicas.
+    pub fn tracked_replicas(&self) -> BTreeMap<String, BTreeSet<String>> {
+        self.replicas.iter()
+            .map(|(service, replicas)| (service.clone(), replicas.addrs.keys().cloned().collect()))
+            .collect()
+    }
+
+    /// Containers whose services have been applied.
+    pub fn tracked_containers(&self) -> BTreeSet<String> {
+        self.container_services.keys().cloned().collect()
+    }
+
+    /// Probes every upstream address in the background.
+    fn spawn_probes(&mut self) {
+        let addrs = self.replicas.values()
+            .flat_map(|replicas| replicas.addrs.values().flatten())
+            .map(String::as_str)
+            .collect::<HashSet<_>>();
+        self.health.retain(&addrs);
+        let addrs = addrs.into_iter().map(str::to_string).collect::<Vec<_>>();
+        let probe_tx = self.probe_tx.clone();
+        tokio::spawn(async move {
+            let _ = probe_tx.send(health::probe_all(addrs).await).await;
+        });
+    }
+
+    /// Evicts addresses that keep failing probes and re-adds recovered ones,
+    /// rewriting the upstreams they belong to.
+    fn handle_probes(&mut self, results: Vec<(String, bool)>) {
+        let mut changed = BTreeMap::new();
+        for (addr, reachable) in results {
+            let Some(transition) = self.health.record_probe(&addr, reachable) else {
+                continue;
+            };
+            for (service, replicas) in &self.replicas {
+                for (container_id, addrs) in &replicas.addrs {
+                    if addrs.contains(&addr) {
+                        changed.insert(service.clone(), container_id.clone());
+                    }
+                }
+            }
+            match transition {
+                Transition::Evicted => {
+                    warn!("Upstream address {} failed {} probes in a row, removing it from its upstream", addr, self.config.address_evict_after);
+                    self.status.metrics.inc("pingap_provider_address_evictions_total", &[]);
+                },
+                Transition::Recovered => {
+                    info!("Upstream address {} is reachable again, adding it back", addr);
+                    self.status.metrics.inc("pingap_provider_address_recoveries_total", &[]);
+                },
+            }
+        }
+        for (service, container_id) in changed {
+            self.spawn_replicas_write(&service, container_id);
+        }
+    }
+
+    /// Reads the load of the replicas of every scaled service in the background.
+    fn spawn_load_reads(&mut self) {
+        let ids = self.replicas.values()
+            .filter(|replicas| replicas.addrs.len() > 1)
+            .flat_map(|replicas| replicas.addrs.keys())
+            .map(String::as_str)
+            .collect::<HashSet<_>>();
+        self.loads.retain(&ids);
+        let reads = self.docker.container_loads(ids.into_iter().map(str::to_string).collect());
+        let load_tx = self.load_tx.clone();
+        tokio::spawn(async move {
+            let _ = load_tx.send(reads.await).await;
+        });
+    }
+
+    /// Rewrites the upstreams of replicas whose weight changed with their load.
+    fn handle_loads(&mut self, loads: Vec<(String, Load)>) {
+        let mut changed = BTreeMap::new();
+        for (container_id, load) in loads {
+            if !self.loads.record(&container_id, load) {
+                continue;
+            }
+            debug!("Container {} uses {:.2} CPU cores and {:.0}% of its memory, weight {}",
+                container_id, load.cpu, load.memory * 100.0, self.loads.weight(&container_id));
+            for (service, replicas) in &self.replicas {
+                if replicas.addrs.len() > 1 && replicas.addrs.contains_key(&container_id) && !replicas.weights.contains_key(&container_id) {
+                    changed.insert(service.clone(), container_id.clone());
+                }
+            }
+        }
+        for (service, container_id) in changed {
+            self.status.metrics.inc("pingap_provider_load_reweights_total", &[]);
+            self.spawn_replicas_write(&service, container_id);
+        }
+    }
+
+    /// Appends each replica's weight to its addresses (`10.0.0.1:80 7`) once
+    /// the service has several replicas: the one its labels give, else its
+    /// load weight when weighting is on.
+    fn weigh_addrs(&self, service: &str, addrs: Vec<String>) -> Vec<String> {
+        let replicas = match self.replicas.get(service) {
+            Some(replicas) if replicas.addrs.len() > 1 => replicas,
+            _ => return addrs,
+        };
+        let by_load = !self.config.load_weight_interval.is_zero();
+        addrs.into_iter()
+            .map(|addr| {
+                let weight = replicas.addrs.iter()
+                    .find(|(_, addrs)| addrs.contains(&addr))
+                    .and_then(|(id, _)| replicas.weights.get(id).copied().or_else(|| by_load.then(|| self.loads.weight(id))));
+                match weight {
+                    Some(weight) => format!("{} {}", addr, weight),
+                    None => addr,
+                }
+            })
+            .collect()
+    }
+
+    fn publish_status(&self) {
+        let (hits, misses) = self.docker.take_inspect_cache_stats();
+        self.status.metrics.add("pingap_provider_inspect_cache_total", &[("result", "hit")], hits);
+        self.status.metrics.add("pingap_provider_inspect_cache_total", &[("result", "miss")], misses);
+
+        let flapping = self.flap.flapping();
+        self.status.metrics.set_gauge("pingap_provider_services", &[], self.container_services.len() as f64);
+        self.status.metrics.set_gauge("pingap_provider_flapping_containers", &[], flapping.len() as f64);
+        self.status.metrics.set_gauge("pingap_provider_evicted_addresses", &[], self.health.evicted().len() as f64);
+        self.status.metrics.set_gauge("pingap_provider_held_back_services", &[], self.held_back.len() as f64);
+        self.status.metrics.set_gauge("pingap_provider_tombstones", &[], self.tombstones.len() as f64);
+        let mut conflicts = self.conflicts.values().flatten().cloned().collect::<Vec<_>>();
+        conflicts.sort_by(|a, b| (&a.service, &a.container).cmp(&(&b.service, &b.container)));
+        self.status.metrics.set_gauge("pingap_provider_service_conflicts", &[], conflicts.len() as f64);
+        let mut route_conflicts = self.route_conflicts.values().flatten().cloned().collect::<Vec<_>>();
+        route_conflicts.sort_by(|a, b| (&a.service, &a.conflicts_with).cmp(&(&b.service, &b.conflicts_with)));
+        let mut policy_violations = self.policy_violations.values().flatten().cloned().collect::<Vec<_>>();
+        policy_violations.sort_by(|a, b| (&a.service, &a.rule).cmp(&(&b.service, &b.rule)));
+        self.status.metrics.set_gauge("pingap_provider_policy_violations", &[], policy_violations.len() as f64);
+        for blocked in [false, true] {
+            let count = route_conflicts.iter().filter(|conflict| conflict.blocked == blocked).count();
+            let policy = if blocked { "block" } else { "warn" };
+            self.status.metrics.set_gauge("pingap_provider_route_conflicts", &[("policy", policy)], count as f64);
+        }
+        for (stage, depth) in [
+            ("in_flight", self.in_flight.tasks.len()),
+            ("waiting", self.waiting.len()),
+            ("grouping", self.project_stops.values().map(|stops| stops.targets.len()).sum()),
+            ("held_back", self.held_back.len()),
+            ("warming", self.warming.len()),
+        ] {
+            self.status.metrics.set_gauge("pingap_provider_queue_depth", &[("stage", stage)], depth as f64);
+        }
+        let failing_resources = self.pingap.failing_resources();
+        self.status.metrics.set_gauge("pingap_provider_failing_resources", &[], failing_resources.len() as f64);
+        let diagnostics_count = self.label_diagnostics.values().map(|(_, d)| d.len()).sum::<usize>();
+        self.status.metrics.set_gauge("pingap_provider_label_diagnostics", &[], diagnostics_count as f64);
+        for kind in ["missing", "different"] {
+            let count = self.drift.iter().filter(|drift| drift.kind() == kind).count();
+            self.status.metrics.set_gauge("pingap_provider_drift", &[("kind", kind)], count as f64);
+        }
+        self.status.update(|s| {
+            s.services = self.container_services.iter()
+                .map(|(k, v)| (k.clone(), v.iter().cloned().collect::<Vec<_>>().join(",")))
+                .collect();
+            s.flapping = flapping;
+            s.retained = self.retained_services();
+            s.tombstones = self.tombstone_expiries(Instant::now());
+            s.waiting_for_dependencies = self.waiting_for_dependencies();
+            s.maintenance = self.services_in_maintenance();
+            s.frozen = self.frozen;
+            s.held_back = self.held_back.iter().cloned().collect();
+            s.evicted_addresses = self.health.evicted();
+            s.address_apply_errors = self.health.apply_errors();
+            s.failing_resources = failing_resources;
+            s.label_diagnostics = self.label_diagnostics.values().cloned().collect();
+            s.service_conflicts = conflicts;
+            s.route_conflicts = route_conflicts;
+            s.policy_violations = policy_violations;
+            s.drift = self.drift.clone();
+        });
+        self.status.set_provider_state(self.export_state(Instant::now()));
+    }
+
+    fn set_docker_up(&self, up: bool) {
+        self.status.metrics.set_gauge("pingap_provider_docker_up", &[], if up { 1.0 } else { 0.0 });
+        self.status.update(|s| s.ready = up);
+    }
+
+    /// Parses a container's labels into its services, logging unusable
+    /// optional labels once per distinct set of problems and keeping them for
+    /// the status API. In strict mode such a container is skipped entirely
+    /// (returns no services).
+    fn parse_container(&mut self, container: &ContainerInfo) -> Result<Vec<PingapServiceConfig>> {
+        let mut service_configs = Vec::new();
+        let mut diagnostics = Vec::new();
+        for (service_config, service_diagnostics) in container.parse_pingap_configs_with_diagnostics()? {
+            if self.config.service_name_strategy == ServiceNameStrategy::Label && !models::names_every_service(&container.labels) {
+                return Err(anyhow!("Container {} has no {} label, which SERVICE_NAME_STRATEGY=label requires",
+                    container.name, models::LABEL_SERVICE_NAME));
+            }
+            if let Some(service_config) = self.run_hook(container, service_config) {
+                service_configs.push(service_config);
+            }
+            // Shared labels are diagnosed once per service
+            for diagnostic in service_diagnostics {
+                if !diagnostics.contains(&diagnostic) {
+                    diagnostics.push(diagnostic);
+                }
+            }
+        }
+        if diagnostics.is_empty() {
+            self.label_diagnostics.remove(&container.id);
+            return Ok(service_configs);
+        }
+
+        let strict = self.config.strict_labels;
+        if self.label_diagnostics.get(&container.id).map(|(_, d)| d) != Some(&diagnostics) {
+            if strict {
+                error!("Strict label mode: not applying container {} ({} invalid labels)", container.name, diagnostics.len());
+                self.status.metrics.inc("pingap_provider_strict_rejections_total", &[]);
+            }
+            for diagnostic in &diagnostics {
+                if strict {
+                    error!("Container {}: label {}", container.name, diagnostic);
+                } else {
+                    warn!("Container {}: label {}", container.name, diagnostic);
+                }
+            }
+            self.label_diagnostics.insert(container.id.clone(), (container.name.clone(), diagnostics));
+        }
+        if strict {
+            service_configs.clear();
+        }
+        Ok(service_configs)
+    }
+
+    /// The service config `CONFIG_HOOK_SCRIPT` makes of `config`, or None if
+    /// the hook refuses it or fails.
+    fn run_hook(&self, container: &ContainerInfo, config: PingapServiceConfig) -> Option<PingapServiceConfig> {
+        let Some(hook) = &self.hook else {
+            return Some(config);
+        };
+        let message = match hook.transform(&config, container) {
+            Ok(HookOutcome::Apply(transformed)) => {
+                if *transformed != config {
+                    debug!("Config hook changed service {} of container {}", config.name, container.name);
+                }
+                self.status.metrics.inc("pingap_provider_config_hook_total", &[("result", "applied")]);
+                return Some(*transformed);
+            }
+            Ok(HookOutcome::Veto(reason)) => {
+                self.status.metrics.inc("pingap_provider_config_hook_total", &[("result", "vetoed")]);
+                format!("Config hook refused service {} of container {}{}", config.name, container.name,
+                    reason.map(|reason| format!(": {}", reason)).unwrap_or_default())
+            }
+            Err(e) => {
+                self.status.metrics.inc("pingap_provider_config_hook_total", &[("result", "failed")]);
+                format!("{:#}, not applying it", e)
+            }
+        };
+        warn!("{}", message);
+        self.status.record_error(message);
+        None
+    }
+
+    /// Guards initial sync against silently overwriting resources that were
+    /// configured by hand in Pingap before the provider was deployed. A
+    /// service whose resources can't be checked is skipped too, the next
+    /// resync checks it again.
+    async fn may_take_ownership(&self, service_config: &PingapServiceConfig) -> bool {
+        match self.pingap.ownership(&service_config.name).await {
+            Ok(Ownership::Absent) | Ok(Ownership::Managed) => true,
+            Ok(Ownership::Unmanaged) if self.config.adopt_existing => {
+                info!("Adopting existing unmanaged Pingap resources for service {}", service_config.name);
+                true
+            },
+            Ok(Ownership::Unmanaged) => {
+                warn!("Skipping service {}: Pingap already has unmanaged resources with this name. \
+                    Start with --adopt-existing to take them over.", service_config.name);
+                false
+            },
+            Err(e) => {
+                let message = format!("Skipping service {}: could not check whether Pingap has unmanaged resources with this name, \
+                    trying again on the next resync: {:#}", service_config.name, e);
+                warn!("{}", message);
+                self.status.record_error(message);
+                false
+            }
+        }
+    }
+
+    pub async fn initial_sync(&mut self) -> Result<()> {
+        info!("Performing initial synchronization...");
+        // Populates the config mirror so ownership checks below need no extra requests
+        if let Err(e) = self.pingap.fetch_full_config().await {
+            warn!("Could not read Pingap's full config, checking services one by one: {:?}", e);
+        }
+        let plugin = match &self.config.maintenance_response {
+            Some(body) => self.pingap.ensure_maintenance_plugin(body).await,
+            None => Ok(()),
+        };
+        if let Err(e) = plugin {
+            warn!("Could not set up the maintenance plugin: {:?}", e);
+        }
+        let synced_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let mut containers = self.docker.running_containers().await?;
+        info!("Found {} running containers", containers.len());
+        if let Some(since) = self.cursor.as_ref().and_then(EventCursor::since) {
+            self.replay = Some(Replay { since, until: synced_at, running: containers.ids() });
+        }
+        // Resolved one at a time, a large host is never held in memory as a whole
+        while let Some(container) = containers.next(&self.docker).await {
+            match self.parse_container(&container) {
+                Ok(service_configs) => {
+                    // Empty if not enabled
+                    for service_config in service_configs {
+                        info!("Found enabled container: {} -> Service: {}", container.name, service_config.name);
+                        if !self.replicas.contains_key(&service_config.name)
+                            && !self.may_take_ownership(&service_config).await {
+                            self.unowned.insert(service_config.name);
+                            continue;
+                        }
+                        self.claim_service(&container, service_config);
+                    }
+                },
+                Err(e) => {
+                    warn!("Failed to parse labels for container {}: {:?}", container.name, e);
+                }
+            }
+        }
+
+        // Replicas of a scaled service are applied together as one upstream
+        self.maintenance = self.status.maintenance_requests();
+        self.update_freeze(SystemTime::now());
+        if self.frozen {
+            info!("Freeze window open, holding back {} services until it ends", self.replicas.len());
+            self.held_back.extend(self.replicas.keys().cloned());
+            self.publish_status();
+            self.set_docker_up(true);
+            return Ok(());
+        }
+        let mut configs = self.replicas.values().map(|replicas| self.desired_config(replicas)).collect::<Vec<_>>();
+        configs.sort_by(|a, b| a.name.cmp(&b.name));
+        let (configs, blocked) = self.order_by_dependencies(configs);
+        if self.config.batch_apply {
+            if !configs.is_empty() {
+                let result = self.pingap.apply_batch(&configs).await;
+                record_outcome(&self.status, self.pingap.backend(), "apply_batch", &result);
+                match result {
+                    Ok(()) => {
+                        for service_config in &configs {
+                            self.mark_applied(&service_config.name);
+                        }
+                    },
+                    Err(e) => error!("Failed to apply batched config for {} services: {:?}", configs.len(), e),
+                }
+            }
+        } else {
+            self.apply_concurrently(configs).await;
+        }
+        for service_config in blocked {
+            self.spawn_replicas_write(&service_config.name, String::new());
+        }
+        self.publish_status();
+        self.set_docker_up(true);
+        info!("Initial synchronization complete. Tracking {} services.", self.container_services.len());
+        Ok(())
+    }
+
+    /// Applies the initial sync `INITIAL_SYNC_CONCURRENCY` services at a
+    /// time. `configs` come ordered by dependencies and are applied in waves,
+    /// so a service only starts once the services it depends on are done.
+    async fn apply_concurrently(&mut self, configs: Vec<PingapServiceConfig>) {
+        let started = Instant::now();
+        let total = configs.len();
+        let mut applied = 0;
+        let mut failed = Vec::new();
+        for wave in dependency_waves(configs) {
+            // A dependency that failed to apply holds back its dependents
+            let (ready, waiting): (Vec<_>, Vec<_>) = wave.into_iter()
+                .partition(|service_config| self.missing_dependencies(service_config).is_empty());
+            for service_config in waiting {
+                self.spawn_replicas_write(&service_config.name, String::new());
+                failed.push(service_config.name);
+            }
+            let applies = ready.into_iter().map(|service_config| {
+                let actor = self.replicas.get(&service_config.name)
+                    .map(|replicas| replicas.addrs.keys().cloned().collect::<Vec<_>>().join(","))
+                    .unwrap_or_default();
+                let pingap = self.pingap.clone();
+                async move {
+                    let result = ACTOR.scope(actor, pingap.apply_config(&service_config)).await;
+                    (service_config.name, result)
+                }
+            });
+            let results = futures::stream::iter(applies)
+                .buffer_unordered(self.config.initial_sync_concurrency.max(1))
+                .collect::<Vec<_>>().await;
+            for (service, result) in results {
+                record_outcome(&self.status, self.pingap.backend(), "apply", &result);
+                if let Some(journal) = &self.journal {
+                    let containers = self.replicas.get(&service)
+                        .map(|replicas| replicas.addrs.keys().cloned().collect::<Vec<_>>())
+                        .unwrap_or_default();
+                    for container in containers {
+                        journal.record(&Entry::write(&service, &container, "apply", &result));
+                    }
+                }
+                match result {
+                    Ok(()) => {
+                        self.mark_applied(&service);
+                        applied += 1;
+                    },
+                    Err(e) => {
+                        if self.error_groups.record(&e.root_cause().to_string(), &service, Instant::now()) {
+                            error!("Failed to apply config for service {}: {:?}", service, e);
+                        }
+                        self.status.record_error(format!("Failed to apply config for service {}: {}", service, e));
+                        failed.push(service);
+                    },
+                }
+            }
+        }
+        if failed.is_empty() {
+            info!("Initial sync applied {} services in {:.1}s", applied, started.elapsed().as_secs_f64());
+        } else {
+            failed.sort();
+            warn!("Initial sync applied {} of {} services in {:.1}s; failed: {}",
+                  applied, total, started.elapsed().as_secs_f64(), failed.join(", "));
+        }
+    }
+
+    /// Waits until the Docker daemon answers again, backing off between
+    /// pings. Returns false if shutdown was requested in the meantime.
+    async fn wait_for_docker(&mut self) -> bool {
+        self.set_docker_up(false);
+        let mut backoff = self.config.retry.backoff(Duration::MAX);
+        backoff.max_elapsed_time = None;
+        loop {
+            let delay = backoff.next_backoff().unwrap_or(self.config.retry.max_interval);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                _ = signal::ctrl_c() => return false,
+            }
+            match self.docker.ping().await {
+                Ok(()) => break,
+                Err(e) => debug!("Docker daemon still unreachable: {:?}", e),
+            }
+        }
+        info!("Docker daemon is reachable again");
+        self.status.metrics.inc("pingap_provider_docker_reconnects_total", &[]);
+        true
+    }
+
+    /// Brings Pingap back in line after events may have been missed while
+    /// Docker was unreachable, or on request (SIGHUP).
+    async fn resync(&mut self) -> Result<()> {
+        info!("Resynchronizing...");
+        let mut containers = self.docker.running_containers().await?;
+        let mut desired = Vec::new();
+        while let Some(container) = containers.next(&self.docker).await {
+            match self.parse_container(&container) {
+                Ok(service_configs) if !service_configs.is_empty() => desired.push((container, service_configs)),
+                Ok(_) => {},
+                Err(e) => warn!("Failed to parse labels for container {}: {:?}", container.name, e),
+            }
+        }
+
+        // Removals go first so a service still backed by another container is re-applied after them
+        let running = desired.iter().map(|(container, _)| container.id.as_str()).collect::<HashSet<_>>();
+        let known = self.container_services.keys()
+            .chain(self.replicas.values().flat_map(|replicas| replicas.addrs.keys()));
+        let gone = known
+            .filter(|id| !running.contains(id.as_str()))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        for container_id in gone {
+            self.handle_stop(&container_id, &HashMap::new());
+        }
+
+        // Conflicts are checked again against the containers running now
+        self.conflicts.clear();
+        self.route_conflicts.clear();
+        self.policy_violations.clear();
+        for (container, service_configs) in desired {
+            for service_config in service_configs {
+                self.claim_service(&container, service_config);
+            }
+        }
+        // Events may have been missed, so every service is compared with Pingap again
+        self.spawn_converge();
+
+        self.set_docker_up(true);
+        self.publish_status();
+        Ok(())
+    }
+
+    /// Recovers from a lost Docker connection. Returns the time from which
+    /// events have to be replayed, or None if shutdown was requested.
+    async fn reconnect(&mut self) -> Option<i64> {
+        loop {
+            if !self.wait_for_docker().await {
+                return None;
+            }
+            let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+            match self.resync().await {
+                Ok(()) => return Some(since),
+                Err(e) => error!("Resync after Docker reconnect failed: {:?}", e),
+            }
+        }
+    }
+
+    /// `event_time` is the time of the triggering Docker event, 0 outside of events.
+    async fn handle_start(&mut self, container_id: &str, event_time: i64) {
+        // Inspect to get fresh details
+        match self.docker.inspect_container(container_id, event_time).await {
+            Ok(container) => {
+                let awaits_health = self.docker.awaits_health(container_id).await.unwrap_or(false);
+                self.start_warming(&container, awaits_health)
+            },
+            Err(e) => error!("Failed to inspect started container {}: {:?}", container_id, e),
+        }
+    }
+
+    /// Adds an inspected, started container to its services and writes them.
+    pub fn start_container(&mut self, container: &ContainerInfo) {
+        self.start_warming(container, false);
+    }
+
+    /// Like [`Self::start_container`], keeping the container's addresses out
+    /// of the upstreams of services with `pingap.warmup` for that long, from
+    /// now or, if `awaits_health`, from its health check passing.
+    fn start_warming(&mut self, container: &ContainerInfo, awaits_health: bool) {
+        self.conflicts.remove(&container.id);
+        self.route_conflicts.remove(&container.id);
+        self.policy_violations.remove(&container.id);
+        match self.parse_container(container) {
+            Ok(service_configs) => {
+                for service_config in service_configs {
+                    let service = service_config.name.clone();
+                    if !self.claim_service(container, service_config) {
+                        continue;
+                    }
+                    match self.replicas[&service].addrs.len() {
+                        1 => info!("Applying config for new container: {} -> Service: {}", container.name, service),
+                        n => info!("Container {} joins service {} ({} replicas)", container.name, service, n),
+                    }
+                    let key = (service.clone(), container.id.clone());
+                    match self.replicas[&service].config.warmup {
+                        Some(warmup) => {
+                            info!("Keeping container {} out of service {} for a {} warmup{}", container.name, service,
+                                models::format_duration(warmup), if awaits_health { " once it is healthy" } else { "" });
+                            let state = if awaits_health { Warmup::AwaitingHealth(warmup) } else { Warmup::Until(Instant::now() + warmup) };
+                            self.warming.insert(key, state);
+                        },
+                        None => {
+                            self.warming.remove(&key);
+                        },
+                    }
+                    self.spawn_replicas_write(&service, container.id.clone());
+                }
+            },
+            Err(e) => {
+                warn!("Invalid labels on {}: {:?}", container.name, e);
+                self.status.record_error(format!("Invalid labels on {}: {}", container.name, e));
+            },
+        }
+    }
+
+    /// Starts the warmup of a container that was waiting for its health check.
+    fn handle_healthy(&mut self, container_id: &str, now: Instant) {
+        for ((service, _), state) in self.warming.iter_mut().filter(|((_, id), _)| id == container_id) {
+            if let Warmup::AwaitingHealth(warmup) = *state {
+                info!("Container {} is healthy, adding it to service {} after its {} warmup", container_id, service, models::format_duration(warmup));
+                *state = Warmup::Until(now + warmup);
+            }
+        }
+    }
+
+    /// Adds the replicas whose warmup is over to their upstreams.
+    fn release_warmups(&mut self, now: Instant) {
+        let mut warm = self.warming.iter()
+            .filter(|(_, state)| matches!(state, Warmup::Until(until) if *until <= now))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        warm.sort();
+        for (service, container_id) in warm {
+            self.warming.remove(&(service.clone(), container_id.clone()));
+            if self.replicas.get(&service).is_some_and(|replicas| replicas.addrs.contains_key(&container_id)) {
+                info!("Container {} finished its warmup, adding it to service {}", container_id, service);
+                self.spawn_replicas_write(&service, container_id);
+            }
+        }
+    }
+
+    /// The upstream addresses of `service` without those only warming
+    /// replicas have. Empty if every replica is still warming up.
+    fn without_warming(&self, service: &str, addrs: Vec<String>) -> Vec<String> {
+        let Some(replicas) = self.replicas.get(service) else {
+            return addrs;
+        };
+        let warming = |container_id: &String| self.warming.contains_key(&(service.to_string(), container_id.clone()));
+        let warm = replicas.addrs.iter()
+            .filter(|(container_id, _)| !warming(container_id))
+            .flat_map(|(_, addrs)| addrs)
+            .collect::<HashSet<_>>();
+        let cold = replicas.addrs.iter()
+            .filter(|(container_id, _)| warming(container_id))
+            .flat_map(|(_, addrs)| addrs)
+            .filter(|addr| !warm.contains(addr))
+            .collect::<HashSet<_>>();
+        addrs.into_iter().filter(|addr| !cold.contains(addr)).collect()
+    }
+
+    /// Forgets a stopped container and returns the services to remove for it.
+    fn stopped_services(&mut self, container_id: &str, attributes: &HashMap<String, String>) -> Vec<String> {
+        self.warming.retain(|(_, id), _| id != container_id);
+        self.label_diagnostics.remove(container_id);
+        self.origins.remove(container_id);
+        // The services it was refused belong to other containers, or to
+        // whoever configured them in Pingap before the provider
+        let refused = self.conflicts.remove(container_id).unwrap_or_default().into_iter()
+            .map(|conflict| conflict.service)
+            .chain(self.route_conflicts.remove(container_id).unwrap_or_default().into_iter()
+                .filter(|conflict| conflict.blocked)
+                .map(|conflict| conflict.service))
+            .chain(self.unowned.iter().cloned())
+            .collect::<HashSet<_>>();
+
+        // Try to get service names from state first
+        if let Some(names) = self.container_services.remove(container_id) {
+            info!("Found services {:?} in state for container {}", names, container_id);
+            names.into_iter().collect()
+        } else {
+            // Fallback to attributes if not in state (e.g. started before we started listening and failed sync?)
+            let name = attributes.get("name").cloned().unwrap_or_default();
+            let mut labels = self.config.default_labels();
+            labels.extend(attributes.clone());
+            models::declared_service_names(&labels, &name)
+                .into_iter()
+                .filter(|service| !refused.contains(service))
+                .collect()
+        }
+    }
+
+    /// The `pingap.on_stop` policy of a service, from its replicas or, for
+    /// containers the provider never applied, from the event attributes.
+    fn stop_policy(&self, service: &str, attributes: &HashMap<String, String>) -> StopPolicy {
+        match self.replicas.get(service) {
+            Some(replicas) => replicas.config.on_stop,
+            None => attributes.get("pingap.on_stop")
+                .and_then(|policy| policy.parse().ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Forgets a stopped container and applies its services' stop policies.
+    /// Returns the services whose resources have to be removed because the
+    /// last replica is gone; other changes are written right away.
+    fn release_container(&mut self, container_id: &str, attributes: &HashMap<String, String>) -> Vec<String> {
+        self.stopped_services(container_id, attributes)
+            .into_iter()
+            .filter(|service_name| self.release_service(service_name, container_id, attributes))
+            .collect()
+    }
+
+    /// Applies the stop policy of one of a stopped container's services,
+    /// returning true if the service has to be removed.
+    fn release_service(&mut self, service_name: &str, container_id: &str, attributes: &HashMap<String, String>) -> bool {
+        match self.stop_policy(service_name, attributes) {
+            StopPolicy::Remove => {
+                if self.tombstones.contains_key(service_name) || self.bury(service_name, container_id, Instant::now()) {
+                    return false;
+                }
+                if !self.remove_replica(service_name, container_id) {
+                    return true;
+                }
+                info!("Container {} left service {}, updating its upstream", container_id, service_name);
+                self.spawn_replicas_write(service_name, container_id.to_string());
+            },
+            StopPolicy::Drain => {
+                if self.detach_replica(service_name, container_id) {
+                    info!("Dropping address of container {} from service {} (pingap.on_stop=drain)", container_id, service_name);
+                    self.spawn_replicas_write(service_name, container_id.to_string());
+                }
+            },
+            StopPolicy::Keep => {
+                if self.detach_replica(service_name, container_id) {
+                    info!("Keeping config for service {} after container {} stopped (pingap.on_stop=keep)", service_name, container_id);
+                }
+            },
+        }
+        false
+    }
+
+    pub fn handle_stop(&mut self, container_id: &str, attributes: &HashMap<String, String>) {
+        for service_name in self.release_container(container_id, attributes) {
+            info!("Removing config for service: {}", service_name);
+            self.spawn_operation(service_name, container_id.to_string(), "delete", None);
+        }
+    }
+
+    /// `destroy` is the last event of a container. One that is still tracked
+    /// missed its stop (e.g. `docker rm -f` during an event gap) and its
+    /// services are released now; either way nothing is kept for its ID.
+    fn handle_destroy(&mut self, container_id: &str, attributes: &HashMap<String, String>) {
+        let tracked = self.container_services.contains_key(container_id)
+            || self.replicas.values().any(|replicas| replicas.addrs.contains_key(container_id));
+        if tracked {
+            warn!("Container {} was removed without a stop event, releasing its services", container_id);
+            self.handle_stop(container_id, attributes);
+        }
+        self.label_diagnostics.remove(container_id);
+        self.origins.remove(container_id);
+        self.conflicts.remove(container_id);
+        self.route_conflicts.remove(container_id);
+        self.policy_violations.remove(container_id);
+        self.recent_stops.remove(container_id);
+        self.flap.forget(container_id);
+        if let Some(replay) = &mut self.replay {
+            replay.running.remove(container_id);
+        }
+        self.docker.forget_container(container_id);
+    }
+
+    /// Holds back the removal of a compose container's services until its
+    /// project has had no stops for the grouping window.
+    fn queue_project_stop(&mut self, project: &str, container_id: &str, attributes: &HashMap<String, String>) {
+        // Only the last replica of a service going down is grouped
+        let services = self.release_container(container_id, attributes);
+        if services.is_empty() {
+            return;
+        }
+        self.note_pending(&services);
+        let deadline = Instant::now() + self.config.compose_stop_group_window;
+        let stops = self.project_stops.entry(project.to_string())
+            .or_insert_with(|| ProjectStops { deadline, targets: Vec::new() });
+        stops.deadline = deadline;
+        for service_name in services {
+            // die and stop both fire for a regular stop
+            if !stops.targets.iter().any(|(service, _)| *service == service_name) {
+                stops.targets.push((service_name, container_id.to_string()));
+            }
+        }
+    }
+
+    /// Drops queued removals made obsolete by a newer apply of the service or container.
+    fn cancel_project_stop(&mut self, service_name: &str, container_id: &str) {
+        for stops in self.project_stops.values_mut() {
+            stops.targets.retain(|(service, id)| service != service_name && id != container_id);
+        }
+        self.project_stops.retain(|_, stops| !stops.targets.is_empty());
+    }
+
+    fn flush_project_stops(&mut self, now: Instant) {
+        let due = self.project_stops.iter()
+            .filter(|(_, stops)| stops.deadline <= now)
+            .map(|(project, _)| project.clone())
+            .collect::<Vec<_>>();
+        for project in due {
+            let Some(mut stops) = self.project_stops.remove(&project) else {
+                continue;
+            };
+            if stops.targets.len() == 1 {
+                let (service_name, container_id) = stops.targets.remove(0);
+                info!("Removing config for service: {}", service_name);
+                self.spawn_operation(service_name, container_id, "delete", None);
+            } else {
+                info!("Compose project {} went down, removing {} services", project, stops.targets.len());
+                self.spawn_project_delete(project, stops.targets);
+            }
+        }
+    }
+
+    /// Publishes how much the provider holds in memory: entries per piece of
+    /// state, a rough size of the tracked services and the process's RSS.
+    fn publish_memory_metrics(&self) {
+        let metrics = &self.status.metrics;
+        let (inspect_cache, image_label_cache) = self.docker.cache_sizes();
+        let entries = [
+            ("containers", self.container_services.len()),
+            ("replicas", self.replicas.len()),
+            ("label_diagnostics", self.label_diagnostics.len()),
+            ("waiting", self.waiting.len()),
+            ("project_stops", self.project_stops.len()),
+            ("in_flight", self.in_flight.tasks.len()),
+            ("inspect_cache", inspect_cache),
+            ("image_label_cache", image_label_cache),
+        ];
+        for (state, count) in entries {
+            metrics.set_gauge("pingap_provider_state_entries", &[("state", state)], count as f64);
+        }
+        metrics.set_gauge("pingap_provider_state_bytes", &[], self.estimated_state_bytes() as f64);
+        if let Some(rss) = resident_memory_bytes() {
+            metrics.set_gauge("pingap_provider_resident_memory_bytes", &[], rss as f64);
+        }
+    }
+
+    /// Rough size of the tracked containers, services and diagnostics: their
+    /// strings and configs, without allocator or map overhead.
+    fn estimated_state_bytes(&self) -> usize {
+        let containers = self.container_services.iter()
+            .map(|(id, services)| id.len() + services.iter().map(String::len).sum::<usize>())
+            .sum::<usize>();
+        let replicas = self.replicas.iter()
+            .map(|(service, replicas)| {
+                let config = serde_json::to_vec(&replicas.config).map_or(0, |json| json.len());
+                let addrs = replicas.addrs.iter()
+                    .map(|(id, addrs)| id.len() + addrs.iter().map(String::len).sum::<usize>())
+                    .sum::<usize>();
+                service.len() + config + addrs
+            })
+            .sum::<usize>();
+        let diagnostics = self.label_diagnostics.iter()
+            .map(|(id, (name, diagnostics))| id.len() + name.len() + diagnostics.iter()
+                .map(|d| d.label.len() + d.value.len() + d.problem.len())
+                .sum::<usize>())
+            .sum::<usize>();
+        containers + replicas + diagnostics
+    }
+
+    /// Applied services Pingap has no location for anymore, e.g. after it
+    /// restarted with volatile storage.
+    fn lost_services(&self, full: &Value) -> Vec<String> {
+        let mut lost = self.replicas.iter()
+            .filter(|(_, replicas)| replicas.applied && full["locations"].get(replicas.config.location_name()).is_none())
+            .map(|(service, _)| service.clone())
+            .collect::<Vec<_>>();
+        lost.sort();
+        lost
+    }
+
+    /// Writes the services again when Pingap restarted or lost their config,
+    /// instead of leaving its routes blank until the next Docker event.
+    async fn check_pingap(&mut self) {
+        let restarted = match self.pingap.instance_id().await {
+            Ok(Some(instance)) => self.pingap_instance.replace(instance.clone()).is_some_and(|known| known != instance),
+            Ok(None) => false,
+            Err(e) => {
+                debug!("Could not check Pingap for a restart: {:?}", e);
+                return;
+            },
+        };
+        // Also refreshes the mirror, which would otherwise still show the config from before
+        let full = match self.pingap.fetch_full_config().await {
+            Ok(full) => full,
+            Err(e) => {
+                debug!("Could not check Pingap's config: {:?}", e);
+                return;
+            },
+        };
+        let (reason, mut services) = if restarted {
+            ("restart", self.replicas.keys().cloned().collect::<Vec<_>>())
+        } else {
+            ("lost_config", self.lost_services(&full))
+        };
+        services.sort();
+        if !services.is_empty() {
+            self.reapply(reason, services).await;
+        }
+        // Services just marked for re-applying are left out
+        self.check_drift(&full);
+        self.publish_status();
+    }
+
+    /// The Pingap container started (again): whatever it kept, every service
+    /// is written anew rather than waiting for `PINGAP_POLL_INTERVAL_SECS`
+    /// to notice the restart.
+    async fn pingap_container_started(&mut self) {
+        // The next poll only learns the new instance instead of re-applying once more
+        self.pingap_instance = None;
+        let mut services = self.replicas.keys().cloned().collect::<Vec<_>>();
+        services.sort();
+        self.reapply("restart", services).await;
+    }
+
+    async fn reapply(&mut self, reason: &'static str, services: Vec<String>) {
+        if reason == "restart" {
+            warn!("Pingap restarted, re-applying all {} services", services.len());
+            let plugin = match self.config.maintenance_response.clone() {
+                Some(body) => self.pingap.ensure_maintenance_plugin(&body).await,
+                None => Ok(()),
+            };
+            if let Err(e) = plugin {
+                warn!("Could not set up the maintenance plugin again: {:?}", e);
+            }
+        } else {
+            warn!("Pingap lost the config of {} services, re-applying them: {}", services.len(), services.join(", "));
+        }
+        self.status.metrics.inc("pingap_provider_pingap_reapplies_total", &[("reason", reason)]);
+        self.status.record_event(format!("Pingap {}, re-applying {} services", reason.replace('_', " "), services.len()));
+        for service in services {
+            if let Some(replicas) = self.replicas.get_mut(&service) {
+                replicas.applied = false;
+            }
+            self.spawn_replicas_write(&service, String::new());
+        }
+    }
+
+    /// Compares the applied services with Pingap's config `full` to find
+    /// managed resources someone else edited or deleted. Each one is
+    /// reported when first seen and, under `DRIFT_POLICY=enforce`, written
+    /// back. Services with a write on its way are skipped, and so are
+    /// retained ones, whose upstream is meant to differ.
+    fn check_drift(&mut self, full: &Value) {
+        let policy = self.config.drift_policy;
+        if policy == DriftPolicy::Off {
+            return;
+        }
+        let candidates = self.replicas.iter()
+            .filter(|(service, replicas)| replicas.applied && !replicas.addrs.is_empty()
+                && !self.in_flight.tasks.contains_key(*service) && !self.held_back.contains(*service))
+            .map(|(_, replicas)| self.desired_config(replicas))
+            .collect::<Vec<_>>();
+        let mut owners = HashMap::new();
+        let mut configs = Vec::new();
+        for mut config in candidates {
+            config.upstreams = self.health.filter(config.upstreams);
+            config.upstreams = self.weigh_addrs(&config.name, config.upstreams);
+            let resources = [config.name.clone(), config.location_name().to_string()].into_iter()
+                .chain(plugins::service_plugins(&config).into_iter().map(|(name, _)| name));
+            for resource in resources {
+                owners.entry(resource).or_insert_with(|| config.name.clone());
+            }
+            configs.push(config);
+        }
+        let actions = match self.pingap.plan_against(&configs, full, true) {
+            Ok(actions) => actions,
+            Err(e) => {
+                debug!("Could not compare Pingap's config with the applied services: {:?}", e);
+                return;
+            },
+        };
+
+        // Service -> (its resources changed, whether all of them are gone)
+        let mut changed = BTreeMap::<String, (Vec<String>, bool)>::new();
+        for action in &actions {
+            let deleted = match action {
+                Action::Create { .. } => true,
+                Action::Update { .. } => false,
+                // Resources nothing asks for are for `prune`, not edits of applied services
+                Action::Delete { .. } => continue,
+            };
+            if let Some(service) = owners.get(action.name()) {
+                let entry = changed.entry(service.clone()).or_insert((Vec::new(), true));
+                entry.0.push(format!("{}/{}", action.section(), action.name()));
+                entry.1 &= deleted;
+            }
+        }
+        let drift = changed.into_iter()
+            .map(|(service, (fields, deleted))| if deleted && fields.len() > 1 {
+                Drift::Missing { service }
+            } else {
+                Drift::Different { service, fields }
+            })
+            .collect::<Vec<_>>();
+
+        let policy_label = if policy == DriftPolicy::Enforce { "enforce" } else { "observe" };
+        for change in drift.iter().filter(|change| !self.drift.contains(change)) {
+            warn!("Managed resources changed in Pingap by someone else: {:?}", change);
+            self.status.record_event(format!("External change in Pingap: {:?}", change));
+            self.status.metrics.inc("pingap_provider_external_changes_total", &[("policy", policy_label)]);
+        }
+        if policy == DriftPolicy::Enforce {
+            for change in &drift {
+                let service = match change {
+                    Drift::Missing { service } | Drift::Different { service, .. } | Drift::Orphaned { service } => service.clone(),
+                };
+                info!("Writing service {} back to Pingap (DRIFT_POLICY=enforce)", service);
+                if let Some(replicas) = self.replicas.get_mut(&service) {
+                    replicas.applied = false;
+                }
+                self.spawn_replicas_write(&service, String::new());
+            }
+        }
+        self.drift = drift;
+    }
+
+    /// Logs everything the provider currently knows, on SIGUSR1.
+    fn dump_state(&self) {
+        let mut in_flight = self.in_flight.tasks.keys().cloned().collect::<Vec<_>>();
+        in_flight.sort();
+        let upstreams = self.replicas.iter()
+            .map(|(service, replicas)| (service.clone(), replicas.merged_config().upstreams))
+            .collect::<BTreeMap<_, _>>();
+        let project_stops = self.project_stops.iter()
+            .map(|(project, stops)| (project.clone(), stops.targets.iter().map(|(service, _)| service.clone()).collect::<Vec<_>>()))
+            .collect::<BTreeMap<_, _>>();
+        let dump = serde_json::json!({
+            "status": self.status.snapshot(),
+            "upstreams": upstreams,
+            "in_flight": in_flight,
+            "pending_project_stops": project_stops,
+        });
+        info!("State dump:\n{}", serde_json::to_string_pretty(&dump).unwrap_or_default());
+    }
+
+    fn toggle_debug_logging(&mut self) {
+        let Some(log) = &mut self.log else {
+            warn!("Log level can't be changed at runtime in this process");
+            return;
+        };
+        match log.toggle_debug() {
+            Ok(true) => info!("Debug logging enabled"),
+            Ok(false) => info!("Debug logging disabled"),
+            Err(e) => error!("Failed to toggle debug logging: {:?}", e),
+        }
+    }
+
+    /// Applies whatever state a container settled in after its flapping hold-down.
+    async fn reconcile_container(&mut self, container_id: &str) {
+        match self.docker.is_running(container_id).await {
+            Ok(true) => {
+                info!("Container {} stopped flapping and is running, applying config", container_id);
+                self.handle_start(container_id, 0).await;
+            },
+            Ok(false) | Err(_) => {
+                info!("Container {} stopped flapping and is not running, removing config", container_id);
+                self.handle_stop(container_id, &HashMap::new());
+            }
+        }
+    }
+
+    /// Whether an event happened before the initial sync and is replayed.
+    /// The first live event ends the replay.
+    fn is_replayed(&mut self, event_time: i64) -> bool {
+        let Some(replay) = &self.replay else {
+            return false;
+        };
+        if event_time < replay.until {
+            self.status.metrics.inc("pingap_provider_replayed_events_total", &[]);
+            return true;
+        }
+        info!("Caught up on the Docker events missed while the provider was down");
+        self.replay = None;
+        false
+    }
+
+    /// Whether a replayed event is already taken care of by the initial sync,
+    /// which applied every container running by then. Left are the stops of
+    /// containers that are gone, whose services would otherwise linger.
+    fn covered_by_initial_sync(&self, action: ContainerAction, container_id: &str) -> bool {
+        match (&self.replay, action) {
+            (Some(replay), ContainerAction::ServiceRemoved | ContainerAction::Destroyed) => replay.running.contains(container_id),
+            _ => true,
+        }
+    }
+
+    /// Whether an event changes anything: the second of `die` and `stop`
+    /// within [`STOP_DEDUP_WINDOW`] repeats a removal already handled, and
+    /// would only send a delete that ends in a 404. A start re-arms it.
+    fn is_new_action(&mut self, action: ContainerAction, container_id: &str, now: Instant) -> bool {
+        self.recent_stops.retain(|_, stopped_at| now.duration_since(*stopped_at) < STOP_DEDUP_WINDOW);
+        match action {
+            ContainerAction::Started | ContainerAction::Destroyed => {
+                self.recent_stops.remove(container_id);
+                true
+            },
+            ContainerAction::Healthy => true,
+            ContainerAction::ServiceRemoved => self.recent_stops.insert(container_id.to_string(), now).is_none(),
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        let Some(cursor) = &mut self.cursor else {
+            return;
+        };
+        if let Err(e) = cursor.save() {
+            warn!("Could not save the event cursor: {:?}", e);
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let since = self.replay.as_ref().map(|replay| replay.since);
+        if let Some(since) = since {
+            info!("Replaying Docker events since {} missed while the provider was down", since);
+        }
+        let mut events = self.docker.subscribe_to_events(since).await;
+        let mut hold_down_ticker = tokio::time::interval(self.config.resources.reconcile_interval);
+        let mut ping_ticker = tokio::time::interval(self.config.docker_ping_interval);
+        let probe_interval = self.config.address_probe_interval;
+        let mut probe_ticker = tokio::time::interval(probe_interval.max(Duration::from_secs(1)));
+        let load_interval = self.config.load_weight_interval;
+        let mut load_ticker = tokio::time::interval(load_interval.max(Duration::from_secs(1)));
+        let poll_interval = self.config.pingap_poll_interval;
+        let mut poll_ticker = tokio::time::interval(poll_interval.max(Duration::from_secs(1)));
+        let mut sighup = unix_signal(SignalKind::hangup())?;
+        let mut sigusr1 = unix_signal(SignalKind::user_defined1())?;
+        let mut sigusr2 = unix_signal(SignalKind::user_defined2())?;
+        // Resyncs requested through the gRPC API
+        let mut resyncs = self.status.take_resync_requests().unwrap_or_else(|| mpsc::unbounded_channel().1);
+
+        info!("Listening for Docker events...");
+
+        loop {
+            tokio::select! {
+                event = events.next() => {
+                    match event {
+                        Some(Ok(msg)) => {
+                            let action = msg.action.unwrap_or_default();
+                            let actor = msg.actor.unwrap_or_default();
+                            let attributes = actor.attributes.unwrap_or_default();
+                            let container_id = actor.id.unwrap_or_default();
+                            let event_time = msg.time.unwrap_or_default();
+                            let event_nanos = msg.time_nano.unwrap_or(event_time * 1_000_000_000);
+                            if let Some(cursor) = &mut self.cursor {
+                                cursor.record(event_time);
+                            }
+                            let Some(container_action) = ContainerAction::from_event(&action) else {
+                                continue;
+                            };
+
+                            let replayed = self.is_replayed(event_time);
+                            if replayed && self.covered_by_initial_sync(container_action, &container_id) {
+                                debug!("Replayed {} event for container {} is covered by the initial sync", action, container_id);
+                                continue;
+                            }
+                            // Replayed stops arrive in a burst, which isn't flapping; a removal is no transition
+                            if !replayed && matches!(container_action, ContainerAction::Started | ContainerAction::ServiceRemoved)
+                                && self.flap.record(&container_id, Instant::now()) {
+                                debug!("Suppressing {} event for flapping container {}", action, container_id);
+                                self.status.metrics.inc("pingap_provider_flap_suppressed_events_total", &[]);
+                                self.publish_status();
+                                continue;
+                            }
+                            if !self.is_new_action(container_action, &container_id, Instant::now()) {
+                                debug!("Container {} already stopped, ignoring its {} event", container_id, action);
+                                self.status.metrics.inc("pingap_provider_duplicate_stop_events_total", &[]);
+                                continue;
+                            }
+
+                            let name = attributes.get("name").map(String::as_str).unwrap_or(&container_id);
+                            let replayed = if replayed { " (replayed)" } else { "" };
+                            self.status.record_event(format!("{} {}{}", action, name, replayed));
+                            if let Some(journal) = &self.journal {
+                                journal.record(&Entry::event(event_nanos as u64 / 1_000_000, &container_id, name, &action));
+                            }
+                            // Downtime isn't apply latency, replayed events don't start the clock
+                            self.event_time = (replayed.is_empty() && event_nanos > 0)
+                                .then(|| UNIX_EPOCH + Duration::from_nanos(event_nanos as u64));
+                            let pingap_container = self.config.pingap_container.matches(name, &attributes);
+                            match container_action {
+                                ContainerAction::Started => {
+                                    info!("Container started: {}", container_id);
+                                    self.handle_start(&container_id, event_time).await;
+                                    if pingap_container {
+                                        self.pingap_container_started().await;
+                                    }
+                                },
+                                ContainerAction::ServiceRemoved => {
+                                    info!("Container stopped/died: {}", container_id);
+                                    if pingap_container {
+                                        warn!("The Pingap container {} stopped, its routes are written again when it starts", name);
+                                    }
+                                    match attributes.get(LABEL_COMPOSE_PROJECT) {
+                                        Some(project) if !self.config.compose_stop_group_window.is_zero() => {
+                                            let project = project.clone();
+                                            self.queue_project_stop(&project, &container_id, &attributes);
+                                        },
+                                        _ => self.handle_stop(&container_id, &attributes),
+                                    }
+                                },
+                                ContainerAction::Destroyed => {
+                                    info!("Container removed: {}", container_id);
+                                    self.handle_destroy(&container_id, &attributes);
+                                },
+                                ContainerAction::Healthy => self.handle_healthy(&container_id, Instant::now()),
+                            }
+                            self.event_time = None;
+                            self.publish_status();
+                        },
+                        Some(Err(e)) => {
+                            error!("Docker event stream error: {:?}", e);
+                        },
+                        None => {
+                            warn!("Docker event stream ended, reconnecting...");
+                            match self.reconnect().await {
+                                Some(since) => events = self.docker.subscribe_to_events(Some(since)).await,
+                                None => break,
+                            }
+                        }
+                    }
+                },
+                _ = ping_ticker.tick() => {
+                    self.publish_memory_metrics();
+                    if let Err(e) = self.docker.ping().await {
+                        warn!("Lost connection to Docker, reconnecting: {:?}", e);
+                        match self.reconnect().await {
+                            Some(since) => events = self.docker.subscribe_to_events(Some(since)).await,
+                            None => break,
+                        }
+                    }
+                },
+                Some(done) = self.done_rx.recv() => {
+                    self.handle_done(done);
+                    self.publish_status();
+                },
+                _ = probe_ticker.tick(), if !probe_interval.is_zero() => self.spawn_probes(),
+                _ = poll_ticker.tick(), if !poll_interval.is_zero() => self.check_pingap().await,
+                Some(results) = self.probe_rx.recv() => {
+                    self.handle_probes(results);
+                    self.publish_status();
+                },
+                _ = load_ticker.tick(), if !load_interval.is_zero() => self.spawn_load_reads(),
+                Some(loads) = self.load_rx.recv() => {
+                    self.handle_loads(loads);
+                    self.publish_status();
+                },
+                _ = hold_down_ticker.tick() => {
+                    self.save_cursor();
+                    for summary in self.error_groups.take_summaries(Instant::now()) {
+                        error!("{}", summary);
+                    }
+                    self.update_freeze(SystemTime::now());
+                    self.flush_project_stops(Instant::now());
+                    self.release_waiting();
+                    self.release_warmups(Instant::now());
+                    let tombstones = self.tombstones.len();
+                    self.purge_tombstones(Instant::now());
+                    if let Some(state) = self.status.take_state_import() {
+                        self.import_state(state, Instant::now());
+                    }
+                    if self.status.take_prune_request() {
+                        self.prune_retained();
+                    }
+                    self.sync_maintenance();
+                    // Retries the records that failed
+                    self.sync_dns();
+                    let expired = self.flap.take_expired(Instant::now());
+                    for container_id in &expired {
+                        self.reconcile_container(container_id).await;
+                    }
+                    if !expired.is_empty() || self.tombstones.len() != tombstones {
+                        self.publish_status();
+                    }
+                },
+                _ = sighup.recv() => {
+                    info!("Received SIGHUP, reconciling all containers");
+                    // Re-read Pingap too, so routes changed behind the provider's back are rewritten
+                    if let Err(e) = self.pingap.fetch_full_config().await {
+                        warn!("Could not re-read Pingap's full config: {:?}", e);
+                    }
+                    if let Err(e) = self.resync().await {
+                        error!("Reconcile after SIGHUP failed: {:?}", e);
+                    }
+                    self.publish_status();
+                },
+                Some(reply) = resyncs.recv() => {
+                    info!("Resync requested through the control API");
+                    let result = self.resync().await;
+                    if let Err(e) = &result {
+                        error!("Requested resync failed: {:?}", e);
+                    }
+                    self.publish_status();
+                    let _ = reply.send(result.map(|()| self.replicas.len()).map_err(|e| format!("{:#}", e)));
+                },
+                _ = sigusr1.recv() => self.dump_state(),
+                _ = sigusr2.recv() => self.toggle_debug_logging(),
+                _ = signal::ctrl_c() => {
+                    info!("Received shutdown signal");
+                    break;
+                }
+            }
+        }
+
+        self.save_cursor();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pingap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn names(services: &[&str]) -> Vec<String> {
+        services.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_newer_write_cancels_older() {
+        let mut in_flight = InFlight::default();
+        let old_finished = Arc::new(AtomicBool::new(false));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let flag = old_finished.clone();
+        assert!(!in_flight.start(&names(&["web"]), move |_| async move {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            flag.store(true, Ordering::SeqCst);
+        }));
+        let superseded = in_flight.start(&names(&["web"]), move |generation| async move {
+            let _ = tx.send(generation);
+        });
+        assert!(superseded);
+
+        let generation = rx.recv().await.unwrap();
+        assert!(!old_finished.load(Ordering::SeqCst));
+        assert!(!in_flight.finish("web", generation - 1));
+        assert!(in_flight.finish("web", generation));
+    }
+
+    #[tokio::test]
+    async fn test_finished_write_not_reported_as_superseded() {
+        let mut in_flight = InFlight::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let first_tx = tx.clone();
+        in_flight.start(&names(&["web"]), move |generation| async move {
+            let _ = first_tx.send(generation);
+        });
+        rx.recv().await.unwrap();
+        tokio::task::yield_now().await;
+
+        assert!(!in_flight.start(&names(&["web"]), move |generation| async move {
+            let _ = tx.send(generation);
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_services_tracked_independently() {
+        let mut in_flight = InFlight::default();
+        in_flight.start(&names(&["web"]), |_| std::future::pending());
+        assert!(!in_flight.start(&names(&["api"]), |_| std::future::pending()));
+    }
+
+    #[tokio::test]
+    async fn test_group_write_supersedes_each_service() {
+        let mut in_flight = InFlight::default();
+        in_flight.start(&names(&["web"]), |_| std::future::pending());
+        in_flight.start(&names(&["api"]), |_| std::future::pending());
+
+        assert!(in_flight.start(&names(&["web", "api"]), |_| std::future::pending()));
+        let group = in_flight.next_generation;
+
+        // A later write for one service cancels the group, leaving api to be written again
+        in_flight.start(&names(&["web"]), |_| std::future::pending());
+        assert!(!in_flight.finish("web", group));
+        assert!(!in_flight.finish("api", group));
+        assert_eq!(in_flight.tasks.keys().collect::<Vec<_>>(), ["web"]);
+        assert_eq!(in_flight.abandoned, BTreeSet::from(["api".to_string()]));
+
+        in_flight.start(&names(&["api"]), |_| std::future::pending());
+        assert!(in_flight.abandoned.is_empty());
+    }
+
+    fn test_provider() -> Provider {
+        let config = Config {
+            compose_stop_group_window: Duration::from_secs(2),
+            tombstone_ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        let docker = DockerClient::new(None).unwrap();
+        let pingap = PingapClient::new("http://127.0.0.1:1".to_string());
+        Provider::new(config, docker, pingap, Arc::new(Status::new("sync")))
+    }
+
+    #[tokio::test]
+    async fn test_project_stops_grouped_and_deduplicated() {
+        let mut provider = test_provider();
+        provider.container_services.insert("c1".to_string(), BTreeSet::from(["web".to_string()]));
+        provider.container_services.insert("c2".to_string(), BTreeSet::from(["api".to_string()]));
+
+        provider.queue_project_stop("shop", "c1", &HashMap::new());
+        provider.queue_project_stop("shop", "c2", &HashMap::new());
+        // The die event after stop resolves the same service from its attributes
+        let attributes = HashMap::from([
+            ("pingap.enable".to_string(), "true".to_string()),
+            ("pingap.service.name".to_string(), "web".to_string()),
+        ]);
+        provider.queue_project_stop("shop", "c1", &attributes);
+
+        let stops = &provider.project_stops["shop"];
+        assert_eq!(stops.targets, vec![
+            ("web".to_string(), "c1".to_string()),
+            ("api".to_string(), "c2".to_string()),
+        ]);
+        assert!(provider.container_services.is_empty());
+
+        // Nothing is removed before the project has been quiet for the window
+        provider.flush_project_stops(Instant::now());
+        assert!(provider.project_stops.contains_key("shop"));
+        provider.flush_project_stops(Instant::now() + Duration::from_secs(3));
+        assert!(provider.project_stops.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_restarted_service_leaves_project_stops() {
+        let mut provider = test_provider();
+        provider.container_services.insert("c1".to_string(), BTreeSet::from(["web".to_string()]));
+        provider.container_services.insert("c2".to_string(), BTreeSet::from(["api".to_string()]));
+        provider.queue_project_stop("shop", "c1", &HashMap::new());
+        provider.queue_project_stop("shop", "c2", &HashMap::new());
+
+        provider.cancel_project_stop("web", "c9");
+        assert_eq!(provider.project_stops["shop"].targets, vec![("api".to_string(), "c2".to_string())]);
+        provider.cancel_project_stop("api", "c2");
+        assert!(provider.project_stops.is_empty());
+    }
+
+    fn replica_config(service: &str, addr: &str) -> PingapServiceConfig {
+        models::PingapServiceConfigBuilder::new(service, vec![addr.to_string()], format!("Host(`{}.local`)", service)).build()
+    }
+
+    fn shared_config(service: &str, addr: &str) -> PingapServiceConfig {
+        models::PingapServiceConfigBuilder::new(service, vec![addr.to_string()], format!("Host(`{}.local`)", service))
+            .with_upstream_config(Some(models::UpstreamConfig { shared: Some("shared-api".to_string()), ..Default::default() }))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_shared_upstream_counts_its_users() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", shared_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", shared_config("api", "10.0.0.2:80"));
+        provider.add_replica("c3", replica_config("admin", "10.0.0.3:80"));
+
+        let config = provider.desired_config(&provider.replicas["web"]);
+        assert_eq!(config.upstream_name(), "shared-api");
+        assert_eq!(config.upstreams, ["10.0.0.1:80", "10.0.0.2:80"]);
+        assert_eq!(provider.desired_config(&provider.replicas["admin"]).upstreams, ["10.0.0.3:80"]);
+
+        // The upstream outlives the first service and goes with the last one
+        provider.remove_replica("web", "c1");
+        assert_eq!(provider.release_upstream("web"), None);
+        assert!(provider.in_flight.tasks.contains_key("api"));
+        assert_eq!(provider.desired_config(&provider.replicas["api"]).upstreams, ["10.0.0.2:80"]);
+        provider.remove_replica("api", "c2");
+        assert_eq!(provider.release_upstream("api"), Some("shared-api".to_string()));
+        assert_eq!(provider.release_upstream("admin"), Some("admin".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_shared_upstream_never_takes_a_service_upstream() {
+        let mut provider = test_provider();
+        let container = |id: &str| compose_container(id, "shop", "10.0.0.1");
+        assert!(provider.claim_service(&container("c1"), shared_config("web", "10.0.0.1:80")));
+        // Named like the shared upstream in use
+        assert!(!provider.claim_service(&container("c2"), replica_config("shared-api", "10.0.0.2:80")));
+
+        let mut provider = test_provider();
+        assert!(provider.claim_service(&container("c1"), replica_config("shared-api", "10.0.0.1:80")));
+        // Sharing the upstream of a service that has its own
+        assert!(!provider.claim_service(&container("c2"), shared_config("web", "10.0.0.2:80")));
+        assert_eq!(provider.desired_config(&provider.replicas["shared-api"]).upstreams, ["10.0.0.1:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth_follows_its_service() {
+        let mut provider = test_provider();
+        let mut web = replica_config("web", "10.0.0.1:80");
+        web.middleware_config = Some(models::MiddlewareConfig { forward_auth: Some("http://auth:9000/verify".to_string()), ..Default::default() });
+        let forward_auth = |provider: &Provider| provider.desired_config(&provider.replicas["web"]).middleware_config.unwrap().forward_auth.unwrap();
+
+        // Left to Docker's DNS while the auth service isn't tracked
+        provider.add_replica("c1", web.clone());
+        assert_eq!(forward_auth(&provider), "http://auth:9000/verify");
+        provider.mark_applied("web");
+        provider.note_forward_auth(&provider.desired_config(&provider.replicas["web"]));
+
+        provider.add_replica("c2", replica_config("auth", "10.0.0.2:9000"));
+        assert_eq!(forward_auth(&provider), "http://10.0.0.2:9000/verify");
+
+        // The auth container comes back elsewhere, the service using it is written in full again
+        provider.mark_applied("auth");
+        provider.remove_replica("auth", "c2");
+        provider.add_replica("c3", replica_config("auth", "10.0.0.3:9000"));
+        assert_eq!(forward_auth(&provider), "http://10.0.0.3:9000/verify");
+        provider.spawn_replicas_write("auth", "c3".to_string());
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(!provider.replicas["web"].applied);
+        assert_eq!(provider.forward_auth["web"], "http://10.0.0.3:9000/verify");
+    }
+
+    #[tokio::test]
+    async fn test_scaled_replicas_share_one_upstream() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.mark_applied("web");
+        provider.add_replica("c2", replica_config("web", "10.0.0.2:80"));
+
+        let replicas = &provider.replicas["web"];
+        // Same labels, so only the addresses need updating
+        assert!(replicas.applied);
+        assert_eq!(replicas.merged_config().upstreams, vec!["10.0.0.1:80", "10.0.0.2:80"]);
+
+        provider.mark_applied("web");
+        assert_eq!(provider.container_services.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_replica_with_changed_route_needs_full_apply() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.mark_applied("web");
+
+        let mut changed = replica_config("web", "10.0.0.2:80");
+        changed.location.rule = "Host(`shop.local`)".to_string();
+        provider.add_replica("c2", changed);
+        assert!(!provider.replicas["web"].applied);
+    }
+
+    #[tokio::test]
+    async fn test_dns_replicas_deduplicated() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "web:8080"));
+        provider.add_replica("c2", replica_config("web", "web:8080"));
+        assert_eq!(provider.replicas["web"].merged_config().upstreams, vec!["web:8080"]);
+    }
+
+    #[tokio::test]
+    async fn test_last_replica_removes_service() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("web", "10.0.0.2:80"));
+
+        assert!(provider.remove_replica("web", "c1"));
+        assert_eq!(provider.replicas["web"].merged_config().upstreams, vec!["10.0.0.2:80"]);
+        assert!(!provider.remove_replica("web", "c2"));
+        assert!(!provider.replicas.contains_key("web"));
+    }
+
+    #[tokio::test]
+    async fn test_project_stop_of_one_replica_not_grouped() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("web", "10.0.0.2:80"));
+        provider.mark_applied("web");
+
+        provider.queue_project_stop("shop", "c1", &HashMap::new());
+        assert!(provider.project_stops.is_empty());
+        provider.queue_project_stop("shop", "c2", &HashMap::new());
+        assert_eq!(provider.project_stops["shop"].targets, vec![("web".to_string(), "c2".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_keeps_service_without_replicas() {
+        let mut provider = test_provider();
+        let mut config = replica_config("web", "10.0.0.1:80");
+        config.on_stop = StopPolicy::Drain;
+        provider.add_replica("c1", config);
+        provider.mark_applied("web");
+
+        assert!(provider.release_container("c1", &HashMap::new()).is_empty());
+        assert!(provider.replicas["web"].merged_config().upstreams.is_empty());
+        assert_eq!(provider.retained_services(), vec!["web".to_string()]);
+
+        // A restart only needs its address back, the location stayed in place
+        let mut config = replica_config("web", "10.0.0.2:80");
+        config.on_stop = StopPolicy::Drain;
+        provider.add_replica("c2", config);
+        assert!(provider.replicas["web"].applied);
+        assert!(provider.retained_services().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_keep_retains_until_prune() {
+        let mut provider = test_provider();
+        let mut config = replica_config("web", "10.0.0.1:80");
+        config.on_stop = StopPolicy::Keep;
+        provider.add_replica("c1", config);
+        provider.mark_applied("web");
+
+        assert!(provider.release_container("c1", &HashMap::new()).is_empty());
+        assert!(provider.in_flight.tasks.is_empty());
+        assert_eq!(provider.retained_services(), vec!["web".to_string()]);
+
+        provider.prune_retained();
+        assert!(provider.replicas.is_empty());
+        assert!(provider.in_flight.tasks.contains_key("web"));
+    }
+
+    #[tokio::test]
+    async fn test_tombstone_until_ttl() {
+        let mut provider = test_provider();
+        provider.config.tombstone_ttl = Duration::from_secs(60);
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.mark_applied("web");
+
+        assert!(provider.release_container("c1", &HashMap::new()).is_empty());
+        assert!(provider.in_flight.tasks.is_empty());
+        assert!(provider.retained_services().is_empty());
+        assert_eq!(provider.tombstone_expiries(Instant::now()).keys().collect::<Vec<_>>(), vec!["web"]);
+        // The duplicate stop of the same container leaves the tombstone alone
+        let attributes = HashMap::from([
+            ("pingap.enable".to_string(), "true".to_string()),
+            ("pingap.service.name".to_string(), "web".to_string()),
+        ]);
+        assert!(provider.release_container("c1", &attributes).is_empty());
+        assert!(provider.tombstones.contains_key("web"));
+
+        // Started again in time, only its address is written again
+        provider.container_services.insert("c2".to_string(), BTreeSet::from(["web".to_string()]));
+        provider.add_replica("c2", replica_config("web", "10.0.0.2:80"));
+        assert!(provider.replicas["web"].applied);
+        assert!(provider.tombstones.is_empty());
+
+        provider.release_container("c2", &HashMap::new());
+        provider.purge_tombstones(Instant::now());
+        assert!(provider.replicas.contains_key("web"));
+        provider.purge_tombstones(Instant::now() + Duration::from_secs(61));
+        assert!(provider.replicas.is_empty());
+        assert!(provider.tombstones.is_empty());
+        assert!(provider.in_flight.tasks.contains_key("web"));
+    }
+
+    #[tokio::test]
+    async fn test_state_export_import() {
+        let mut old = test_provider();
+        old.config.tombstone_ttl = Duration::from_secs(600);
+        old.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        old.mark_applied("web");
+        old.release_container("c1", &HashMap::new());
+        let mut kept = replica_config("docs", "10.0.0.3:80");
+        kept.on_stop = StopPolicy::Keep;
+        old.add_replica("c3", kept);
+        old.detach_replica("docs", "c3");
+        old.add_replica("c4", replica_config("api", "10.0.0.4:80"));
+        old.maintenance.insert("docs".to_string());
+        old.pingap.add_orphans(&["plugins/web-cors".to_string()]);
+        let now = Instant::now();
+        let state = old.export_state(now);
+        let secs = state.services["web"].tombstone_secs.unwrap();
+        assert!(secs > 590);
+
+        let json = serde_json::to_string(&state).unwrap();
+        let mut new = test_provider();
+        new.add_replica("c9", replica_config("api", "10.0.0.9:80"));
+        new.import_state(ProviderState::parse(&json).unwrap(), now);
+
+        assert_eq!(new.tombstone_expiries(now), BTreeMap::from([("web".to_string(), secs)]));
+        assert_eq!(new.retained_services(), vec!["docs".to_string()]);
+        // Live replicas win, Pingap of the new host hasn't been read yet
+        assert_eq!(new.replicas["api"].merged_config().upstreams, vec!["10.0.0.9:80"]);
+        assert!(!new.replicas["web"].applied);
+        assert!(new.status.maintenance_requests().contains("docs"));
+        assert_eq!(new.pingap.orphans(), vec!["plugins/web-cors".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_converge_writes_other_services_again() {
+        // Pingap accepts connections but never answers, the converge hangs
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let mut provider = test_provider();
+        provider.pingap = Arc::new(PingapClient::new(format!("http://{}", addr)));
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("api", "10.0.0.2:80"));
+        provider.spawn_converge();
+        let group = provider.in_flight.tasks["api"].generation;
+        assert_eq!(provider.in_flight.tasks["web"].generation, group);
+
+        // An event for web cancels the converge, api gets a write of its own
+        provider.spawn_replicas_write("web", "c1".to_string());
+        assert_ne!(provider.in_flight.tasks["web"].generation, group);
+        assert_ne!(provider.in_flight.tasks["api"].generation, group);
+        assert_ne!(provider.in_flight.tasks["api"].generation, provider.in_flight.tasks["web"].generation);
+        assert!(provider.in_flight.abandoned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_converge_writes_only_differences() {
+        let mut server = mockito::Server::new_async().await;
+        let _config_mock = server.mock("GET", "/config")
+            .with_status(200)
+            .with_body(serde_json::json!({
+                "upstreams": {
+                    "web": { "addrs": ["10.0.0.1:80"], "remark": pingap::MANAGED_REMARK },
+                    "kept": { "addrs": ["10.0.0.3:80"], "remark": pingap::MANAGED_REMARK },
+                    "gone": { "addrs": ["10.0.0.4:80"], "remark": pingap::MANAGED_REMARK },
+                },
+                "locations": {
+                    "kept": { "upstream": "kept", "remark": pingap::MANAGED_REMARK },
+                    "gone": { "upstream": "gone", "remark": pingap::MANAGED_REMARK },
+                },
+            }).to_string())
+            .create_async()
+            .await;
+        let writes = [("POST", "/upstreams/web"), ("POST", "/locations/web"), ("DELETE", "/locations/gone"), ("DELETE", "/upstreams/gone")];
+        let mut mocks = Vec::new();
+        for (method, path) in writes {
+            mocks.push(server.mock(method, path).with_status(200).expect(1).create_async().await);
+        }
+
+        let mut provider = test_provider();
+        provider.pingap = Arc::new(PingapClient::new(server.url()));
+        provider.add_replica("c1", replica_config("web", "10.0.0.2:80"));
+        // A retained service stays as Pingap has it
+        let mut kept = replica_config("kept", "10.0.0.3:80");
+        kept.on_stop = StopPolicy::Keep;
+        provider.add_replica("c2", kept);
+        provider.detach_replica("kept", "c2");
+
+        provider.spawn_converge();
+        assert!(provider.drain_writes(Duration::from_secs(5)).await);
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        assert!(provider.replicas["web"].applied);
+        assert!(!provider.replicas["kept"].applied);
+    }
+
+    #[tokio::test]
+    async fn test_stop_policy_from_attributes_of_unknown_container() {
+        let provider = test_provider();
+        let attributes = HashMap::from([("pingap.on_stop".to_string(), "keep".to_string())]);
+        assert_eq!(provider.stop_policy("web", &attributes), StopPolicy::Keep);
+        assert_eq!(provider.stop_policy("web", &HashMap::new()), StopPolicy::Remove);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_address_rewrites_its_upstream() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("web", "10.0.0.2:80"));
+        provider.add_replica("c3", replica_config("api", "10.0.0.3:80"));
+        provider.mark_applied("web");
+        provider.mark_applied("api");
+
+        provider.handle_probes(vec![
+            ("10.0.0.1:80".to_string(), false),
+            ("10.0.0.3:80".to_string(), true),
+        ]);
+        assert_eq!(provider.health.evicted(), vec!["10.0.0.1:80".to_string()]);
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(!provider.in_flight.tasks.contains_key("api"));
+    }
+
+    #[tokio::test]
+    async fn test_load_weights_rewrite_scaled_upstreams() {
+        let mut provider = test_provider();
+        provider.config.load_weight_interval = Duration::from_secs(10);
+        provider.loads = LoadWeights::new(10, 1.0, false);
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("web", "10.0.0.2:80"));
+        provider.add_replica("c3", replica_config("api", "10.0.0.3:80"));
+        provider.mark_applied("web");
+        provider.mark_applied("api");
+
+        provider.handle_loads(vec![
+            ("c1".to_string(), Load { cpu: 0.8, memory: 0.0 }),
+            ("c2".to_string(), Load { cpu: 0.0, memory: 0.0 }),
+            ("c3".to_string(), Load { cpu: 0.8, memory: 0.0 }),
+        ]);
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        // A single replica has nothing to balance against
+        assert!(!provider.in_flight.tasks.contains_key("api"));
+        let addrs = provider.replicas["web"].merged_config().upstreams;
+        assert_eq!(provider.weigh_addrs("web", addrs), ["10.0.0.1:80 2", "10.0.0.2:80 10"]);
+        assert_eq!(provider.weigh_addrs("api", vec!["10.0.0.3:80".to_string()]), ["10.0.0.3:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_label_weights_of_scaled_upstreams() {
+        let mut provider = test_provider();
+        let weighted = |addr: &str, weight: u32| models::PingapServiceConfigBuilder::new("web", vec![addr.to_string()], "Host(`web.local`)")
+            .with_upstream_config(Some(models::UpstreamConfig { weight: Some(weight), ..Default::default() }))
+            .build();
+        provider.add_replica("c1", weighted("10.0.0.1:80", 3));
+        assert_eq!(provider.weigh_addrs("web", vec!["10.0.0.1:80".to_string()]), ["10.0.0.1:80"]);
+        provider.add_replica("c2", weighted("10.0.0.2:80", 1));
+        provider.add_replica("c3", replica_config("web", "10.0.0.3:80"));
+        let addrs = provider.replicas["web"].merged_config().upstreams;
+        assert_eq!(provider.weigh_addrs("web", addrs.clone()), ["10.0.0.1:80 3", "10.0.0.2:80 1", "10.0.0.3:80"]);
+
+        // Load weights fill in for replicas without a weight label
+        provider.config.load_weight_interval = Duration::from_secs(10);
+        provider.loads = LoadWeights::new(10, 1.0, false);
+        provider.handle_loads(vec![("c1".to_string(), Load { cpu: 0.8, memory: 0.0 })]);
+        assert!(!provider.in_flight.tasks.contains_key("web"));
+        assert_eq!(provider.weigh_addrs("web", addrs), ["10.0.0.1:80 3", "10.0.0.2:80 1", "10.0.0.3:80 10"]);
+
+        provider.remove_replica("web", "c1");
+        assert!(!provider.replicas["web"].weights.contains_key("c1"));
+    }
+
+    #[tokio::test]
+    async fn test_container_with_several_services() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("app-web", "10.0.0.1:8080"));
+        provider.add_replica("c1", replica_config("app-metrics", "10.0.0.1:9090"));
+        provider.mark_applied("app-web");
+        provider.mark_applied("app-metrics");
+        assert_eq!(provider.container_services["c1"].len(), 2);
+
+        let removed = provider.release_container("c1", &HashMap::new());
+        assert_eq!(removed, vec!["app-metrics".to_string(), "app-web".to_string()]);
+        assert!(provider.replicas.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stopped_services_from_indexed_attributes() {
+        let mut provider = test_provider();
+        let attributes = HashMap::from([
+            ("name".to_string(), "app".to_string()),
+            ("pingap.enable".to_string(), "true".to_string()),
+            ("pingap.services.web.port".to_string(), "8080".to_string()),
+            ("pingap.services.metrics.port".to_string(), "9090".to_string()),
+        ]);
+        assert_eq!(provider.stopped_services("c1", &attributes), vec!["app-metrics".to_string(), "app-web".to_string()]);
+    }
+
+    fn dependent_config(service: &str, addr: &str, depends_on: &[&str]) -> PingapServiceConfig {
+        models::PingapServiceConfigBuilder::new(service, vec![addr.to_string()], format!("Host(`{}.local`)", service))
+            .with_depends_on(depends_on.iter().map(|d| d.to_string()).collect())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_route_waits_for_dependency() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", dependent_config("frontend", "10.0.0.1:80", &["api"]));
+        provider.spawn_replicas_write("frontend", "c1".to_string());
+        assert!(provider.in_flight.tasks.is_empty());
+        assert_eq!(provider.waiting_for_dependencies()["frontend"], vec!["api".to_string()]);
+
+        provider.add_replica("c2", replica_config("api", "10.0.0.2:80"));
+        provider.mark_applied("api");
+        provider.release_waiting();
+        assert!(provider.in_flight.tasks.contains_key("frontend"));
+        assert!(provider.waiting.is_empty());
+    }
+
+    #[test]
+    fn test_dependency_waves() {
+        let configs = vec![
+            replica_config("api", "10.0.0.1:80"),
+            replica_config("db", "10.0.0.2:80"),
+            dependent_config("frontend", "10.0.0.3:80", &["api"]),
+            // Dependencies outside the sync don't hold a service back
+            dependent_config("admin", "10.0.0.4:80", &["auth"]),
+            dependent_config("edge", "10.0.0.5:80", &["frontend", "db"]),
+        ];
+        let names = dependency_waves(configs).into_iter()
+            .map(|wave| wave.into_iter().map(|config| config.name).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![vec!["api", "db", "admin"], vec!["frontend"], vec!["edge"]]);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_keeps_started_replica_out() {
+        let mut provider = test_provider();
+        provider.start_container(&compose_container("c1", "shop", "10.0.0.1"));
+        let mut starting = compose_container("c2", "shop", "10.0.0.2");
+        starting.labels.insert("pingap.warmup".to_string(), "30s".to_string());
+        provider.start_warming(&starting, true);
+        let addrs = |provider: &Provider| provider.without_warming("web", provider.replicas["web"].merged_config().upstreams);
+        assert_eq!(addrs(&provider), ["10.0.0.1:80"]);
+
+        // The warmup only starts once the health check passes
+        let now = Instant::now();
+        provider.release_warmups(now + Duration::from_secs(60));
+        assert_eq!(addrs(&provider), ["10.0.0.1:80"]);
+        provider.handle_healthy("c2", now);
+        provider.release_warmups(now + Duration::from_secs(29));
+        assert_eq!(addrs(&provider), ["10.0.0.1:80"]);
+        provider.release_warmups(now + Duration::from_secs(30));
+        assert_eq!(addrs(&provider), ["10.0.0.1:80", "10.0.0.2:80"]);
+
+        // A service whose only replica warms up isn't written yet
+        let config = models::PingapServiceConfigBuilder::new("api", vec!["10.0.0.3:80".to_string()], "Host(`api.local`)")
+            .with_warmup(Some(Duration::from_secs(30)))
+            .build();
+        provider.add_replica("c3", config);
+        provider.warming.insert(("api".to_string(), "c3".to_string()), Warmup::Until(now + Duration::from_secs(30)));
+        provider.spawn_replicas_write("api", "c3".to_string());
+        assert!(!provider.in_flight.tasks.contains_key("api"));
+        provider.handle_stop("c3", &HashMap::new());
+        assert!(provider.warming.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dependency_wait_times_out() {
+        let mut provider = test_provider();
+        provider.config.dependency_timeout = Duration::from_secs(5);
+        provider.add_replica("c1", dependent_config("frontend", "10.0.0.1:80", &["api"]));
+        provider.spawn_replicas_write("frontend", "c1".to_string());
+        provider.release_waiting();
+        assert!(provider.in_flight.tasks.is_empty());
+
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(10)).unwrap();
+        provider.waiting.insert("frontend".to_string(), long_ago);
+        provider.release_waiting();
+        assert!(provider.in_flight.tasks.contains_key("frontend"));
+    }
+
+    #[tokio::test]
+    async fn test_order_by_dependencies() {
+        let provider = test_provider();
+        let (ordered, blocked) = provider.order_by_dependencies(vec![
+            dependent_config("frontend", "10.0.0.1:80", &["api"]),
+            dependent_config("api", "10.0.0.2:80", &["auth"]),
+            replica_config("auth", "10.0.0.3:80"),
+            dependent_config("lonely", "10.0.0.4:80", &["missing"]),
+            dependent_config("ping", "10.0.0.5:80", &["pong"]),
+            dependent_config("pong", "10.0.0.6:80", &["ping"]),
+        ]);
+        let names = |configs: &[PingapServiceConfig]| configs.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&ordered), vec!["auth", "api", "frontend"]);
+        assert_eq!(names(&blocked), vec!["lonely", "ping", "pong"]);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_requested_through_status_api() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.mark_applied("web");
+
+        provider.status.set_maintenance("web", true);
+        provider.status.set_maintenance("idle", true);
+        provider.sync_maintenance();
+        assert!(!provider.replicas["web"].applied);
+        assert!(provider.desired_config(&provider.replicas["web"]).maintenance);
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(!provider.in_flight.tasks.contains_key("idle"));
+        assert_eq!(provider.status.snapshot().maintenance, vec!["idle".to_string(), "web".to_string()]);
+
+        provider.status.set_maintenance("web", false);
+        provider.sync_maintenance();
+        assert!(!provider.desired_config(&provider.replicas["web"]).maintenance);
+    }
+
+    #[tokio::test]
+    async fn test_freeze_window_holds_back_writes() {
+        let mut provider = test_provider();
+        provider.config.freeze_windows = "08:00-10:00".parse().unwrap();
+        let at = |h: u64| UNIX_EPOCH + Duration::from_secs(h * 3600);
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("api", "10.0.0.2:80"));
+        provider.mark_applied("api");
+
+        provider.update_freeze(at(9));
+        provider.spawn_replicas_write("web", "c1".to_string());
+        provider.handle_stop("c2", &HashMap::new());
+        assert!(provider.in_flight.tasks.is_empty());
+        assert_eq!(provider.held_back, BTreeSet::from(["api".to_string(), "web".to_string()]));
+        assert!(provider.status.snapshot().frozen);
+
+        // Closing the window writes the state the services are in by then
+        provider.update_freeze(at(10));
+        assert!(provider.held_back.is_empty());
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(provider.in_flight.tasks.contains_key("api"));
+        assert!(!provider.status.snapshot().frozen);
+    }
+
+    #[tokio::test]
+    async fn test_freeze_window_lets_removals_through() {
+        let mut provider = test_provider();
+        provider.config.freeze_windows = "08:00-10:00".parse().unwrap();
+        provider.config.freeze_allow_deletes = true;
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.mark_applied("web");
+
+        provider.update_freeze(UNIX_EPOCH + Duration::from_secs(9 * 3600));
+        provider.handle_stop("c1", &HashMap::new());
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(provider.held_back.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replayed_events_reconciled_with_initial_sync() {
+        let mut provider = test_provider();
+        provider.replay = Some(Replay { since: 100, until: 200, running: HashSet::from(["c1".to_string()]) });
+
+        assert!(provider.is_replayed(150));
+        // Whatever was running at the sync has been applied by it
+        assert!(provider.covered_by_initial_sync(ContainerAction::Started, "c2"));
+        assert!(provider.covered_by_initial_sync(ContainerAction::ServiceRemoved, "c1"));
+        // A container that stopped while the provider was down has not
+        assert!(!provider.covered_by_initial_sync(ContainerAction::ServiceRemoved, "c2"));
+
+        assert!(!provider.is_replayed(200));
+        assert!(provider.replay.is_none());
+        assert!(!provider.is_replayed(150));
+        assert_eq!(provider.status.metrics.counter("pingap_provider_replayed_events_total", &[]), 1);
+    }
+
+    #[tokio::test]
+    async fn test_die_and_stop_handled_once() {
+        let mut provider = test_provider();
+        let now = Instant::now();
+        assert_eq!(ContainerAction::from_event("die"), ContainerAction::from_event("stop"));
+        assert_eq!(ContainerAction::from_event("create"), None);
+
+        let removed = ContainerAction::ServiceRemoved;
+        assert!(provider.is_new_action(removed, "c1", now));
+        assert!(!provider.is_new_action(removed, "c1", now + Duration::from_millis(20)));
+        assert!(provider.is_new_action(removed, "c2", now));
+
+        // A restart in between is a new stop
+        assert!(provider.is_new_action(ContainerAction::Started, "c1", now + Duration::from_secs(1)));
+        assert!(provider.is_new_action(removed, "c1", now + Duration::from_secs(2)));
+        // And so is a stop long after the last one
+        assert!(provider.is_new_action(removed, "c2", now + STOP_DEDUP_WINDOW));
+        assert_eq!(provider.recent_stops.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_destroy_releases_container_that_missed_its_stop() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.mark_applied("web");
+        provider.flap.record("c1", Instant::now());
+
+        provider.handle_destroy("c1", &HashMap::new());
+        assert!(provider.container_services.is_empty());
+        assert!(provider.replicas.is_empty());
+        assert!(provider.in_flight.tasks.contains_key("web"));
+
+        // After a regular stop the destroy only cleans up
+        provider.add_replica("c2", replica_config("api", "10.0.0.2:80"));
+        provider.mark_applied("api");
+        provider.handle_stop("c2", &HashMap::new());
+        provider.in_flight.tasks.clear();
+        assert!(provider.is_new_action(ContainerAction::ServiceRemoved, "c2", Instant::now()));
+        provider.handle_destroy("c2", &HashMap::new());
+        assert!(provider.in_flight.tasks.is_empty());
+        assert!(provider.recent_stops.is_empty());
+    }
+
+    fn compose_container(id: &str, project: &str, ip: &str) -> ContainerInfo {
+        ContainerInfo {
+            id: id.to_string(),
+            name: format!("{}-web-{}", project, id),
+            image: "nginx:alpine".to_string(),
+            labels: HashMap::from([
+                ("pingap.enable".to_string(), "true".to_string()),
+                ("pingap.service.name".to_string(), "web".to_string()),
+                ("pingap.service.port".to_string(), "80".to_string()),
+                ("pingap.http.host".to_string(), "web.local".to_string()),
+                (LABEL_COMPOSE_PROJECT.to_string(), project.to_string()),
+            ]),
+            ip_address: Some(ip.to_string()),
+            ports: vec![80],
+            networks: HashMap::from([("bridge".to_string(), ip.to_string())]),
+            ipv6_networks: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ownership_check_fails_closed() {
+        let config = models::PingapServiceConfigBuilder::new("web", vec!["10.0.0.1:80".to_string()], "Host(`web.local`)").build();
+        // Pingap can't be reached
+        let provider = test_provider();
+        assert!(!provider.may_take_ownership(&config).await);
+        assert_eq!(provider.status.snapshot().recent_errors.len(), 1);
+
+        let mut server = mockito::Server::new_async().await;
+        let _absent = server.mock("GET", mockito::Matcher::Any).with_status(404).create_async().await;
+        let mut provider = test_provider();
+        provider.pingap = Arc::new(PingapClient::new(server.url()));
+        assert!(provider.may_take_ownership(&config).await);
+    }
+
+    #[tokio::test]
+    async fn test_service_name_collision_refused() {
+        let mut provider = test_provider();
+        provider.start_container(&compose_container("c1", "shop", "10.0.0.1"));
+        provider.start_container(&compose_container("c2", "shop", "10.0.0.2"));
+        provider.start_container(&compose_container("c3", "blog", "10.0.0.3"));
+        assert_eq!(provider.replicas["web"].addrs.keys().collect::<Vec<_>>(), ["c1", "c2"]);
+
+        provider.publish_status();
+        let conflicts = provider.status.snapshot().service_conflicts;
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].container, "blog-web-c3");
+        assert_eq!(conflicts[0].origin, "compose project blog");
+        assert_eq!(conflicts[0].running.len(), 2);
+        assert_eq!(conflicts[0].running["shop-web-c1"], "compose project shop");
+
+        // Stopping the refused container leaves the service to the others
+        provider.in_flight.tasks.clear();
+        let attributes = HashMap::from([
+            ("name".to_string(), "blog-web-c3".to_string()),
+            ("pingap.service.name".to_string(), "web".to_string()),
+        ]);
+        provider.handle_stop("c3", &attributes);
+        assert!(provider.in_flight.tasks.is_empty());
+        assert!(provider.conflicts.is_empty());
+        assert_eq!(provider.replicas["web"].addrs.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stop_leaves_services_left_alone_at_initial_sync() {
+        let mut provider = test_provider();
+        provider.unowned.insert("web".to_string());
+        let attributes = HashMap::from([
+            ("name".to_string(), "shop-web-c1".to_string()),
+            ("pingap.enable".to_string(), "true".to_string()),
+            ("pingap.service.name".to_string(), "web".to_string()),
+        ]);
+        // Pingap has web without the ownership marker, the labels alone don't make it ours
+        provider.handle_stop("c1", &attributes);
+        assert!(provider.in_flight.tasks.is_empty());
+
+        provider.unowned.clear();
+        provider.handle_stop("c2", &attributes);
+        assert!(provider.in_flight.tasks.contains_key("web"));
+    }
+
+    #[tokio::test]
+    async fn test_route_conflict_policy() {
+        let team_container = |id: &str, service: &str, ip: &str| {
+            let mut container = compose_container(id, service, ip);
+            container.labels.insert("pingap.service.name".to_string(), service.to_string());
+            container.labels.insert("pingap.http.paths".to_string(), "/api".to_string());
+            container
+        };
+
+        let mut provider = test_provider();
+        provider.start_container(&team_container("c1", "shop", "10.0.0.1"));
+        provider.start_container(&team_container("c2", "blog", "10.0.0.2"));
+        // A higher priority is a deliberate override
+        let mut priority = team_container("c3", "admin", "10.0.0.3");
+        priority.labels.insert("pingap.http.priority".to_string(), "10".to_string());
+        provider.start_container(&priority);
+        assert_eq!(provider.replicas.len(), 3);
+        provider.publish_status();
+        let conflicts = provider.status.snapshot().route_conflicts;
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!((conflicts[0].service.as_str(), conflicts[0].conflicts_with.as_str()), ("blog", "shop"));
+        assert_eq!(conflicts[0].route, "Host(`web.local`) && PathPrefix(`/api`)");
+        assert!(!conflicts[0].blocked);
+
+        let mut provider = test_provider();
+        provider.config.conflict_policy = ConflictPolicy::Block;
+        provider.start_container(&team_container("c1", "shop", "10.0.0.1"));
+        provider.start_container(&team_container("c2", "blog", "10.0.0.2"));
+        assert_eq!(provider.replicas.keys().collect::<Vec<_>>(), ["shop"]);
+        assert!(provider.route_conflicts["c2"][0].blocked);
+
+        // Stopping the blocked container leaves the route to the other service
+        provider.in_flight.tasks.clear();
+        let attributes = HashMap::from([
+            ("name".to_string(), "blog-web-c2".to_string()),
+            ("pingap.enable".to_string(), "true".to_string()),
+            ("pingap.service.name".to_string(), "blog".to_string()),
+        ]);
+        provider.handle_stop("c2", &attributes);
+        assert!(provider.in_flight.tasks.is_empty());
+        assert!(provider.route_conflicts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_policy_refuses_service() {
+        let policy = Policy::parse("policy.json", r#"{ "rules": [{ "name": "auth", "paths": ["/admin"], "require": ["basic_auth"] }] }"#).unwrap();
+        let mut provider = test_provider().with_policy(Some(Arc::new(policy)));
+        let mut admin = compose_container("c1", "shop", "10.0.0.1");
+        admin.labels.insert("pingap.http.paths".to_string(), "/admin".to_string());
+        provider.start_container(&admin);
+        assert!(provider.replicas.is_empty());
+        provider.publish_status();
+        let violations = provider.status.snapshot().policy_violations;
+        assert_eq!((violations[0].service.as_str(), violations[0].rule.as_str()), ("web", "auth"));
+        assert_eq!(violations[0].problem, "requires basic_auth");
+
+        // Fixed labels clear the violation
+        admin.labels.insert("pingap.middleware.basic_auth".to_string(), "admin:secret".to_string());
+        provider.start_container(&admin);
+        assert!(provider.replicas.contains_key("web"));
+        assert!(provider.policy_violations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hostname_checks() {
+        let underscored = |id: &str| {
+            let mut container = compose_container(id, "shop", "10.0.0.1");
+            container.labels.insert("pingap.http.host".to_string(), "my_shop.local".to_string());
+            container
+        };
+        let mut provider = test_provider();
+        provider.start_container(&underscored("c1"));
+        assert!(provider.replicas.contains_key("web"));
+
+        let mut provider = test_provider();
+        provider.config.hostname_policy = HostnamePolicy::Strict;
+        provider.start_container(&underscored("c1"));
+        assert!(provider.replicas.is_empty());
+        assert!(provider.status.snapshot().recent_errors[0].message.contains("my_shop.local"));
+
+        let localhost = [IpAddr::from([127, 0, 0, 1])];
+        assert_eq!(resolution_problem("127.0.0.1", &localhost).await, None);
+        let problem = resolution_problem("127.0.0.1", &[IpAddr::from([10, 0, 0, 9])]).await.unwrap();
+        assert!(problem.contains("resolves to 127.0.0.1 instead of the proxy (10.0.0.9)"), "{}", problem);
+        assert!(resolution_problem("invalid.invalid", &localhost).await.unwrap().starts_with("does not resolve"));
+    }
+
+    #[tokio::test]
+    async fn test_dns_hosts_of_applied_services() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("api", "10.0.0.2:80"));
+        // Records only once Pingap serves the service
+        assert!(provider.dns_hosts().is_empty());
+        provider.mark_applied("web");
+        assert_eq!(provider.dns_hosts(), BTreeSet::from(["web.local".to_string()]));
+
+        provider.remove_replica("web", "c1");
+        assert!(provider.dns_hosts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_config_hook_changes_and_refuses_services() {
+        let mut provider = test_provider().with_config_hook(Some(Arc::new(ConfigHook::from_source("policy.lua", r#"
+            function transform(config, container)
+              if container.labels["com.docker.compose.project"] == "legacy" then
+                return false, "legacy projects are frozen"
+              end
+              config.location.priority = 7
+              return config
+            end
+        "#).unwrap())));
+        provider.start_container(&compose_container("c1", "shop", "10.0.0.1"));
+        assert_eq!(provider.replicas["web"].config.location.priority, Some(7));
+
+        provider.start_container(&compose_container("c2", "legacy", "10.0.0.2"));
+        assert_eq!(provider.replicas["web"].addrs.len(), 1);
+        assert!(provider.status.snapshot().recent_errors[0].message.contains("legacy projects are frozen"));
+    }
+
+    #[tokio::test]
+    async fn test_pingap_restart_reapplies_everything() {
+        let mut server = mockito::Server::new_async().await;
+        let mut provider = test_provider();
+        provider.pingap = Arc::new(PingapClient::new(server.url()));
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("api", "10.0.0.2:80"));
+        provider.mark_applied("web");
+        provider.mark_applied("api");
+        let full = serde_json::json!({ "locations": { "web": {}, "api": {} } });
+        let _config = server.mock("GET", "/config").with_body(full.to_string()).create_async().await;
+
+        let first = server.mock("GET", "/basic").with_body(r#"{"start_time": 1}"#).create_async().await;
+        provider.check_pingap().await;
+        assert!(provider.in_flight.tasks.is_empty());
+        first.remove_async().await;
+
+        let _restarted = server.mock("GET", "/basic").with_body(r#"{"start_time": 2}"#).create_async().await;
+        provider.check_pingap().await;
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(provider.in_flight.tasks.contains_key("api"));
+        assert!(!provider.replicas["web"].applied);
+    }
+
+    #[tokio::test]
+    async fn test_pingap_container_start_reapplies_once() {
+        let mut server = mockito::Server::new_async().await;
+        let mut provider = test_provider();
+        provider.pingap = Arc::new(PingapClient::new(server.url()));
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.mark_applied("web");
+        provider.pingap_instance = Some("1".to_string());
+
+        provider.pingap_container_started().await;
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(!provider.replicas["web"].applied);
+
+        // The poll seeing the new instance doesn't re-apply a second time
+        let _config = server.mock("GET", "/config").with_body(r#"{"locations": {}}"#).create_async().await;
+        let _basic = server.mock("GET", "/basic").with_body(r#"{"start_time": 2}"#).create_async().await;
+        provider.check_pingap().await;
+        assert!(provider.status.metrics.render().contains("pingap_provider_pingap_reapplies_total{reason=\"restart\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_external_changes_observed_or_enforced() {
+        let mut server = mockito::Server::new_async().await;
+        let mut provider = test_provider();
+        provider.pingap = Arc::new(PingapClient::new(server.url()));
+        let config = replica_config("web", "10.0.0.1:80");
+        provider.add_replica("c1", config.clone());
+        provider.mark_applied("web");
+        // Someone pointed the upstream elsewhere, the location is untouched
+        let full = serde_json::json!({
+            "upstreams": { "web": { "addrs": ["10.0.0.9:80"], "remark": pingap::MANAGED_REMARK } },
+            "locations": { "web": pingap::location_payload(&config).unwrap() },
+        });
+        let _config = server.mock("GET", "/config").with_body(full.to_string()).create_async().await;
+        let _basic = server.mock("GET", "/basic").with_body(r#"{"start_time": 1}"#).create_async().await;
+
+        provider.check_pingap().await;
+        assert_eq!(provider.drift, vec![Drift::Different { service: "web".to_string(), fields: vec!["upstreams/web".to_string()] }]);
+        assert!(provider.in_flight.tasks.is_empty());
+        // Reported once, not on every poll
+        provider.check_pingap().await;
+        let metrics = provider.status.metrics.render();
+        assert!(metrics.contains("pingap_provider_external_changes_total{policy=\"observe\"} 1"));
+        assert!(metrics.contains("pingap_provider_drift{kind=\"different\"} 1"));
+
+        provider.config.drift_policy = DriftPolicy::Enforce;
+        provider.check_pingap().await;
+        assert!(provider.in_flight.tasks.contains_key("web"));
+        assert!(!provider.replicas["web"].applied);
+    }
+
+    #[tokio::test]
+    async fn test_lost_services() {
+        let mut provider = test_provider();
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("api", "10.0.0.2:80"));
+        provider.mark_applied("web");
+        provider.mark_applied("api");
+        // Not applied yet, a write is still on its way
+        provider.add_replica("c3", replica_config("auth", "10.0.0.3:80"));
+
+        let full = serde_json::json!({ "locations": { "api": {} } });
+        assert_eq!(provider.lost_services(&full), ["web"]);
+    }
+
+    #[tokio::test]
+    async fn test_event_to_apply_latency() {
+        let mut provider = test_provider();
+        let event = UNIX_EPOCH + Duration::from_secs(1000);
+        provider.event_time = Some(event);
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.spawn_replicas_write("web", "c1".to_string());
+        // A second event before the write lands keeps the first one's start
+        provider.event_time = Some(event + Duration::from_secs(1));
+        provider.spawn_replicas_write("web", "c1".to_string());
+        provider.event_time = None;
+        assert_eq!(provider.pending_since["web"], event);
+
+        provider.publish_status();
+        assert!(provider.status.metrics.render().contains("pingap_provider_queue_depth{stage=\"in_flight\"} 1\n"));
+
+        provider.observe_applied("web", event + Duration::from_millis(1500));
+        provider.observe_applied("api", event);
+        let rendered = provider.status.metrics.render();
+        assert!(rendered.contains("pingap_provider_event_to_apply_seconds_bucket{backend=\"admin_api\",le=\"1\"} 0\n"));
+        assert!(rendered.contains("pingap_provider_event_to_apply_seconds_bucket{backend=\"admin_api\",le=\"2.5\"} 1\n"));
+        assert!(rendered.contains("pingap_provider_event_to_apply_seconds_count{backend=\"admin_api\"} 1\n"));
+        assert!(provider.pending_since.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_metrics() {
+        let mut provider = test_provider();
+        provider.publish_memory_metrics();
+        let empty = provider.estimated_state_bytes();
+        assert_eq!(empty, 0);
+
+        provider.add_replica("c1", replica_config("web", "10.0.0.1:80"));
+        provider.add_replica("c2", replica_config("web", "10.0.0.2:80"));
+        provider.mark_applied("web");
+        provider.publish_memory_metrics();
+        assert!(provider.estimated_state_bytes() > empty);
+
+        let rendered = provider.status.metrics.render();
+        assert!(rendered.contains("pingap_provider_state_entries{state=\"containers\"} 2"));
+        assert!(rendered.contains("pingap_provider_state_entries{state=\"replicas\"} 1"));
+        assert!(rendered.contains("pingap_provider_state_bytes "));
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use tracing::{error, info};
+use crate::audit::{self, DesiredConfigs, Drift};
+use crate::docker::DockerClient;
+use crate::hooks::ConfigHook;
+use crate::pingap::PingapClient;
+
+/// Provider-owned services in Pingap's config that no running container
+/// asks for, leaving out the ones the config hook holds back.
+fn stale_services(desired: &DesiredConfigs, actual: &Value) -> Result<Vec<String>> {
+    Ok(desired.drift(actual)?
+        .into_iter()
+        .filter_map(|drift| match drift {
+            Drift::Orphaned { service } => Some(service),
+            _ => None,
+        })
+        .collect())
+}
+
+/// `pingap-docker-provider prune [--dry-run]`: lists the managed upstreams
+/// and locations without a running container behind them on stdout and,
+/// unless `dry_run` is set, deletes them. Resources without the ownership
+/// marker are never touched.
+pub async fn run(docker: &DockerClient, pingap: &PingapClient, hook: Option<&ConfigHook>, dry_run: bool) -> Result<()> {
+    let containers = docker.get_running_containers().await?;
+    let desired = audit::desired_configs(&containers, hook);
+    for config in &desired.held {
+        info!("Keeping service {}, the config hook refused it or failed", config.name);
+    }
+    let actual = pingap.fetch_full_config().await?;
+
+    let stale = stale_services(&desired, &actual)?;
+    if stale.is_empty() {
+        info!("No stale managed resources found");
+        return Ok(());
+    }
+    for service in &stale {
+        println!("{}", service);
+    }
+    if dry_run {
+        info!("Dry run: {} stale services would be removed", stale.len());
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for service in &stale {
+        if let Err(e) = pingap.delete_config(service).await {
+            error!("Failed to remove stale service {}: {:?}", service, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("Failed to remove {} of {} stale services", failed, stale.len()));
+    }
+    info!("Removed {} stale services", stale.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{PingapServiceConfig, PingapServiceConfigBuilder};
+    use crate::pingap::MANAGED_REMARK;
+
+    fn config(name: &str) -> PingapServiceConfig {
+        PingapServiceConfigBuilder::new(name, vec!["10.0.0.1:80".to_string()], format!("Host(`{}.local`)", name)).build()
+    }
+
+    #[test]
+    fn test_stale_services_only_managed_without_container() {
+        let actual = serde_json::json!({
+            "upstreams": {
+                "web": { "addrs": ["10.0.0.1:80"], "remark": MANAGED_REMARK },
+                "gone": { "addrs": ["10.0.0.2:80"], "remark": MANAGED_REMARK },
+                "manual": { "addrs": ["10.0.0.3:80"] },
+            },
+            "locations": {
+                "leftover": { "upstream": "leftover", "remark": MANAGED_REMARK },
+            },
+        });
+
+        let desired = DesiredConfigs { configs: vec![config("web")], held: Vec::new() };
+        let stale = stale_services(&desired, &actual).unwrap();
+        assert_eq!(stale, vec!["gone".to_string(), "leftover".to_string()]);
+
+        // A service the config hook failed on keeps its route
+        let desired = DesiredConfigs { configs: vec![config("web")], held: vec![config("gone")] };
+        assert_eq!(stale_services(&desired, &actual).unwrap(), vec!["leftover".to_string()]);
+    }
+}
@@ -0,0 +1,93 @@
+use serde_json::Value;
+
+/// What a secret is replaced with in logs, the change log and the status API.
+/// Pingap itself always gets the real value.
+pub const MASK: &str = "***";
+
+// Parts of a label, payload field or header name that mark its value as a credential
+const SENSITIVE_NAMES: &[&str] = &[
+    "auth", "token", "secret", "password", "passwd", "credential",
+    "api_key", "api-key", "apikey", "private_key", "cookie",
+];
+
+/// Whether a label, payload field or header called `name` holds a credential.
+pub fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SENSITIVE_NAMES.iter().any(|part| name.contains(part))
+}
+
+/// A `Name: value` header with the value masked when the header carries
+/// credentials (`Authorization`, `Cookie`, `X-Api-Key`...). Anything that
+/// isn't a header is returned as-is.
+pub fn header(entry: &str) -> String {
+    match entry.split_once(':') {
+        Some((name, _)) if is_header_name(name.trim()) && is_sensitive(name) => format!("{}: {}", name, MASK),
+        _ => entry.to_string(),
+    }
+}
+
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A label value as it may be shown: masked whole for credential labels like
+/// `pingap.middleware.basic_auth`, per header for header lists.
+pub fn label_value(label: &str, value: &str) -> String {
+    if is_sensitive(label) {
+        return MASK.to_string();
+    }
+    value.split(',').map(header).collect::<Vec<_>>().join(",")
+}
+
+/// A copy of a Pingap payload with every credential field and header masked.
+pub fn value(value: &Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(fields.iter()
+            .map(|(name, field)| {
+                let field = match field {
+                    Value::Null => Value::Null,
+                    _ if is_sensitive(name) => Value::String(MASK.to_string()),
+                    _ => self::value(field),
+                };
+                (name.clone(), field)
+            })
+            .collect()),
+        Value::Array(items) => Value::Array(items.iter().map(self::value).collect()),
+        Value::String(s) => Value::String(header(s)),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_value() {
+        assert_eq!(label_value("pingap.middleware.basic_auth", "admin:hunter2"), MASK);
+        assert_eq!(label_value("pingap.headers.custom_request", "X-Req: 1,Authorization: Bearer abc, X-Api-Key:k"),
+            "X-Req: 1,Authorization: ***, X-Api-Key: ***");
+        assert_eq!(label_value("pingap.http.rule", "Host(`a.local`) && PathPrefix(`/auth`)"), "Host(`a.local`) && PathPrefix(`/auth`)");
+        assert_eq!(label_value("pingap.service.port", "8080"), "8080");
+    }
+
+    #[test]
+    fn test_value_masks_nested_fields_and_headers() {
+        let payload = serde_json::json!({
+            "addrs": ["10.0.0.1:80"],
+            "remark": "managed-by: pingap-docker-provider",
+            "basic_auth": "admin:hunter2",
+            "token": null,
+            "plugin": { "jwt_secret": "s3cret", "status": 503 },
+            "headers": ["Cookie: id=1", "X-Trace: on"],
+        });
+        assert_eq!(value(&payload), serde_json::json!({
+            "addrs": ["10.0.0.1:80"],
+            "remark": "managed-by: pingap-docker-provider",
+            "basic_auth": MASK,
+            "token": null,
+            "plugin": { "jwt_secret": MASK, "status": 503 },
+            "headers": ["Cookie: ***", "X-Trace: on"],
+        }));
+    }
+}
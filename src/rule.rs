@@ -0,0 +1,632 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+
+/// A single host matcher extracted from a routing rule.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostMatcher {
+    Exact(String),
+    /// `*.example.com` - exactly one leading label is matched by the wildcard
+    Wildcard(String),
+    Regex(String),
+}
+
+impl HostMatcher {
+    /// Rule term for this matcher, e.g. ``Host(`*.example.com`)``.
+    pub fn to_rule(&self) -> String {
+        match self {
+            HostMatcher::Exact(host) => format!("Host(`{}`)", host),
+            HostMatcher::Wildcard(domain) => format!("Host(`*.{}`)", domain),
+            HostMatcher::Regex(re) => format!("HostRegexp(`{}`)", re),
+        }
+    }
+}
+
+/// A single path matcher extracted from a routing rule.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathMatcher {
+    Prefix(String),
+    Exact(String),
+    Regex(String),
+}
+
+impl PathMatcher {
+    /// Rule term for this matcher, e.g. ``PathPrefix(`/api`)``.
+    pub fn to_rule(&self) -> String {
+        match self {
+            PathMatcher::Prefix(p) => format!("PathPrefix(`{}`)", p),
+            PathMatcher::Exact(p) => format!("Path(`{}`)", p),
+            PathMatcher::Regex(re) => format!("PathRegexp(`{}`)", re),
+        }
+    }
+}
+
+/// A request header (or cookie) a route requires, with its exact value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueMatcher {
+    pub name: String,
+    pub value: String,
+}
+
+/// Hosts and paths referenced by a Traefik-style rule such as
+/// ``Host(`a.com`) && (PathPrefix(`/api`) || PathPrefix(`/v1`))``.
+///
+/// Pingap locations match on one host list and one path: any listed host
+/// combined with any listed path, see [`parse_rule`] for the rules that fit.
+/// ``Header(`X-Beta`, `true`)`` and ``Cookie(`beta`, `1`)`` narrow that down
+/// further, every one of them has to match.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteMatch {
+    pub hosts: Vec<HostMatcher>,
+    pub paths: Vec<PathMatcher>,
+    pub headers: Vec<ValueMatcher>,
+    pub cookies: Vec<ValueMatcher>,
+}
+
+impl RouteMatch {
+    /// Value for the Pingap location `host` field. Exact hosts are comma-separated,
+    /// wildcard and regex hosts use Pingap's `~` regex prefix.
+    pub fn pingap_host(&self) -> String {
+        let exact_hosts = self.hosts.iter()
+            .map(|h| match h {
+                HostMatcher::Exact(host) => Some(host.as_str()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>();
+        if let Some(hosts) = exact_hosts {
+            return hosts.join(",");
+        }
+
+        // Pingap accepts a single regex per location, so mixed matchers are
+        // folded into one alternation
+        let alternatives = self.hosts.iter()
+            .map(|h| match h {
+                HostMatcher::Exact(host) => format!("^{}$", regex::escape(host)),
+                HostMatcher::Wildcard(domain) => format!("^[^.]+\\.{}$", regex::escape(domain)),
+                HostMatcher::Regex(re) => format!("(?:{})", re),
+            })
+            .collect::<Vec<_>>();
+        format!("~{}", alternatives.join("|"))
+    }
+
+    /// Value for the Pingap location `match_headers` field, as `Name:value`.
+    pub fn pingap_headers(&self) -> Vec<String> {
+        self.headers.iter().map(|header| format!("{}:{}", header.name, header.value)).collect()
+    }
+
+    /// Value for the Pingap location `match_cookies` field, as `name=value`.
+    pub fn pingap_cookies(&self) -> Vec<String> {
+        self.cookies.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect()
+    }
+
+    /// Value for the Pingap location `path` field (`=` exact, `~` regex, plain prefix).
+    pub fn pingap_path(&self) -> String {
+        match self.paths.as_slice() {
+            [] => String::new(),
+            [PathMatcher::Prefix(p)] => p.clone(),
+            [PathMatcher::Exact(p)] => format!("={}", p),
+            [PathMatcher::Regex(re)] => format!("~{}", re),
+            paths => {
+                let alternatives = paths.iter()
+                    .map(|p| match p {
+                        PathMatcher::Prefix(p) => regex::escape(p),
+                        PathMatcher::Exact(p) => format!("{}$", regex::escape(p)),
+                        // Unanchored patterns may match anywhere in the path
+                        PathMatcher::Regex(re) => format!(".*?(?:{})", re),
+                    })
+                    .collect::<Vec<_>>();
+                format!("~^(?:{})", alternatives.join("|"))
+            }
+        }
+    }
+
+    /// A host and path both routes match in the same way, e.g.
+    /// ``Host(`a.com`) && PathPrefix(`/api`)``, or None if Pingap can tell
+    /// them apart. Matchers are compared as written, so two different regexes
+    /// never overlap here even if they match the same requests. Header and
+    /// cookie matchers set routes apart unless both require the same ones.
+    pub fn overlap(&self, other: &RouteMatch) -> Option<String> {
+        if !same_matchers(&self.headers, &other.headers) || !same_matchers(&self.cookies, &other.cookies) {
+            return None;
+        }
+        let host = match (self.hosts.as_slice(), other.hosts.as_slice()) {
+            ([], []) => None,
+            (hosts, others) => Some(hosts.iter().find(|host| others.contains(host))?),
+        };
+        // No path matcher routes everything, like `/`
+        let root = [PathMatcher::Prefix("/".to_string())];
+        let paths = |route: &RouteMatch| if route.paths.is_empty() { root.to_vec() } else { route.paths.clone() };
+        let others = paths(other);
+        let path = paths(self).into_iter().find(|path| others.contains(path))?;
+        Some(match host {
+            Some(host) => format!("{} && {}", host.to_rule(), path.to_rule()),
+            None => path.to_rule(),
+        })
+    }
+}
+
+fn same_matchers(a: &[ValueMatcher], b: &[ValueMatcher]) -> bool {
+    a.len() == b.len() && a.iter().all(|matcher| b.contains(matcher))
+}
+
+/// Validates a host label value. Wildcards are only supported as the complete
+/// leftmost label (`*.example.com`), which is what Pingap can express as a regex.
+/// The hostname is normalized, see [`normalize_hostname`].
+pub fn parse_host(host: &str) -> Result<HostMatcher> {
+    let host = host.trim();
+    if host.is_empty() {
+        return Err(anyhow!("Host must not be empty"));
+    }
+
+    if let Some(domain) = host.strip_prefix("*.") {
+        if domain.is_empty() || domain.contains('*') {
+            return Err(anyhow!("Unsupported wildcard host '{}': only a leading '*.' is allowed", host));
+        }
+        return Ok(HostMatcher::Wildcard(normalize_hostname(domain)?));
+    }
+
+    if host.contains('*') {
+        return Err(anyhow!("Unsupported wildcard host '{}': only a leading '*.' is allowed", host));
+    }
+
+    Ok(HostMatcher::Exact(normalize_hostname(host)?))
+}
+
+/// Longest hostname DNS can carry, without the trailing dot.
+const MAX_HOSTNAME_LEN: usize = 253;
+
+/// A hostname or FQDN the way clients send it in the Host header: unicode
+/// labels punycode-encoded (`bücher.example` is `xn--bcher-kva.example`),
+/// lowercase, without a trailing dot. IP addresses pass as they are.
+/// Labels may hold letters, digits, hyphens (not at either end) and
+/// underscores, which only a strict `HOSTNAME_POLICY` refuses.
+pub fn normalize_hostname(host: &str) -> Result<String> {
+    let host = host.trim();
+    if host.chars().any(char::is_whitespace) {
+        return Err(anyhow!("Invalid hostname '{}': contains whitespace", host));
+    }
+    let ascii = match url::Host::parse(host.strip_suffix('.').unwrap_or(host)) {
+        Ok(url::Host::Domain(domain)) => domain,
+        Ok(ip) => return Ok(ip.to_string()),
+        Err(e) => return Err(anyhow!("Invalid hostname '{}': {}", host, e)),
+    };
+    if ascii.len() > MAX_HOSTNAME_LEN {
+        return Err(anyhow!("Invalid hostname '{}': longer than {} characters", host, MAX_HOSTNAME_LEN));
+    }
+    for label in ascii.split('.') {
+        let valid = (1..=63).contains(&label.len())
+            && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(anyhow!("Invalid hostname '{}': '{}' is no valid DNS label", host, label));
+        }
+    }
+    Ok(ascii)
+}
+
+/// Validates a host regex. Pingap compiles location hosts with the Rust `regex`
+/// crate, so anything accepted here is accepted by the proxy.
+pub fn parse_host_regex(pattern: &str) -> Result<HostMatcher> {
+    Regex::new(pattern)
+        .map_err(|e| anyhow!("Invalid host regex '{}': {}", pattern, e))?;
+    Ok(HostMatcher::Regex(pattern.to_string()))
+}
+
+/// Parses one `pingap.http.paths` entry, honoring its match modifier:
+/// `/api` or `/api*` (prefix), `=/healthz` (exact), `~^/v[0-9]+/` (regex).
+pub fn parse_path_entry(entry: &str) -> Result<PathMatcher> {
+    let entry = entry.trim();
+    let matcher = if let Some(path) = entry.strip_prefix('=') {
+        PathMatcher::Exact(path.to_string())
+    } else if let Some(pattern) = entry.strip_prefix('~') {
+        return parse_path_regex(pattern);
+    } else if let Some(path) = entry.strip_suffix('*') {
+        PathMatcher::Prefix(path.to_string())
+    } else {
+        PathMatcher::Prefix(entry.to_string())
+    };
+
+    match &matcher {
+        PathMatcher::Prefix(p) | PathMatcher::Exact(p) if !p.starts_with('/') => {
+            Err(anyhow!("Invalid path '{}': paths must start with '/'", entry))
+        }
+        _ => Ok(matcher),
+    }
+}
+
+/// Parses a `pingap.http.match.header` entry, `X-Beta:true`, into its rule
+/// term ``Header(`X-Beta`, `true`)``.
+pub fn parse_header_entry(entry: &str) -> Result<String> {
+    let (name, value) = entry.split_once(':')
+        .ok_or_else(|| anyhow!("Invalid header match '{}': expected Name:value", entry.trim()))?;
+    let header = value_matcher("header", name, value)?;
+    Ok(format!("Header(`{}`, `{}`)", header.name, header.value))
+}
+
+/// Parses a `pingap.http.match.cookie` entry, `beta=1`, into its rule term
+/// ``Cookie(`beta`, `1`)``.
+pub fn parse_cookie_entry(entry: &str) -> Result<String> {
+    let (name, value) = entry.split_once('=')
+        .ok_or_else(|| anyhow!("Invalid cookie match '{}': expected name=value", entry.trim()))?;
+    let cookie = value_matcher("cookie", name, value)?;
+    Ok(format!("Cookie(`{}`, `{}`)", cookie.name, cookie.value))
+}
+
+fn value_matcher(kind: &str, name: &str, value: &str) -> Result<ValueMatcher> {
+    let (name, value) = (name.trim(), value.trim());
+    let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_|~".contains(&b));
+    if !valid_name {
+        return Err(anyhow!("Invalid {} name '{}'", kind, name));
+    }
+    if value.contains('`') {
+        return Err(anyhow!("Invalid {} value '{}': backticks are not allowed", kind, value));
+    }
+    Ok(ValueMatcher { name: name.to_string(), value: value.to_string() })
+}
+
+fn parse_path_regex(pattern: &str) -> Result<PathMatcher> {
+    Regex::new(pattern)
+        .map_err(|e| anyhow!("Invalid path regex '{}': {}", pattern, e))?;
+    Ok(PathMatcher::Regex(pattern.to_string()))
+}
+
+/// A routing rule as written, matchers joined by `&&`, `||` and parentheses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Host(HostMatcher),
+    Path(PathMatcher),
+    Header(ValueMatcher),
+    Cookie(ValueMatcher),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+/// Parses a rule into its [`Expr`] tree. `&&` binds tighter than `||`, and
+/// a matcher with several arguments, ``Host(`a.com`, `b.com`)``, is an `||`
+/// of them.
+pub fn parse_expr(rule: &str) -> Result<Expr> {
+    let mut parser = Parser { rule, rest: rule.trim_start() };
+    let expr = parser.or()?;
+    if !parser.rest.is_empty() {
+        return Err(anyhow!("Invalid rule '{}': unexpected '{}'", rule, parser.rest));
+    }
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    rule: &'a str,
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest.trim_start();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut terms = vec![self.and()?];
+        while self.eat("||") {
+            terms.push(self.and()?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { Expr::Or(terms) })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut terms = vec![self.term()?];
+        while self.eat("&&") {
+            terms.push(self.term()?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { Expr::And(terms) })
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        let rule = self.rule;
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(anyhow!("Invalid rule '{}': expected ')' near '{}'", rule, self.rest));
+            }
+            return Ok(expr);
+        }
+
+        let open = self.rest.find('(')
+            .ok_or_else(|| anyhow!("Invalid rule '{}': expected matcher near '{}'", rule, self.rest))?;
+        let matcher = self.rest[..open].trim();
+        if matcher.is_empty() || !matcher.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(anyhow!("Invalid rule '{}': expected matcher near '{}'", rule, self.rest));
+        }
+        let (args, remaining) = parse_args(&self.rest[open + 1..])
+            .map_err(|e| anyhow!("Invalid rule '{}': {}", rule, e))?;
+        self.rest = remaining.trim_start();
+
+        // Header and cookie matchers take a name and a value, the others a list
+        let mut alternatives = match (matcher, args.as_slice()) {
+            ("Header", [name, value]) => vec![Expr::Header(value_matcher("header", name, value)?)],
+            ("Cookie", [name, value]) => vec![Expr::Cookie(value_matcher("cookie", name, value)?)],
+            ("Header" | "Cookie", _) => {
+                return Err(anyhow!("Invalid rule '{}': {} takes a name and a value", rule, matcher));
+            },
+            _ => args.into_iter()
+                .map(|arg| Ok(match matcher {
+                    "Host" => Expr::Host(parse_host(&arg)?),
+                    "HostRegexp" => Expr::Host(parse_host_regex(&arg)?),
+                    "PathPrefix" => Expr::Path(PathMatcher::Prefix(arg)),
+                    "Path" => Expr::Path(PathMatcher::Exact(arg)),
+                    "PathRegexp" => Expr::Path(parse_path_regex(&arg)?),
+                    other => return Err(anyhow!("Invalid rule '{}': unsupported matcher '{}'", rule, other)),
+                }))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        match alternatives.len() {
+            0 => Err(anyhow!("Invalid rule '{}': {} needs an argument", rule, matcher)),
+            1 => Ok(alternatives.remove(0)),
+            _ => Ok(Expr::Or(alternatives)),
+        }
+    }
+}
+
+/// Parses a rule into the [`RouteMatch`] of a Pingap location. The rule has
+/// to be an `&&` of at most one group of hosts and one group of paths, each
+/// joined with `||`, and any header and cookie matchers. Anything else, like
+/// ``Host(`a.com`) || PathPrefix(`/api`)``, can't be one location and is
+/// rejected.
+pub fn parse_rule(rule: &str) -> Result<RouteMatch> {
+    let mut result = RouteMatch::default();
+    let (mut hosts, mut paths) = (false, false);
+    for term in conjuncts(parse_expr(rule)?) {
+        match term {
+            Expr::Header(header) => result.headers.push(header),
+            Expr::Cookie(cookie) => result.cookies.push(cookie),
+            term => {
+                let alternatives = alternatives(term);
+                let kind = if alternatives.iter().all(|expr| matches!(expr, Expr::Host(_))) {
+                    &mut hosts
+                } else if alternatives.iter().all(|expr| matches!(expr, Expr::Path(_))) {
+                    &mut paths
+                } else {
+                    return Err(anyhow!("Invalid rule '{}': '||' can only join Host matchers or Path matchers, \
+                        Pingap matches one host list and one path per location", rule));
+                };
+                if *kind {
+                    return Err(anyhow!("Invalid rule '{}': join the Host and the Path matchers with '||' each, \
+                        Pingap matches one host list and one path per location", rule));
+                }
+                *kind = true;
+                for expr in alternatives {
+                    match expr {
+                        Expr::Host(host) => result.hosts.push(host),
+                        Expr::Path(path) => result.paths.push(path),
+                        _ => unreachable!("alternatives are all hosts or all paths"),
+                    }
+                }
+            },
+        }
+    }
+
+    if result.hosts.is_empty() && result.paths.is_empty() {
+        return Err(anyhow!("Invalid rule '{}': no Host or Path matcher found", rule));
+    }
+
+    Ok(result)
+}
+
+/// The terms `expr` requires all of, nested `&&` included.
+fn conjuncts(expr: Expr) -> Vec<Expr> {
+    match expr {
+        Expr::And(terms) => terms.into_iter().flat_map(conjuncts).collect(),
+        expr => vec![expr],
+    }
+}
+
+/// The terms `expr` requires any of, nested `||` included.
+fn alternatives(expr: Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Or(terms) => terms.into_iter().flat_map(alternatives).collect(),
+        expr => vec![expr],
+    }
+}
+
+/// Parses backtick-quoted, comma-separated arguments up to the closing `)`.
+fn parse_args(input: &str) -> Result<(Vec<String>, &str)> {
+    let mut args = Vec::new();
+    let mut rest = input.trim_start();
+
+    loop {
+        if let Some(r) = rest.strip_prefix(')') {
+            return Ok((args, r));
+        }
+        if let Some(r) = rest.strip_prefix(',') {
+            rest = r.trim_start();
+            continue;
+        }
+        let r = rest.strip_prefix('`')
+            .ok_or_else(|| anyhow!("expected backtick-quoted argument near '{}'", rest))?;
+        let end = r.find('`')
+            .ok_or_else(|| anyhow!("unterminated argument near '{}'", rest))?;
+        args.push(r[..end].to_string());
+        rest = r[end + 1..].trim_start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_rule() {
+        let m = parse_rule("Host(`example.com`)").unwrap();
+        assert_eq!(m.hosts, vec![HostMatcher::Exact("example.com".to_string())]);
+        assert_eq!(m.pingap_host(), "example.com");
+        assert_eq!(m.pingap_path(), "");
+    }
+
+    #[test]
+    fn test_parse_host_and_paths() {
+        let m = parse_rule("Host(`api.example.com`) && (PathPrefix(`/v1`) || PathPrefix(`/v2`))").unwrap();
+        assert_eq!(m.pingap_host(), "api.example.com");
+        assert_eq!(m.paths.len(), 2);
+        assert_eq!(m.pingap_path(), "~^(?:/v1|/v2)");
+    }
+
+    #[test]
+    fn test_parse_exact_path() {
+        let m = parse_rule("Host(`custom.com`) && Path(`/special`)").unwrap();
+        assert_eq!(m.pingap_path(), "=/special");
+    }
+
+    #[test]
+    fn test_wildcard_host() {
+        let m = parse_rule("Host(`*.example.com`)").unwrap();
+        assert_eq!(m.hosts, vec![HostMatcher::Wildcard("example.com".to_string())]);
+        assert_eq!(m.pingap_host(), "~^[^.]+\\.example\\.com$");
+    }
+
+    #[test]
+    fn test_host_regexp() {
+        let m = parse_rule("HostRegexp(`^(www|api)\\.example\\.com$`)").unwrap();
+        assert_eq!(m.pingap_host(), "~(?:^(www|api)\\.example\\.com$)");
+    }
+
+    #[test]
+    fn test_multiple_exact_hosts() {
+        let m = parse_rule("Host(`a.com`, `b.com`)").unwrap();
+        assert_eq!(m.pingap_host(), "a.com,b.com");
+    }
+
+    #[test]
+    fn test_mixed_hosts_fold_into_regex() {
+        let m = parse_rule("Host(`a.com`) || Host(`*.b.com`)").unwrap();
+        assert_eq!(m.pingap_host(), "~^a\\.com$|^[^.]+\\.b\\.com$");
+    }
+
+    #[test]
+    fn test_invalid_wildcards() {
+        assert!(parse_host("api.*.example.com").is_err());
+        assert!(parse_host("*.").is_err());
+        assert!(parse_host("**.example.com").is_err());
+        assert!(parse_host("").is_err());
+    }
+
+    #[test]
+    fn test_hostnames() {
+        assert_eq!(parse_host("App.Example.com.").unwrap(), HostMatcher::Exact("app.example.com".to_string()));
+        assert_eq!(parse_host("bücher.example").unwrap(), HostMatcher::Exact("xn--bcher-kva.example".to_string()));
+        assert_eq!(parse_host("*.bücher.example").unwrap(), HostMatcher::Wildcard("xn--bcher-kva.example".to_string()));
+        assert_eq!(parse_host("my_app.local").unwrap(), HostMatcher::Exact("my_app.local".to_string()));
+        assert_eq!(parse_host("10.0.0.1").unwrap(), HostMatcher::Exact("10.0.0.1".to_string()));
+        assert!(parse_host("app example.com").is_err());
+        assert!(parse_host("-app.example.com").is_err());
+        assert!(parse_host("app..example.com").is_err());
+        assert!(parse_host("app!.example.com").is_err());
+        assert!(parse_host(&format!("{}.com", "a".repeat(64))).is_err());
+        assert!(parse_rule("Host(`app example.com`)").is_err());
+    }
+
+    #[test]
+    fn test_invalid_host_regex() {
+        assert!(parse_rule("HostRegexp(`(unclosed`)").is_err());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(parse_rule("Host(example.com)").is_err());
+        assert!(parse_rule("Host(`example.com`").is_err());
+        assert!(parse_rule("Method(`GET`)").is_err());
+        assert!(parse_rule("garbage").is_err());
+        assert!(parse_rule("").is_err());
+    }
+
+    #[test]
+    fn test_rule_structure() {
+        let host = |h: &str| Expr::Host(HostMatcher::Exact(h.to_string()));
+        let prefix = |p: &str| Expr::Path(PathMatcher::Prefix(p.to_string()));
+        assert_eq!(parse_expr("Host(`a.com`) && PathPrefix(`/x`) || Host(`b.com`)").unwrap(),
+            Expr::Or(vec![Expr::And(vec![host("a.com"), prefix("/x")]), host("b.com")]));
+        assert_eq!(parse_expr("Host(`a.com`, `b.com`)").unwrap(), Expr::Or(vec![host("a.com"), host("b.com")]));
+
+        assert_eq!(parse_rule("Host(`a.com`) || Host(`b.com`)").unwrap().pingap_host(), "a.com,b.com");
+        assert_eq!(parse_rule("((Host(`a.com`)) && (PathPrefix(`/x`)))").unwrap().pingap_path(), "/x");
+        // One location can't route either a host or a path, or a host's path or another host
+        assert!(parse_rule("Host(`a.com`) || PathPrefix(`/x`)").is_err());
+        assert!(parse_rule("Host(`a.com`) && PathPrefix(`/x`) || Host(`b.com`)").is_err());
+        assert!(parse_rule("Host(`a.com`) || Header(`X-Beta`, `true`)").is_err());
+        assert!(parse_rule("Host(`a.com`) && Host(`b.com`)").is_err());
+        assert!(parse_rule("(Host(`a.com`)").is_err());
+        assert!(parse_rule("Host(`a.com`))").is_err());
+        assert!(parse_rule("Host(`a.com`) &&").is_err());
+        assert!(parse_rule("Host()").is_err());
+    }
+
+    #[test]
+    fn test_parse_path_entries() {
+        assert_eq!(parse_path_entry("/api").unwrap(), PathMatcher::Prefix("/api".to_string()));
+        assert_eq!(parse_path_entry(" /api* ").unwrap(), PathMatcher::Prefix("/api".to_string()));
+        assert_eq!(parse_path_entry("=/healthz").unwrap(), PathMatcher::Exact("/healthz".to_string()));
+        assert_eq!(parse_path_entry("~^/v[0-9]+/").unwrap(), PathMatcher::Regex("^/v[0-9]+/".to_string()));
+    }
+
+    #[test]
+    fn test_parse_invalid_path_entries() {
+        assert!(parse_path_entry("api").is_err());
+        assert!(parse_path_entry("=healthz").is_err());
+        assert!(parse_path_entry("~(unclosed").is_err());
+    }
+
+    #[test]
+    fn test_path_regexp_rule() {
+        let m = parse_rule("PathRegexp(`^/v[0-9]+/`)").unwrap();
+        assert_eq!(m.pingap_path(), "~^/v[0-9]+/");
+        assert_eq!(m.paths[0].to_rule(), "PathRegexp(`^/v[0-9]+/`)");
+    }
+
+    #[test]
+    fn test_header_and_cookie_matchers() {
+        let m = parse_rule("Host(`app.com`) && Header(`X-Beta`, `true`) && Cookie(`beta`, `1`)").unwrap();
+        assert_eq!(m.pingap_host(), "app.com");
+        assert_eq!(m.pingap_headers(), ["X-Beta:true"]);
+        assert_eq!(m.pingap_cookies(), ["beta=1"]);
+
+        assert_eq!(parse_header_entry(" X-Beta: true ").unwrap(), "Header(`X-Beta`, `true`)");
+        assert_eq!(parse_cookie_entry("beta=1").unwrap(), "Cookie(`beta`, `1`)");
+        assert!(parse_header_entry("X-Beta").is_err());
+        assert!(parse_header_entry("X Beta:true").is_err());
+        assert!(parse_cookie_entry("=1").is_err());
+        assert!(parse_rule("Host(`app.com`) && Header(`X-Beta`)").is_err());
+        // A header alone doesn't make a route
+        assert!(parse_rule("Header(`X-Beta`, `true`)").is_err());
+    }
+
+    #[test]
+    fn test_overlap() {
+        let route = |rule: &str| parse_rule(rule).unwrap();
+        let api = route("Host(`shop.local`) && PathPrefix(`/api`)");
+        assert_eq!(api.overlap(&route("Host(`shop.local`, `www.shop.local`) && (PathPrefix(`/v1`) || PathPrefix(`/api`))")).as_deref(),
+            Some("Host(`shop.local`) && PathPrefix(`/api`)"));
+        assert_eq!(api.overlap(&route("Host(`blog.local`) && PathPrefix(`/api`)")), None);
+        assert_eq!(api.overlap(&route("Host(`shop.local`) && PathPrefix(`/admin`)")), None);
+        // A more specific route wins in Pingap, that is not a conflict
+        assert_eq!(api.overlap(&route("PathPrefix(`/api`)")), None);
+        assert_eq!(api.overlap(&route("Host(`shop.local`) && Path(`/api`)")), None);
+
+        // No path is the same as `/`
+        assert_eq!(route("Host(`*.shop.local`)").overlap(&route("Host(`*.shop.local`) && PathPrefix(`/`)")).as_deref(),
+            Some("Host(`*.shop.local`) && PathPrefix(`/`)"));
+        assert_eq!(route("PathPrefix(`/`)").overlap(&route("PathPrefix(`/`)")).as_deref(), Some("PathPrefix(`/`)"));
+
+        // A/B routes differ in their header or cookie matchers
+        let beta = route("Host(`shop.local`) && PathPrefix(`/api`) && Header(`X-Beta`, `true`)");
+        assert_eq!(api.overlap(&beta), None);
+        assert!(beta.overlap(&beta.clone()).is_some());
+    }
+
+    #[test]
+    fn test_mixed_path_modes() {
+        let m = parse_rule("PathPrefix(`/api`) || Path(`/healthz`) || PathRegexp(`^/v[0-9]+/`)").unwrap();
+        assert_eq!(m.pingap_path(), "~^(?:/api|/healthz$|.*?(?:^/v[0-9]+/))");
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use crate::models::{LabelSpec, LabelType, LABEL_ERRORS_PREFIX, LABEL_SERVICES_PREFIX, LABEL_SPECS};
+
+/// A value with a `{{ ... }}` label template, only known once rendered.
+const TEMPLATE_PATTERN: &str = r"\{\{.*\}\}";
+
+/// Comma-separated entries out of `values`, with optional spaces around them.
+fn list_pattern(values: &[&str]) -> String {
+    let item = format!("({})", values.iter().map(|value| regex::escape(value)).collect::<Vec<_>>().join("|"));
+    format!(r"^\s*{item}(\s*,\s*{item})*\s*$")
+}
+
+fn label_schema(spec: &LabelSpec) -> Value {
+    // Compose accepts unquoted YAML booleans and numbers as label values too
+    let variants = match spec.kind {
+        LabelType::Bool => vec![json!({ "enum": ["true", "false"] }), json!({ "type": "boolean" })],
+        LabelType::Integer => vec![json!({ "type": "string", "pattern": r"^\s*-?[0-9]+\s*$" }), json!({ "type": "integer" })],
+        LabelType::Duration => vec![json!({ "type": "string", "pattern": r"^\s*([0-9]+(ms|s|m|h))+\s*$" })],
+        LabelType::Text | LabelType::List => vec![json!({ "type": "string" })],
+        LabelType::OneOf(values) => vec![json!({ "enum": values })],
+        LabelType::ListOf(values) => vec![json!({ "type": "string", "pattern": list_pattern(values) })],
+        LabelType::Ratio => vec![
+            json!({ "type": "string", "pattern": r"^\s*(0(\.[0-9]*)?|1(\.0*)?|\.[0-9]+)\s*$" }),
+            json!({ "type": "number", "minimum": 0, "maximum": 1 }),
+        ],
+    };
+    let mut schema = match spec.kind {
+        LabelType::Text | LabelType::List => variants.into_iter().next().unwrap_or_default(),
+        _ => json!({ "anyOf": variants.into_iter().chain([json!({ "$ref": "#/$defs/template" })]).collect::<Vec<_>>() }),
+    };
+    let description = match spec.kind {
+        LabelType::List | LabelType::ListOf(_) => format!("{} (comma-separated)", spec.description),
+        _ => spec.description.to_string(),
+    };
+    schema["description"] = Value::String(description);
+    if let Some(default) = spec.default {
+        schema["default"] = Value::String(default.to_string());
+    }
+    schema
+}
+
+/// JSON Schema (draft 2020-12) of a container's labels in map form: every
+/// supported `pingap.*` label with its type, default and description. Any
+/// other `pingap.*` name is rejected, like `STRICT_LABELS` does.
+pub fn labels_schema() -> Value {
+    let properties = LABEL_SPECS.iter()
+        .map(|spec| (spec.name.to_string(), label_schema(spec)))
+        .collect::<Map<_, _>>();
+    let names = LABEL_SPECS.iter().map(|spec| spec.name).collect::<Vec<_>>();
+    let errors = format!("^{}[45][0-9]{{2}}$", regex::escape(LABEL_ERRORS_PREFIX));
+    let services = format!(r"^{}[^.]+\..+$", regex::escape(LABEL_SERVICES_PREFIX));
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "pingap-docker-provider labels",
+        "type": "object",
+        "properties": properties,
+        "patternProperties": {
+            errors.clone(): { "type": "string", "description": "Page served instead of the upstream's response for this 4xx/5xx status" },
+            services.clone(): {
+                "type": ["string", "number", "boolean"],
+                "description": "Label of one of several services of the container, read relative to pingap.",
+            },
+        },
+        "propertyNames": {
+            "anyOf": [
+                { "not": { "pattern": r"^pingap\." } },
+                { "enum": names },
+                { "pattern": errors },
+                { "pattern": services },
+            ],
+        },
+        "$defs": {
+            "template": { "type": "string", "pattern": TEMPLATE_PATTERN, "description": "Label template, rendered per container" },
+        },
+    })
+}
+
+fn type_name(kind: LabelType) -> String {
+    match kind {
+        LabelType::Bool => "`true`/`false`".to_string(),
+        LabelType::Integer => "integer".to_string(),
+        LabelType::Text => "string".to_string(),
+        LabelType::Duration => "duration".to_string(),
+        LabelType::List => "list".to_string(),
+        LabelType::OneOf(values) => values.iter().map(|value| format!("`{}`", value)).collect::<Vec<_>>().join(", "),
+        LabelType::ListOf(values) => format!("list of {}", values.iter().map(|value| format!("`{}`", value)).collect::<Vec<_>>().join(", ")),
+        LabelType::Ratio => "number from 0 to 1".to_string(),
+    }
+}
+
+/// Markdown table of every supported label, for the docs.
+pub fn labels_markdown() -> String {
+    let mut out = "| Label | Values | Default | Description | Example |\n|-------|--------|---------|-------------|---------|\n".to_string();
+    for spec in LABEL_SPECS {
+        out.push_str(&format!("| `{}` | {} | {} | {} | `{}` |\n",
+            spec.name,
+            type_name(spec.kind),
+            spec.default.map(|default| format!("`{}`", default)).unwrap_or_else(|| "-".to_string()),
+            spec.description,
+            spec.example.replace('|', "\\|")));
+    }
+    out
+}
+
+/// `pingap-docker-provider labels-schema [--markdown]`: prints the schema for
+/// IDEs and CI linters, or the label reference as a Markdown table.
+pub fn run(args: &[String]) -> Result<()> {
+    match args {
+        [] => println!("{}", serde_json::to_string_pretty(&labels_schema())?),
+        [flag] if flag == "--markdown" => print!("{}", labels_markdown()),
+        _ => return Err(anyhow!("Usage: pingap-docker-provider labels-schema [--markdown]")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    #[test]
+    fn test_every_label_described() {
+        let schema = labels_schema();
+        assert_eq!(schema["properties"].as_object().unwrap().len(), LABEL_SPECS.len());
+        assert_eq!(schema["properties"]["pingap.on_stop"], json!({
+            "anyOf": [{ "enum": ["remove", "drain", "keep"] }, { "$ref": "#/$defs/template" }],
+            "description": "What a stopped container does to its service",
+            "default": "remove",
+        }));
+        assert_eq!(schema["properties"]["pingap.http.host"]["type"], "string");
+    }
+
+    #[test]
+    fn test_markdown() {
+        let markdown = labels_markdown();
+        assert_eq!(markdown.lines().count(), LABEL_SPECS.len() + 2);
+        assert!(markdown.contains("| `pingap.on_stop` | `remove`, `drain`, `keep` | `remove` | What a stopped container does to its service | `drain` |\n"));
+        assert!(markdown.contains(r"`^(www\|api)\.example\.com$`"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let list = Regex::new(&list_pattern(&["connect", "timeout", "5xx"])).unwrap();
+        assert!(list.is_match("5xx"));
+        assert!(list.is_match(" 5xx, timeout "));
+        assert!(!list.is_match("5xx,4xx"));
+        assert!(!list.is_match(""));
+
+        let schema = labels_schema();
+        let names = schema["propertyNames"]["anyOf"].as_array().unwrap();
+        let errors = Regex::new(names[2]["pattern"].as_str().unwrap()).unwrap();
+        assert!(errors.is_match("pingap.errors.502"));
+        assert!(!errors.is_match("pingap.errors.200"));
+        let services = Regex::new(names[3]["pattern"].as_str().unwrap()).unwrap();
+        assert!(services.is_match("pingap.services.metrics.port"));
+        assert!(!services.is_match("pingap.services.metrics"));
+        assert!(Regex::new(TEMPLATE_PATTERN).unwrap().is_match("{{ container_name }}.local"));
+    }
+}
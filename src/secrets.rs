@@ -0,0 +1,431 @@
+//! Secret references: `{{ secret "<provider>:<path>" }}` in label values and
+//! in the credentials of the config, resolved from the environment, files,
+//! HashiCorp Vault or SOPS-encrypted files instead of being written out.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use tokio::process::Command;
+use tracing::{debug, warn};
+use url::Url;
+use crate::config::SecretsConfig;
+
+/// Where a secret comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretRef {
+    /// `env:NAME`
+    Env(String),
+    /// `file:/run/secrets/name`, the whole file without surrounding whitespace
+    File(String),
+    /// `vault:<path>#<key>`, a key of a KV v1 or v2 secret, e.g. `vault:secret/data/web#password`
+    Vault { path: String, key: String },
+    /// `sops:<file>#<key>`, a key of a SOPS-encrypted JSON or YAML file, dotted for nested keys
+    Sops { file: String, key: String },
+}
+
+impl SecretRef {
+    pub fn parse(reference: &str) -> Result<Self> {
+        let (provider, path) = reference.split_once(':')
+            .ok_or_else(|| anyhow!("secret '{}' must be <provider>:<path>", reference))?;
+        let keyed = |path: &str| match path.rsplit_once('#') {
+            Some((path, key)) if !path.is_empty() && !key.is_empty() => Ok((path.to_string(), key.to_string())),
+            _ => Err(anyhow!("secret '{}' must name a key, as {}:<path>#<key>", reference, provider)),
+        };
+        Ok(match provider {
+            "env" if !path.is_empty() => SecretRef::Env(path.to_string()),
+            "file" if !path.is_empty() => SecretRef::File(path.to_string()),
+            "vault" => {
+                let (path, key) = keyed(path)?;
+                let segments = path.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>();
+                if segments.iter().any(|segment| matches!(*segment, "." | "..") || segment.contains(['%', '?'])) {
+                    bail!("secret '{}' must be a plain Vault path, without ., .., % or ?", reference);
+                }
+                SecretRef::Vault { path: segments.join("/"), key }
+            },
+            "sops" => {
+                let (file, key) = keyed(path)?;
+                SecretRef::Sops { file, key }
+            },
+            "env" | "file" => bail!("secret '{}' has no {} name", reference, provider),
+            other => bail!("unknown secret provider '{}' in '{}', expected env, file, vault or sops", other, reference),
+        })
+    }
+
+    /// The reference as `<provider>:<path>`, with the Vault path normalized
+    /// the way it is fetched.
+    pub fn normalized(&self) -> String {
+        match self {
+            SecretRef::Env(name) => format!("env:{}", name),
+            SecretRef::File(path) => format!("file:{}", path),
+            SecretRef::Vault { path, key } => format!("vault:{}#{}", path, key),
+            SecretRef::Sops { file, key } => format!("sops:{}#{}", file, key),
+        }
+    }
+}
+
+/// The `{{ secret "..." }}` expressions of `value`, with their position.
+fn references(value: &str) -> Vec<(Range<usize>, String)> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = value[offset..].find("{{").map(|start| offset + start) {
+        let Some(end) = value[start..].find("}}").map(|end| start + end + 2) else {
+            break;
+        };
+        let reference = value[start + 2..end - 2].trim()
+            .strip_prefix("secret")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .map(str::trim)
+            .and_then(|rest| rest.strip_prefix('"'))
+            .and_then(|rest| rest.strip_suffix('"'))
+            .filter(|reference| !reference.contains('"'));
+        if let Some(reference) = reference {
+            found.push((start..end, reference.to_string()));
+        }
+        offset = end;
+    }
+    found
+}
+
+/// `value` with each secret expression replaced by a placeholder, so the
+/// rest of a label template can be checked without resolving anything.
+pub fn without_references(value: &str) -> String {
+    let mut output = value.to_string();
+    for (range, _) in references(value).into_iter().rev() {
+        output.replace_range(range, "secret");
+    }
+    output
+}
+
+/// A fetched Vault secret or decrypted SOPS file.
+struct Cached {
+    document: Value,
+    expires: Instant,
+}
+
+/// Resolves secret references. Vault secrets and SOPS files are cached for
+/// `SECRETS_CACHE_TTL_SECS` (or a Vault lease, if shorter); when fetching
+/// them again fails, the cached one is used until it succeeds.
+pub struct Secrets {
+    config: SecretsConfig,
+    client: reqwest::Client,
+    /// `vault:<path>` or `sops:<file>` -> its document
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+impl Secrets {
+    pub fn new(config: SecretsConfig) -> Self {
+        Self { config, client: reqwest::Client::new(), cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// `value` with every `{{ secret "..." }}` expression replaced by its secret.
+    pub async fn render(&self, value: &str) -> Result<String> {
+        let mut output = value.to_string();
+        for (range, reference) in references(value).into_iter().rev() {
+            output.replace_range(range, &self.resolve(&reference).await?);
+        }
+        Ok(output)
+    }
+
+    /// `value` of a label with its secrets resolved, if `LABEL_SECRETS_ALLOW`
+    /// lets labels use every one of them.
+    pub async fn render_label(&self, value: &str) -> Result<String> {
+        for (_, reference) in references(value) {
+            self.label_may_use(&reference)?;
+        }
+        self.render(value).await
+    }
+
+    /// Labels are written by whoever starts a container, so they only get the
+    /// Vault and SOPS secrets under `LABEL_SECRETS_ALLOW`, never the provider's
+    /// environment or files.
+    fn label_may_use(&self, reference: &str) -> Result<()> {
+        let secret = SecretRef::parse(reference)?;
+        match &secret {
+            SecretRef::Env(_) | SecretRef::File(_) => bail!("secret {}: labels can't use env: or file: secrets", reference),
+            SecretRef::Sops { file, .. } if file.split('/').any(|segment| matches!(segment, "." | "..")) => {
+                bail!("secret {}: labels can't use . or .. in secret paths", reference)
+            },
+            _ if self.config.label_allow.iter().any(|allowed| allows(allowed, &secret.normalized())) => Ok(()),
+            _ => bail!("secret {} is not in LABEL_SECRETS_ALLOW", reference),
+        }
+    }
+
+    /// Container labels with their secrets resolved. A label whose secret
+    /// can't be resolved, or may not be used, is left as it is and fails the
+    /// container's config.
+    pub async fn render_labels(&self, mut labels: HashMap<String, String>, container: &str) -> HashMap<String, String> {
+        for (label, value) in labels.iter_mut().filter(|(label, value)| label.starts_with("pingap.") && value.contains("{{")) {
+            match self.render_label(value).await {
+                Ok(rendered) => *value = rendered,
+                Err(e) => warn!("Couldn't resolve the secret in {} of container {}: {:#}", label, container, e),
+            }
+        }
+        labels
+    }
+
+    pub async fn resolve(&self, reference: &str) -> Result<String> {
+        let secret = match SecretRef::parse(reference)? {
+            SecretRef::Env(name) => std::env::var(&name)
+                .map_err(|_| anyhow!("secret {}: {} is not set", reference, name))?,
+            SecretRef::File(path) => tokio::fs::read_to_string(&path).await
+                .context(format!("secret {}: failed to read {}", reference, path))?
+                .trim().to_string(),
+            SecretRef::Vault { path, key } => {
+                let document = self.document(&format!("vault:{}", path)).await?;
+                lookup(&document, &key).context(format!("secret {}", reference))?
+            },
+            SecretRef::Sops { file, key } => {
+                let document = self.document(&format!("sops:{}", file)).await?;
+                lookup(&document, &key).context(format!("secret {}", reference))?
+            },
+        };
+        Ok(secret)
+    }
+
+    /// The document behind `source` (`vault:<path>` or `sops:<file>`), from
+    /// the cache while it is fresh.
+    async fn document(&self, source: &str) -> Result<Value> {
+        if let Some(cached) = self.cache.lock().unwrap().get(source).filter(|cached| cached.expires > Instant::now()) {
+            return Ok(cached.document.clone());
+        }
+        match self.fetch(source).await {
+            Ok((document, ttl)) => {
+                let cached = Cached { document: document.clone(), expires: Instant::now() + ttl };
+                self.cache.lock().unwrap().insert(source.to_string(), cached);
+                Ok(document)
+            },
+            Err(e) => match self.cache.lock().unwrap().get(source) {
+                Some(stale) => {
+                    warn!("Failed to fetch {} again, using the cached secrets: {:#}", source, e);
+                    Ok(stale.document.clone())
+                },
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn fetch(&self, source: &str) -> Result<(Value, Duration)> {
+        match source.split_once(':') {
+            Some(("vault", path)) => self.fetch_vault(path).await,
+            Some(("sops", file)) => Ok((self.decrypt_sops(file).await?, self.config.cache_ttl)),
+            _ => unreachable!("documents are only cached for vault and sops"),
+        }
+    }
+
+    /// A KV secret; KV v2 nests the keys under `data.data`.
+    async fn fetch_vault(&self, path: &str) -> Result<(Value, Duration)> {
+        let vault = self.config.vault.as_ref()
+            .ok_or_else(|| anyhow!("secret vault:{} needs VAULT_ADDR and VAULT_TOKEN", path))?;
+        // Each segment is escaped on its own, so none of them can step out of the path
+        let mut url = Url::parse(&vault.addr).context(format!("Invalid VAULT_ADDR {}", vault.addr))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid VAULT_ADDR {}", vault.addr))?
+            .pop_if_empty()
+            .push("v1")
+            .extend(path.split('/'));
+        let mut request = self.client.get(url.clone()).header("X-Vault-Token", &vault.token);
+        if let Some(namespace) = &vault.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let resp = request.send().await.context(format!("Failed to reach Vault at {}", url))?;
+        let status = resp.status();
+        if !status.is_success() {
+            bail!("Vault refused to read {} ({})", path, status);
+        }
+        let body: Value = resp.json().await.context(format!("Invalid Vault response for {}", path))?;
+        let data = &body["data"];
+        let document = match (&data["data"], &data["metadata"]) {
+            (Value::Object(_), Value::Object(_)) => data["data"].clone(),
+            _ => data.clone(),
+        };
+        let ttl = match body["lease_duration"].as_u64() {
+            Some(lease) if lease > 0 => self.config.cache_ttl.min(Duration::from_secs(lease)),
+            _ => self.config.cache_ttl,
+        };
+        Ok((document, ttl))
+    }
+
+    async fn decrypt_sops(&self, file: &str) -> Result<Value> {
+        let output = Command::new(&self.config.sops_binary)
+            .args(["--decrypt", "--output-type", "json", file])
+            .kill_on_drop(true)
+            .output().await
+            .context(format!("Failed to run {} to decrypt {}", self.config.sops_binary, file))?;
+        if !output.status.success() {
+            bail!("{} failed to decrypt {}: {}", self.config.sops_binary, file, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        serde_json::from_slice(&output.stdout).context(format!("Decrypted {} is no JSON document", file))
+    }
+
+    /// Renews the Vault token and fetches the cached secrets that expired
+    /// again, so neither runs out while the provider is idle.
+    pub async fn renew(&self) {
+        if let Some(vault) = &self.config.vault {
+            let url = format!("{}/v1/auth/token/renew-self", vault.addr.trim_end_matches('/'));
+            let mut request = self.client.post(&url).header("X-Vault-Token", &vault.token);
+            if let Some(namespace) = &vault.namespace {
+                request = request.header("X-Vault-Namespace", namespace);
+            }
+            match request.send().await.map(|resp| resp.status()) {
+                Ok(status) if status.is_success() => debug!("Renewed the Vault token"),
+                Ok(status) => warn!("Vault refused to renew the token ({})", status),
+                Err(e) => warn!("Failed to renew the Vault token: {:?}", e),
+            }
+        }
+        let expired = self.cache.lock().unwrap().iter()
+            .filter(|(_, cached)| cached.expires <= Instant::now())
+            .map(|(source, _)| source.clone())
+            .collect::<Vec<_>>();
+        for source in expired {
+            // Failures are logged by document(), the stale secrets stay in use
+            let _ = self.document(&source).await;
+        }
+    }
+}
+
+/// Whether the `LABEL_SECRETS_ALLOW` entry `allowed` covers `reference`: the
+/// same reference, or one below it, `vault:secret/data/web` covering
+/// `vault:secret/data/web#password` but not `vault:secret/data/webadmin#password`.
+fn allows(allowed: &str, reference: &str) -> bool {
+    reference.strip_prefix(allowed)
+        .is_some_and(|rest| rest.is_empty() || allowed.ends_with(['/', '#']) || rest.starts_with(['/', '#']))
+}
+
+/// The value at a dotted `key` of a secret's document, as text.
+fn lookup(document: &Value, key: &str) -> Result<String> {
+    let value = key.split('.').try_fold(document, |value, part| value.get(part))
+        .ok_or_else(|| anyhow!("no key {}", key))?;
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(_) | Value::Bool(_) => Ok(value.to_string()),
+        _ => Err(anyhow!("key {} is no string", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VaultConfig;
+
+    #[test]
+    fn test_parse_references() {
+        assert_eq!(SecretRef::parse("env:API_TOKEN").unwrap(), SecretRef::Env("API_TOKEN".into()));
+        assert_eq!(SecretRef::parse("vault:/secret/data/web#password").unwrap(),
+            SecretRef::Vault { path: "secret/data/web".into(), key: "password".into() });
+        assert_eq!(SecretRef::parse("sops:/secrets/web.enc.json#auth.basic").unwrap(),
+            SecretRef::Sops { file: "/secrets/web.enc.json".into(), key: "auth.basic".into() });
+        assert!(SecretRef::parse("vault:secret/data/web").is_err());
+        assert!(SecretRef::parse("aws:web").is_err());
+        assert!(SecretRef::parse("env:").is_err());
+
+        let value = r#"Bearer {{ secret "env:TOKEN" }} {{ container_name }}"#;
+        assert_eq!(references(value), vec![(7..31, "env:TOKEN".to_string())]);
+        assert_eq!(without_references(value), "Bearer secret {{ container_name }}");
+        assert!(references(r#"{{ secrets "env:TOKEN" }}"#).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_from_env_and_file() {
+        let secrets = Secrets::new(SecretsConfig::default());
+        unsafe { std::env::set_var("PINGAP_SECRETS_TEST_TOKEN", "s3cr3t"); }
+        let file = std::env::temp_dir().join(format!("pingap-secret-{}", std::process::id()));
+        std::fs::write(&file, "admin:hunter2\n").unwrap();
+
+        let value = format!(r#"{{{{ secret "env:PINGAP_SECRETS_TEST_TOKEN" }}}}/{{{{ secret "file:{}" }}}}"#, file.display());
+        assert_eq!(secrets.render(&value).await.unwrap(), "s3cr3t/admin:hunter2");
+        assert!(secrets.render(r#"{{ secret "env:PINGAP_SECRETS_TEST_UNSET" }}"#).await.is_err());
+
+        // Labels never get the provider's environment or files, they are kept for the label parser to refuse
+        let labels = secrets.render_labels(HashMap::from([
+            ("pingap.middleware.basic_auth".to_string(), format!(r#"{{{{ secret "file:{}" }}}}"#, file.display())),
+            ("pingap.headers.custom_request".to_string(), r#"X-Token: {{ secret "env:PINGAP_SECRETS_TEST_TOKEN" }}"#.to_string()),
+        ]), "/web").await;
+        assert!(labels["pingap.middleware.basic_auth"].contains("{{ secret"));
+        assert!(labels["pingap.headers.custom_request"].contains("{{ secret"));
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[tokio::test]
+    async fn test_label_secrets_allowlist() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/v1/secret/data/web")
+            .with_body(r#"{"data": {"data": {"password": "pw1"}, "metadata": {"version": 1}}}"#)
+            .create_async().await;
+        let config = |label_allow: Vec<String>| SecretsConfig {
+            vault: Some(VaultConfig { addr: server.url(), token: "root".into(), namespace: None }),
+            label_allow,
+            ..Default::default()
+        };
+
+        // Nothing is allowed by default
+        let secrets = Secrets::new(config(Vec::new()));
+        assert!(secrets.render_label(r#"{{ secret "vault:secret/data/web#password" }}"#).await.is_err());
+
+        let secrets = Secrets::new(config(vec!["vault:secret/data/web".into(), "env:PINGAP_ADMIN_HMAC_SECRET".into()]));
+        assert_eq!(secrets.render_label(r#"admin:{{ secret "vault:secret/data/web#password" }}"#).await.unwrap(), "admin:pw1");
+        assert!(secrets.render_label(r#"{{ secret "vault:secret/data/webadmin#password" }}"#).await.is_err());
+        assert!(secrets.render_label(r#"{{ secret "vault:secret/data/web/../../../sys/x#password" }}"#).await.is_err());
+        assert!(secrets.render_label(r#"{{ secret "vault:secret/data/web/%2e%2e/%2e%2e/sys/x#password" }}"#).await.is_err());
+        assert!(secrets.render_label(r#"{{ secret "vault:secret/data/web/./x?list=true#password" }}"#).await.is_err());
+        assert!(secrets.render_label(r#"{{ secret "sops:/secrets/web/../admin.enc.json#password" }}"#).await.is_err());
+        // Checked on the path as it is fetched
+        assert_eq!(secrets.render_label(r#"{{ secret "vault:/secret//data/web#password" }}"#).await.unwrap(), "pw1");
+        assert!(secrets.render_label(r#"{{ secret "env:PINGAP_ADMIN_HMAC_SECRET" }}"#).await.is_err());
+        // Config secrets are not limited
+        unsafe { std::env::set_var("PINGAP_SECRETS_TEST_CONFIG", "c0nf"); }
+        assert_eq!(secrets.render(r#"{{ secret "env:PINGAP_SECRETS_TEST_CONFIG" }}"#).await.unwrap(), "c0nf");
+    }
+
+    #[tokio::test]
+    async fn test_vault_secrets_cached() {
+        let mut server = mockito::Server::new_async().await;
+        let read = server.mock("GET", "/v1/secret/data/web")
+            .match_header("X-Vault-Token", "root")
+            .with_body(r#"{"data": {"data": {"password": "pw1", "port": 8080}, "metadata": {"version": 3}}, "lease_duration": 0}"#)
+            .expect(1)
+            .create_async().await;
+        let renew = server.mock("POST", "/v1/auth/token/renew-self").expect(1).create_async().await;
+        let secrets = Secrets::new(SecretsConfig {
+            vault: Some(VaultConfig { addr: server.url(), token: "root".into(), namespace: None }),
+            ..Default::default()
+        });
+
+        assert_eq!(secrets.resolve("vault:secret/data/web#password").await.unwrap(), "pw1");
+        assert_eq!(secrets.resolve("vault:secret/data/web#port").await.unwrap(), "8080");
+        assert!(secrets.resolve("vault:secret/data/web#user").await.is_err());
+        read.assert_async().await;
+
+        // Expired, but Vault is gone: the stale secret stays in use
+        secrets.cache.lock().unwrap().get_mut("vault:secret/data/web").unwrap().expires = Instant::now();
+        read.remove_async().await;
+        secrets.renew().await;
+        renew.assert_async().await;
+        assert_eq!(secrets.resolve("vault:secret/data/web#password").await.unwrap(), "pw1");
+
+        // KV v1 keeps the keys right under data
+        server.mock("GET", "/v1/kv/api").with_body(r#"{"data": {"token": "t1"}, "lease_duration": 60}"#).create_async().await;
+        assert_eq!(secrets.resolve("vault:kv/api#token").await.unwrap(), "t1");
+        assert!(Secrets::new(SecretsConfig::default()).resolve("vault:kv/api#token").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sops_file_decrypted() {
+        let dir = std::env::temp_dir().join(format!("pingap-sops-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Stands in for sops, printing the decrypted document
+        let sops = dir.join("sops");
+        std::fs::write(&sops, "#!/bin/sh\n[ \"$1\" = --decrypt ] || exit 1\necho '{\"auth\": {\"basic\": \"admin:pw\"}}'\n").unwrap();
+        std::fs::set_permissions(&sops, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let secrets = Secrets::new(SecretsConfig { sops_binary: sops.display().to_string(), ..Default::default() });
+        assert_eq!(secrets.resolve("sops:/secrets/web.enc.yaml#auth.basic").await.unwrap(), "admin:pw");
+        assert!(secrets.resolve("sops:/secrets/web.enc.yaml#auth").await.is_err());
+
+        let failing = Secrets::new(SecretsConfig { sops_binary: "false".to_string(), ..Default::default() });
+        assert!(failing.resolve("sops:/secrets/web.enc.yaml#auth.basic").await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,83 @@
+//! Signing of Admin API requests (`PINGAP_ADMIN_HMAC_SECRET`), for a Pingap
+//! behind a gateway that only lets signed internal requests through.
+
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Request;
+use sha2::{Digest, Sha256};
+use anyhow::{anyhow, Result};
+
+/// Signs every request with HMAC-SHA256 over
+/// `<timestamp>\n<METHOD>\n<path and query>\n<hex SHA-256 of the body>`,
+/// sent as hex in `header`, with the Unix timestamp in `<header>-Timestamp`.
+#[derive(Clone)]
+pub struct RequestSigner {
+    header: HeaderName,
+    timestamp_header: HeaderName,
+    secret: Vec<u8>,
+}
+
+impl RequestSigner {
+    pub fn new(header: &str, secret: &str) -> Result<Self> {
+        let name = |name: String| HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| anyhow!("Invalid signature header name '{}'", name));
+        if secret.is_empty() {
+            return Err(anyhow!("The request signing secret must not be empty"));
+        }
+        Ok(Self {
+            header: name(header.to_string())?,
+            timestamp_header: name(format!("{}-Timestamp", header))?,
+            secret: secret.as_bytes().to_vec(),
+        })
+    }
+
+    pub fn sign(&self, request: &mut Request, timestamp: u64) {
+        let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+        let url = request.url();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let signature = self.signature(timestamp, request.method().as_str(), &path, body);
+        let headers = request.headers_mut();
+        headers.insert(self.header.clone(), HeaderValue::from_str(&signature).expect("hex is a valid header value"));
+        headers.insert(self.timestamp_header.clone(), HeaderValue::from(timestamp));
+    }
+
+    fn signature(&self, timestamp: u64, method: &str, path: &str, body: &[u8]) -> String {
+        let canonical = format!("{}\n{}\n{}\n{}", timestamp, method, path, hex(&Sha256::digest(body)));
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(canonical.as_bytes());
+        hex(&mac.finalize().into_bytes())
+    }
+}
+
+/// Lowercase hex of a digest or signature.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signs_method_path_and_body() {
+        let signer = RequestSigner::new("X-Signature", "secret").unwrap();
+        let client = reqwest::Client::new();
+        let mut request = client.post("http://pingap:6188/admin/upstreams/web?force=1")
+            .body(r#"{"addrs":[]}"#)
+            .build().unwrap();
+        signer.sign(&mut request, 1_700_000_000);
+
+        let expected = "a318e9c372870ae5492686cd5fa9cf68782fcb0ea2ace33f7c5483477c8fc6e9";
+        assert_eq!(request.headers()["X-Signature"], expected);
+        assert_eq!(request.headers()["X-Signature-Timestamp"], "1700000000");
+        // Anything else signed gives another signature
+        assert_ne!(expected, signer.signature(1_700_000_000, "PUT", "/admin/upstreams/web?force=1", br#"{"addrs":[]}"#));
+        assert_ne!(expected, signer.signature(1_700_000_001, "POST", "/admin/upstreams/web?force=1", br#"{"addrs":[]}"#));
+
+        assert!(RequestSigner::new("X Signature", "secret").is_err());
+        assert!(RequestSigner::new("X-Signature", "").is_err());
+    }
+}
@@ -0,0 +1,457 @@
+//! `pingap-docker-provider --simulate`: a soak test of the event handling.
+//! Synthetic container starts and stops drive the provider against an
+//! in-process mock of Pingap's Admin API that answers with random latency and
+//! fails a share of the writes. Once the storm has settled, the mock's config
+//! must match the containers left running.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::config::{Config, RetryPolicy};
+use crate::docker::DockerClient;
+use crate::models::ContainerInfo;
+use crate::pingap::PingapClient;
+use crate::provider::Provider;
+use crate::status::Status;
+
+const USAGE: &str = "Usage: pingap-docker-provider --simulate [--containers N] [--services N] [--events N] [--seed N] [--latency-ms N] [--fail-rate 0.0-1.0]";
+// How long the writes still running after the last event may take
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub containers: usize,
+    pub services: usize,
+    pub events: usize,
+    pub seed: u64,
+    /// Upper bound of the random latency of every Admin API response
+    pub latency: Duration,
+    /// Share of Admin API writes answered with a 503
+    pub fail_rate: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            containers: 50,
+            services: 10,
+            events: 2000,
+            seed: 1,
+            latency: Duration::from_millis(5),
+            fail_rate: 0.05,
+        }
+    }
+}
+
+impl Options {
+    /// Parses `--key value` and `--key=value` arguments.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key, value.to_string()),
+                None => (arg.as_str(), args.next().with_context(|| format!("{} needs a value\n{}", arg, USAGE))?.clone()),
+            };
+            let invalid = || format!("Invalid value for {}: {}", key, value);
+            match key {
+                "--containers" => options.containers = value.parse().with_context(invalid)?,
+                "--services" => options.services = value.parse().with_context(invalid)?,
+                "--events" => options.events = value.parse().with_context(invalid)?,
+                "--seed" => options.seed = value.parse().with_context(invalid)?,
+                "--latency-ms" => options.latency = Duration::from_millis(value.parse().with_context(invalid)?),
+                "--fail-rate" => options.fail_rate = value.parse().with_context(invalid)?,
+                _ => bail!("Unknown argument {}\n{}", key, USAGE),
+            }
+        }
+        if options.containers == 0 || options.services == 0 {
+            bail!("--containers and --services must be at least 1");
+        }
+        if !(0.0..1.0).contains(&options.fail_rate) {
+            bail!("--fail-rate must be at least 0 and below 1");
+        }
+        Ok(options)
+    }
+}
+
+/// Xorshift, reproducible from `--seed`.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift never leaves 0
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Pingap's Admin API as far as the provider uses it, kept in memory.
+struct MockPingap {
+    url: String,
+    config: Arc<Mutex<Value>>,
+    requests: Arc<AtomicU64>,
+    failures: Arc<AtomicU64>,
+}
+
+impl MockPingap {
+    async fn start(options: &Options) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let mock = Self {
+            url,
+            config: Arc::new(Mutex::new(json!({ "upstreams": {}, "locations": {} }))),
+            requests: Arc::new(AtomicU64::new(0)),
+            failures: Arc::new(AtomicU64::new(0)),
+        };
+        let faults = Arc::new(Faults {
+            rng: Mutex::new(Rng::new(options.seed.wrapping_add(1))),
+            latency: options.latency,
+            fail_rate: options.fail_rate,
+            requests: mock.requests.clone(),
+            failures: mock.failures.clone(),
+        });
+        let config = mock.config.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (config, faults) = (config.clone(), faults.clone());
+                tokio::spawn(async move {
+                    let _ = serve(stream, &config, &faults).await;
+                });
+            }
+        });
+        Ok(mock)
+    }
+
+    fn section(&self, section: &str) -> BTreeMap<String, Value> {
+        self.config.lock().unwrap()[section].as_object()
+            .map(|resources| resources.iter().map(|(name, value)| (name.clone(), value.clone())).collect())
+            .unwrap_or_default()
+    }
+}
+
+struct Faults {
+    rng: Mutex<Rng>,
+    latency: Duration,
+    fail_rate: f64,
+    requests: Arc<AtomicU64>,
+    failures: Arc<AtomicU64>,
+}
+
+impl Faults {
+    /// The latency of a response and whether it fails.
+    fn roll(&self, write: bool) -> (Duration, bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut rng = self.rng.lock().unwrap();
+        let latency = match self.latency.as_millis() as usize {
+            0 => Duration::ZERO,
+            max => Duration::from_millis(rng.below(max + 1) as u64),
+        };
+        let fail = write && rng.chance(self.fail_rate);
+        if fail {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        (latency, fail)
+    }
+}
+
+async fn serve(mut stream: TcpStream, config: &Mutex<Value>, faults: &Faults) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let (head_len, body_len) = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..pos]).to_lowercase();
+            let body_len = head.lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|len| len.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            break (pos + 4, body_len);
+        }
+    };
+    while buf.len() < head_len + body_len {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let body = serde_json::from_slice::<Value>(&buf[head_len..]).unwrap_or(Value::Null);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+
+    let (latency, fail) = faults.roll(method != "GET");
+    tokio::time::sleep(latency).await;
+    let (status, response) = if fail {
+        ("503 Service Unavailable", "{}".to_string())
+    } else {
+        handle(&method, &path, body, &mut config.lock().unwrap())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, response.len(), response
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn handle(method: &str, path: &str, body: Value, config: &mut Value) -> (&'static str, String) {
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    match (method, segments.as_slice()) {
+        ("GET", ["config"]) => ("200 OK", config.to_string()),
+        ("PUT", ["config"]) => {
+            *config = body;
+            ("200 OK", "{}".to_string())
+        }
+        ("GET", [section, name]) => match config[*section].get(*name) {
+            Some(resource) => ("200 OK", resource.to_string()),
+            None => ("404 Not Found", "{}".to_string()),
+        },
+        ("POST", [section, name]) => {
+            if !config[*section].is_object() {
+                config[*section] = json!({});
+            }
+            config[*section][*name] = body;
+            ("200 OK", "{}".to_string())
+        }
+        ("DELETE", [section, name]) => match config[*section].as_object_mut().and_then(|s| s.remove(*name)) {
+            Some(_) => ("200 OK", "{}".to_string()),
+            None => ("404 Not Found", "{}".to_string()),
+        },
+        _ => ("404 Not Found", "{}".to_string()),
+    }
+}
+
+fn synthetic_container(index: usize, services: usize) -> ContainerInfo {
+    let service = format!("svc-{}", index % services);
+    let labels = HashMap::from([
+        ("pingap.enable".to_string(), "true".to_string()),
+        ("pingap.service.name".to_string(), service.clone()),
+        ("pingap.service.port".to_string(), "80".to_string()),
+        ("pingap.http.host".to_string(), format!("{}.local", service)),
+    ]);
+    let ip = format!("10.{}.{}.{}", (index >> 16) & 0xff, (index >> 8) & 0xff, index & 0xff);
+    ContainerInfo {
+        id: format!("sim-{:06}", index),
+        name: format!("sim-{}", index),
+        image: "pingap-sim".to_string(),
+        labels,
+        ip_address: Some(ip.clone()),
+        ports: vec![80],
+        networks: HashMap::from([("bridge".to_string(), ip)]),
+        ipv6_networks: HashMap::new(),
+    }
+}
+
+/// What a soak run did and whether Pingap ended up consistent.
+#[derive(Debug)]
+pub struct Report {
+    pub events: usize,
+    pub elapsed: Duration,
+    pub requests: u64,
+    pub injected_failures: u64,
+    pub superseded_writes: u64,
+    pub failed_writes: u64,
+    pub running: usize,
+    pub settled: bool,
+    pub mismatches: Vec<String>,
+}
+
+impl Report {
+    pub fn consistent(&self) -> bool {
+        self.settled && self.mismatches.is_empty()
+    }
+
+    fn print(&self) {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        println!("events:            {} ({:.0}/s)", self.events, self.events as f64 / secs);
+        println!("pingap requests:   {} ({:.0}/s)", self.requests, self.requests as f64 / secs);
+        println!("injected failures: {}", self.injected_failures);
+        println!("superseded writes: {}", self.superseded_writes);
+        println!("failed writes:     {}", self.failed_writes);
+        println!("running at end:    {}", self.running);
+        println!("elapsed:           {:.2}s", secs);
+        if !self.settled {
+            println!("writes still running after {}s", SETTLE_TIMEOUT.as_secs());
+        }
+        for mismatch in &self.mismatches {
+            println!("mismatch: {}", mismatch);
+        }
+        println!("consistent:        {}", self.consistent());
+    }
+}
+
+pub async fn run(args: &[String]) -> Result<()> {
+    let options = Options::parse(args)?;
+    let report = simulate(&options).await?;
+    report.print();
+    if !report.consistent() {
+        bail!("Pingap config does not match the running containers after the simulation");
+    }
+    Ok(())
+}
+
+pub async fn simulate(options: &Options) -> Result<Report> {
+    let mock = MockPingap::start(options).await?;
+    let config = Config {
+        pingap_admin_url: mock.url.clone(),
+        flap_threshold: 0,
+        compose_stop_group_window: Duration::ZERO,
+        tombstone_ttl: Duration::ZERO,
+        retry: RetryPolicy {
+            initial_interval: Duration::from_millis(5),
+            max_interval: Duration::from_millis(50),
+            apply_max_elapsed: Duration::from_secs(10),
+            delete_max_elapsed: Duration::from_secs(10),
+            circuit_breaker_threshold: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let pingap = PingapClient::new(config.pingap_admin_url.clone())
+        .with_retry_policy(config.retry.clone());
+    // Containers come from the simulation, the daemon is never contacted
+    let docker = DockerClient::new(None)?;
+    let status = Arc::new(Status::new("simulate"));
+    let mut provider = Provider::new(config, docker, pingap, status.clone());
+
+    let containers = (0..options.containers)
+        .map(|index| synthetic_container(index, options.services))
+        .collect::<Vec<_>>();
+    let mut running = BTreeSet::new();
+    let mut rng = Rng::new(options.seed);
+    let started = Instant::now();
+    for _ in 0..options.events {
+        let container = &containers[rng.below(containers.len())];
+        if running.remove(&container.id) {
+            let mut attributes = container.labels.clone();
+            attributes.insert("name".to_string(), container.name.clone());
+            provider.handle_stop(&container.id, &attributes);
+        } else {
+            running.insert(container.id.clone());
+            provider.start_container(container);
+        }
+        provider.poll_writes();
+        tokio::task::yield_now().await;
+    }
+    let settled = provider.drain_writes(SETTLE_TIMEOUT).await;
+    let elapsed = started.elapsed();
+
+    let running = containers.iter().filter(|c| running.contains(&c.id)).collect::<Vec<_>>();
+    let mismatches = compare(&provider, &mock, &running);
+    Ok(Report {
+        events: options.events,
+        elapsed,
+        requests: mock.requests.load(Ordering::Relaxed),
+        injected_failures: mock.failures.load(Ordering::Relaxed),
+        superseded_writes: status.metrics.counter("pingap_provider_superseded_operations_total", &[]),
+        failed_writes: ["apply", "scale", "delete"].iter()
+            .map(|operation| status.metrics.counter("pingap_provider_operations_total", &[("operation", operation), ("outcome", "error")]))
+            .sum(),
+        running: running.len(),
+        settled,
+        mismatches,
+    })
+}
+
+/// Differences between the running containers and what the provider tracks
+/// and Pingap serves.
+fn compare(provider: &Provider, mock: &MockPingap, running: &[&ContainerInfo]) -> Vec<String> {
+    let mut expected: BTreeMap<String, (BTreeSet<String>, BTreeSet<String>)> = BTreeMap::new();
+    for container in running {
+        let (ids, addrs) = expected.entry(container.labels["pingap.service.name"].clone()).or_default();
+        ids.insert(container.id.clone());
+        addrs.insert(format!("{}:80", container.ip_address.as_deref().unwrap_or_default()));
+    }
+    let mut mismatches = Vec::new();
+
+    let tracked = provider.tracked_replicas();
+    let expected_replicas = expected.iter()
+        .map(|(service, (ids, _))| (service.clone(), ids.clone()))
+        .collect::<BTreeMap<_, _>>();
+    if tracked != expected_replicas {
+        mismatches.push(format!("provider tracks replicas {:?}, running are {:?}", tracked, expected_replicas));
+    }
+    let tracked = provider.tracked_containers();
+    let running_ids = running.iter().map(|c| c.id.clone()).collect::<BTreeSet<_>>();
+    if tracked != running_ids {
+        mismatches.push(format!("provider tracks {} applied containers, {} are running", tracked.len(), running_ids.len()));
+    }
+
+    let upstreams = mock.section("upstreams");
+    let locations = mock.section("locations");
+    let services = expected.keys().chain(upstreams.keys()).chain(locations.keys()).collect::<BTreeSet<_>>();
+    for service in services {
+        let served = upstreams.get(service)
+            .and_then(|upstream| upstream["addrs"].as_array().cloned())
+            .map(|addrs| addrs.iter().filter_map(|addr| addr.as_str().map(str::to_string)).collect::<BTreeSet<_>>());
+        match (expected.get(service), served) {
+            (Some((_, addrs)), Some(served)) if *addrs == served => {},
+            (Some((_, addrs)), served) => mismatches.push(format!("upstream {} has {:?}, expected {:?}", service, served, addrs)),
+            (None, Some(served)) => mismatches.push(format!("upstream {} has {:?} without running containers", service, served)),
+            (None, None) => {},
+        }
+        if expected.contains_key(service) != locations.contains_key(service) {
+            mismatches.push(format!("location {} is {}", service, if locations.contains_key(service) { "left over" } else { "missing" }));
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = Options::parse(&args(&["--containers", "20", "--services=4", "--fail-rate", "0.2", "--latency-ms=0"])).unwrap();
+        assert_eq!(options.containers, 20);
+        assert_eq!(options.services, 4);
+        assert_eq!(options.fail_rate, 0.2);
+        assert_eq!(options.latency, Duration::ZERO);
+        assert_eq!(options.events, Options::default().events);
+
+        assert!(Options::parse(&args(&["--containers"])).is_err());
+        assert!(Options::parse(&args(&["--fail-rate=1"])).is_err());
+        assert!(Options::parse(&args(&["--bogus=1"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_event_storm_settles_consistently() {
+        // Without latency a cancelled request can't land after the write that superseded it
+        let options = Options { containers: 12, services: 3, events: 150, seed: 7, latency: Duration::ZERO, fail_rate: 0.2 };
+        let report = simulate(&options).await.unwrap();
+        assert!(report.consistent(), "{:?}", report);
+        assert!(report.injected_failures > 0);
+    }
+}
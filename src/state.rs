@@ -0,0 +1,202 @@
+use std::fmt;
+use anyhow::Result;
+use serde_json::Value;
+use tracing::{debug, info};
+use crate::audit;
+use crate::docker::DockerClient;
+use crate::hooks::ConfigHook;
+use crate::pingap::{self, PingapClient};
+use crate::plugins;
+
+/// One write that moves Pingap's config towards the desired state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Create { section: &'static str, name: String, payload: Value },
+    Update { section: &'static str, name: String, payload: Value },
+    Delete { section: &'static str, name: String },
+}
+
+impl Action {
+    pub fn section(&self) -> &'static str {
+        match self {
+            Action::Create { section, .. } | Action::Update { section, .. } | Action::Delete { section, .. } => section,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Action::Create { name, .. } | Action::Update { name, .. } | Action::Delete { name, .. } => name,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = match self {
+            Action::Create { .. } => "create",
+            Action::Update { .. } => "update",
+            Action::Delete { .. } => "delete",
+        };
+        write!(f, "{} {}/{}", verb, self.section(), self.name())
+    }
+}
+
+/// Sections in the order they are written, so a location never points at a
+/// missing upstream or plugin. Deletes go the other way round.
+const WRITE_ORDER: [&str; 3] = ["upstreams", "plugins", "locations"];
+
+/// The actions that turn `actual` (Pingap's full config) into `desired`
+/// (section -> name -> payload, see [`pingap::desired_resources`]):
+/// resources missing or different in `actual` are written, managed
+/// upstreams and locations nothing desires anymore are deleted along with
+/// their generated plugins. Resources without the ownership marker are only
+/// overwritten with `adopt` and never deleted.
+pub fn plan(desired: &Value, actual: &Value, adopt: bool) -> Vec<Action> {
+    let mut actions = Vec::new();
+    for section in WRITE_ORDER {
+        let Some(entries) = desired[section].as_object() else {
+            continue;
+        };
+        for (name, payload) in entries {
+            let action = match actual[section].get(name) {
+                None => Action::Create { section, name: name.clone(), payload: payload.clone() },
+                Some(existing) if pingap::up_to_date(section, existing, payload) => continue,
+                Some(existing) if !adopt && !pingap::is_managed(existing) => {
+                    debug!("Leaving {}/{} alone, it isn't managed by the provider", section, name);
+                    continue;
+                },
+                Some(_) => Action::Update { section, name: name.clone(), payload: payload.clone() },
+            };
+            actions.push(action);
+        }
+    }
+
+    let stale = |section: &str, name: &str, resource: &Value| {
+        pingap::is_managed(resource) && desired[section].get(name).is_none()
+    };
+    // Checked rather than assumed: the active slot of a blue/green service stays while its location does
+    let routed = |upstream: &str| {
+        let routes = |name: &String, location: &Value| location["upstream"].as_str() == Some(upstream) && !stale("locations", name, location);
+        [&desired["locations"], &actual["locations"]].into_iter()
+            .filter_map(Value::as_object)
+            .any(|locations| locations.iter().any(|(name, location)| routes(name, location)))
+    };
+    let mut plugins = Vec::new();
+    for section in ["locations", "upstreams"] {
+        let Some(entries) = actual[section].as_object() else {
+            continue;
+        };
+        for (name, resource) in entries.iter().filter(|(name, resource)| stale(section, name, resource)) {
+            if section == "upstreams" && routed(name) {
+                debug!("Keeping upstream {}, a remaining location routes to it", name);
+                continue;
+            }
+            if section == "locations" {
+                plugins.extend(plugins::generated_plugins(name, resource).into_iter()
+                    .filter(|plugin| actual["plugins"].get(plugin).is_some_and(|resource| stale("plugins", plugin, resource))));
+            }
+            actions.push(Action::Delete { section, name: name.clone() });
+        }
+    }
+    plugins.sort();
+    plugins.dedup();
+    actions.extend(plugins.into_iter().map(|name| Action::Delete { section: "plugins", name }));
+    actions
+}
+
+/// `pingap-docker-provider plan`: prints the actions that would bring
+/// Pingap in line with the running containers on stdout, without writing
+/// anything. Unmanaged resources only show up with `adopt`.
+pub async fn run(docker: &DockerClient, pingap: &PingapClient, hook: Option<&ConfigHook>, adopt: bool) -> Result<()> {
+    let containers = docker.get_running_containers().await?;
+    let desired = audit::desired_configs(&containers, hook);
+    let mut actions = pingap.plan(&desired.configs, adopt).await?;
+    // What Pingap has of the services the config hook holds back stays
+    actions.retain(|action| !matches!(action, Action::Delete { name, .. } if desired.holds(name)));
+    for action in &actions {
+        println!("{}", action);
+    }
+    if actions.is_empty() {
+        info!("Pingap matches the running containers, nothing to do");
+    } else {
+        info!("{} actions would bring Pingap in line with the running containers", actions.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pingap::MANAGED_REMARK;
+
+    #[test]
+    fn test_plan_creates_updates_and_deletes() {
+        let desired = serde_json::json!({
+            "upstreams": {
+                "web": { "addrs": ["10.0.0.2:80"], "remark": MANAGED_REMARK },
+                "api": { "addrs": ["10.0.0.3:80"], "remark": MANAGED_REMARK },
+                "manual": { "addrs": ["10.0.0.4:80"], "remark": MANAGED_REMARK },
+            },
+            "locations": {
+                "web": { "upstream": "web", "remark": MANAGED_REMARK },
+            },
+        });
+        let actual = serde_json::json!({
+            "upstreams": {
+                "web": { "addrs": ["10.0.0.1:80"], "remark": MANAGED_REMARK },
+                "manual": { "addrs": ["10.0.0.9:80"] },
+                "gone": { "addrs": ["10.0.0.5:80"], "remark": MANAGED_REMARK },
+                "other": { "addrs": ["10.0.0.6:80"] },
+            },
+            "locations": {
+                // Pingap's own defaults are no difference
+                "web": { "upstream": "web", "remark": MANAGED_REMARK, "weight": 1 },
+                "gone": { "upstream": "gone", "remark": MANAGED_REMARK, "plugins": ["gone-compress"] },
+            },
+            "plugins": {
+                "gone-compress": { "category": "compression", "remark": MANAGED_REMARK },
+            },
+        });
+
+        let actions = plan(&desired, &actual, false).iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(actions, [
+            "create upstreams/api",
+            "update upstreams/web",
+            "delete locations/gone",
+            "delete upstreams/gone",
+            "delete plugins/gone-compress",
+        ]);
+
+        // Adopting takes over the unmanaged upstream of the same name
+        assert!(plan(&desired, &actual, true).iter().any(|action| action.to_string() == "update upstreams/manual"));
+    }
+
+    #[test]
+    fn test_plan_keeps_the_active_slot() {
+        // Only the blue slot runs, the location stays on green until a cutover
+        let desired = serde_json::json!({
+            "upstreams": { "shop-blue": { "addrs": ["10.0.0.1:80"], "remark": MANAGED_REMARK } },
+            "locations": { "shop": { "upstream": "shop-green", "remark": MANAGED_REMARK } },
+        });
+        let actual = serde_json::json!({
+            "upstreams": {
+                "shop-blue": { "addrs": ["10.0.0.1:80"], "remark": MANAGED_REMARK },
+                "shop-green": { "addrs": ["10.0.0.2:80"], "remark": MANAGED_REMARK },
+            },
+            "locations": { "shop": { "upstream": "shop-green", "remark": MANAGED_REMARK } },
+        });
+        assert!(plan(&desired, &actual, false).is_empty());
+
+        // Once the location points at blue, green is idle and goes
+        let desired = serde_json::json!({
+            "upstreams": desired["upstreams"],
+            "locations": { "shop": { "upstream": "shop-blue", "remark": MANAGED_REMARK } },
+        });
+        let actual = serde_json::json!({
+            "upstreams": actual["upstreams"],
+            "locations": { "shop": { "upstream": "shop-blue", "remark": MANAGED_REMARK } },
+        });
+        let actions = plan(&desired, &actual, false).iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(actions, ["delete upstreams/shop-green"]);
+    }
+}
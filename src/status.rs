@@ -0,0 +1,588 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, info};
+use crate::audit::Drift;
+use crate::export::ProviderState;
+use crate::metrics::Metrics;
+use crate::models::LabelDiagnostic;
+use crate::policy::PolicyViolation;
+
+/// How many of the latest events and errors `/status` keeps.
+const RECENT_ENTRIES: usize = 20;
+
+/// Largest request body taken, enough for the exported state of thousands of services.
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Something the provider handled or failed at, for `recent_events` and `recent_errors`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecentEntry {
+    /// Unix time in seconds
+    pub time: u64,
+    pub message: String,
+}
+
+/// A container refused its service because containers of another origin
+/// (compose project or image) already run a service of that name.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServiceConflict {
+    pub service: String,
+    /// Name of the refused container
+    pub container: String,
+    pub origin: String,
+    /// Container name -> origin of the containers that keep the service
+    pub running: BTreeMap<String, String>,
+}
+
+/// A service routes the same host and path as another service at the same
+/// priority, so Pingap is left to pick one of them (`CONFLICT_POLICY`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteConflict {
+    pub service: String,
+    /// Name of the container whose service was checked last
+    pub container: String,
+    /// The service already routing the requests
+    pub conflicts_with: String,
+    /// Host and path matched by both, e.g. ``Host(`shop.local`) && PathPrefix(`/api`)``
+    pub route: String,
+    /// Whether the service was refused (`CONFLICT_POLICY=block`) or applied anyway
+    pub blocked: bool,
+}
+
+/// Point-in-time view of the provider served as JSON on `/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatusSnapshot {
+    pub mode: String,
+    /// False until the first sync and while Docker is unreachable
+    pub ready: bool,
+    /// ContainerID -> service name of everything currently applied
+    pub services: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub drift: Vec<Drift>,
+    /// Services kept in Pingap by `pingap.on_stop` although no container runs them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retained: Vec<String>,
+    /// Service -> seconds until its tombstone expires and it is removed from Pingap
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tombstones: BTreeMap<String, u64>,
+    /// Service -> `pingap.depends_on` services it waits for before its route is activated
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub waiting_for_dependencies: BTreeMap<String, Vec<String>>,
+    /// Services answered by the maintenance plugin, by label or through the API
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub maintenance: Vec<String>,
+    /// Whether a `FREEZE_WINDOWS` window holds back Pingap writes right now
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+    /// Services with changes waiting for the freeze window to end
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub held_back: Vec<String>,
+    /// Upstream addresses left out of their upstream because probes can't reach them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub evicted_addresses: Vec<String>,
+    /// Address -> failed Pingap writes that included it
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub address_apply_errors: BTreeMap<String, u64>,
+    /// `<section>/<name>` -> failed writes in a row, of the upstreams and
+    /// locations Pingap didn't accept last time; each is retried on its own
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub failing_resources: BTreeMap<String, u32>,
+    /// Containers held down because they are crash-looping
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub flapping: Vec<String>,
+    /// Containers whose service name is already taken by unrelated containers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub service_conflicts: Vec<ServiceConflict>,
+    /// Services whose routes overlap routes of other services
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub route_conflicts: Vec<RouteConflict>,
+    /// Services refused by a rule of `POLICY_FILE`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub policy_violations: Vec<PolicyViolation>,
+    /// Container name -> optional labels that were left out of its config
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub label_diagnostics: BTreeMap<String, Vec<LabelDiagnostic>>,
+    /// Latest Docker events handled, oldest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recent_events: Vec<RecentEntry>,
+    /// Latest failed Pingap writes and unusable containers, oldest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub recent_errors: Vec<RecentEntry>,
+}
+
+fn push_recent(entries: &mut Vec<RecentEntry>, message: String) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    entries.push(RecentEntry { time, message });
+    if entries.len() > RECENT_ENTRIES {
+        entries.remove(0);
+    }
+}
+
+/// State shared between the sync loop and the status server.
+pub struct Status {
+    snapshot: RwLock<StatusSnapshot>,
+    pub metrics: Metrics,
+    // Services put into maintenance through the API, picked up by the sync loop
+    maintenance: RwLock<BTreeSet<String>>,
+    // Tracked state served on `GET /state` and by the gRPC API, as of the last publish
+    provider_state: watch::Sender<Option<ProviderState>>,
+    // State sent with `POST /state`, picked up by the sync loop
+    state_import: RwLock<Option<ProviderState>>,
+    // Resyncs requested through the gRPC API, answered with the number of services after it
+    resync_tx: mpsc::UnboundedSender<ResyncReply>,
+    resync_rx: Mutex<Option<mpsc::UnboundedReceiver<ResyncReply>>>,
+    // Prune of the retained services requested with `POST /prune`, picked up by the sync loop
+    prune: AtomicBool,
+    // Bearer token `/state`, `/maintenance` and `/prune` require (`STATUS_API_TOKEN`); without one only loopback clients get them
+    api_token: Option<String>,
+}
+
+/// Where the sync loop answers a requested resync.
+pub type ResyncReply = oneshot::Sender<Result<usize, String>>;
+
+impl Status {
+    pub fn new(mode: &str) -> Self {
+        let (resync_tx, resync_rx) = mpsc::unbounded_channel();
+        Self {
+            snapshot: RwLock::new(StatusSnapshot {
+                mode: mode.to_string(),
+                ..Default::default()
+            }),
+            metrics: Metrics::default(),
+            maintenance: RwLock::new(BTreeSet::new()),
+            provider_state: watch::channel(None).0,
+            state_import: RwLock::new(None),
+            resync_tx,
+            resync_rx: Mutex::new(Some(resync_rx)),
+            prune: AtomicBool::new(false),
+            api_token: None,
+        }
+    }
+
+    pub fn with_api_token(mut self, token: Option<String>) -> Self {
+        self.api_token = token;
+        self
+    }
+
+    /// Whether a request with `head` from `peer` may use the endpoints that
+    /// read secrets or change the provider's state.
+    fn authorized(&self, head: &str, peer: IpAddr) -> bool {
+        let authorization = head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+            .map(|(_, value)| value.trim())
+            .collect::<Vec<_>>();
+        self.authorizes(&authorization, Some(peer))
+    }
+
+    /// Whether a client at `peer` sending these `Authorization` values may
+    /// read secrets or change the provider's state: the bearer token of
+    /// `STATUS_API_TOKEN`, or without one a loopback address.
+    pub fn authorizes(&self, authorization: &[&str], peer: Option<IpAddr>) -> bool {
+        let Some(token) = &self.api_token else {
+            return peer.is_some_and(|peer| peer.is_loopback());
+        };
+        authorization.iter()
+            .filter_map(|value| value.strip_prefix("Bearer "))
+            .any(|sent| constant_time_eq(sent.trim().as_bytes(), token.as_bytes()))
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut StatusSnapshot)) {
+        f(&mut self.snapshot.write().unwrap());
+    }
+
+    pub fn snapshot(&self) -> StatusSnapshot {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    pub fn record_event(&self, message: String) {
+        self.update(|s| push_recent(&mut s.recent_events, message));
+    }
+
+    pub fn record_error(&self, message: String) {
+        self.update(|s| push_recent(&mut s.recent_errors, message));
+    }
+
+    pub fn set_maintenance(&self, service: &str, enabled: bool) {
+        let mut maintenance = self.maintenance.write().unwrap();
+        if enabled {
+            maintenance.insert(service.to_string());
+        } else {
+            maintenance.remove(service);
+        }
+    }
+
+    pub fn maintenance_requests(&self) -> BTreeSet<String> {
+        self.maintenance.read().unwrap().clone()
+    }
+
+    pub fn set_provider_state(&self, state: ProviderState) {
+        self.provider_state.send_replace(Some(state));
+    }
+
+    /// The tracked state of every publish from now on, None until the first.
+    pub fn watch_provider_state(&self) -> watch::Receiver<Option<ProviderState>> {
+        self.provider_state.subscribe()
+    }
+
+    /// The resyncs requested with [`Status::request_resync`], taken once by the sync loop.
+    pub fn take_resync_requests(&self) -> Option<mpsc::UnboundedReceiver<ResyncReply>> {
+        self.resync_rx.lock().unwrap().take()
+    }
+
+    /// Has the sync loop re-read every container, like after a Docker
+    /// reconnect, and returns how many services it tracks afterwards.
+    pub async fn request_resync(&self) -> Result<usize> {
+        let (reply, answer) = oneshot::channel();
+        self.resync_tx.send(reply).map_err(|_| anyhow!("the sync loop is not running"))?;
+        answer.await.map_err(|_| anyhow!("the sync loop stopped"))?.map_err(|e| anyhow!(e))
+    }
+
+    /// Whether a prune of the retained services was requested since the last call.
+    pub fn take_prune_request(&self) -> bool {
+        self.prune.swap(false, Ordering::Relaxed)
+    }
+
+    /// The state imported through the API since the last call, if any.
+    pub fn take_state_import(&self) -> Option<ProviderState> {
+        self.state_import.write().unwrap().take()
+    }
+}
+
+/// Serves `/status` (JSON) and `/metrics` (Prometheus text) until the task is
+/// dropped, and takes `POST`/`DELETE /maintenance/<service>`, `POST /prune`
+/// and the `GET`/`POST /state` of `state export|import` in sync mode.
+pub async fn serve(listener: TcpListener, status: Arc<Status>) -> Result<()> {
+    info!("Status API listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let status = status.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer.ip(), &status).await {
+                debug!("Status API connection from {} failed: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// Reads a request's head and, as far as its `Content-Length` goes, its body.
+async fn read_request(stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = vec![0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        buf.extend_from_slice(&chunk[..n]);
+        let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+            if n == 0 || buf.len() > chunk.len() * 4 {
+                return Ok((String::from_utf8_lossy(&buf).to_string(), Vec::new()));
+            }
+            continue;
+        };
+        let head = String::from_utf8_lossy(&buf[..end]).to_string();
+        let length = head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0)
+            .min(MAX_BODY_BYTES);
+        let mut body = buf.split_off(end + 4);
+        while body.len() < length {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..n]);
+        }
+        body.truncate(length);
+        return Ok((head, body));
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, peer: IpAddr, status: &Status) -> Result<()> {
+    let (head, body) = read_request(&mut stream).await?;
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let (code, content_type, body) = match path.split('?').next() {
+        Some(path) if (path == "/state" || path == "/prune" || path.starts_with("/maintenance/")) && !status.authorized(&head, peer) => unauthorized(status),
+        Some("/state") => route_state(method, &String::from_utf8_lossy(&body), status),
+        _ => route(method, path, status),
+    };
+    let reason = match code {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code, reason, content_type, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn route(method: &str, path: &str, status: &Status) -> (u16, &'static str, String) {
+    let path = path.split('?').next().unwrap_or_default();
+    if let Some(service) = path.strip_prefix("/maintenance/") {
+        return route_maintenance(method, service, status);
+    }
+    if path == "/prune" {
+        return route_prune(method, status);
+    }
+    if method != "GET" {
+        return (405, "text/plain", "method not allowed\n".to_string());
+    }
+    match path {
+        "/status" => match serde_json::to_string_pretty(&status.snapshot()) {
+            Ok(json) => (200, "application/json", json),
+            Err(e) => (500, "text/plain", format!("{}\n", e)),
+        },
+        "/metrics" => (200, "text/plain; version=0.0.4", status.metrics.render()),
+        "/ready" => match status.snapshot().ready {
+            true => (200, "text/plain", "ready\n".to_string()),
+            false => (503, "text/plain", "not ready\n".to_string()),
+        },
+        _ => (404, "text/plain", "not found\n".to_string()),
+    }
+}
+
+fn route_maintenance(method: &str, service: &str, status: &Status) -> (u16, &'static str, String) {
+    if service.is_empty() || service.contains('/') {
+        return (404, "text/plain", "not found\n".to_string());
+    }
+    let enabled = match method {
+        "POST" | "PUT" => true,
+        "DELETE" => false,
+        _ => return (405, "text/plain", "method not allowed\n".to_string()),
+    };
+    if status.snapshot().mode != "sync" {
+        return (409, "text/plain", "maintenance needs MODE=sync\n".to_string());
+    }
+    status.set_maintenance(service, enabled);
+    let state = if enabled { "enabled" } else { "disabled" };
+    (200, "text/plain", format!("maintenance {} for {}\n", state, service))
+}
+
+/// `POST /prune` removes the services `pingap.on_stop=drain|keep` retained
+/// on the sync loop's next tick.
+fn route_prune(method: &str, status: &Status) -> (u16, &'static str, String) {
+    if method != "POST" {
+        return (405, "text/plain", "method not allowed\n".to_string());
+    }
+    if status.snapshot().mode != "sync" {
+        return (409, "text/plain", "prune needs MODE=sync\n".to_string());
+    }
+    status.prune.store(true, Ordering::Relaxed);
+    (200, "text/plain", "prune of retained services queued\n".to_string())
+}
+
+fn unauthorized(status: &Status) -> (u16, &'static str, String) {
+    match status.api_token {
+        Some(_) => (401, "text/plain", "missing or wrong bearer token\n".to_string()),
+        None => (401, "text/plain", "only served to loopback clients without STATUS_API_TOKEN\n".to_string()),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// `GET /state` serves the tracked state with its credentials masked,
+/// `POST /state` queues an export to be merged into it.
+fn route_state(method: &str, body: &str, status: &Status) -> (u16, &'static str, String) {
+    if method != "GET" && method != "POST" {
+        return (405, "text/plain", "method not allowed\n".to_string());
+    }
+    if status.snapshot().mode != "sync" {
+        return (409, "text/plain", "provider state needs MODE=sync\n".to_string());
+    }
+    if method == "GET" {
+        let state = status.provider_state.borrow().as_ref().map(ProviderState::redacted);
+        return match state.map(|state| state.and_then(|state| Ok(serde_json::to_string_pretty(&state)?))) {
+            Some(Ok(json)) => (200, "application/json", json),
+            Some(Err(e)) => (500, "text/plain", format!("{:#}\n", e)),
+            None => (503, "text/plain", "initial sync still running\n".to_string()),
+        };
+    }
+    match ProviderState::parse(body) {
+        Ok(state) => {
+            let services = state.services.len();
+            *status.state_import.write().unwrap() = Some(state);
+            (200, "text/plain", format!("state with {} services queued for import\n", services))
+        },
+        Err(e) => (400, "text/plain", format!("{:#}\n", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_status() {
+        let status = Status::new("sync");
+        status.update(|s| {
+            s.services.insert("abc123".to_string(), "web".to_string());
+        });
+
+        let (code, content_type, body) = route("GET", "/status", &status);
+        assert_eq!(code, 200);
+        assert_eq!(content_type, "application/json");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["mode"], "sync");
+        assert_eq!(json["services"]["abc123"], "web");
+    }
+
+    #[test]
+    fn test_recent_entries_bounded() {
+        let status = Status::new("sync");
+        for i in 0..RECENT_ENTRIES + 5 {
+            status.record_event(format!("start c{}", i));
+        }
+        status.record_error("Failed to apply config for service web".to_string());
+
+        let snapshot = status.snapshot();
+        assert_eq!(snapshot.recent_events.len(), RECENT_ENTRIES);
+        assert_eq!(snapshot.recent_events[0].message, "start c5");
+        assert_eq!(snapshot.recent_errors.len(), 1);
+    }
+
+    #[test]
+    fn test_route_metrics() {
+        let status = Status::new("sync");
+        status.metrics.inc("pingap_provider_applies_total", &[("outcome", "success")]);
+
+        let (code, _, body) = route("GET", "/metrics?format=text", &status);
+        assert_eq!(code, 200);
+        assert!(body.contains("pingap_provider_applies_total{outcome=\"success\"} 1"));
+    }
+
+    #[test]
+    fn test_route_ready() {
+        let status = Status::new("sync");
+        assert_eq!(route("GET", "/ready", &status).0, 503);
+
+        status.update(|s| s.ready = true);
+        assert_eq!(route("GET", "/ready", &status).0, 200);
+    }
+
+    #[test]
+    fn test_route_errors() {
+        let status = Status::new("sync");
+        assert_eq!(route("GET", "/nope", &status).0, 404);
+        assert_eq!(route("POST", "/status", &status).0, 405);
+    }
+
+    #[test]
+    fn test_route_maintenance() {
+        let status = Status::new("sync");
+        assert_eq!(route("POST", "/maintenance/web", &status).0, 200);
+        assert_eq!(route("POST", "/maintenance/api", &status).0, 200);
+        assert_eq!(route("DELETE", "/maintenance/api", &status).0, 200);
+        assert_eq!(status.maintenance_requests(), BTreeSet::from(["web".to_string()]));
+
+        assert_eq!(route("GET", "/maintenance/web", &status).0, 405);
+        assert_eq!(route("POST", "/maintenance/", &status).0, 404);
+        assert_eq!(route("POST", "/maintenance/web", &Status::new("audit")).0, 409);
+    }
+
+    #[test]
+    fn test_route_prune() {
+        let status = Status::new("sync");
+        assert!(!status.take_prune_request());
+        assert_eq!(route("GET", "/prune", &status).0, 405);
+        assert_eq!(route("POST", "/prune", &status).0, 200);
+        assert!(status.take_prune_request());
+        assert!(!status.take_prune_request());
+        assert_eq!(route("POST", "/prune", &Status::new("audit")).0, 409);
+    }
+
+    #[test]
+    fn test_route_state() {
+        let status = Status::new("sync");
+        assert_eq!(route_state("GET", "", &status).0, 503);
+        status.set_provider_state(ProviderState { version: 1, exported_at: 42, ..Default::default() });
+        let (code, _, body) = route_state("GET", "", &status);
+        assert_eq!(code, 200);
+        assert_eq!(ProviderState::parse(&body).unwrap().exported_at, 42);
+
+        assert_eq!(route_state("POST", "{\"version\": 1}", &status).0, 400);
+        assert_eq!(route_state("POST", &body, &status).0, 200);
+        assert_eq!(status.take_state_import().unwrap().exported_at, 42);
+        assert!(status.take_state_import().is_none());
+
+        assert_eq!(route_state("DELETE", "", &status).0, 405);
+        assert_eq!(route_state("GET", "", &Status::new("audit")).0, 409);
+    }
+
+    #[test]
+    fn test_state_needs_token_or_loopback() {
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "10.0.0.7".parse().unwrap();
+        let head = |auth: &str| format!("GET /state HTTP/1.1\r\nHost: provider\r\n{}", auth);
+        let status = Status::new("sync");
+        assert!(status.authorized(&head(""), loopback));
+        assert!(!status.authorized(&head(""), remote));
+
+        let status = Status::new("sync").with_api_token(Some("s3cr3t".to_string()));
+        assert!(status.authorized(&head("authorization: Bearer s3cr3t"), remote));
+        assert!(!status.authorized(&head("Authorization: Bearer wrong"), remote));
+        assert!(!status.authorized(&head(""), loopback));
+    }
+
+    #[test]
+    fn test_state_export_masks_credentials() {
+        let status = Status::new("sync");
+        let mut config = crate::models::PingapServiceConfigBuilder::new("web", vec![], "Host(`web.local`)").build();
+        config.middleware_config = Some(crate::models::MiddlewareConfig {
+            basic_auth: Some("admin:hunter2".to_string()),
+            custom_request_headers: Some(vec!["Authorization: Bearer abc".to_string(), "X-Env: prod".to_string()]),
+            ..Default::default()
+        });
+        status.set_provider_state(ProviderState {
+            version: 1,
+            services: BTreeMap::from([("web".to_string(), crate::export::ServiceState {
+                config,
+                containers: BTreeMap::new(),
+                weights: BTreeMap::new(),
+                applied: true,
+                tombstone_secs: None,
+                label_diagnostics: Vec::new(),
+            })]),
+            ..Default::default()
+        });
+        let (code, _, body) = route_state("GET", "", &status);
+        assert_eq!(code, 200);
+        assert!(!body.contains("hunter2") && !body.contains("Bearer abc"), "{}", body);
+        let exported = ProviderState::parse(&body).unwrap();
+        assert!(exported.services["web"].is_redacted());
+        let headers = exported.services["web"].config.middleware_config.as_ref().unwrap().custom_request_headers.clone();
+        assert_eq!(headers.unwrap(), ["Authorization: ***", "X-Env: prod"]);
+    }
+
+    #[tokio::test]
+    async fn test_serve_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let status = Arc::new(Status::new("audit"));
+        tokio::spawn(serve(listener, status));
+
+        let body = reqwest::get(format!("http://{}/status", addr)).await.unwrap()
+            .text().await.unwrap();
+        assert!(body.contains("\"mode\": \"audit\""));
+    }
+}
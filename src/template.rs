@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+use crate::signing::hex;
+
+/// Renders the `{{ ... }}` expressions in a label value, e.g.
+/// `{{ container_name | replace "_" "-" }}.local`.
+///
+/// An expression is a variable (or a quoted literal) followed by any number
+/// of `| function "arg"...` stages. Functions: `lower`, `upper`,
+/// `replace OLD NEW`, `trimPrefix P`, `trimSuffix S`, `sha1` and
+/// `default VALUE` (used when the value so far is empty).
+pub fn render(input: &str, vars: &HashMap<&str, String>) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = input;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}")
+            .ok_or_else(|| anyhow!("unterminated '{{{{' in '{}'", input))?;
+        output.push_str(&evaluate(&after[..end], vars)?);
+        rest = &after[end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Pipe,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '|' {
+            chars.next();
+            tokens.push(Token::Pipe);
+        } else if c == '"' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => value.push(c),
+                    None => return Err(anyhow!("unterminated string in '{{{{{}}}}}'", expr)),
+                }
+            }
+            tokens.push(Token::Str(value));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '|' || c == '"' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+fn evaluate(expr: &str, vars: &HashMap<&str, String>) -> Result<String> {
+    let tokens = tokenize(expr)?;
+    let mut stages = tokens.split(|token| *token == Token::Pipe);
+
+    let mut value = match stages.next().unwrap_or_default() {
+        [Token::Word(var)] => vars.get(var.as_str())
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown template variable '{}'", var))?,
+        [Token::Str(literal)] => literal.clone(),
+        // Left in place by the Docker client when it couldn't be resolved
+        [Token::Word(word), Token::Str(reference)] if word == "secret" => {
+            return Err(anyhow!("secret '{}' couldn't be resolved, see the provider log", reference));
+        },
+        _ => return Err(anyhow!("expected a variable at the start of '{{{{{}}}}}'", expr)),
+    };
+
+    for stage in stages {
+        let (function, args) = match stage {
+            [Token::Word(function), args @ ..] => (function.as_str(), args),
+            _ => return Err(anyhow!("expected a function name after '|' in '{{{{{}}}}}'", expr)),
+        };
+        let args = args.iter()
+            .map(|arg| match arg {
+                Token::Word(s) | Token::Str(s) => s.as_str(),
+                Token::Pipe => unreachable!("split on pipes"),
+            })
+            .collect::<Vec<_>>();
+        value = apply(function, &args, value)?;
+    }
+    Ok(value)
+}
+
+fn apply(function: &str, args: &[&str], value: String) -> Result<String> {
+    let expect = |n: usize| -> Result<()> {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(anyhow!("template function '{}' takes {} argument(s), got {}", function, n, args.len()))
+        }
+    };
+    Ok(match function {
+        "lower" => { expect(0)?; value.to_lowercase() },
+        "upper" => { expect(0)?; value.to_uppercase() },
+        "replace" => { expect(2)?; value.replace(args[0], args[1]) },
+        "trimPrefix" => { expect(1)?; value.strip_prefix(args[0]).unwrap_or(&value).to_string() },
+        "trimSuffix" => { expect(1)?; value.strip_suffix(args[0]).unwrap_or(&value).to_string() },
+        "sha1" => { expect(0)?; sha1_hex(value.as_bytes()) },
+        "default" => {
+            expect(1)?;
+            if value.is_empty() { args[0].to_string() } else { value }
+        },
+        other => return Err(anyhow!("unknown template function '{}'", other)),
+    })
+}
+
+/// SHA-1 as lowercase hex. Only used to derive stable names, not for security.
+fn sha1_hex(data: &[u8]) -> String {
+    hex(&Sha1::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("container_name", "My_App_1".to_string()),
+            ("compose_service", String::new()),
+        ])
+    }
+
+    #[test]
+    fn test_plain_value_unchanged() {
+        assert_eq!(render("app.example.com", &vars()).unwrap(), "app.example.com");
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let host = render(r#"{{ container_name | lower | replace "_" "-" }}.local"#, &vars()).unwrap();
+        assert_eq!(host, "my-app-1.local");
+    }
+
+    #[test]
+    fn test_trim_and_default() {
+        assert_eq!(render(r#"{{ container_name | trimPrefix "My_" | trimSuffix "_1" }}"#, &vars()).unwrap(), "App");
+        assert_eq!(render(r#"{{ compose_service | default "web" }}"#, &vars()).unwrap(), "web");
+        assert_eq!(render(r#"{{ container_name | default "web" }}"#, &vars()).unwrap(), "My_App_1");
+    }
+
+    #[test]
+    fn test_literal_and_upper() {
+        assert_eq!(render(r#"{{ "api" | upper }}-v1"#, &vars()).unwrap(), "API-v1");
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(render("{{ container_name", &vars()).is_err());
+        assert!(render("{{ nope }}", &vars()).is_err());
+        assert!(render("{{ container_name | shout }}", &vars()).is_err());
+        assert!(render(r#"{{ container_name | replace "_" }}"#, &vars()).is_err());
+        assert!(render(r#"{{ container_name | replace "_ }}"#, &vars()).is_err());
+        let error = render(r#"{{ secret "vault:secret/data/web#password" }}"#, &vars()).unwrap_err();
+        assert!(error.to_string().contains("couldn't be resolved"), "{}", error);
+    }
+}
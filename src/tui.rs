@@ -0,0 +1,418 @@
+use std::collections::BTreeSet;
+use std::io::{stdout, Stdout};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute};
+use futures::StreamExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, ListState, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use serde_json::Value;
+use crate::maintenance::local_addr;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+/// Events and errors kept, newest first
+const RECENT_SHOWN: usize = 20;
+
+/// `pingap-docker-provider tui [--interval <secs>]`: live tables of the
+/// running provider's `/status` (`STATUS_ADDR`), for operating a headless
+/// Docker host over SSH. Tab moves between the panels, the arrow keys scroll
+/// them, `r` refreshes right away and `q` quits.
+pub async fn run(status_addr: Option<&str>, args: &[String]) -> Result<()> {
+    let interval = match args {
+        [] => DEFAULT_INTERVAL,
+        [flag, secs] if flag == "--interval" => match secs.parse::<u64>() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => return Err(anyhow!("--interval expects a number of seconds greater than 0, got '{}'", secs)),
+        },
+        _ => return Err(anyhow!("Usage: pingap-docker-provider tui [--interval <secs>]")),
+    };
+    let addr = status_addr
+        .ok_or_else(|| anyhow!("STATUS_ADDR must be set to reach the running provider"))?;
+    let url = format!("http://{}/status", local_addr(addr));
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(5)).build()?;
+
+    let mut terminal = TerminalGuard::enter()?;
+    let mut keys = EventStream::new();
+    let mut ticker = tokio::time::interval(interval);
+    let mut screen = Screen::default();
+    let mut view = View::default();
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                match fetch(&client, &url).await {
+                    Ok(status) => screen = Screen::from_status(&status, now),
+                    // The last tables stay up while the provider can't be reached
+                    Err(e) => screen.title = format!("{}  provider unreachable: {:#}", clock(now), e),
+                }
+            },
+            event = keys.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    // Raw mode turns Ctrl-C into a key press
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+                    KeyCode::Char('r') => ticker.reset_immediately(),
+                    KeyCode::Tab => view.focus = view.focus.next(),
+                    KeyCode::BackTab => view.focus = view.focus.next().next(),
+                    KeyCode::Down | KeyCode::Char('j') => view.scroll(1),
+                    KeyCode::Up | KeyCode::Char('k') => view.scroll(-1),
+                    _ => continue,
+                },
+                // Resizes only need a redraw
+                Some(Ok(_)) => {},
+                Some(Err(e)) => return Err(e).context("Failed to read the terminal"),
+                None => break,
+            },
+        }
+        terminal.0.draw(|frame| draw(frame, &screen, &mut view, interval))?;
+    }
+    Ok(())
+}
+
+/// Raw mode and the alternate screen while the TUI runs. Dropping it, also
+/// on an error or a panic, gives the shell its terminal and cursor back.
+struct TerminalGuard(Terminal<CrosstermBackend<Stdout>>);
+
+impl TerminalGuard {
+    fn enter() -> Result<Self> {
+        enable_raw_mode().context("Failed to switch the terminal to raw mode")?;
+        // From here on the drop below restores whatever was switched
+        let mut guard = Self(Terminal::new(CrosstermBackend::new(stdout()))?);
+        execute!(guard.0.backend_mut(), EnterAlternateScreen)?;
+        guard.0.hide_cursor()?;
+        guard.0.clear()?;
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0.backend_mut(), LeaveAlternateScreen, cursor::Show);
+    }
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<Value> {
+    let resp = client.get(url).send().await
+        .context(format!("Failed to reach the provider at {}", url))?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("{} answered {}", url, resp.status()));
+    }
+    Ok(resp.json().await?)
+}
+
+/// `HH:MM:SS` (UTC) of a Unix time.
+fn clock(time: u64) -> String {
+    format!("{:02}:{:02}:{:02}", time / 3600 % 24, time / 60 % 60, time % 60)
+}
+
+fn strings(value: &Value) -> BTreeSet<String> {
+    value.as_array().into_iter().flatten()
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Newest first, at most [`RECENT_SHOWN`].
+fn recent(entries: &Value) -> Vec<String> {
+    let mut lines = entries.as_array().into_iter().flatten()
+        .map(|entry| format!("{}  {}", clock(entry["time"].as_u64().unwrap_or_default()),
+            entry["message"].as_str().unwrap_or_default()))
+        .collect::<Vec<_>>();
+    lines.reverse();
+    lines.truncate(RECENT_SHOWN);
+    lines
+}
+
+/// What the TUI shows of one `/status`: containers and their services, sync
+/// state, recent events and everything that went wrong.
+#[derive(Debug, Default)]
+struct Screen {
+    title: String,
+    containers: Vec<Vec<String>>,
+    sync: Vec<Vec<String>>,
+    events: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl Screen {
+    fn from_status(status: &Value, now: u64) -> Self {
+        let title = format!("pingap-docker-provider  mode: {}  ready: {}{}  {} UTC",
+            status["mode"].as_str().unwrap_or("?"),
+            if status["ready"].as_bool().unwrap_or(false) { "yes" } else { "no" },
+            if status["frozen"].as_bool().unwrap_or(false) { "  FREEZE WINDOW" } else { "" },
+            clock(now));
+
+        let maintenance = strings(&status["maintenance"]);
+        let held_back = strings(&status["held_back"]);
+        let waiting = status["waiting_for_dependencies"].as_object().cloned().unwrap_or_default();
+        let flapping = strings(&status["flapping"]);
+        let services = status["services"].as_object().cloned().unwrap_or_default();
+        let containers = services.iter()
+            .map(|(container, names)| {
+                let names = names.as_str().unwrap_or_default();
+                let mut state = names.split(',')
+                    .flat_map(|service| [
+                        maintenance.contains(service).then(|| format!("{} in maintenance", service)),
+                        held_back.contains(service).then(|| format!("{} held back", service)),
+                        waiting.contains_key(service).then(|| format!("{} waiting", service)),
+                    ])
+                    .flatten()
+                    .collect::<Vec<_>>();
+                if flapping.contains(container) {
+                    state.insert(0, "flapping".to_string());
+                }
+                let state = if state.is_empty() { "applied".to_string() } else { state.join(", ") };
+                vec![container.chars().take(12).collect(), names.to_string(), state]
+            })
+            .collect();
+
+        let mut sync = Vec::new();
+        let mut list = |name: &str, items: Vec<String>| {
+            if !items.is_empty() {
+                sync.push(vec![name.to_string(), items.join(", ")]);
+            }
+        };
+        list("retained", strings(&status["retained"]).into_iter().collect());
+        list("tombstones", status["tombstones"].as_object().into_iter().flatten()
+            .map(|(service, secs)| format!("{} ({}s left)", service, secs))
+            .collect());
+        list("waiting", waiting.iter()
+            .map(|(service, deps)| format!("{} (for {})", service, strings(deps).into_iter().collect::<Vec<_>>().join(", ")))
+            .collect());
+        list("maintenance", maintenance.iter().cloned().collect());
+        list("held back", held_back.iter().cloned().collect());
+        list("evicted", strings(&status["evicted_addresses"]).into_iter().collect());
+        list("drift", status["drift"].as_array().into_iter().flatten()
+            .map(|drift| format!("{} {}", drift["kind"].as_str().unwrap_or_default(), drift["service"].as_str().unwrap_or_default()))
+            .collect());
+        list("refused", status["policy_violations"].as_array().into_iter().flatten()
+            .map(|violation| format!("{} (rule {})", violation["service"].as_str().unwrap_or_default(), violation["rule"].as_str().unwrap_or_default()))
+            .collect());
+
+        let mut errors = recent(&status["recent_errors"]);
+        for (container, diagnostics) in status["label_diagnostics"].as_object().into_iter().flatten() {
+            for diagnostic in diagnostics.as_array().into_iter().flatten() {
+                errors.push(format!("{}: label {}='{}': {}", container,
+                    diagnostic["label"].as_str().unwrap_or_default(),
+                    diagnostic["value"].as_str().unwrap_or_default(),
+                    diagnostic["problem"].as_str().unwrap_or_default()));
+            }
+        }
+        for (addr, count) in status["address_apply_errors"].as_object().into_iter().flatten() {
+            errors.push(format!("{}: in {} failed writes", addr, count));
+        }
+
+        Self { title, containers, sync, events: recent(&status["recent_events"]), errors }
+    }
+}
+
+/// The panel the arrow keys scroll.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Focus {
+    #[default]
+    Containers,
+    Events,
+    Errors,
+}
+
+impl Focus {
+    fn next(self) -> Self {
+        match self {
+            Focus::Containers => Focus::Events,
+            Focus::Events => Focus::Errors,
+            Focus::Errors => Focus::Containers,
+        }
+    }
+}
+
+/// Which panel has the focus and how far each one is scrolled.
+#[derive(Default)]
+struct View {
+    focus: Focus,
+    containers: TableState,
+    events: ListState,
+    errors: ListState,
+}
+
+impl View {
+    fn scroll(&mut self, by: isize) {
+        let selected = match self.focus {
+            Focus::Containers => self.containers.selected_mut(),
+            Focus::Events => self.events.selected_mut(),
+            Focus::Errors => self.errors.selected_mut(),
+        };
+        // Drawing clamps it to the rows there are
+        *selected = Some(selected.unwrap_or(0).saturating_add_signed(by));
+    }
+}
+
+/// Columns as wide as their widest cell, the last one takes the rest.
+fn widths(headers: &[&str], rows: &[Vec<String>]) -> Vec<Constraint> {
+    let mut widths = headers.iter().map(|header| header.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let last = widths.len() - 1;
+    widths.iter().enumerate()
+        .map(|(i, width)| if i == last { Constraint::Min(*width as u16) } else { Constraint::Length(*width as u16) })
+        .collect()
+}
+
+fn table<'a>(headers: &[&'a str], rows: &'a [Vec<String>]) -> Table<'a> {
+    Table::new(rows.iter().map(|row| Row::new(row.iter().map(String::as_str))), widths(headers, rows))
+        .header(Row::new(headers.to_vec()).style(Style::new().add_modifier(Modifier::BOLD)))
+}
+
+fn block(title: String, focused: bool) -> Block<'static> {
+    let block = Block::bordered().title(title);
+    if focused { block.border_style(Style::new().add_modifier(Modifier::BOLD)) } else { block }
+}
+
+/// Keeps `selected` on one of `len` rows, none while there are none.
+fn clamp(selected: &mut Option<usize>, len: usize) {
+    *selected = match len {
+        0 => None,
+        len => selected.map(|selected| selected.min(len - 1)),
+    };
+}
+
+fn draw(frame: &mut Frame, screen: &Screen, view: &mut View, interval: Duration) {
+    // Two rows of borders and a header row around the sync table
+    let sync_height = if screen.sync.is_empty() { 3 } else { screen.sync.len() as u16 + 3 };
+    let [title, containers, sync, recent, help] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(5),
+        Constraint::Length(sync_height),
+        Constraint::Length(12),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+    let [events, errors] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(recent);
+    let highlight = Style::new().add_modifier(Modifier::REVERSED);
+
+    frame.render_widget(Paragraph::new(screen.title.as_str()), title);
+
+    clamp(view.containers.selected_mut(), screen.containers.len());
+    let rows = table(&["CONTAINER", "SERVICES", "STATE"], &screen.containers)
+        .block(block(format!("Containers ({})", screen.containers.len()), view.focus == Focus::Containers))
+        .row_highlight_style(highlight);
+    frame.render_stateful_widget(rows, containers, &mut view.containers);
+
+    if screen.sync.is_empty() {
+        frame.render_widget(Paragraph::new("in sync").block(block("Sync".to_string(), false)), sync);
+    } else {
+        frame.render_widget(table(&["STATE", "SERVICES"], &screen.sync).block(block("Sync".to_string(), false)), sync);
+    }
+
+    for (lines, area, state, focus, name) in [
+        (&screen.events, events, &mut view.events, Focus::Events, "Recent events"),
+        (&screen.errors, errors, &mut view.errors, Focus::Errors, "Errors"),
+    ] {
+        clamp(state.selected_mut(), lines.len());
+        let list = match lines.is_empty() {
+            true => List::new(["none"]),
+            false => List::new(lines.iter().map(String::as_str)),
+        };
+        let list = list.block(block(name.to_string(), view.focus == focus)).highlight_style(highlight);
+        frame.render_stateful_widget(list, area, state);
+    }
+
+    let keys = format!("refreshing every {}s  Tab: next panel  ↑/↓: scroll  r: refresh  q: quit", interval.as_secs());
+    frame.render_widget(Paragraph::new(keys), help);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use serde_json::json;
+
+    /// One frame of `screen` drawn on a `width` x `height` terminal.
+    fn draw_on(width: u16, height: u16, screen: &Screen) -> Terminal<TestBackend> {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|frame| draw(frame, screen, &mut View::default(), DEFAULT_INTERVAL)).unwrap();
+        terminal
+    }
+
+    fn lines(terminal: &Terminal<TestBackend>) -> Vec<String> {
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| (0..buffer.area.width).map(|x| buffer[(x, y)].symbol()).collect::<String>().trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_screen_from_status() {
+        let status = json!({
+            "mode": "sync",
+            "ready": true,
+            "frozen": true,
+            "services": { "0123456789abcdef": "web,metrics", "fedcba9876543210": "api" },
+            "maintenance": ["web"],
+            "held_back": ["api"],
+            "flapping": ["fedcba9876543210"],
+            "recent_events": [
+                { "time": 3600, "message": "start web-1" },
+                { "time": 3661, "message": "die api-1" },
+            ],
+            "recent_errors": [{ "time": 3700, "message": "Failed to apply config for service api: 503" }],
+            "label_diagnostics": { "web-1": [{ "label": "pingap.http.priority", "value": "high", "problem": "not a number" }] },
+        });
+        let screen = Screen::from_status(&status, 7322);
+        assert_eq!(screen.title, "pingap-docker-provider  mode: sync  ready: yes  FREEZE WINDOW  02:02:02 UTC");
+        assert_eq!(screen.containers, [
+            ["0123456789ab", "web,metrics", "web in maintenance"],
+            ["fedcba987654", "api", "flapping, api held back"],
+        ]);
+        assert_eq!(screen.sync, [["maintenance", "web"], ["held back", "api"]]);
+        assert_eq!(screen.events, ["01:01:01  die api-1", "01:00:00  start web-1"]);
+        assert_eq!(screen.errors, [
+            "01:01:40  Failed to apply config for service api: 503",
+            "web-1: label pingap.http.priority='high': not a number",
+        ]);
+
+        let lines = lines(&draw_on(100, 30, &screen));
+        assert_eq!(lines[0], screen.title);
+        assert!(lines[1].starts_with("┌Containers (2)"));
+        assert!(lines[2].starts_with("│CONTAINER    SERVICES    STATE "));
+        assert!(lines[3].starts_with("│0123456789ab web,metrics web in maintenance "));
+        assert!(lines.iter().any(|line| line.starts_with("│maintenance web")));
+        assert!(lines.iter().any(|line| line.contains("│01:01:01  die api-1")));
+        assert!(lines[29].ends_with("q: quit"));
+    }
+
+    #[test]
+    fn test_empty_status() {
+        let screen = Screen::from_status(&json!({ "mode": "sync", "ready": false }), 0);
+        assert!(screen.title.contains("ready: no"));
+        assert!(screen.containers.is_empty() && screen.sync.is_empty() && screen.errors.is_empty());
+
+        let lines = lines(&draw_on(80, 24, &screen));
+        assert!(lines[1].starts_with("┌Containers (0)"));
+        assert!(lines.iter().any(|line| line.starts_with("│in sync")));
+        assert!(lines.iter().any(|line| line.starts_with("│none")));
+    }
+
+    #[test]
+    fn test_scrolling_stays_on_the_rows() {
+        let mut view = View::default();
+        view.scroll(-1);
+        view.scroll(5);
+        clamp(view.containers.selected_mut(), 2);
+        assert_eq!(view.containers.selected(), Some(1));
+        clamp(view.containers.selected_mut(), 0);
+        assert_eq!(view.containers.selected(), None);
+
+        view.focus = view.focus.next();
+        view.scroll(1);
+        assert_eq!(view.events.selected(), Some(1));
+        assert_eq!(view.focus.next().next(), Focus::Containers);
+    }
+}
@@ -0,0 +1,328 @@
+//! `pingap-docker-provider verify <service>`: sends a request through Pingap
+//! the way a client would, catching what config-level checks miss, such as
+//! Pingap not reaching the containers' network or a firewall in between.
+
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::header::{HeaderMap, COOKIE, HOST, LOCATION};
+use reqwest::{redirect, Client, Method, StatusCode, Url};
+use crate::export::ProviderState;
+use crate::maintenance::{local_addr, status_request};
+use crate::rule::{self, HostMatcher, PathMatcher};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Response header Pingap is expected to report the upstream address it
+/// proxied to in, unless `--upstream-header` names another.
+const DEFAULT_UPSTREAM_HEADER: &str = "X-Upstream-Addr";
+
+/// The request sent through Pingap: a host and path the service's rule
+/// routes, with the headers and cookies it requires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    /// `None` for rules matching any host, the proxy's own is sent then
+    pub host: Option<String>,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Probe {
+    /// The first exact host and path of `rule`. Regexes can't be turned into
+    /// a request, `host` and `path` stand in for them.
+    pub fn for_rule(rule: &str, host: Option<&str>, path: Option<&str>) -> Result<Self> {
+        let route = rule::parse_rule(rule)?;
+        let exact = route.hosts.iter().find_map(|matcher| match matcher {
+            HostMatcher::Exact(host) => Some(host.clone()),
+            _ => None,
+        });
+        let wildcard = route.hosts.iter().find_map(|matcher| match matcher {
+            HostMatcher::Wildcard(domain) => Some(format!("verify.{}", domain)),
+            _ => None,
+        });
+        let host = match (host, exact.or(wildcard)) {
+            (Some(host), _) => Some(host.to_string()),
+            (None, Some(host)) => Some(host),
+            (None, None) if route.hosts.is_empty() => None,
+            (None, None) => bail!("The rule matches hosts by regex only, pass --host"),
+        };
+
+        let routed = route.paths.iter().find_map(|matcher| match matcher {
+            PathMatcher::Prefix(path) | PathMatcher::Exact(path) => Some(path.clone()),
+            PathMatcher::Regex(_) => None,
+        });
+        let path = match (path, routed) {
+            (Some(path), _) => path.to_string(),
+            (None, Some(path)) => path,
+            (None, None) if route.paths.is_empty() => "/".to_string(),
+            (None, None) => bail!("The rule matches paths by regex only, pass --path"),
+        };
+
+        let mut headers = route.headers.iter()
+            .map(|header| (header.name.clone(), header.value.clone()))
+            .collect::<Vec<_>>();
+        if !route.cookies.is_empty() {
+            let cookies = route.cookies.iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>();
+            headers.push((COOKIE.to_string(), cookies.join("; ")));
+        }
+        Ok(Self { host, path, headers })
+    }
+
+    /// The URL requested through the proxy at `proxy`.
+    fn url(&self, proxy: &Url) -> Result<Url> {
+        let mut url = proxy.clone();
+        if let Some(host) = &self.host {
+            url.set_host(Some(host)).context(format!("Invalid host {}", host))?;
+        }
+        Ok(url.join(&self.path)?)
+    }
+
+    /// Sends the request to Pingap listening at `proxy`, with the host
+    /// resolving to the proxy so TLS sees the routed name.
+    async fn through(&self, proxy: &Url) -> Result<Answer> {
+        let url = self.url(proxy)?;
+        let mut client = Client::builder();
+        if let Some(host) = &self.host {
+            let addr = proxy.socket_addrs(|| None)?.into_iter().next()
+                .ok_or_else(|| anyhow!("{} doesn't resolve to an address", proxy))?;
+            client = client.resolve(host, addr);
+        }
+        self.send(client, url.as_str(), None).await
+    }
+
+    /// The path a replica gets once Pingap removed `strip_prefix` from it.
+    fn upstream_path(&self, strip_prefix: Option<&str>) -> String {
+        let Some(prefix) = strip_prefix.map(|prefix| prefix.trim_end_matches('/')) else {
+            return self.path.clone();
+        };
+        match self.path.strip_prefix(prefix) {
+            Some("") => "/".to_string(),
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            _ => self.path.clone(),
+        }
+    }
+
+    /// Sends the request straight to a replica's upstream address, with the
+    /// path as Pingap rewrites it.
+    async fn direct(&self, addr: &str, strip_prefix: Option<&str>) -> Result<Answer> {
+        let url = format!("http://{}{}", addr, self.upstream_path(strip_prefix));
+        self.send(Client::builder(), &url, self.host.as_deref()).await
+    }
+
+    async fn send(&self, client: reqwest::ClientBuilder, url: &str, host: Option<&str>) -> Result<Answer> {
+        let client = client.redirect(redirect::Policy::none()).timeout(TIMEOUT).build()?;
+        let mut request = client.get(url);
+        if let Some(host) = host {
+            request = request.header(HOST, host);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        let resp = request.send().await.context(format!("GET {} failed", url))?;
+        let status = resp.status();
+        let location = resp.headers().get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string);
+        let headers = resp.headers().clone();
+        Ok(Answer { status, location, headers })
+    }
+}
+
+/// A response to the probe.
+#[derive(Debug)]
+struct Answer {
+    status: StatusCode,
+    location: Option<String>,
+    headers: HeaderMap,
+}
+
+fn describe(answer: &Result<Answer>) -> String {
+    match answer {
+        Ok(Answer { status, location: Some(location), .. }) if status.is_redirection() => format!("{} to {}", status, location),
+        Ok(answer) => answer.status.to_string(),
+        Err(e) => format!("{:#}", e),
+    }
+}
+
+/// `pingap-docker-provider verify <service> [--host <host>] [--path <path>]
+/// [--upstream-header <name>]`: requests a service applied by the running
+/// provider (`STATUS_ADDR`) through Pingap (`PINGAP_PROXY_URL`) and from
+/// each of its replicas, and fails unless Pingap answers with a 2xx. The
+/// container that served it is only named when Pingap reports the upstream
+/// address in the `--upstream-header` response header.
+pub async fn run(status_addr: Option<&str>, token: Option<&str>, proxy_url: Option<&str>, args: &[String]) -> Result<()> {
+    let usage = || anyhow!("Usage: pingap-docker-provider verify <service> [--host <host>] [--path <path>] [--upstream-header <name>]");
+    let (service, options) = args.split_first().ok_or_else(usage)?;
+    if service.starts_with("--") {
+        return Err(usage());
+    }
+    let (mut host, mut path, mut upstream_header) = (None, None, DEFAULT_UPSTREAM_HEADER);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--host" => host = Some(options.next().ok_or_else(usage)?.as_str()),
+            "--path" => path = Some(options.next().ok_or_else(usage)?.as_str()),
+            "--upstream-header" => upstream_header = options.next().ok_or_else(usage)?.as_str(),
+            _ => return Err(usage()),
+        }
+    }
+    let addr = status_addr
+        .ok_or_else(|| anyhow!("STATUS_ADDR must be set to reach the running provider"))?;
+    let proxy_url = proxy_url
+        .ok_or_else(|| anyhow!("PINGAP_PROXY_URL must be set to send requests through Pingap"))?;
+    let proxy = Url::parse(proxy_url).context(format!("Invalid PINGAP_PROXY_URL {}", proxy_url))?;
+
+    let url = format!("http://{}/state", local_addr(addr));
+    let resp = status_request(Method::GET, &url, token).send().await
+        .context(format!("Failed to reach the provider at {}", url))?;
+    let status = resp.status();
+    let text = resp.text().await.unwrap_or_default();
+    if !status.is_success() {
+        bail!("Provider refused the request ({}): {}", status, text.trim());
+    }
+    let state = ProviderState::parse(&text)?;
+    let tracked = state.services.get(service)
+        .ok_or_else(|| anyhow!("The provider doesn't track a service {}", service))?;
+    if !tracked.applied {
+        bail!("{} isn't applied to Pingap yet, see the status API for why", service);
+    }
+    if tracked.config.maintenance || state.maintenance.contains(service) {
+        bail!("{} is in maintenance, Pingap answers with the maintenance response", service);
+    }
+    let probe = Probe::for_rule(&tracked.config.location.rule, host, path)
+        .context(format!("Can't build a request for {}", service))?;
+
+    let proxied = probe.through(&proxy).await;
+    println!("GET {} through {}: {}", probe.url(&proxy)?, proxy, describe(&proxied));
+    let strip_prefix = tracked.config.middleware_config.as_ref().and_then(|m| m.strip_prefix.as_deref());
+    let mut replicas_serve = false;
+    for (container, addrs) in &tracked.containers {
+        let container = container.chars().take(12).collect::<String>();
+        for addr in addrs {
+            let direct = probe.direct(addr, strip_prefix).await;
+            println!("  {} {}: {}", container, addr, describe(&direct));
+            replicas_serve |= matches!(&direct, Ok(answer) if answer.status.is_success());
+        }
+    }
+
+    match proxied {
+        Ok(answer) if answer.status.is_success() => {
+            let upstream = answer.headers.get(upstream_header).and_then(|value| value.to_str().ok()).map(str::trim);
+            let served_by = upstream.and_then(|upstream| tracked.containers.iter()
+                .find(|(_, addrs)| addrs.iter().any(|addr| addr == upstream))
+                .map(|(container, _)| container.chars().take(12).collect::<String>()));
+            match (upstream, served_by) {
+                (Some(upstream), Some(container)) => println!("{} serves {} through Pingap, from container {} ({})", service, answer.status, container, upstream),
+                (Some(upstream), None) => bail!("{} serves {} through Pingap, but from {}, which is none of its replicas: another route overlaps it or its upstream is stale", service, answer.status, upstream),
+                (None, _) => println!("{} serves {} through Pingap; the container that served it can't be identified, Pingap doesn't report the upstream address in {}", service, answer.status, upstream_header),
+            }
+            Ok(())
+        },
+        _ if replicas_serve => Err(anyhow!("{} doesn't serve 2xx through Pingap while its replicas do directly: Pingap can't reach them (network, firewall) or routes the request elsewhere", service)),
+        _ => Err(anyhow!("{} doesn't serve 2xx through Pingap, nor do its replicas directly", service)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use crate::export::{ServiceState, STATE_VERSION};
+    use crate::models::PingapServiceConfigBuilder;
+    use crate::status::{self, Status};
+
+    #[test]
+    fn test_probe_for_rule() {
+        let probe = Probe::for_rule("Host(`*.example.com`) && PathPrefix(`/api`) && Header(`X-Beta`, `true`) && Cookie(`beta`, `1`)", None, None).unwrap();
+        assert_eq!(probe.host.as_deref(), Some("verify.example.com"));
+        assert_eq!(probe.path, "/api");
+        assert_eq!(probe.headers, vec![
+            ("X-Beta".to_string(), "true".to_string()),
+            ("cookie".to_string(), "beta=1".to_string()),
+        ]);
+
+        assert_eq!(probe.upstream_path(None), "/api");
+        assert_eq!(probe.upstream_path(Some("/api/")), "/");
+        let probe = Probe::for_rule("PathPrefix(`/api/users`)", None, None).unwrap();
+        assert_eq!(probe.upstream_path(Some("/api")), "/users");
+        assert_eq!(probe.upstream_path(Some("/ap")), "/api/users");
+
+        let probe = Probe::for_rule("PathPrefix(`/`)", None, None).unwrap();
+        assert_eq!(probe.host, None);
+        let proxy = Url::parse("http://pingap:8080").unwrap();
+        assert_eq!(probe.url(&proxy).unwrap().as_str(), "http://pingap:8080/");
+
+        assert!(Probe::for_rule("HostRegexp(`^app[0-9]+\\.local$`)", None, None).is_err());
+        let probe = Probe::for_rule("HostRegexp(`^app[0-9]+\\.local$`) && PathRegexp(`^/v[0-9]+`)", Some("app1.local"), Some("/v2")).unwrap();
+        assert_eq!(probe.url(&proxy).unwrap().as_str(), "http://app1.local:8080/v2");
+    }
+
+    #[tokio::test]
+    async fn test_verify_through_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let status = Arc::new(Status::new("sync"));
+        tokio::spawn(status::serve(listener, status.clone()));
+
+        let mut proxy = mockito::Server::new_async().await;
+        let mut replica = mockito::Server::new_async().await;
+        let replica_mock = replica.mock("GET", "/api")
+            .match_header("host", "web.local")
+            .with_body("hello")
+            .create_async().await;
+        let config = PingapServiceConfigBuilder::new("web", vec![], "Host(`web.local`) && PathPrefix(`/api`)").build();
+        status.set_provider_state(ProviderState {
+            version: STATE_VERSION,
+            services: BTreeMap::from([("web".to_string(), ServiceState {
+                config,
+                containers: BTreeMap::from([("3f2a1b4c5d6e7f80".to_string(), vec![replica.host_with_port()])]),
+                weights: BTreeMap::new(),
+                applied: true,
+                tombstone_secs: None,
+                label_diagnostics: Vec::new(),
+            })]),
+            ..Default::default()
+        });
+        let proxy_url = proxy.url();
+        let verify = |service: &str| {
+            let (addr, proxy_url) = (addr.clone(), proxy_url.clone());
+            let args = vec![service.to_string()];
+            async move { run(Some(&addr), None, Some(&proxy_url), &args).await }
+        };
+
+        let routed = proxy.mock("GET", "/api")
+            .match_header("host", mockito::Matcher::Regex("^web.local:[0-9]+$".to_string()))
+            .with_header(DEFAULT_UPSTREAM_HEADER, &replica.host_with_port())
+            .with_body("hello")
+            .create_async().await;
+        verify("web").await.unwrap();
+        routed.assert_async().await;
+        replica_mock.assert_async().await;
+        routed.remove_async().await;
+
+        // Answered by an address that is none of the service's replicas
+        let misrouted = proxy.mock("GET", "/api")
+            .with_header(DEFAULT_UPSTREAM_HEADER, "10.9.9.9:8080")
+            .with_body("hello")
+            .create_async().await;
+        let error = verify("web").await.unwrap_err().to_string();
+        assert!(error.contains("10.9.9.9:8080, which is none of its replicas"), "{}", error);
+        misrouted.remove_async().await;
+
+        // Without the header the serving container is unknown, which doesn't fail the check
+        let routed = proxy.mock("GET", "/api").with_body("hello").create_async().await;
+        verify("web").await.unwrap();
+        routed.remove_async().await;
+
+        // The replica answers, Pingap doesn't
+        proxy.mock("GET", "/api").with_status(502).create_async().await;
+        let error = verify("web").await.unwrap_err().to_string();
+        assert!(error.contains("while its replicas do directly"), "{}", error);
+
+        assert!(verify("api").await.is_err());
+        assert!(run(Some(&addr), None, None, &["web".to_string()]).await.is_err());
+        assert!(run(Some(&addr), None, Some(&proxy_url), &["web".to_string(), "--host".to_string()]).await.is_err());
+    }
+}
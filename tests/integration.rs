@@ -0,0 +1,271 @@
+//! End-to-end tests: the provider binary against a real Docker daemon and an
+//! in-process fake of Pingap's Admin API. They need a reachable daemon (the
+//! local socket or `DOCKER_HOST`) that can pull `busybox`, so they only build
+//! with `cargo test --features integration --test integration`.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bollard::Docker;
+use bollard::container::{Config, CreateContainerOptions, RemoveContainerOptions};
+use bollard::image::CreateImageOptions;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, Command};
+
+const IMAGE: &str = "busybox:latest";
+const WAIT: Duration = Duration::from_secs(30);
+
+/// Pingap's Admin API as far as the provider uses it: per-resource
+/// GET/POST/DELETE and GET/PUT of the full config, kept in memory.
+#[derive(Clone)]
+struct FakePingap {
+    url: String,
+    config: Arc<Mutex<Value>>,
+}
+
+impl FakePingap {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let config = Arc::new(Mutex::new(json!({ "upstreams": {}, "locations": {} })));
+        let state = config.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, &state).await;
+                });
+            }
+        });
+        Self { url, config }
+    }
+
+    fn resource(&self, section: &str, name: &str) -> Option<Value> {
+        self.config.lock().unwrap()[section].get(name).cloned()
+    }
+
+    /// Polls until `check` accepts the upstream of `service` (None when absent).
+    async fn wait_for(&self, service: &str, what: &str, check: impl Fn(Option<Value>) -> bool) {
+        let deadline = tokio::time::Instant::now() + WAIT;
+        while !check(self.resource("upstreams", service)) {
+            if tokio::time::Instant::now() > deadline {
+                panic!("Timed out waiting for {} of {}, Pingap has: {}", what, service, self.config.lock().unwrap());
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, state: &Mutex<Value>) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let (head_len, body_len) = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..pos]).to_lowercase();
+            let body_len = head.lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|len| len.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            break (pos + 4, body_len);
+        }
+    };
+    while buf.len() < head_len + body_len {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let body = serde_json::from_slice::<Value>(&buf[head_len..]).unwrap_or(Value::Null);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let (status, response) = handle(&method, &path, body, &mut state.lock().unwrap());
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, response.len(), response
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn handle(method: &str, path: &str, body: Value, config: &mut Value) -> (&'static str, String) {
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    match (method, segments.as_slice()) {
+        ("GET", ["config"]) => ("200 OK", config.to_string()),
+        ("PUT", ["config"]) => {
+            *config = body;
+            ("200 OK", "{}".to_string())
+        }
+        ("GET", [section, name]) => match config[*section].get(*name) {
+            Some(resource) => ("200 OK", resource.to_string()),
+            None => ("404 Not Found", "{}".to_string()),
+        },
+        ("POST", [section, name]) => {
+            if !config[*section].is_object() {
+                config[*section] = json!({});
+            }
+            config[*section][*name] = body;
+            ("200 OK", "{}".to_string())
+        }
+        ("DELETE", [section, name]) => match config[*section].as_object_mut().and_then(|s| s.remove(*name)) {
+            Some(_) => ("200 OK", "{}".to_string()),
+            None => ("404 Not Found", "{}".to_string()),
+        },
+        _ => ("404 Not Found", "{}".to_string()),
+    }
+}
+
+async fn docker() -> Docker {
+    let docker = Docker::connect_with_local_defaults().expect("Docker daemon needed for integration tests");
+    docker.ping().await.expect("Docker daemon needed for integration tests");
+    let mut pull = docker.create_image(Some(CreateImageOptions { from_image: IMAGE, ..Default::default() }), None, None);
+    while let Some(progress) = pull.next().await {
+        progress.expect("Failed to pull the test image");
+    }
+    docker
+}
+
+/// A running labeled container, removed again by [`remove`](Self::remove).
+struct TestContainer {
+    id: String,
+}
+
+impl TestContainer {
+    async fn start(docker: &Docker, name: &str, labels: &[(&str, &str)]) -> Self {
+        // Left over by an earlier run that panicked
+        let _ = docker.remove_container(name, Some(RemoveContainerOptions { force: true, ..Default::default() })).await;
+        let config = Config {
+            image: Some(IMAGE.to_string()),
+            cmd: Some(vec!["sleep".to_string(), "300".to_string()]),
+            labels: Some(labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>()),
+            ..Default::default()
+        };
+        let created = docker.create_container(Some(CreateContainerOptions { name, platform: None }), config).await
+            .expect("Failed to create test container");
+        docker.start_container::<String>(&created.id, None).await.expect("Failed to start test container");
+        Self { id: created.id }
+    }
+
+    async fn ip(&self, docker: &Docker) -> String {
+        let inspect = docker.inspect_container(&self.id, None).await.unwrap();
+        inspect.network_settings.and_then(|ns| ns.networks).into_iter().flatten()
+            .find_map(|(_, endpoint)| endpoint.ip_address.filter(|ip| !ip.is_empty()))
+            .expect("Test container has no IP address")
+    }
+
+    async fn remove(self, docker: &Docker) {
+        docker.remove_container(&self.id, Some(RemoveContainerOptions { force: true, ..Default::default() })).await
+            .expect("Failed to remove test container");
+    }
+}
+
+fn start_provider(pingap: &FakePingap) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_pingap-docker-provider"))
+        .env("PINGAP_ADMIN_URL", &pingap.url)
+        .env("LOG_LEVEL", "debug")
+        .env("COMPOSE_STOP_GROUP_SECS", "0")
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to start the provider")
+}
+
+/// Service names unique per test and run, other labeled containers on the daemon don't interfere.
+fn service_name(test: &str) -> String {
+    format!("it-{}-{}", std::process::id(), test)
+}
+
+fn web_labels(service: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("pingap.enable", "true".to_string()),
+        ("pingap.service.name", service.to_string()),
+        ("pingap.service.port", "80".to_string()),
+        ("pingap.http.host", format!("{}.local", service)),
+    ]
+}
+
+fn borrowed<'a>(labels: &'a [(&'static str, String)]) -> Vec<(&'a str, &'a str)> {
+    labels.iter().map(|(k, v)| (*k, v.as_str())).collect()
+}
+
+#[tokio::test]
+async fn started_container_is_routed_and_removed_on_stop() {
+    let docker = docker().await;
+    let pingap = FakePingap::start().await;
+    let _provider = start_provider(&pingap);
+    let service = service_name("events");
+
+    // Give the provider time to subscribe before the container starts
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let labels = web_labels(&service);
+    let container = TestContainer::start(&docker, &service, &borrowed(&labels)).await;
+    let addr = format!("{}:80", container.ip(&docker).await);
+
+    pingap.wait_for(&service, "the upstream", |upstream| {
+        upstream.is_some_and(|u| u["addrs"] == json!([addr.clone()]))
+    }).await;
+    let location = pingap.resource("locations", &service).expect("location written with the upstream");
+    assert_eq!(location["upstream"], service.as_str());
+    assert_eq!(location["host"], format!("{}.local", service));
+
+    container.remove(&docker).await;
+    pingap.wait_for(&service, "removal", |upstream| upstream.is_none()).await;
+    assert!(pingap.resource("locations", &service).is_none());
+}
+
+#[tokio::test]
+async fn running_container_is_picked_up_by_initial_sync() {
+    let docker = docker().await;
+    let pingap = FakePingap::start().await;
+    let service = service_name("initial");
+
+    let labels = web_labels(&service);
+    let container = TestContainer::start(&docker, &service, &borrowed(&labels)).await;
+    let _provider = start_provider(&pingap);
+
+    pingap.wait_for(&service, "the upstream", |upstream| upstream.is_some()).await;
+    let upstream = pingap.resource("upstreams", &service).unwrap();
+    assert_eq!(upstream["remark"], "managed-by: pingap-docker-provider");
+
+    container.remove(&docker).await;
+}
+
+#[tokio::test]
+async fn replicas_share_one_upstream() {
+    let docker = docker().await;
+    let pingap = FakePingap::start().await;
+    let _provider = start_provider(&pingap);
+    let service = service_name("replicas");
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let labels = web_labels(&service);
+    let first = TestContainer::start(&docker, &format!("{}-1", service), &borrowed(&labels)).await;
+    let second = TestContainer::start(&docker, &format!("{}-2", service), &borrowed(&labels)).await;
+
+    pingap.wait_for(&service, "both replicas", |upstream| {
+        upstream.is_some_and(|u| u["addrs"].as_array().is_some_and(|addrs| addrs.len() == 2))
+    }).await;
+
+    // One replica leaving only shrinks the upstream
+    first.remove(&docker).await;
+    pingap.wait_for(&service, "one replica", |upstream| {
+        upstream.is_some_and(|u| u["addrs"].as_array().is_some_and(|addrs| addrs.len() == 1))
+    }).await;
+    assert!(pingap.resource("locations", &service).is_some());
+
+    second.remove(&docker).await;
+    pingap.wait_for(&service, "removal", |upstream| upstream.is_none()).await;
+}